@@ -0,0 +1,240 @@
+//! Deciding which requests to the server may do what, through an `Authorizer`. The
+//! server asks its authorizer before every operation on the store; embedders of the
+//! server pass their own, and `TokenAuthorizer` covers static bearer tokens.
+
+use std::{collections::HashMap, future::Future, net::SocketAddr, pin::Pin};
+
+use actix_web::{http::header::AUTHORIZATION, HttpRequest};
+use serde::Deserialize;
+
+/// Who sent a request, as far as the server can tell. Interpreting this is left
+/// to the `Authorizer`.
+#[derive(Clone, Debug, Default)]
+pub struct Identity {
+    pub peer: Option<SocketAddr>,
+    /// The token from an `Authorization: Bearer <token>` header.
+    pub bearer_token: Option<String>,
+}
+
+impl Identity {
+    pub fn from_request(req: &HttpRequest) -> Self {
+        let bearer_token = req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(|token| token.trim().to_owned());
+        Self {
+            peer: req.peer_addr(),
+            bearer_token,
+        }
+    }
+}
+
+/// What a request wants to do with the key it names.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    Read,
+    /// Listing keys. The key passed along is the prefix of the listed keys.
+    List,
+    Write,
+    Delete,
+    /// Deleting every key with a prefix. The key passed along is the prefix.
+    DeletePrefix,
+    /// Administrative operations, such as freezing writes. The key passed along is
+    /// empty.
+    Admin,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Decision {
+    Allow,
+    Deny,
+}
+
+pub type DecisionFuture = Pin<Box<dyn Future<Output = Decision> + Send>>;
+
+/// Decides whether a request may perform an operation on a key. Every handler
+/// which touches the store asks the authorizer first, and responds with 403 if
+/// it denies the request.
+///
+/// Any `Fn(Identity, Operation, String) -> impl Future<Output = Decision>` is an
+/// authorizer, so a policy engine can be plugged in with a closure.
+pub trait Authorizer: Send + Sync {
+    fn authorize(&self, identity: Identity, operation: Operation, key: String) -> DecisionFuture;
+}
+
+impl<F, Fut> Authorizer for F
+where
+    F: Fn(Identity, Operation, String) -> Fut + Send + Sync,
+    Fut: Future<Output = Decision> + Send + 'static,
+{
+    fn authorize(&self, identity: Identity, operation: Operation, key: String) -> DecisionFuture {
+        Box::pin(self(identity, operation, key))
+    }
+}
+
+/// Allows everything, which is the default.
+pub struct AllowAll;
+
+impl Authorizer for AllowAll {
+    fn authorize(&self, _: Identity, _: Operation, _: String) -> DecisionFuture {
+        Box::pin(async { Decision::Allow })
+    }
+}
+
+/// Lets the holder of a bearer token perform some operations on the keys starting
+/// with a prefix, see `TokenAuthorizer`.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Grant {
+    /// The bearer token of the requests the grant applies to.
+    pub token: String,
+    /// The keys the grant covers. Listings and deletions of prefixes are covered if
+    /// their prefix starts with this.
+    #[serde(default)]
+    pub prefix: String,
+    pub operations: Vec<Operation>,
+}
+
+/// Allows what the `Grant`s of a request's bearer token allow, and denies everything
+/// else, including all requests without a token.
+#[derive(Debug, Default)]
+pub struct TokenAuthorizer {
+    grants: HashMap<String, Vec<Grant>>,
+}
+
+impl TokenAuthorizer {
+    pub fn new(grants: impl IntoIterator<Item = Grant>) -> Self {
+        let mut by_token: HashMap<String, Vec<Grant>> = HashMap::new();
+        for grant in grants {
+            by_token.entry(grant.token.clone()).or_default().push(grant);
+        }
+        Self { grants: by_token }
+    }
+
+    fn decide(&self, identity: &Identity, operation: Operation, key: &str) -> Decision {
+        let grants = identity
+            .bearer_token
+            .as_ref()
+            .and_then(|token| self.grants.get(token));
+        let allowed = grants
+            .into_iter()
+            .flatten()
+            .any(|grant| grant.operations.contains(&operation) && key.starts_with(&grant.prefix));
+        if allowed {
+            Decision::Allow
+        } else {
+            Decision::Deny
+        }
+    }
+}
+
+impl Authorizer for TokenAuthorizer {
+    fn authorize(&self, identity: Identity, operation: Operation, key: String) -> DecisionFuture {
+        let decision = self.decide(&identity, operation, &key);
+        Box::pin(async move { decision })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_closure_authorizer() {
+        let authorizer = |identity: Identity, operation, key: String| async move {
+            let is_admin = identity.bearer_token.as_deref() == Some("secret");
+            if operation == Operation::Read || key.starts_with("public/") || is_admin {
+                Decision::Allow
+            } else {
+                Decision::Deny
+            }
+        };
+        let authorizer: &dyn Authorizer = &authorizer;
+
+        let anonymous =
+            Identity::from_request(&actix_web::test::TestRequest::default().to_http_request());
+        let admin = Identity::from_request(
+            &actix_web::test::TestRequest::default()
+                .insert_header((AUTHORIZATION, "Bearer secret"))
+                .to_http_request(),
+        );
+        assert_eq!(admin.bearer_token.as_deref(), Some("secret"));
+
+        let decide = |identity: &Identity, operation, key: &str| {
+            authorizer.authorize(identity.clone(), operation, key.to_owned())
+        };
+        assert_eq!(
+            decide(&anonymous, Operation::Read, "a").await,
+            Decision::Allow
+        );
+        assert_eq!(
+            decide(&anonymous, Operation::Write, "public/a").await,
+            Decision::Allow
+        );
+        assert_eq!(
+            decide(&anonymous, Operation::Write, "a").await,
+            Decision::Deny
+        );
+        assert_eq!(decide(&admin, Operation::Write, "a").await, Decision::Allow);
+        assert_eq!(
+            AllowAll
+                .authorize(anonymous, Operation::Admin, String::new())
+                .await,
+            Decision::Allow
+        );
+    }
+
+    #[tokio::test]
+    async fn test_token_authorizer() {
+        let grant = |token: &str, prefix: &str, operations: &[Operation]| Grant {
+            token: token.to_owned(),
+            prefix: prefix.to_owned(),
+            operations: operations.to_vec(),
+        };
+        let authorizer = TokenAuthorizer::new([
+            grant("reader", "", &[Operation::Read, Operation::List]),
+            grant("writer", "users/", &[Operation::Write, Operation::Delete]),
+            grant("writer", "", &[Operation::Read]),
+        ]);
+        let identity = |token: Option<&str>| Identity {
+            peer: None,
+            bearer_token: token.map(str::to_owned),
+        };
+        let decide = |token, operation, key: &str| {
+            authorizer.authorize(identity(token), operation, key.to_owned())
+        };
+
+        assert_eq!(decide(None, Operation::Read, "a").await, Decision::Deny);
+        assert_eq!(
+            decide(Some("other"), Operation::Read, "a").await,
+            Decision::Deny
+        );
+        assert_eq!(
+            decide(Some("reader"), Operation::List, "users/").await,
+            Decision::Allow
+        );
+        assert_eq!(
+            decide(Some("reader"), Operation::Write, "a").await,
+            Decision::Deny
+        );
+        assert_eq!(
+            decide(Some("writer"), Operation::Write, "users/1").await,
+            Decision::Allow
+        );
+        assert_eq!(
+            decide(Some("writer"), Operation::Read, "a").await,
+            Decision::Allow
+        );
+        assert_eq!(
+            decide(Some("writer"), Operation::Write, "a").await,
+            Decision::Deny
+        );
+        assert_eq!(
+            decide(Some("writer"), Operation::Admin, "").await,
+            Decision::Deny
+        );
+    }
+}
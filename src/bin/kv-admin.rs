@@ -0,0 +1,80 @@
+//! Administration of database files, without running the server.
+//!
+//! `kv-admin inspect <path>` prints the settings recorded in a database file's header.
+
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::SystemTime,
+};
+
+use clap::{Parser, Subcommand};
+
+use polling_test::kv::{header::FORMAT_VERSION, migration::read_header, result::KVResult};
+
+#[derive(Parser)]
+#[command(version, about = "Administration of kv-api database files")]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Print the settings recorded in the header of a database file.
+    Inspect {
+        /// The database file, which is only read.
+        path: PathBuf,
+    },
+}
+
+fn inspect(path: &Path) -> KVResult<()> {
+    let Some((header, len)) = read_header(File::open(path)?)? else {
+        println!("{} is empty", path.display());
+        return Ok(());
+    };
+    println!("format version:        {}", header.version);
+    if header.version > FORMAT_VERSION {
+        println!("(newer than version {} of this build)", FORMAT_VERSION);
+        return Ok(());
+    }
+    if header.version < FORMAT_VERSION {
+        println!("(migrated to version {} when opened)", FORMAT_VERSION);
+    }
+    println!("header length:         {} bytes", len);
+    println!("key normalization:     {}", header.key_normalization);
+    match header.compression_threshold {
+        Some(threshold) => println!("compression threshold: {} bytes", threshold),
+        None => println!("compression threshold: not recorded"),
+    }
+    println!(
+        "encrypted:             {}",
+        if header.encrypted { "yes" } else { "no" }
+    );
+    let created_at = header
+        .created_at
+        .and_then(|created_at| created_at.duration_since(SystemTime::UNIX_EPOCH).ok());
+    match created_at {
+        Some(since_epoch) => println!(
+            "created at:            {} (seconds since the Unix epoch)",
+            since_epoch.as_secs()
+        ),
+        None => println!("created at:            not recorded"),
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+    let result = match &args.command {
+        Command::Inspect { path } => inspect(path),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("kv-admin: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,337 @@
+//! Buckets: key spaces of their own, addressed as `/_buckets/{bucket}/{key}`, so
+//! that applications sharing a store don't have to agree on how to name their keys.
+//! Their paths are below `/_buckets/`, like those of other endpoints, so that every
+//! other path, like `/users/42/avatar`, addresses a key as it is.
+//!
+//! A bucket's keys are stored prefix-encoded, as `_bucket_keys/{bucket}/{key}`,
+//! which is also what they're authorized, listed and counted by, e.g. in
+//! `/_scan?prefix=_bucket_keys/{bucket}/`. Like all keys starting with `_`, they can
+//! only be written through the endpoints they belong to, here those of the bucket.
+//! So keys which were stored as `{bucket}/...` before don't become the bucket's, and
+//! a namespace of the same name (see `namespaces`) is a different key space.
+//! Reading or writing a key of a bucket which doesn't exist is answered with 404 Not
+//! Found. `{key}` is the rest of the path, so it may contain `/` as well.
+//!
+//! Every bucket is recorded as a marker entry below `BUCKET_MARKER_PREFIX`, whose
+//! value is the time it was created at, so buckets survive restarts. Removing a
+//! bucket removes its keys as well, which needs a capability token (see
+//! `capabilities`).
+
+use std::{collections::BTreeMap, sync::RwLock, time::SystemTime};
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use serde::Serialize;
+
+use crate::capabilities::{check_capability, Action};
+use crate::write_queue::{SetOutcome, WriteError};
+use crate::{authorize, check_writable, write_error_response, AppState, GetQuery};
+use polling_test::auth::Operation;
+use polling_test::kv::{
+    backend::StorageBackend, codec::to_millis, entry::Entry, result::KVResult, store::KVStore,
+};
+
+/// Marker entries of buckets are stored under this prefix, followed by the name.
+const BUCKET_MARKER_PREFIX: &str = "_buckets/";
+/// The keys of buckets are stored under this prefix, followed by the name and `/`.
+const BUCKET_KEY_PREFIX: &str = "_bucket_keys/";
+
+fn marker_key(name: &str) -> String {
+    format!("{}{}", BUCKET_MARKER_PREFIX, name)
+}
+
+pub(crate) fn key_prefix(name: &str) -> String {
+    format!("{}{}/", BUCKET_KEY_PREFIX, name)
+}
+
+/// When the bucket with the given marker was created. Markers with invalid values
+/// are treated as if it was at the Unix epoch.
+fn created_at(marker: &Entry) -> u64 {
+    std::str::from_utf8(&marker.value)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0)
+}
+
+/// The buckets, and when they were created, in milliseconds since the Unix epoch.
+pub(crate) struct Buckets {
+    buckets: RwLock<BTreeMap<String, u64>>,
+}
+
+impl Buckets {
+    /// Loads the buckets recorded in the store.
+    pub(crate) async fn load<B: StorageBackend>(store: &KVStore<B>) -> KVResult<Self> {
+        let keys: Vec<String> = store
+            .scan_prefix(BUCKET_MARKER_PREFIX)
+            .map(|(key, _)| key)
+            .collect();
+        let mut buckets = BTreeMap::new();
+        for key in keys {
+            if let Some(marker) = store.peek(&key).await? {
+                let name = &key[BUCKET_MARKER_PREFIX.len()..];
+                buckets.insert(name.to_owned(), created_at(&marker));
+            }
+        }
+        Ok(Self {
+            buckets: RwLock::new(buckets),
+        })
+    }
+
+    fn contains(&self, name: &str) -> bool {
+        self.buckets.read().unwrap().contains_key(name)
+    }
+}
+
+/// Why `name` can't be the name of a bucket, or of another `kind` of key prefix like
+/// namespaces, if it can't.
+pub(crate) fn check_name(kind: &str, name: &str) -> Result<(), String> {
+    if name.starts_with('_') {
+        // Like `/_keys`, those paths are taken, and the keys reserved.
+        return Err(format!("Invalid {} name: Can't start with '_'", kind));
+    }
+    if name.is_empty() || name.contains('/') {
+        return Err(format!(
+            "Invalid {} name: Must be a single path segment",
+            kind
+        ));
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct Bucket {
+    name: String,
+    /// Milliseconds since the Unix epoch.
+    created_at: u64,
+    /// Number of values in the bucket.
+    values: usize,
+    /// Size of the keys and values, like `/_admin/usage` reports it.
+    bytes: u64,
+}
+
+/// Reports the buckets with `names`, skipping those which were removed meanwhile.
+async fn report<B: StorageBackend>(
+    data: &AppState<B>,
+    names: Vec<String>,
+) -> Result<Vec<Bucket>, WriteError> {
+    let usage = data
+        .store
+        .run(move |store| {
+            Box::pin(async move {
+                names
+                    .into_iter()
+                    .map(|name| {
+                        let usage = store.usage().get(&key_prefix(&name));
+                        (name, usage)
+                    })
+                    .collect::<Vec<_>>()
+            })
+        })
+        .await?;
+    let buckets = data.buckets.buckets.read().unwrap();
+    Ok(usage
+        .into_iter()
+        .filter_map(|(name, usage)| {
+            let created_at = *buckets.get(&name)?;
+            Some(Bucket {
+                name,
+                created_at,
+                values: usage.values,
+                bytes: usage.bytes,
+            })
+        })
+        .collect())
+}
+
+/// Lists the buckets, with how much they hold.
+pub(crate) async fn list_buckets<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+) -> Result<HttpResponse, WriteError> {
+    if let Some(response) = authorize(&req, &data, Operation::Admin, "").await {
+        return Ok(response);
+    }
+    let names = data
+        .buckets
+        .buckets
+        .read()
+        .unwrap()
+        .keys()
+        .cloned()
+        .collect();
+    Ok(HttpResponse::Ok().json(report(&data, names).await?))
+}
+
+/// Reports how much the bucket `{name}` holds.
+pub(crate) async fn bucket_stats<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    name: web::Path<String>,
+) -> Result<HttpResponse, WriteError> {
+    if let Some(response) = authorize(&req, &data, Operation::Admin, "").await {
+        return Ok(response);
+    }
+    match report(&data, vec![name.into_inner()]).await?.pop() {
+        Some(bucket) => Ok(HttpResponse::Ok().json(bucket)),
+        None => Ok(no_such_bucket()),
+    }
+}
+
+/// Creates the bucket `{name}`, which holds no keys yet.
+pub(crate) async fn create_bucket<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    name: web::Path<String>,
+) -> impl Responder {
+    if let Some(response) = authorize(&req, &data, Operation::Admin, "").await {
+        return response;
+    }
+    if let Some(response) = check_writable(&data) {
+        return response;
+    }
+    if let Err(e) = check_name("bucket", &name) {
+        return HttpResponse::BadRequest().body(e);
+    }
+    let created_at = to_millis(SystemTime::now());
+    let marker = Entry::new(created_at.to_string().into_bytes(), "text/plain");
+    // Only the first of concurrent requests creates the bucket.
+    match data
+        .writes
+        .set(marker_key(&name), marker, |current| current.is_none())
+        .await
+    {
+        Ok(SetOutcome::Set) => data.write_guard.record_success(),
+        Ok(SetOutcome::ConditionFailed(_)) => {
+            return HttpResponse::Conflict().body("Bucket already exists")
+        }
+        Err(e) => {
+            log::error!("Error creating bucket {:?}: {}", name, e);
+            return write_error_response(&data, &e);
+        }
+    }
+    data.buckets
+        .buckets
+        .write()
+        .unwrap()
+        .insert(name.clone(), created_at);
+    match report(&data, vec![name.into_inner()]).await {
+        Ok(mut bucket) => match bucket.pop() {
+            Some(bucket) => HttpResponse::Created().json(bucket),
+            None => no_such_bucket(),
+        },
+        Err(e) => write_error_response(&data, &e),
+    }
+}
+
+/// Removes the bucket `{name}` and all of its keys right away. Needs a capability
+/// token, see `capabilities`.
+pub(crate) async fn delete_bucket<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    name: web::Path<String>,
+) -> impl Responder {
+    if let Some(response) = authorize(&req, &data, Operation::Admin, "").await {
+        return response;
+    }
+    if let Some(response) = check_writable(&data) {
+        return response;
+    }
+    let capability = match check_capability(&req, &data, Action::DeleteBucket, &name) {
+        Ok(capability) => capability,
+        Err(response) => return response,
+    };
+    // The bucket is gone for requests first, so that they don't write keys to it
+    // while they're removed.
+    let Some(created_at) = data.buckets.buckets.write().unwrap().remove(&*name) else {
+        return no_such_bucket();
+    };
+    let removed = async {
+        let removed = data.writes.remove_prefix(key_prefix(&name)).await?;
+        data.writes.remove(marker_key(&name), |_| true).await?;
+        Ok::<_, WriteError>(removed)
+    };
+    match removed.await {
+        Ok(removed) => {
+            capability.consume();
+            data.write_guard.record_success();
+            log::info!("Removed bucket {:?} with {} keys", name, removed);
+            HttpResponse::NoContent().finish()
+        }
+        Err(e) => {
+            log::error!("Error removing bucket {:?}: {}", name, e);
+            // Its marker is still there.
+            data.buckets
+                .buckets
+                .write()
+                .unwrap()
+                .insert(name.into_inner(), created_at);
+            write_error_response(&data, &e)
+        }
+    }
+}
+
+/// The key `{key}` of the bucket `{bucket}` is stored under, unless there's no such
+/// bucket.
+fn bucket_key<B: StorageBackend>(
+    data: &AppState<B>,
+    path: web::Path<(String, String)>,
+) -> Option<web::Path<String>> {
+    let (bucket, key) = path.into_inner();
+    data.buckets
+        .contains(&bucket)
+        .then(|| web::Path::from(format!("{}{}", key_prefix(&bucket), key)))
+}
+
+fn no_such_bucket() -> HttpResponse {
+    HttpResponse::NotFound().body("No such bucket")
+}
+
+/// `GET /_buckets/{bucket}/{key}`, like `GET /{key}`.
+pub(crate) async fn get_value<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    path: web::Path<(String, String)>,
+    query: web::Query<GetQuery>,
+) -> HttpResponse {
+    match bucket_key(&data, path) {
+        Some(key) => crate::get_value(req, data, key, query).await,
+        None => no_such_bucket(),
+    }
+}
+
+/// `POST /_buckets/{bucket}/{key}`, like `POST /{key}`.
+pub(crate) async fn set_value<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    path: web::Path<(String, String)>,
+    value: web::Bytes,
+) -> HttpResponse {
+    match bucket_key(&data, path) {
+        Some(key) => crate::store_value(&req, &data, &key, value).await,
+        None => no_such_bucket(),
+    }
+}
+
+/// `DELETE /_buckets/{bucket}/{key}`, like `DELETE /{key}`.
+pub(crate) async fn delete_value<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    path: web::Path<(String, String)>,
+) -> HttpResponse {
+    match bucket_key(&data, path) {
+        Some(key) => crate::remove_value(&req, &data, &key).await,
+        None => no_such_bucket(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_name() {
+        assert!(check_name("bucket", "photos").is_ok());
+        assert!(check_name("bucket", "_keys").is_err());
+        assert!(check_name("bucket", "").is_err());
+        assert!(check_name("bucket", "a/b").is_err());
+    }
+}
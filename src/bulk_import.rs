@@ -0,0 +1,294 @@
+//! Bulk imports, for loading many entries at once, e.g. the initial data of a store,
+//! which would take a request per entry otherwise.
+//!
+//! `POST /_admin/bulk-import` streams a body of newline delimited JSON, a
+//! `BatchEntry` per line, like the entries of a `/_batch` request. The entries are
+//! collected into chunks, which are written with `KVStore::import` as single writes
+//! to the backing storage. Nothing is synced until the import is done, and the store
+//! is flushed once. Each chunk is visible as soon as it's written, so readers see an
+//! import progress chunk by chunk, and an import which fails or is cancelled keeps
+//! the chunks written before.
+//!
+//! Only one import runs at a time. `GET /_admin/bulk-import` reports its progress.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Instant,
+};
+
+use actix_web::{body::MessageBody, web, HttpRequest, HttpResponse, Responder};
+use serde::Serialize;
+use tokio_stream::StreamExt;
+
+use crate::{
+    authorize, check_batch_entry, check_writable, write_error_response, AppState, BatchEntry,
+};
+use polling_test::auth::Operation;
+use polling_test::kv::{backend::StorageBackend, entry::Entry};
+
+/// Entries are written once this many bytes of values are collected.
+const IMPORT_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+/// Most entries written at once, so that chunks of small values don't hold up
+/// other writes for long.
+const MAX_IMPORT_CHUNK_ENTRIES: usize = 10_000;
+/// Longest line of an import, in bytes. Values are base64 encoded, so lines are
+/// longer than the values they hold.
+const MAX_IMPORT_LINE_LEN: usize = 32 * 1024 * 1024;
+
+/// How far an import got.
+struct Progress {
+    started: Instant,
+    entries: AtomicUsize,
+    /// Bytes of values imported.
+    bytes: AtomicU64,
+}
+
+impl Progress {
+    fn report(&self) -> ImportReport {
+        ImportReport {
+            imported: self.entries.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            elapsed_ms: self.started.elapsed().as_millis() as u64,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ImportReport {
+    /// Entries written so far.
+    imported: usize,
+    bytes: u64,
+    elapsed_ms: u64,
+}
+
+/// The bulk import which is running, if any.
+pub(crate) struct BulkImports {
+    running: Mutex<Option<Arc<Progress>>>,
+}
+
+impl BulkImports {
+    pub(crate) fn new() -> Self {
+        Self {
+            running: Mutex::new(None),
+        }
+    }
+
+    /// Starts tracking an import, unless one is running already.
+    fn start(&self) -> Option<Running<'_>> {
+        let mut running = self.running.lock().unwrap();
+        if running.is_some() {
+            return None;
+        }
+        let progress = Arc::new(Progress {
+            started: Instant::now(),
+            entries: AtomicUsize::new(0),
+            bytes: AtomicU64::new(0),
+        });
+        *running = Some(progress.clone());
+        Some(Running {
+            imports: self,
+            progress,
+        })
+    }
+}
+
+/// Stops tracking the import when it's dropped, which also happens if the client
+/// goes away.
+struct Running<'a> {
+    imports: &'a BulkImports,
+    progress: Arc<Progress>,
+}
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        *self.imports.running.lock().unwrap() = None;
+    }
+}
+
+/// Collects the entries of an import into chunks, and writes them.
+struct Import<'a, B: StorageBackend> {
+    req: &'a HttpRequest,
+    data: &'a AppState<B>,
+    running: Running<'a>,
+    chunk: Vec<(String, Entry)>,
+    chunk_size: usize,
+    /// The number of the last line read, for error messages.
+    line: usize,
+}
+
+impl<B: StorageBackend> Import<'_, B> {
+    /// Adds the entry on `line`, writing the chunk once it's full. Blank lines are
+    /// skipped.
+    async fn add_line(&mut self, line: &[u8]) -> Result<(), HttpResponse> {
+        self.line += 1;
+        if line.trim_ascii().is_empty() {
+            return Ok(());
+        }
+        let rejected = |response: HttpResponse| self.rejected(response);
+        let batch_entry: BatchEntry = serde_json::from_slice(line).map_err(|e| {
+            rejected(HttpResponse::BadRequest().body(format!("Invalid entry: {}", e)))
+        })?;
+        let key = &batch_entry.key;
+        if let Some(response) = authorize(self.req, self.data, Operation::Write, key).await {
+            return Err(rejected(response));
+        }
+        let (key, entry) = check_batch_entry(self.data, batch_entry).map_err(rejected)?;
+        self.chunk_size += entry.value.len();
+        self.chunk.push((key, entry));
+        if self.chunk_size >= IMPORT_CHUNK_SIZE || self.chunk.len() >= MAX_IMPORT_CHUNK_ENTRIES {
+            self.write_chunk().await?;
+        }
+        Ok(())
+    }
+
+    /// Prefixes the message of `response` with the line it's about.
+    fn rejected(&self, response: HttpResponse) -> HttpResponse {
+        let status = response.status();
+        let message = match response.into_body().try_into_bytes() {
+            Ok(body) => String::from_utf8_lossy(&body).into_owned(),
+            Err(_) => String::new(),
+        };
+        HttpResponse::build(status).body(format!("Line {}: {}", self.line, message))
+    }
+
+    async fn write_chunk(&mut self) -> Result<(), HttpResponse> {
+        if self.chunk.is_empty() {
+            return Ok(());
+        }
+        if let Some(response) = check_writable(self.data) {
+            return Err(response);
+        }
+        let chunk = std::mem::take(&mut self.chunk);
+        let (entries, bytes) = (chunk.len(), std::mem::take(&mut self.chunk_size));
+        if let Err(e) = self.data.writes.import(chunk).await {
+            log::error!("Error importing {} entries: {}", entries, e);
+            return Err(write_error_response(self.data, &e));
+        }
+        self.data.write_guard.record_success();
+        let progress = &self.running.progress;
+        progress.entries.fetch_add(entries, Ordering::Relaxed);
+        progress.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        log::debug!("Imported {} entries ({} bytes)", entries, bytes);
+        Ok(())
+    }
+
+    /// Writes what's left, and makes sure that the whole import is persisted.
+    async fn finish(&mut self) -> Result<ImportReport, HttpResponse> {
+        self.write_chunk().await?;
+        if let Err(e) = self.data.writes.flush().await {
+            log::error!("Error flushing the store after an import: {}", e);
+            return Err(write_error_response(self.data, &e));
+        }
+        Ok(self.running.progress.report())
+    }
+}
+
+/// Imports the entries of the newline delimited JSON body, see the module
+/// documentation. If a line is rejected, the entries before it are imported, and
+/// the rest aren't.
+pub(crate) async fn bulk_import<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    mut body: web::Payload,
+) -> impl Responder {
+    if let Some(response) = authorize(&req, &data, Operation::Admin, "").await {
+        return response;
+    }
+    if let Some(response) = check_writable(&data) {
+        return response;
+    }
+    let Some(running) = data.imports.start() else {
+        return HttpResponse::Conflict().body("An import is already running");
+    };
+    let mut import = Import {
+        req: &req,
+        data: &data,
+        running,
+        chunk: Vec::new(),
+        chunk_size: 0,
+        line: 0,
+    };
+    // Bytes after the last complete line.
+    let mut pending = Vec::new();
+    let mut result = Ok(());
+    while let Some(bytes) = body.next().await {
+        let bytes = match bytes {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                result = Err(HttpResponse::BadRequest().body(format!("Error reading body: {}", e)));
+                break;
+            }
+        };
+        pending.extend_from_slice(&bytes);
+        let mut start = 0;
+        while let Some(len) = pending[start..].iter().position(|byte| *byte == b'\n') {
+            result = import.add_line(&pending[start..start + len]).await;
+            start += len + 1;
+            if result.is_err() {
+                break;
+            }
+        }
+        pending.drain(..start);
+        if result.is_ok() && pending.len() > MAX_IMPORT_LINE_LEN {
+            result = Err(
+                import.rejected(HttpResponse::PayloadTooLarge().body(format!(
+                    "Exceeds the limit of {} bytes",
+                    MAX_IMPORT_LINE_LEN
+                ))),
+            );
+        }
+        if result.is_err() {
+            break;
+        }
+    }
+    if result.is_ok() {
+        result = import.add_line(&pending).await;
+    }
+    // The entries before a rejected line are imported either way.
+    let finished = import.finish().await;
+    let report = match (result, finished) {
+        (Ok(()), Ok(report)) => report,
+        (Err(response), _) | (_, Err(response)) => return response,
+    };
+    log::info!(
+        "Imported {} entries ({} bytes) in {} ms",
+        report.imported,
+        report.bytes,
+        report.elapsed_ms
+    );
+    HttpResponse::Ok().json(report)
+}
+
+/// Reports the progress of the running import.
+pub(crate) async fn import_progress<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+) -> impl Responder {
+    if let Some(response) = authorize(&req, &data, Operation::Admin, "").await {
+        return response;
+    }
+    let running = data.imports.running.lock().unwrap().clone();
+    match running {
+        Some(progress) => HttpResponse::Ok().json(progress.report()),
+        None => HttpResponse::NotFound().body("No import is running"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bulk_imports() {
+        let imports = BulkImports::new();
+        let running = imports.start().unwrap();
+        assert!(imports.start().is_none());
+        running.progress.entries.fetch_add(3, Ordering::Relaxed);
+        assert_eq!(running.progress.report().imported, 3);
+        drop(running);
+        assert_eq!(imports.start().unwrap().progress.report().imported, 0);
+    }
+}
@@ -0,0 +1,230 @@
+//! Capability tokens confirming destructive operations, so that a script can't remove
+//! a whole prefix, namespace or bucket by accident.
+//!
+//! A token is requested from `/_admin/capabilities` for one action on one target,
+//! and has to be sent back in the `X-KV-Capability` header of the request performing
+//! it. Every token can only be used once, and expires after `CAPABILITY_TTL`. It's
+//! only used up once the action succeeded, so an action which failed, e.g. because
+//! the storage was full, can be retried with the same token.
+//!
+//! Deleting namespaces and buckets always needs a token. `DELETE /_keys` only needs
+//! one if `ServerConfig::confirm_delete_prefix` is set, as it didn't before tokens
+//! existed.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+
+use crate::{authorize, buckets, namespaces, AppState};
+use polling_test::auth::Operation;
+use polling_test::kv::backend::StorageBackend;
+
+/// Header carrying the capability token confirming a destructive operation.
+const X_KV_CAPABILITY: &str = "x-kv-capability";
+/// How long a token can be used after it was issued.
+const CAPABILITY_TTL: Duration = Duration::from_secs(60);
+
+/// The operations which need a capability token.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[allow(clippy::enum_variant_names)]
+pub(crate) enum Action {
+    /// `DELETE /_keys`, the target is the prefix.
+    DeletePrefix,
+    /// `DELETE /_namespaces/{name}`, the target is the name.
+    DeleteNamespace,
+    /// `DELETE /_admin/buckets/{name}`, the target is the name.
+    DeleteBucket,
+}
+
+impl Action {
+    /// The operation and key the authorizer is asked about to issue a token for
+    /// the action, which are the same it's asked about to perform it.
+    fn operation(&self, target: &str) -> (Operation, String) {
+        match self {
+            Action::DeletePrefix => (Operation::DeletePrefix, target.to_owned()),
+            Action::DeleteNamespace => (Operation::DeletePrefix, namespaces::key_prefix(target)),
+            Action::DeleteBucket => (Operation::DeletePrefix, buckets::key_prefix(target)),
+        }
+    }
+}
+
+struct Grant {
+    action: Action,
+    target: String,
+    expires_at: Instant,
+}
+
+/// The tokens which were issued and not used yet.
+pub(crate) struct Capabilities {
+    grants: Mutex<HashMap<String, Grant>>,
+}
+
+impl Capabilities {
+    pub(crate) fn new() -> Self {
+        Self {
+            grants: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Issues a token allowing `action` on `target` once, until `CAPABILITY_TTL`
+    /// after `now`.
+    fn issue(&self, action: Action, target: String, now: Instant) -> String {
+        let mut grants = self.grants.lock().unwrap();
+        // Tokens which were never used would pile up otherwise.
+        grants.retain(|_, grant| grant.expires_at > now);
+        let token = format!("{:032x}", rand::random::<u128>());
+        let grant = Grant {
+            action,
+            target,
+            expires_at: now + CAPABILITY_TTL,
+        };
+        grants.insert(token.clone(), grant);
+        token
+    }
+
+    /// Takes out the token if it allows `action` on `target` at `now`, so that no
+    /// other request can use it meanwhile. Tokens for anything else are left as they
+    /// are.
+    fn redeem(
+        &self,
+        token: &str,
+        action: Action,
+        target: &str,
+        now: Instant,
+    ) -> Option<Redeemed<'_>> {
+        let mut grants = self.grants.lock().unwrap();
+        let valid = grants.get(token).is_some_and(|grant| {
+            grant.action == action && grant.target == target && grant.expires_at > now
+        });
+        if !valid {
+            return None;
+        }
+        let grant = grants.remove(token)?;
+        Some(Redeemed {
+            capabilities: self,
+            token: token.to_owned(),
+            grant: Some(grant),
+        })
+    }
+}
+
+/// A token taken out for an action, which is used up with `consume` once the action
+/// succeeded. Dropping it otherwise hands the token back.
+pub(crate) struct Redeemed<'a> {
+    capabilities: &'a Capabilities,
+    token: String,
+    grant: Option<Grant>,
+}
+
+impl Redeemed<'_> {
+    pub(crate) fn consume(mut self) {
+        self.grant = None;
+    }
+}
+
+impl Drop for Redeemed<'_> {
+    fn drop(&mut self) {
+        if let Some(grant) = self.grant.take() {
+            let mut grants = self.capabilities.grants.lock().unwrap();
+            grants.insert(std::mem::take(&mut self.token), grant);
+        }
+    }
+}
+
+/// Checks that the request carries a token for `action` on `target`, and takes it
+/// out until the action succeeded, see `Redeemed`. Returns the response to send if it
+/// doesn't.
+// The response is returned by the handler right away, like those of the other checks.
+#[allow(clippy::result_large_err)]
+pub(crate) fn check_capability<'a, B: StorageBackend>(
+    req: &HttpRequest,
+    data: &'a AppState<B>,
+    action: Action,
+    target: &str,
+) -> Result<Redeemed<'a>, HttpResponse> {
+    let Some(token) = req.headers().get(X_KV_CAPABILITY) else {
+        return Err(HttpResponse::PreconditionRequired().body(
+            "This operation must be confirmed with a token from \
+             POST /_admin/capabilities in the X-KV-Capability header",
+        ));
+    };
+    let token = token.to_str().unwrap_or_default();
+    data.capabilities
+        .redeem(token, action, target, Instant::now())
+        .ok_or_else(|| {
+            HttpResponse::Forbidden().body("Capability token is invalid, expired, or used up")
+        })
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CapabilityQuery {
+    action: Action,
+    target: String,
+}
+
+#[derive(Serialize)]
+struct Capability {
+    token: String,
+    action: Action,
+    target: String,
+    /// Seconds until the token expires.
+    expires_in: u64,
+}
+
+/// Issues a one-time token for the action in the query, if the request would be
+/// allowed to perform it.
+pub(crate) async fn issue_capability<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    query: web::Query<CapabilityQuery>,
+) -> impl Responder {
+    let CapabilityQuery { action, target } = query.into_inner();
+    let (operation, key) = action.operation(&target);
+    if let Some(response) = authorize(&req, &data, operation, &key).await {
+        return response;
+    }
+    let token = data
+        .capabilities
+        .issue(action, target.clone(), Instant::now());
+    log::info!("Issued capability token for {:?} on {:?}", action, target);
+    HttpResponse::Ok().json(Capability {
+        token,
+        action,
+        target,
+        expires_in: CAPABILITY_TTL.as_secs(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redeem() {
+        let capabilities = Capabilities::new();
+        let now = Instant::now();
+        let token = capabilities.issue(Action::DeletePrefix, "logs/".to_owned(), now);
+
+        let redeem = |action, target| capabilities.redeem(&token, action, target, now);
+
+        assert!(redeem(Action::DeleteNamespace, "logs/").is_none());
+        assert!(redeem(Action::DeletePrefix, "users/").is_none());
+        assert!(capabilities
+            .redeem(&token, Action::DeletePrefix, "logs/", now + CAPABILITY_TTL)
+            .is_none());
+        let redeemed = redeem(Action::DeletePrefix, "logs/").unwrap();
+        // Other requests can't use it while the action runs...
+        assert!(redeem(Action::DeletePrefix, "logs/").is_none());
+        // ...but it's handed back if the action failed.
+        drop(redeemed);
+        redeem(Action::DeletePrefix, "logs/").unwrap().consume();
+        // Every token can only be used once.
+        assert!(redeem(Action::DeletePrefix, "logs/").is_none());
+    }
+}
@@ -0,0 +1,132 @@
+//! Content-addressed storage: values stored under the SHA-256 of their bytes, e.g. for
+//! build artifacts or chunks of larger files.
+//!
+//! Blobs are write-once. Storing a value which is already stored leaves it as it is,
+//! so duplicates only take up space once. Like every key starting with `_`, blobs
+//! can't be written or removed through the other endpoints, and they're checked
+//! against their hash before they're served.
+
+use actix_web::{
+    http::{header::LOCATION, Method},
+    web, HttpMessage, HttpRequest, HttpResponse, Responder,
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::throttle::throttle;
+use crate::write_queue::{SetOutcome, WriteError};
+use crate::{authorize, check_writable, get_value, write_error_response, AppState, GetQuery};
+use polling_test::auth::Operation;
+use polling_test::kv::{backend::StorageBackend, entry::Entry};
+
+/// Blobs are stored under this prefix, followed by their hash.
+const CAS_PREFIX: &str = "_cas/";
+
+fn blob_key(hash: &str) -> String {
+    format!("{}{}", CAS_PREFIX, hash)
+}
+
+/// The SHA-256 of `value`, as a lowercase hex string.
+fn hash_of(value: &[u8]) -> String {
+    Sha256::digest(value)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Whether `hash` is one `hash_of` could have returned.
+fn is_valid_hash(hash: &str) -> bool {
+    hash.len() == 64 && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+#[derive(Serialize)]
+struct StoredBlob {
+    hash: String,
+    size: usize,
+}
+
+/// Stores the request body under its hash, using the request's Content-Type as the
+/// MIME type. Responds with 201 if the blob is new, and with 200 if it was already
+/// stored, in which case its MIME type is kept.
+pub(crate) async fn store_blob<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    value: web::Bytes,
+) -> impl Responder {
+    let hash = hash_of(&value);
+    let key = blob_key(&hash);
+    if let Some(response) = authorize(&req, &data, Operation::Write, &key).await {
+        return response;
+    }
+    if let Some(response) = check_writable(&data) {
+        return response;
+    }
+    if let Some(response) = throttle(&data, [key.as_str()]).await {
+        return response;
+    }
+    if req.content_type().contains("*") {
+        return HttpResponse::BadRequest().body("Invalid Content-Type: Must be non-generic");
+    }
+    let size = value.len();
+    let entry = Entry::new(value.to_vec(), req.content_type().to_string());
+    let mut response = match data
+        .writes
+        .set(key, entry, |current| current.is_none())
+        .await
+    {
+        Ok(SetOutcome::Set) => {
+            data.write_guard.record_success();
+            HttpResponse::Created()
+        }
+        Ok(SetOutcome::ConditionFailed(_)) => HttpResponse::Ok(),
+        Err(e) => {
+            log::error!("Error storing blob {}: {}", hash, e);
+            return write_error_response(&data, &e);
+        }
+    };
+    response
+        .insert_header((LOCATION, format!("/{}", blob_key(&hash))))
+        .json(StoredBlob { hash, size })
+}
+
+/// Gets the blob with the hash `{hash}`, like `GET /{key}` does. Blobs which don't
+/// match their hash aren't served.
+pub(crate) async fn get_blob<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    hash: web::Path<String>,
+    query: web::Query<GetQuery>,
+) -> Result<HttpResponse, WriteError> {
+    if !is_valid_hash(&hash) {
+        return Ok(HttpResponse::BadRequest().body("Invalid hash: Must be a lowercase hex SHA-256"));
+    }
+    // HEAD requests don't get the value, so there's nothing to check.
+    if req.method() != Method::HEAD {
+        if let Some(blob) = data.store.peek(blob_key(&hash)).await? {
+            if hash_of(&blob.value) != *hash {
+                log::error!("Blob {} doesn't match its hash", hash);
+                return Ok(HttpResponse::InternalServerError().body("Blob doesn't match its hash"));
+            }
+        }
+    }
+    let key = web::Path::from(blob_key(&hash));
+    Ok(get_value(req, data, key, query).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_of() {
+        let hash = hash_of(b"hello");
+        assert_eq!(
+            hash,
+            "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824"
+        );
+        assert!(is_valid_hash(&hash));
+        assert!(!is_valid_hash(&hash.to_uppercase()));
+        assert!(!is_valid_hash(&hash[..63]));
+        assert!(!is_valid_hash("../keys"));
+    }
+}
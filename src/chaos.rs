@@ -0,0 +1,244 @@
+//! Fault injection for testing how clients cope with a slow or failing server, only
+//! compiled in with the `chaos` feature. Never enable it in production.
+//!
+//! The faults are configured at runtime with `PUT /_admin/chaos`, and apply to the
+//! whole process until they're reset with `DELETE /_admin/chaos`.
+
+use std::{sync::RwLock, time::Duration};
+
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::Next,
+    web, HttpRequest, HttpResponse, Responder,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{authorize, AppState};
+use polling_test::auth::Operation;
+use polling_test::kv::{
+    backend::{RecordReader, StorageBackend},
+    codec::Limits,
+    entry::KVEntry,
+    header::Header,
+    result::{KVError, KVResult},
+};
+
+/// The faults injected, see `chaos`.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Chaos {
+    /// Added to every request before it's handled, in milliseconds.
+    latency_ms: u64,
+    /// Fraction of storage operations which fail with an I/O error, from 0 to 1.
+    error_rate: f64,
+    /// Added to every storage operation, in milliseconds.
+    disk_delay_ms: u64,
+}
+
+impl Chaos {
+    const NONE: Chaos = Chaos {
+        latency_ms: 0,
+        error_rate: 0.0,
+        disk_delay_ms: 0,
+    };
+}
+
+impl Default for Chaos {
+    fn default() -> Self {
+        Self::NONE
+    }
+}
+
+static CHAOS: RwLock<Chaos> = RwLock::new(Chaos::NONE);
+
+fn current() -> Chaos {
+    *CHAOS.read().unwrap()
+}
+
+/// Delays every request by the configured latency.
+pub(crate) async fn delay_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let latency_ms = current().latency_ms;
+    // Faults can always be reset, however high the latency is.
+    if latency_ms > 0 && req.path() != "/_admin/chaos" {
+        tokio::time::sleep(Duration::from_millis(latency_ms)).await;
+    }
+    next.call(req).await
+}
+
+/// Delays a storage operation by the configured disk delay, and then fails it at the
+/// configured error rate.
+async fn disturb(operation: &str) -> KVResult<()> {
+    let chaos = current();
+    if chaos.disk_delay_ms > 0 {
+        tokio::time::sleep(Duration::from_millis(chaos.disk_delay_ms)).await;
+    }
+    if rand::random::<f64>() < chaos.error_rate {
+        return Err(KVError::IO(std::io::Error::other(format!(
+            "Injected failure of {}",
+            operation
+        ))));
+    }
+    Ok(())
+}
+
+/// Wraps a backend to inject the configured faults into the operations which touch
+/// the storage.
+pub(crate) struct ChaosBackend<B> {
+    inner: B,
+}
+
+impl<B> ChaosBackend<B> {
+    pub(crate) fn new(inner: B) -> Self {
+        Self { inner }
+    }
+}
+
+impl<B: StorageBackend> StorageBackend for ChaosBackend<B> {
+    async fn load_all(
+        &mut self,
+        on_record: impl FnMut(u64, KVEntry) + Send,
+    ) -> KVResult<Option<Header>> {
+        self.inner.load_all(on_record).await
+    }
+
+    async fn create(&mut self, header: &Header) -> KVResult<()> {
+        self.inner.create(header).await
+    }
+
+    async fn append(&mut self, record: &KVEntry) -> KVResult<u64> {
+        disturb("append").await?;
+        self.inner.append(record).await
+    }
+
+    async fn append_batch(&mut self, records: &[KVEntry]) -> KVResult<Vec<u64>> {
+        disturb("append").await?;
+        self.inner.append_batch(records).await
+    }
+
+    async fn read(&self, offset: u64) -> KVResult<KVEntry> {
+        disturb("read").await?;
+        self.inner.read(offset).await
+    }
+
+    fn keeps_records(&self) -> bool {
+        self.inner.keeps_records()
+    }
+
+    async fn sync(&mut self) -> KVResult<()> {
+        disturb("sync").await?;
+        self.inner.sync().await
+    }
+
+    async fn probe(&mut self) -> KVResult<()> {
+        disturb("probe").await?;
+        self.inner.probe().await
+    }
+
+    fn size(&self) -> u64 {
+        self.inner.size()
+    }
+
+    async fn reader(&self) -> KVResult<Option<RecordReader>> {
+        disturb("read").await?;
+        self.inner.reader().await
+    }
+
+    async fn create_sibling(&self) -> KVResult<Self> {
+        Ok(Self::new(self.inner.create_sibling().await?))
+    }
+
+    async fn replace(&mut self, compacted: Self) -> KVResult<()> {
+        self.inner.replace(compacted.inner).await
+    }
+
+    fn compresses(&self, record: &KVEntry) -> bool {
+        self.inner.compresses(record)
+    }
+
+    fn compression_threshold(&self) -> Option<u64> {
+        self.inner.compression_threshold()
+    }
+
+    fn set_compression_threshold(&mut self, bytes: usize) {
+        self.inner.set_compression_threshold(bytes)
+    }
+
+    fn set_compression_level(&mut self, level: i32) {
+        self.inner.set_compression_level(level)
+    }
+
+    fn limits(&self) -> Limits {
+        self.inner.limits()
+    }
+}
+
+/// Responds with the faults currently injected.
+pub(crate) async fn get_chaos<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+) -> impl Responder {
+    if let Some(response) = authorize(&req, &data, Operation::Admin, "").await {
+        return response;
+    }
+    HttpResponse::Ok().json(current())
+}
+
+/// Injects the faults in the body, replacing the ones injected before. Faults which
+/// aren't given are turned off.
+pub(crate) async fn set_chaos<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    chaos: web::Json<Chaos>,
+) -> impl Responder {
+    if let Some(response) = authorize(&req, &data, Operation::Admin, "").await {
+        return response;
+    }
+    let chaos = chaos.into_inner();
+    if !(0.0..=1.0).contains(&chaos.error_rate) {
+        return HttpResponse::BadRequest().body("Invalid error_rate: Must be from 0 to 1");
+    }
+    log::warn!("Injecting faults: {:?}", chaos);
+    *CHAOS.write().unwrap() = chaos;
+    HttpResponse::Ok().json(chaos)
+}
+
+/// Stops injecting faults.
+pub(crate) async fn reset_chaos<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+) -> impl Responder {
+    if let Some(response) = authorize(&req, &data, Operation::Admin, "").await {
+        return response;
+    }
+    log::info!("Stopped injecting faults");
+    *CHAOS.write().unwrap() = Chaos::NONE;
+    HttpResponse::NoContent().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use polling_test::kv::{backend::MemoryBackend, store::KVStore};
+    use polling_test::Entry;
+
+    #[tokio::test]
+    async fn test_chaos_backend() -> KVResult<()> {
+        let mut store = KVStore::new(ChaosBackend::new(MemoryBackend::new())).await?;
+        let value = Entry::new(b"test_value".to_vec(), "text/plain");
+        *CHAOS.write().unwrap() = Chaos {
+            error_rate: 1.0,
+            ..Chaos::NONE
+        };
+        let failed = store.set("a", value.clone()).await;
+        *CHAOS.write().unwrap() = Chaos::NONE;
+        assert!(matches!(failed, Err(KVError::IO(_))));
+        assert!(store.get("a").await?.is_none());
+        store.set("a", value).await?;
+        assert!(store.get("a").await?.is_some());
+        Ok(())
+    }
+}
@@ -0,0 +1,188 @@
+//! Conversion of text values to the charset a client asks for with `Accept-Charset`,
+//! for legacy clients which can't handle UTF-8.
+//!
+//! Text values are assumed to be stored as UTF-8, unless their media type says
+//! otherwise. Values in any other charset are always sent as stored.
+
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Charset {
+    Utf8,
+    /// Big endian with a byte order mark, as RFC 2781 recommends.
+    Utf16,
+    Utf16Le,
+    Utf16Be,
+    Latin1,
+}
+
+impl Charset {
+    fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "utf-8" | "utf8" => Some(Charset::Utf8),
+            "utf-16" => Some(Charset::Utf16),
+            "utf-16le" => Some(Charset::Utf16Le),
+            "utf-16be" => Some(Charset::Utf16Be),
+            "iso-8859-1" | "latin1" | "latin-1" => Some(Charset::Latin1),
+            _ => None,
+        }
+    }
+
+    /// Encodes the text, or returns `None` if the charset can't represent all of it.
+    fn encode(&self, text: &str) -> Option<Vec<u8>> {
+        match self {
+            Charset::Utf8 => Some(text.as_bytes().to_vec()),
+            Charset::Utf16 => Some(
+                [0xFE, 0xFF]
+                    .into_iter()
+                    .chain(text.encode_utf16().flat_map(u16::to_be_bytes))
+                    .collect(),
+            ),
+            Charset::Utf16Le => Some(text.encode_utf16().flat_map(u16::to_le_bytes).collect()),
+            Charset::Utf16Be => Some(text.encode_utf16().flat_map(u16::to_be_bytes).collect()),
+            Charset::Latin1 => text.chars().map(|c| u8::try_from(c).ok()).collect(),
+        }
+    }
+}
+
+impl fmt::Display for Charset {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Charset::Utf8 => "utf-8",
+            Charset::Utf16 => "utf-16",
+            Charset::Utf16Le => "utf-16le",
+            Charset::Utf16Be => "utf-16be",
+            Charset::Latin1 => "iso-8859-1",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// None of the charsets the client accepts can represent the value.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct NotAcceptable;
+
+/// Converts a text value stored as UTF-8 to the charset with the highest preference in
+/// an `Accept-Charset` header which can represent all of it.
+///
+/// Returns `Ok(None)` if the value isn't UTF-8 text, and is sent as stored.
+pub(crate) fn convert(
+    mime: &str,
+    value: &[u8],
+    accept_charset: &str,
+) -> Result<Option<(Charset, Vec<u8>)>, NotAcceptable> {
+    if !is_utf8_text(mime) {
+        return Ok(None);
+    }
+    let Ok(text) = std::str::from_utf8(value) else {
+        return Ok(None);
+    };
+    for name in preferred(accept_charset) {
+        let charset = if name == "*" {
+            Some(Charset::Utf8)
+        } else {
+            Charset::from_name(name)
+        };
+        if let Some(converted) = charset.and_then(|charset| Some((charset, charset.encode(text)?)))
+        {
+            return Ok(Some(converted));
+        }
+    }
+    Err(NotAcceptable)
+}
+
+/// The media type with its charset parameter replaced by `charset`.
+pub(crate) fn with_charset(mime: &str, charset: Charset) -> String {
+    let mut parts: Vec<&str> = mime
+        .split(';')
+        .map(str::trim)
+        .filter(|part| !is_charset_param(part))
+        .collect();
+    let charset = format!("charset={}", charset);
+    parts.push(&charset);
+    parts.join("; ")
+}
+
+/// Whether the media type is `text/*`, and doesn't name a charset other than UTF-8.
+pub(crate) fn is_utf8_text(mime: &str) -> bool {
+    let mut parts = mime.split(';').map(str::trim);
+    let is_text = parts
+        .next()
+        .is_some_and(|essence| essence.to_ascii_lowercase().starts_with("text/"));
+    is_text
+        && parts.filter(|part| is_charset_param(part)).all(|part| {
+            Charset::from_name(part["charset=".len()..].trim_matches('"')) == Some(Charset::Utf8)
+        })
+}
+
+fn is_charset_param(part: &str) -> bool {
+    part.get(.."charset=".len())
+        .is_some_and(|name| name.eq_ignore_ascii_case("charset="))
+}
+
+/// The charset names of an `Accept-Charset` header, most preferred first. Names with
+/// a quality of 0 are left out.
+fn preferred(accept_charset: &str) -> Vec<&str> {
+    let mut names: Vec<(&str, f32)> = accept_charset
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';').map(str::trim);
+            let name = parts.next().filter(|name| !name.is_empty())?;
+            let quality = parts
+                .find_map(|param| param.strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse().ok())?;
+            (quality > 0.0).then_some((name, quality))
+        })
+        .collect();
+    // Stable, so names of the same quality keep their order.
+    names.sort_by(|a, b| b.1.total_cmp(&a.1));
+    names.into_iter().map(|(name, _)| name).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_convert() {
+        let text = "caf\u{e9}".as_bytes();
+        assert_eq!(
+            convert("text/plain", text, "iso-8859-1, utf-8;q=0.5"),
+            Ok(Some((Charset::Latin1, b"caf\xe9".to_vec())))
+        );
+        assert_eq!(
+            convert("text/plain; charset=UTF-8", text, "utf-8;q=0.1, UTF-16LE"),
+            Ok(Some((Charset::Utf16Le, b"c\0a\0f\0\xe9\0".to_vec())))
+        );
+        assert_eq!(
+            convert("text/plain", b"hi", "utf-16"),
+            Ok(Some((Charset::Utf16, b"\xfe\xff\0h\0i".to_vec())))
+        );
+        // Latin-1 can't represent the euro sign, so the next best charset is used.
+        assert_eq!(
+            convert("text/plain", "\u{20ac}".as_bytes(), "latin1, *;q=0.1"),
+            Ok(Some((Charset::Utf8, "\u{20ac}".as_bytes().to_vec())))
+        );
+        assert_eq!(
+            convert("text/plain", "\u{20ac}".as_bytes(), "latin1, utf-8;q=0"),
+            Err(NotAcceptable)
+        );
+        assert_eq!(convert("application/json", text, "latin1"), Ok(None));
+        assert_eq!(
+            convert("text/plain; charset=iso-8859-1", b"caf\xe9", "utf-16"),
+            Ok(None)
+        );
+    }
+
+    #[test]
+    fn test_with_charset() {
+        assert_eq!(
+            with_charset("text/plain", Charset::Latin1),
+            "text/plain; charset=iso-8859-1"
+        );
+        assert_eq!(
+            with_charset("text/csv; charset=utf-8; header=present", Charset::Utf16),
+            "text/csv; header=present; charset=utf-16"
+        );
+    }
+}
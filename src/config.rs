@@ -0,0 +1,662 @@
+//! Server settings, read from a TOML file like
+//!
+//! ```toml
+//! bind = "0.0.0.0:8080"
+//! db = "/var/lib/kv-api/data.db"
+//! mirror = "/mnt/backup/kv-api/data.db"
+//! seed = "/var/lib/kv-api/seed.db"
+//! compression_threshold = 4096
+//! compression_level = 9
+//! max_value_size = 1048576
+//! workers = 4
+//! access_time_granularity = 86400
+//! sync = "100ms"
+//! delta_threshold = 65536
+//! cache_size = 33554432
+//! max_key_size = 1024
+//! max_mime_size = 256
+//! max_stored_value_size = 16777216
+//! snapshot_ttl = 300
+//! max_response_size = 8388608
+//! key_normalization = "nfc_lowercase"
+//! confirm_delete_prefix = true
+//! write_queue_capacity = 256
+//! write_failure_threshold = 3
+//! write_retry_interval = 5
+//! compaction_threshold = 67108864
+//! compaction_check_interval = 60
+//! verify_interval = 600
+//! verify_sample_size = 64
+//! access_time_persist_interval = 60
+//! lifecycle_sweep_interval = 600
+//! log_level = "info"
+//! ```
+//!
+//! To listen on more than one socket, each with its own middleware, list them instead
+//! of `bind`, which can't be given along with them, neither as `KV_BIND` nor `--bind`:
+//!
+//! ```toml
+//! [[listeners]]
+//! bind = "[::]:8443"
+//! tls = { cert = "/etc/kv-api/cert.pem", key = "/etc/kv-api/key.pem" }
+//! token = "secret"
+//!
+//! [[listeners]]
+//! bind = "127.0.0.1:8080"
+//! read_only = true
+//! ```
+//!
+//! Requests are allowed everything unless grants are listed, in which case they're
+//! only allowed what the grants of their bearer token allow, see `TokenAuthorizer`:
+//!
+//! ```toml
+//! [[grants]]
+//! token = "secret"
+//! prefix = "users/"
+//! operations = ["read", "list", "write", "delete"]
+//! ```
+//!
+//! Every setting is optional. Those besides listeners and grants can also be given as
+//! the environment variables `KV_BIND`, `KV_DB_PATH`, `KV_MIRROR_PATH`, `KV_SEED`,
+//! `KV_READ_ONLY`,
+//! `KV_COMPRESSION_THRESHOLD`, `KV_COMPRESSION_LEVEL`, `KV_MAX_VALUE_SIZE`,
+//! `KV_WORKERS`, `KV_ACCESS_TIME_GRANULARITY`, `KV_SYNC`, `KV_DELTA_THRESHOLD`,
+//! `KV_CACHE_SIZE`, `KV_MAX_KEY_SIZE`, `KV_MAX_MIME_SIZE`,
+//! `KV_MAX_STORED_VALUE_SIZE`, `KV_SNAPSHOT_TTL`, `KV_MAX_RESPONSE_SIZE`,
+//! `KV_KEY_NORMALIZATION`, `KV_CONFIRM_DELETE_PREFIX`,
+//! `KV_WRITE_QUEUE_CAPACITY`, `KV_WRITE_FAILURE_THRESHOLD`, `KV_WRITE_RETRY_INTERVAL`,
+//! `KV_COMPACTION_THRESHOLD`, `KV_COMPACTION_CHECK_INTERVAL`, `KV_VERIFY_INTERVAL`,
+//! `KV_VERIFY_SAMPLE_SIZE`, `KV_ACCESS_TIME_PERSIST_INTERVAL`,
+//! `KV_LIFECYCLE_SWEEP_INTERVAL` and `KV_LOG_LEVEL`, and `KV_CONFIG` selects the configuration file.
+//!
+//! Limits which are part of the HTTP API, like the size of batches, transactions and
+//! headers, aren't settings but documented in `openapi.yaml`. Neither are the
+//! intervals of the tasks which run every second, like removing expired values or
+//! checking the storage after writes failed.
+//!
+//! Settings are taken from, in order of precedence:
+//! 1. command line flags,
+//! 2. environment variables,
+//! 3. the configuration file,
+//! 4. the defaults.
+
+use std::{
+    fmt::Display,
+    io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+
+use serde::{Deserialize, Deserializer};
+
+use crate::write_queue::WRITE_QUEUE_CAPACITY;
+use polling_test::auth::{AllowAll, Authorizer, Grant, TokenAuthorizer};
+use polling_test::kv::{
+    backend::DEFAULT_COMPRESSION_THRESHOLD,
+    codec::{self, Limits, DEFAULT_COMPRESSION_LEVEL},
+    normalization::KeyNormalization,
+    store::SyncPolicy,
+};
+
+/// The configuration file read if no other one is given.
+pub(crate) const CONFIG_PATH: &str = "./kv-api.toml";
+/// Largest value accepted, unless configured otherwise.
+pub(crate) const DEFAULT_MAX_VALUE_SIZE: usize = 256 * 1024;
+/// Bytes of values kept in memory after reading them, unless configured otherwise.
+pub(crate) const DEFAULT_CACHE_SIZE: usize = 32 * 1024 * 1024;
+/// Largest listing sent at once, unless configured otherwise.
+pub(crate) const DEFAULT_MAX_RESPONSE_SIZE: usize = 8 * 1024 * 1024;
+/// Address and port listened on, unless configured otherwise.
+const DEFAULT_BIND: &str = "127.0.0.1:8080";
+
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ServerConfig {
+    /// Address and port to listen on, `DEFAULT_BIND` unless this or `listeners` are
+    /// given.
+    pub(crate) bind: Option<String>,
+    /// Sockets to listen on, each with its own middleware.
+    pub(crate) listeners: Vec<ListenerConfig>,
+    /// What the bearer tokens of requests allow. Without any, everything is allowed.
+    pub(crate) grants: Vec<Grant>,
+    /// Path of the database file, which is created if it doesn't exist.
+    pub(crate) db: PathBuf,
+    /// Path of a copy of the database file, e.g. on another disk, which every write
+    /// goes to as well. See `FileBackend::with_mirror`.
+    pub(crate) mirror: Option<PathBuf>,
+    /// Path of a database which is loaded read-only underneath `db`, which then only
+    /// holds the changes made on top of it. The server doesn't start if it's missing.
+    pub(crate) seed: Option<PathBuf>,
+    /// Serves the database file without writing to it, e.g. a replica's copy of it,
+    /// or one which is inspected. Writes are rejected with 403, the file has to exist,
+    /// and nothing is written in the background either, like removing expired values.
+    pub(crate) read_only: bool,
+    /// Values larger than this many bytes are compressed. It's recorded in the
+    /// database when it's created, and the database fails to load with another one.
+    pub(crate) compression_threshold: usize,
+    /// The zstd level values are compressed at, higher ones compress better but take
+    /// longer. 0 selects zstd's default. Unlike the threshold, it can be changed at any
+    /// time.
+    pub(crate) compression_level: i32,
+    /// Largest value accepted, in bytes.
+    pub(crate) max_value_size: usize,
+    /// Number of threads handling requests on each listener, one per CPU core if not
+    /// set.
+    pub(crate) workers: Option<usize>,
+    /// Reading an entry only updates when it was last read if that was longer ago
+    /// than this many seconds. 0 turns tracking access times off.
+    pub(crate) access_time_granularity: u64,
+    /// When writes are synced to disk: `always`, `never`, or an interval like `100ms`.
+    #[serde(deserialize_with = "deserialize_from_str")]
+    pub(crate) sync: SyncPolicy,
+    /// Overwriting a value larger than this many bytes with another one stores only
+    /// what changed. 0 turns this off.
+    pub(crate) delta_threshold: usize,
+    /// Bytes of values kept in memory after reading them, or prefetching them with
+    /// `POST /_prefetch`. 0 turns the cache off.
+    pub(crate) cache_size: usize,
+    /// Longest key written or read back from the database, in bytes.
+    pub(crate) max_key_size: usize,
+    /// Longest MIME type written or read back from the database, in bytes.
+    pub(crate) max_mime_size: usize,
+    /// Largest value read back from the database, in bytes. The database fails to
+    /// load if it holds a larger one, which is how corrupted lengths are caught
+    /// before they're allocated. Unlike `max_value_size`, lowering it doesn't only
+    /// affect new values.
+    pub(crate) max_stored_value_size: usize,
+    /// Seconds snapshots taken with `POST /_snapshots` can be read from.
+    pub(crate) snapshot_ttl: u64,
+    /// About how many bytes a listing of keys may take up, like `/_keys` or `/_scan`.
+    /// Longer ones, streamed or not, are cut short, with a link to the rest.
+    pub(crate) max_response_size: usize,
+    /// How keys are normalized before they're written or looked up: `none`,
+    /// `lowercase`, `nfc` or `nfc_lowercase`. It's recorded in the database when it's
+    /// created, and the database fails to load with another one. If not set, whatever
+    /// the database records is used.
+    #[serde(deserialize_with = "deserialize_some_from_str")]
+    pub(crate) key_normalization: Option<KeyNormalization>,
+    /// `DELETE /_keys` needs a capability token from `POST /_admin/capabilities`, like
+    /// deleting namespaces and buckets. Off by default, as it didn't before.
+    pub(crate) confirm_delete_prefix: bool,
+    /// Writes which may wait for the storage at once. Further ones are rejected with
+    /// 503.
+    pub(crate) write_queue_capacity: usize,
+    /// Writes failing in a row after which writes are rejected up front, until the
+    /// storage passes a check, see `WriteGuard`.
+    pub(crate) write_failure_threshold: u32,
+    /// Seconds between checks of the storage while writes are rejected.
+    pub(crate) write_retry_interval: u64,
+    /// The database file is compacted once it's larger than this many bytes, and
+    /// twice its size after the last compaction.
+    pub(crate) compaction_threshold: u64,
+    /// Seconds between checks of the size of the database file.
+    pub(crate) compaction_check_interval: u64,
+    /// Seconds between comparing a sample of the index against the database file.
+    pub(crate) verify_interval: u64,
+    /// Keys compared per verification.
+    pub(crate) verify_sample_size: usize,
+    /// Seconds between persisting the access times updated by reads.
+    pub(crate) access_time_persist_interval: u64,
+    /// Seconds between checking values against their lifecycle rules.
+    pub(crate) lifecycle_sweep_interval: u64,
+    /// Most verbose level of messages to log: `off`, `error`, `warn`, `info`, `debug`
+    /// or `trace`.
+    #[serde(deserialize_with = "deserialize_from_str")]
+    pub(crate) log_level: log::LevelFilter,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: None,
+            listeners: Vec::new(),
+            grants: Vec::new(),
+            db: PathBuf::from("./test.db"),
+            mirror: None,
+            seed: None,
+            read_only: false,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            compression_level: DEFAULT_COMPRESSION_LEVEL,
+            max_value_size: DEFAULT_MAX_VALUE_SIZE,
+            workers: None,
+            access_time_granularity: 60 * 60,
+            sync: SyncPolicy::default(),
+            delta_threshold: 0,
+            cache_size: DEFAULT_CACHE_SIZE,
+            max_key_size: codec::MAX_KEY_LEN,
+            max_mime_size: codec::MAX_MIME_LEN,
+            max_stored_value_size: codec::DEFAULT_MAX_VALUE_LEN,
+            snapshot_ttl: 5 * 60,
+            max_response_size: DEFAULT_MAX_RESPONSE_SIZE,
+            key_normalization: None,
+            confirm_delete_prefix: false,
+            write_queue_capacity: WRITE_QUEUE_CAPACITY,
+            write_failure_threshold: 3,
+            write_retry_interval: 5,
+            compaction_threshold: 64 * 1024 * 1024,
+            compaction_check_interval: 60,
+            verify_interval: 10 * 60,
+            verify_sample_size: 64,
+            access_time_persist_interval: 60,
+            lifecycle_sweep_interval: 10 * 60,
+            log_level: log::LevelFilter::Info,
+        }
+    }
+}
+
+/// A socket to listen on, along with the middleware requests arriving on it go
+/// through, see `listeners`.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ListenerConfig {
+    /// Address and port to listen on.
+    pub(crate) bind: String,
+    /// Serves HTTPS instead of HTTP. Needs the `tls` feature.
+    pub(crate) tls: Option<TlsConfig>,
+    /// Only requests with an `Authorization: Bearer <token>` header are handled.
+    pub(crate) token: Option<String>,
+    /// Only reads are handled.
+    #[serde(default)]
+    pub(crate) read_only: bool,
+}
+
+impl ListenerConfig {
+    /// Listens on `bind` without any middleware.
+    fn plain(bind: String) -> Self {
+        Self {
+            bind,
+            tls: None,
+            token: None,
+            read_only: false,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TlsConfig {
+    /// PEM file with the certificate chain, leaf first.
+    pub(crate) cert: PathBuf,
+    /// PEM file with the private key.
+    pub(crate) key: PathBuf,
+}
+
+impl ServerConfig {
+    /// The configured listeners, or a plain one on `bind` if there are none.
+    pub(crate) fn listeners(&self) -> Vec<ListenerConfig> {
+        if self.listeners.is_empty() {
+            let bind = self.bind.as_deref().unwrap_or(DEFAULT_BIND);
+            vec![ListenerConfig::plain(bind.to_owned())]
+        } else {
+            self.listeners.clone()
+        }
+    }
+
+    /// Decides what requests may do: a `TokenAuthorizer` with the configured grants,
+    /// or `AllowAll` without any.
+    pub(crate) fn authorizer(&self) -> Arc<dyn Authorizer> {
+        if self.grants.is_empty() {
+            Arc::new(AllowAll)
+        } else {
+            Arc::new(TokenAuthorizer::new(self.grants.iter().cloned()))
+        }
+    }
+
+    /// Reads the configuration file at `path`. If there's none at the default
+    /// `CONFIG_PATH`, the defaults are used.
+    pub(crate) fn load(path: Option<&Path>) -> Result<Self, String> {
+        let (path, required) = match path {
+            Some(path) => (path, true),
+            None => (Path::new(CONFIG_PATH), false),
+        };
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && !required => {
+                return Ok(Self::default())
+            }
+            Err(e) => return Err(format!("Error reading {}: {}", path.display(), e)),
+        };
+        let config =
+            Self::parse(&text).map_err(|e| format!("Invalid {}: {}", path.display(), e))?;
+        log::info!("Loaded configuration from {}", path.display());
+        Ok(config)
+    }
+
+    fn parse(text: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(text)
+    }
+
+    /// Overrides the settings whose environment variables are set, as returned by
+    /// `var`.
+    pub(crate) fn apply_env(&mut self, var: impl Fn(&str) -> Option<String>) -> Result<(), String> {
+        if let Some(bind) = var("KV_BIND") {
+            self.bind = Some(bind);
+        }
+        if let Some(db) = var("KV_DB_PATH") {
+            self.db = PathBuf::from(db);
+        }
+        if let Some(mirror) = var("KV_MIRROR_PATH") {
+            self.mirror = Some(PathBuf::from(mirror));
+        }
+        if let Some(seed) = var("KV_SEED") {
+            self.seed = Some(PathBuf::from(seed));
+        }
+        if let Some(read_only) = parse_var(&var, "KV_READ_ONLY")? {
+            self.read_only = read_only;
+        }
+        if let Some(threshold) = parse_var(&var, "KV_COMPRESSION_THRESHOLD")? {
+            self.compression_threshold = threshold;
+        }
+        if let Some(level) = parse_var(&var, "KV_COMPRESSION_LEVEL")? {
+            self.compression_level = level;
+        }
+        if let Some(size) = parse_var(&var, "KV_MAX_VALUE_SIZE")? {
+            self.max_value_size = size;
+        }
+        if let Some(workers) = parse_var(&var, "KV_WORKERS")? {
+            self.workers = Some(workers);
+        }
+        if let Some(granularity) = parse_var(&var, "KV_ACCESS_TIME_GRANULARITY")? {
+            self.access_time_granularity = granularity;
+        }
+        if let Some(sync) = parse_var(&var, "KV_SYNC")? {
+            self.sync = sync;
+        }
+        if let Some(threshold) = parse_var(&var, "KV_DELTA_THRESHOLD")? {
+            self.delta_threshold = threshold;
+        }
+        if let Some(size) = parse_var(&var, "KV_CACHE_SIZE")? {
+            self.cache_size = size;
+        }
+        if let Some(size) = parse_var(&var, "KV_MAX_KEY_SIZE")? {
+            self.max_key_size = size;
+        }
+        if let Some(size) = parse_var(&var, "KV_MAX_MIME_SIZE")? {
+            self.max_mime_size = size;
+        }
+        if let Some(size) = parse_var(&var, "KV_MAX_STORED_VALUE_SIZE")? {
+            self.max_stored_value_size = size;
+        }
+        if let Some(ttl) = parse_var(&var, "KV_SNAPSHOT_TTL")? {
+            self.snapshot_ttl = ttl;
+        }
+        if let Some(size) = parse_var(&var, "KV_MAX_RESPONSE_SIZE")? {
+            self.max_response_size = size;
+        }
+        if let Some(normalization) = parse_var(&var, "KV_KEY_NORMALIZATION")? {
+            self.key_normalization = Some(normalization);
+        }
+        if let Some(confirm) = parse_var(&var, "KV_CONFIRM_DELETE_PREFIX")? {
+            self.confirm_delete_prefix = confirm;
+        }
+        if let Some(capacity) = parse_var(&var, "KV_WRITE_QUEUE_CAPACITY")? {
+            self.write_queue_capacity = capacity;
+        }
+        if let Some(threshold) = parse_var(&var, "KV_WRITE_FAILURE_THRESHOLD")? {
+            self.write_failure_threshold = threshold;
+        }
+        if let Some(interval) = parse_var(&var, "KV_WRITE_RETRY_INTERVAL")? {
+            self.write_retry_interval = interval;
+        }
+        if let Some(threshold) = parse_var(&var, "KV_COMPACTION_THRESHOLD")? {
+            self.compaction_threshold = threshold;
+        }
+        if let Some(interval) = parse_var(&var, "KV_COMPACTION_CHECK_INTERVAL")? {
+            self.compaction_check_interval = interval;
+        }
+        if let Some(interval) = parse_var(&var, "KV_VERIFY_INTERVAL")? {
+            self.verify_interval = interval;
+        }
+        if let Some(size) = parse_var(&var, "KV_VERIFY_SAMPLE_SIZE")? {
+            self.verify_sample_size = size;
+        }
+        if let Some(interval) = parse_var(&var, "KV_ACCESS_TIME_PERSIST_INTERVAL")? {
+            self.access_time_persist_interval = interval;
+        }
+        if let Some(interval) = parse_var(&var, "KV_LIFECYCLE_SWEEP_INTERVAL")? {
+            self.lifecycle_sweep_interval = interval;
+        }
+        if let Some(level) = parse_var(&var, "KV_LOG_LEVEL")? {
+            self.log_level = level;
+        }
+        Ok(())
+    }
+
+    /// Rejects settings which contradict each other or the server can't start with,
+    /// once the environment variables and flags were applied.
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        if self.bind.is_some() && !self.listeners.is_empty() {
+            return Err(
+                "bind (KV_BIND, --bind) can't be combined with listeners, list its address as \
+                 one of the listeners instead"
+                    .to_owned(),
+            );
+        }
+        let zeros = [
+            ("workers", self.workers == Some(0)),
+            ("write_queue_capacity", self.write_queue_capacity == 0),
+            ("write_failure_threshold", self.write_failure_threshold == 0),
+            (
+                "compaction_check_interval",
+                self.compaction_check_interval == 0,
+            ),
+            ("verify_interval", self.verify_interval == 0),
+            (
+                "access_time_persist_interval",
+                self.access_time_persist_interval == 0,
+            ),
+            (
+                "lifecycle_sweep_interval",
+                self.lifecycle_sweep_interval == 0,
+            ),
+        ];
+        if let Some((name, _)) = zeros.iter().find(|(_, zero)| *zero) {
+            return Err(format!("{} must be greater than 0", name));
+        }
+        Ok(())
+    }
+
+    /// The longest records the database is read with.
+    pub(crate) fn limits(&self) -> Limits {
+        Limits {
+            max_key_len: self.max_key_size,
+            max_value_len: self.max_stored_value_size,
+            max_mime_len: self.max_mime_size,
+        }
+    }
+}
+
+/// Parses the environment variable `name`, if it's set.
+fn parse_var<T>(var: impl Fn(&str) -> Option<String>, name: &str) -> Result<Option<T>, String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    var(name)
+        .map(|value| {
+            value
+                .parse()
+                .map_err(|e| format!("Invalid {}={:?}: {}", name, value, e))
+        })
+        .transpose()
+}
+
+/// Deserializes a string setting using its `FromStr` implementation.
+fn deserialize_from_str<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    String::deserialize(deserializer)?
+        .parse()
+        .map_err(serde::de::Error::custom)
+}
+
+/// Like `deserialize_from_str`, for settings which are optional.
+fn deserialize_some_from_str<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    deserialize_from_str(deserializer).map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use polling_test::auth::Operation;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(ServerConfig::parse("").unwrap(), ServerConfig::default());
+        let config = ServerConfig::parse(
+            r#"
+            bind = "0.0.0.0:9000"
+            max_value_size = 1024
+            workers = 2
+            sync = "250ms"
+            key_normalization = "lowercase"
+            log_level = "warn"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.bind.as_deref(), Some("0.0.0.0:9000"));
+        assert_eq!(config.sync, SyncPolicy::EveryNMillis(250));
+        assert_eq!(config.key_normalization, Some(KeyNormalization::Lowercase));
+        assert_eq!(config.max_value_size, 1024);
+        assert_eq!(config.workers, Some(2));
+        assert_eq!(config.log_level, log::LevelFilter::Warn);
+        assert_eq!(config.db, ServerConfig::default().db);
+        // Misspelled settings would be ignored silently otherwise.
+        assert!(ServerConfig::parse("max_value_sise = 1024").is_err());
+        assert!(ServerConfig::parse("sync = \"sometimes\"").is_err());
+        assert!(ServerConfig::parse("key_normalization = \"upper\"").is_err());
+        assert!(ServerConfig::parse("log_level = \"loud\"").is_err());
+    }
+
+    #[test]
+    fn test_parse_listeners() {
+        let config = ServerConfig::default();
+        assert_eq!(
+            config.listeners(),
+            [ListenerConfig::plain("127.0.0.1:8080".to_owned())]
+        );
+        let mut config = ServerConfig::parse(
+            r#"
+            [[listeners]]
+            bind = "[::]:8443"
+            tls = { cert = "cert.pem", key = "key.pem" }
+            token = "secret"
+
+            [[listeners]]
+            bind = "127.0.0.1:8080"
+            read_only = true
+            "#,
+        )
+        .unwrap();
+        let listeners = config.listeners();
+        assert_eq!(listeners.len(), 2);
+        assert_eq!(listeners[0].bind, "[::]:8443");
+        assert_eq!(
+            listeners[0].tls.as_ref().unwrap().key,
+            PathBuf::from("key.pem")
+        );
+        assert_eq!(listeners[0].token.as_deref(), Some("secret"));
+        assert!(!listeners[0].read_only);
+        assert!(listeners[1].read_only);
+        assert!(ServerConfig::parse("[[listeners]]\nread_only = true").is_err());
+        assert!(config.validate().is_ok());
+        // Listening on `bind` as well as the listeners was likely not meant.
+        config
+            .apply_env(|name| (name == "KV_BIND").then(|| "0.0.0.0:9000".to_owned()))
+            .unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_parse_grants() {
+        let config = ServerConfig::parse(
+            r#"
+            [[grants]]
+            token = "secret"
+            prefix = "users/"
+            operations = ["read", "delete_prefix"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.grants[0].prefix, "users/");
+        assert_eq!(
+            config.grants[0].operations,
+            [Operation::Read, Operation::DeletePrefix]
+        );
+        assert!(ServerConfig::parse("[[grants]]\ntoken = \"secret\"").is_err());
+        assert!(ServerConfig::parse(
+            "[[grants]]\ntoken = \"secret\"\noperations = [\"everything\"]"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_apply_env() {
+        let mut config = ServerConfig::parse("bind = \"0.0.0.0:9000\"\nworkers = 2").unwrap();
+        let vars = |name: &str| match name {
+            "KV_DB_PATH" => Some("/data/kv.db".to_owned()),
+            "KV_MIRROR_PATH" => Some("/backup/kv.db".to_owned()),
+            "KV_SEED" => Some("/data/seed.db".to_owned()),
+            "KV_READ_ONLY" => Some("true".to_owned()),
+            "KV_MAX_VALUE_SIZE" => Some("1024".to_owned()),
+            "KV_COMPRESSION_LEVEL" => Some("-5".to_owned()),
+            "KV_DELTA_THRESHOLD" => Some("65536".to_owned()),
+            "KV_MAX_MIME_SIZE" => Some("256".to_owned()),
+            "KV_SNAPSHOT_TTL" => Some("60".to_owned()),
+            "KV_MAX_RESPONSE_SIZE" => Some("4096".to_owned()),
+            "KV_LOG_LEVEL" => Some("debug".to_owned()),
+            _ => None,
+        };
+        config.apply_env(vars).unwrap();
+        assert_eq!(config.db, PathBuf::from("/data/kv.db"));
+        assert_eq!(config.mirror, Some(PathBuf::from("/backup/kv.db")));
+        assert_eq!(config.seed, Some(PathBuf::from("/data/seed.db")));
+        assert!(config.read_only);
+        assert_eq!(config.max_value_size, 1024);
+        assert_eq!(config.compression_level, -5);
+        assert_eq!(config.delta_threshold, 65536);
+        assert_eq!(config.limits().max_mime_len, 256);
+        assert_eq!(config.snapshot_ttl, 60);
+        assert_eq!(config.max_response_size, 4096);
+        assert_eq!(config.log_level, log::LevelFilter::Debug);
+        // Settings without a variable are kept from the file.
+        assert_eq!(config.bind.as_deref(), Some("0.0.0.0:9000"));
+        assert_eq!(config.workers, Some(2));
+
+        let invalid = |name: &str| (name == "KV_WORKERS").then(|| "many".to_owned());
+        assert!(config.apply_env(invalid).is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(ServerConfig::default().validate().is_ok());
+        let mut config = ServerConfig::parse(
+            "verify_interval = 60
+write_queue_capacity = 16",
+        )
+        .unwrap();
+        assert_eq!(config.verify_interval, 60);
+        assert_eq!(config.write_queue_capacity, 16);
+        assert!(config.validate().is_ok());
+
+        // Actix and tokio panic on these instead.
+        config
+            .apply_env(|name| (name == "KV_WORKERS").then(|| "0".to_owned()))
+            .unwrap();
+        assert_eq!(
+            config.validate().unwrap_err(),
+            "workers must be greater than 0"
+        );
+        let config = ServerConfig::parse("lifecycle_sweep_interval = 0").unwrap();
+        assert!(config.validate().is_err());
+    }
+}
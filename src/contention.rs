@@ -0,0 +1,343 @@
+//! Diagnostics of contention for the store. The store task owns the store, so it
+//! works like a lock every request takes: Writes and jobs queue up for it, and each
+//! holds it while it runs (see `write_queue::run_store`).
+//!
+//! `GET /_debug/contention` reports what holds the store right now, how many writes
+//! and jobs wait for it, and how often and how long each kind of operation held it
+//! since the server started. `GET /_admin/metrics` exports the same in the Prometheus
+//! text format, so regressions show up on dashboards rather than in latencies, along
+//! with the state of the circuit breaker guarding writes, see `WriteGuard`, and
+//! whether writes are frozen.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{atomic::Ordering, Mutex},
+    time::{Duration, Instant},
+};
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use serde::Serialize;
+
+use crate::write_guard::{CircuitState, WriteGuard};
+use crate::{authorize, AppState};
+use polling_test::auth::Operation;
+use polling_test::kv::backend::StorageBackend;
+
+/// How often and how long an operation held the store.
+#[derive(Clone, Copy, Default)]
+struct HoldStats {
+    count: u64,
+    total: Duration,
+    longest: Duration,
+}
+
+/// What holds the store, and what held it before.
+pub(crate) struct StoreActivity {
+    /// The operation holding the store, and since when.
+    holder: Mutex<Option<(&'static str, Instant)>>,
+    holds: Mutex<BTreeMap<&'static str, HoldStats>>,
+}
+
+impl StoreActivity {
+    pub(crate) fn new() -> Self {
+        Self {
+            holder: Mutex::new(None),
+            holds: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Records that `operation` holds the store until the returned guard is dropped.
+    pub(crate) fn hold(&self, operation: &'static str) -> Hold<'_> {
+        let started = Instant::now();
+        *self.holder.lock().unwrap() = Some((operation, started));
+        Hold {
+            activity: self,
+            operation,
+            started,
+        }
+    }
+
+    fn holder(&self) -> Option<(&'static str, Duration)> {
+        let holder = *self.holder.lock().unwrap();
+        holder.map(|(operation, since)| (operation, since.elapsed()))
+    }
+
+    fn holds(&self) -> BTreeMap<&'static str, HoldStats> {
+        self.holds.lock().unwrap().clone()
+    }
+}
+
+/// Releases the store when it's dropped, see `StoreActivity::hold`.
+pub(crate) struct Hold<'a> {
+    activity: &'a StoreActivity,
+    operation: &'static str,
+    started: Instant,
+}
+
+impl Drop for Hold<'_> {
+    fn drop(&mut self) {
+        let held = self.started.elapsed();
+        *self.activity.holder.lock().unwrap() = None;
+        let mut holds = self.activity.holds.lock().unwrap();
+        let stats = holds.entry(self.operation).or_default();
+        stats.count += 1;
+        stats.total += held;
+        stats.longest = stats.longest.max(held);
+    }
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+#[derive(Serialize)]
+struct Holder {
+    operation: &'static str,
+    held_ms: f64,
+}
+
+#[derive(Serialize)]
+struct QueueReport {
+    /// Writes or jobs waiting for the store.
+    queued: usize,
+    capacity: usize,
+    /// Jobs waiting for room in the queue, as it's full.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    waiting: Option<usize>,
+    /// Writes rejected since the server started, as the queue was full.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rejected: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct HoldReport {
+    operation: &'static str,
+    count: u64,
+    total_ms: f64,
+    longest_ms: f64,
+}
+
+#[derive(Serialize)]
+struct ContentionReport {
+    /// What holds the store, unless nothing does.
+    holder: Option<Holder>,
+    writes: QueueReport,
+    jobs: QueueReport,
+    holds: Vec<HoldReport>,
+}
+
+impl ContentionReport {
+    fn new<B: StorageBackend>(data: &AppState<B>) -> Self {
+        let activity = &data.store_activity;
+        Self {
+            holder: activity.holder().map(|(operation, held)| Holder {
+                operation,
+                held_ms: millis(held),
+            }),
+            writes: QueueReport {
+                queued: data.writes.queued(),
+                capacity: data.writes.capacity(),
+                waiting: None,
+                rejected: Some(data.writes.rejected()),
+            },
+            jobs: QueueReport {
+                queued: data.store.queued(),
+                capacity: data.store.capacity(),
+                waiting: Some(data.store.waiting()),
+                rejected: None,
+            },
+            holds: activity
+                .holds()
+                .into_iter()
+                .map(|(operation, stats)| HoldReport {
+                    operation,
+                    count: stats.count,
+                    total_ms: millis(stats.total),
+                    longest_ms: millis(stats.longest),
+                })
+                .collect(),
+        }
+    }
+
+    /// The report in the Prometheus text format.
+    fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, f64)]| {
+            write_metric(&mut out, name, kind, help, samples)
+        };
+        let queue = |queue: &str| format!("{{queue=\"{}\"}}", queue);
+        let operation = |operation: &str| format!("{{operation=\"{}\"}}", operation);
+        metric(
+            "kv_store_queued",
+            "gauge",
+            "Writes and jobs waiting for the store.",
+            &[
+                (queue("writes"), self.writes.queued as f64),
+                (queue("jobs"), self.jobs.queued as f64),
+            ],
+        );
+        metric(
+            "kv_store_queue_capacity",
+            "gauge",
+            "Most writes and jobs which can wait for the store.",
+            &[
+                (queue("writes"), self.writes.capacity as f64),
+                (queue("jobs"), self.jobs.capacity as f64),
+            ],
+        );
+        metric(
+            "kv_store_jobs_waiting",
+            "gauge",
+            "Jobs waiting for room in the full queue.",
+            &[(String::new(), self.jobs.waiting.unwrap_or(0) as f64)],
+        );
+        metric(
+            "kv_store_writes_rejected_total",
+            "counter",
+            "Writes rejected as the queue was full.",
+            &[(String::new(), self.writes.rejected.unwrap_or(0) as f64)],
+        );
+        let held = self.holder.as_ref().map_or(0.0, |holder| holder.held_ms);
+        metric(
+            "kv_store_held_seconds",
+            "gauge",
+            "How long the current operation has held the store, 0 if none does.",
+            &[(String::new(), held / 1000.0)],
+        );
+        let holds = |value: fn(&HoldReport) -> f64| -> Vec<(String, f64)> {
+            self.holds
+                .iter()
+                .map(|hold| (operation(hold.operation), value(hold)))
+                .collect()
+        };
+        metric(
+            "kv_store_holds_total",
+            "counter",
+            "How often operations held the store.",
+            &holds(|hold| hold.count as f64),
+        );
+        metric(
+            "kv_store_hold_seconds_total",
+            "counter",
+            "How long operations held the store.",
+            &holds(|hold| hold.total_ms / 1000.0),
+        );
+        metric(
+            "kv_store_hold_seconds_max",
+            "gauge",
+            "The longest an operation held the store.",
+            &holds(|hold| hold.longest_ms / 1000.0),
+        );
+        out
+    }
+}
+
+/// Appends a metric with its help and type lines to `out`.
+fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(String, f64)]) {
+    // Writing to a `String` can't fail.
+    _ = writeln!(out, "# HELP {} {}", name, help);
+    _ = writeln!(out, "# TYPE {} {}", name, kind);
+    for (labels, value) in samples {
+        _ = writeln!(out, "{}{} {}", name, labels, value);
+    }
+}
+
+/// The state of `guard` in the Prometheus text format: 1 for the state the circuit
+/// is in and 0 for the others, and how often it opened.
+fn circuit_to_prometheus(guard: &WriteGuard) -> String {
+    let mut out = String::new();
+    let current = guard.state();
+    let states = [
+        ("closed", CircuitState::Closed),
+        ("open", CircuitState::Open),
+        ("half_open", CircuitState::HalfOpen),
+    ]
+    .map(|(label, state)| {
+        let value = if state == current { 1.0 } else { 0.0 };
+        (format!("{{state=\"{}\"}}", label), value)
+    });
+    write_metric(
+        &mut out,
+        "kv_write_circuit_state",
+        "gauge",
+        "State of the circuit breaker guarding writes to the backing storage.",
+        &states,
+    );
+    write_metric(
+        &mut out,
+        "kv_write_circuit_trips_total",
+        "counter",
+        "How often the circuit breaker opened, rejecting writes.",
+        &[(String::new(), guard.trips() as f64)],
+    );
+    out
+}
+
+/// Reports the contention for the store, see the module documentation.
+pub(crate) async fn contention<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+) -> impl Responder {
+    if let Some(response) = authorize(&req, &data, Operation::Admin, "").await {
+        return response;
+    }
+    HttpResponse::Ok().json(ContentionReport::new(&data))
+}
+
+/// Exports the contention for the store and the state of the circuit breaker as
+/// Prometheus metrics.
+pub(crate) async fn metrics<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+) -> impl Responder {
+    if let Some(response) = authorize(&req, &data, Operation::Admin, "").await {
+        return response;
+    }
+    let mut metrics = ContentionReport::new(&data).to_prometheus();
+    metrics.push_str(&circuit_to_prometheus(&data.write_guard));
+    let frozen = data.frozen.load(Ordering::SeqCst);
+    write_metric(
+        &mut metrics,
+        "kv_writes_frozen",
+        "gauge",
+        "Whether writes are frozen by an admin, see /_admin/freeze.",
+        &[(String::new(), if frozen { 1.0 } else { 0.0 })],
+    );
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(metrics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use polling_test::kv::result::KVError;
+
+    #[test]
+    fn test_store_activity() {
+        let activity = StoreActivity::new();
+        assert!(activity.holder().is_none());
+        let hold = activity.hold("set");
+        assert_eq!(activity.holder().unwrap().0, "set");
+        drop(hold);
+        drop(activity.hold("set"));
+        drop(activity.hold("job"));
+        assert!(activity.holder().is_none());
+        let holds = activity.holds();
+        assert_eq!(holds["set"].count, 2);
+        assert_eq!(holds["job"].count, 1);
+        assert!(holds["set"].longest <= holds["set"].total);
+    }
+
+    #[test]
+    fn test_circuit_to_prometheus() {
+        let guard = WriteGuard::new(1, Duration::from_secs(5));
+        let error = KVError::IO(std::io::Error::other("failed"));
+        guard.record_failure(&error);
+        let metrics = circuit_to_prometheus(&guard);
+        assert!(metrics.contains("kv_write_circuit_state{state=\"open\"} 1\n"));
+        assert!(metrics.contains("kv_write_circuit_state{state=\"closed\"} 0\n"));
+        assert!(metrics.contains("kv_write_circuit_trips_total 1\n"));
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::BTreeMap;
+
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{HeaderName, HeaderValue, ACCEPT, AUTHORIZATION, COOKIE, PROXY_AUTHORIZATION},
+    middleware::Next,
+    web, HttpMessage, HttpRequest, HttpResponse, Responder,
+};
+use serde::Serialize;
+
+use crate::{authorize, AppState};
+use polling_test::auth::Operation;
+use polling_test::kv::backend::StorageBackend;
+
+/// Header carrying an ID to correlate a request with its response and log lines.
+pub(crate) const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+/// Headers with credentials, whose values `echo_request` leaves out.
+const REDACTED_HEADERS: [HeaderName; 3] = [AUTHORIZATION, PROXY_AUTHORIZATION, COOKIE];
+
+/// Echoes the client's `X-Request-Id` on the response, or generates one if the
+/// client didn't send it.
+pub(crate) async fn request_id(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let id = match req.headers().get(X_REQUEST_ID) {
+        Some(id) => id.clone(),
+        None => {
+            let id = HeaderValue::from_str(&format!("{:016x}", rand::random::<u64>())).unwrap();
+            req.headers_mut().insert(X_REQUEST_ID, id.clone());
+            id
+        }
+    };
+    log::debug!("{} {} (request id {:?})", req.method(), req.path(), id);
+    let mut res = next.call(req).await?;
+    res.headers_mut().insert(X_REQUEST_ID, id);
+    Ok(res)
+}
+
+/// How the server understood a request, see `echo_request`.
+#[derive(Serialize)]
+struct RequestInfo {
+    request_id: Option<String>,
+    method: String,
+    path: String,
+    query: String,
+    matched_route: Option<String>,
+    peer: Option<String>,
+    content_type: String,
+    accept: Option<String>,
+    headers: BTreeMap<String, String>,
+}
+
+/// Responds with a description of how the request was parsed, to help debug client
+/// integrations. Only admins may ask, and credentials are redacted regardless.
+pub(crate) async fn echo_request<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+) -> impl Responder {
+    if let Some(response) = authorize(&req, &data, Operation::Admin, "").await {
+        return response;
+    }
+    describe(&req)
+}
+
+fn describe(req: &HttpRequest) -> HttpResponse {
+    let header = |value: &HeaderValue| String::from_utf8_lossy(value.as_bytes()).into_owned();
+    let info = RequestInfo {
+        request_id: req.headers().get(X_REQUEST_ID).map(header),
+        method: req.method().to_string(),
+        path: req.path().to_string(),
+        query: req.query_string().to_string(),
+        matched_route: req.match_pattern(),
+        peer: req.peer_addr().map(|addr| addr.to_string()),
+        content_type: req.content_type().to_string(),
+        accept: req.headers().get(ACCEPT).map(header),
+        headers: req
+            .headers()
+            .iter()
+            .map(|(name, value)| {
+                let value = if REDACTED_HEADERS.contains(name) {
+                    "[redacted]".to_owned()
+                } else {
+                    header(value)
+                };
+                (name.to_string(), value)
+            })
+            .collect(),
+    };
+    HttpResponse::Ok().json(info)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{middleware::from_fn, test, web, App};
+
+    async fn echo(req: HttpRequest) -> HttpResponse {
+        describe(&req)
+    }
+
+    #[actix_web::test]
+    async fn test_echo_request_with_request_id() {
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(request_id))
+                .route("/_debug/request", web::route().to(echo)),
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/_debug/request?x=1")
+            .insert_header((X_REQUEST_ID, "abc"))
+            .insert_header((ACCEPT, "text/plain"))
+            .insert_header((AUTHORIZATION, "Bearer secret"))
+            .insert_header((COOKIE, "session=secret"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.headers().get(X_REQUEST_ID).unwrap(), "abc");
+        let info: serde_json::Value = test::read_body_json(res).await;
+        assert_eq!(info["request_id"], "abc");
+        assert_eq!(info["query"], "x=1");
+        assert_eq!(info["matched_route"], "/_debug/request");
+        assert_eq!(info["accept"], "text/plain");
+        assert_eq!(info["headers"]["authorization"], "[redacted]");
+        assert_eq!(info["headers"]["cookie"], "[redacted]");
+
+        let req = test::TestRequest::get().uri("/_debug/request").to_request();
+        let res = test::call_service(&app, req).await;
+        assert!(res.headers().contains_key(X_REQUEST_ID));
+    }
+}
@@ -0,0 +1,189 @@
+//! Error responses in the format the client asks for. Errors are sent as JSON
+//! `ErrorBody`s, unless the client's `Accept` header prefers `text/plain`, in which
+//! case only the message is sent.
+//!
+//! Handlers answer errors either with a `KVError`, which already is an `ErrorBody`, or
+//! with a plain text message, which `negotiate` turns into one. The code of such
+//! errors is derived from the status, e.g. `precondition_failed` for 412.
+
+use actix_web::{
+    body::{self, BoxBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::{
+        header::{self, Header, HeaderValue, CONTENT_TYPE},
+        StatusCode,
+    },
+    middleware::Next,
+    mime, HttpRequest, HttpResponse,
+};
+
+use polling_test::kv::result::ErrorBody;
+
+/// Marks error responses whose body isn't an error message, such as the current
+/// value sent with a failed precondition, so that `negotiate` leaves them alone.
+pub(crate) struct Verbatim;
+
+/// Whether the client prefers error messages as plain text over JSON.
+fn prefers_text(req: &HttpRequest) -> bool {
+    let Ok(accept) = header::Accept::parse(req) else {
+        return false;
+    };
+    accept
+        .ranked()
+        .iter()
+        .find_map(|mime| match (mime.type_(), mime.subtype()) {
+            (mime::TEXT, mime::PLAIN | mime::STAR) => Some(true),
+            (mime::APPLICATION, mime::JSON | mime::STAR) | (mime::STAR, _) => Some(false),
+            _ => None,
+        })
+        .unwrap_or(false)
+}
+
+/// The code of errors which don't have one of their own, e.g. `not_found`.
+fn status_code(status: StatusCode) -> String {
+    let reason = status.canonical_reason().unwrap_or("Error");
+    reason
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == ' ' || *c == '-')
+        .map(|c| match c {
+            ' ' | '-' => '_',
+            c => c.to_ascii_lowercase(),
+        })
+        .collect()
+}
+
+/// Converts the body of an error response to the format the client asks for. Bodies
+/// which are neither plain text nor an `ErrorBody` are kept.
+fn convert(
+    req: &HttpRequest,
+    status: StatusCode,
+    content_type: Option<&str>,
+    body: &[u8],
+) -> Option<(&'static str, Vec<u8>)> {
+    let essence = content_type.map(|content_type| {
+        content_type
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase()
+    });
+    let error = match essence.as_deref() {
+        None | Some("text/plain") => {
+            let message = String::from_utf8_lossy(body);
+            let message = match message.trim() {
+                "" => status.canonical_reason().unwrap_or("Error"),
+                message => message,
+            };
+            ErrorBody {
+                error: message.to_owned(),
+                code: status_code(status),
+                key: req.match_info().get("key").map(str::to_owned),
+            }
+        }
+        Some("application/json") => serde_json::from_slice(body).ok()?,
+        Some(_) => return None,
+    };
+    if prefers_text(req) {
+        Some(("text/plain; charset=utf-8", error.error.into_bytes()))
+    } else {
+        let body = serde_json::to_vec(&error).expect("ErrorBody always serializes");
+        Some(("application/json", body))
+    }
+}
+
+/// Sends error responses in the format the client asks for, see the module
+/// documentation.
+pub(crate) async fn negotiate(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, actix_web::Error> {
+    let res = next.call(req).await?;
+    let status = res.status();
+    if !(status.is_client_error() || status.is_server_error())
+        || res.response().extensions().contains::<Verbatim>()
+    {
+        return Ok(res.map_into_boxed_body());
+    }
+    let (req, res) = res.into_parts();
+    let (res, res_body) = res.into_parts();
+    // Error responses are small, and held in memory.
+    let res_body = match body::to_bytes(res_body).await {
+        Ok(res_body) => res_body,
+        Err(e) => {
+            log::error!("Error reading the body of an error response: {}", e.into());
+            let res = HttpResponse::InternalServerError().finish();
+            return Ok(ServiceResponse::new(req, res));
+        }
+    };
+    let content_type = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    let res = match convert(&req, status, content_type, &res_body) {
+        Some((content_type, converted)) => {
+            let mut res = res.set_body(BoxBody::new(converted));
+            let content_type = HeaderValue::from_static(content_type);
+            res.headers_mut().insert(CONTENT_TYPE, content_type);
+            res
+        }
+        None => res.set_body(BoxBody::new(res_body)),
+    };
+    Ok(ServiceResponse::new(req, res))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::header::ACCEPT, middleware::from_fn, test, web, App};
+
+    #[actix_web::test]
+    async fn test_negotiate() {
+        let app = test::init_service(
+            App::new()
+                .wrap(from_fn(negotiate))
+                .route(
+                    "/{key}",
+                    web::get().to(|| async { HttpResponse::NotFound().finish() }),
+                )
+                .route(
+                    "/{key}",
+                    web::post().to(|| async {
+                        let mut res = HttpResponse::PreconditionFailed().body("current value");
+                        res.extensions_mut().insert(Verbatim);
+                        res
+                    }),
+                ),
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/a").to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let body: ErrorBody = test::read_body_json(res).await;
+        assert_eq!(
+            body,
+            ErrorBody {
+                error: "Not Found".to_owned(),
+                code: "not_found".to_owned(),
+                key: Some("a".to_owned()),
+            }
+        );
+
+        let req = test::TestRequest::get()
+            .uri("/a")
+            .insert_header((ACCEPT, "text/plain, application/json;q=0.5"))
+            .to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert_eq!(body, "Not Found");
+
+        let req = test::TestRequest::post().uri("/a").to_request();
+        let body = test::call_and_read_body(&app, req).await;
+        assert_eq!(body, "current value");
+
+        assert_eq!(
+            status_code(StatusCode::PAYLOAD_TOO_LARGE),
+            "payload_too_large"
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use actix_web::http::header::HeaderName;
+use serde::Serialize;
+
+use crate::write_guard::CircuitState;
+
+/// Header carrying the health score, so load balancers can weigh nodes without
+/// parsing the body.
+pub(crate) const X_HEALTH_SCORE: HeaderName = HeaderName::from_static("x-health-score");
+
+/// Average write latency above which the node reports itself as degraded.
+const DEGRADED_WRITE_LATENCY: Duration = Duration::from_millis(100);
+/// Most points slow writes can cost, reached at five times the threshold.
+const MAX_LATENCY_PENALTY: u8 = 50;
+/// Points lost while writes are rejected because the backing storage failed.
+const READ_ONLY_PENALTY: u8 = 50;
+/// Points lost while writes are let through again after the backing storage failed,
+/// but none succeeded yet.
+const HALF_OPEN_PENALTY: u8 = 25;
+/// Points lost while writes are frozen by an admin.
+const FROZEN_PENALTY: u8 = 20;
+
+/// Keeps a moving average of how long writes to the backing storage take.
+pub(crate) struct WriteLatency {
+    /// Average in microseconds, 0 until the first write.
+    average_us: AtomicU64,
+}
+
+impl WriteLatency {
+    pub(crate) fn new() -> Self {
+        Self {
+            average_us: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn record(&self, latency: Duration) {
+        let sample = (latency.as_micros() as u64).max(1);
+        _ = self
+            .average_us
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |average| {
+                if average == 0 {
+                    Some(sample)
+                } else {
+                    // Exponential moving average, weighing the new sample by 1/8.
+                    Some((average * 7 + sample) / 8)
+                }
+            });
+    }
+
+    pub(crate) fn average(&self) -> Duration {
+        Duration::from_micros(self.average_us.load(Ordering::Relaxed))
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum HealthStatus {
+    Ok,
+    /// Still serving, but a load balancer should prefer other nodes.
+    Degraded,
+}
+
+/// Response body of `/healthz`.
+#[derive(Serialize)]
+pub(crate) struct HealthReport {
+    pub(crate) status: HealthStatus,
+    /// 100 for a fully healthy node, lower the more degraded it is.
+    pub(crate) score: u8,
+    pub(crate) write_latency_ms: f64,
+    /// Whether writes are rejected because the backing storage failed.
+    pub(crate) read_only: bool,
+    /// State of the circuit breaker guarding writes to the backing storage.
+    pub(crate) circuit: CircuitState,
+    /// How often the circuit breaker opened since the server started.
+    pub(crate) circuit_trips: u64,
+    pub(crate) frozen: bool,
+}
+
+impl HealthReport {
+    pub(crate) fn new(
+        write_latency: Duration,
+        circuit: CircuitState,
+        circuit_trips: u64,
+        frozen: bool,
+    ) -> Self {
+        let mut score = 100u8;
+        score -= latency_penalty(write_latency);
+        score -= match circuit {
+            CircuitState::Closed => 0,
+            CircuitState::Open => READ_ONLY_PENALTY,
+            CircuitState::HalfOpen => HALF_OPEN_PENALTY,
+        };
+        if frozen {
+            score = score.saturating_sub(FROZEN_PENALTY);
+        }
+        Self {
+            status: if score == 100 {
+                HealthStatus::Ok
+            } else {
+                HealthStatus::Degraded
+            },
+            score,
+            write_latency_ms: write_latency.as_secs_f64() * 1000.0,
+            read_only: circuit == CircuitState::Open,
+            circuit,
+            circuit_trips,
+            frozen,
+        }
+    }
+}
+
+/// No penalty up to the threshold, growing linearly to the maximum at five times it.
+fn latency_penalty(latency: Duration) -> u8 {
+    if latency <= DEGRADED_WRITE_LATENCY {
+        return 0;
+    }
+    let excess = (latency - DEGRADED_WRITE_LATENCY).as_secs_f64()
+        / (DEGRADED_WRITE_LATENCY * 4).as_secs_f64();
+    // Any latency above the threshold costs at least one point, so the node shows up as degraded.
+    (excess * MAX_LATENCY_PENALTY as f64).clamp(1.0, MAX_LATENCY_PENALTY as f64) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_health_score() {
+        let healthy = HealthReport::new(Duration::from_millis(5), CircuitState::Closed, 0, false);
+        assert_eq!(healthy.status, HealthStatus::Ok);
+        assert_eq!(healthy.score, 100);
+
+        let slow = HealthReport::new(Duration::from_millis(300), CircuitState::Closed, 0, false);
+        assert_eq!(slow.status, HealthStatus::Degraded);
+        assert_eq!(slow.score, 75);
+
+        let recovering =
+            HealthReport::new(Duration::from_millis(5), CircuitState::HalfOpen, 1, false);
+        assert_eq!(recovering.score, 75);
+        assert!(!recovering.read_only);
+
+        let worst = HealthReport::new(Duration::from_secs(10), CircuitState::Open, 1, true);
+        assert_eq!(worst.score, 0);
+        assert!(worst.read_only);
+    }
+
+    #[test]
+    fn test_write_latency_average() {
+        let latency = WriteLatency::new();
+        assert_eq!(latency.average(), Duration::ZERO);
+        latency.record(Duration::from_millis(80));
+        assert_eq!(latency.average(), Duration::from_millis(80));
+        latency.record(Duration::from_millis(0));
+        assert_eq!(latency.average(), Duration::from_millis(70));
+    }
+}
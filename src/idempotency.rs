@@ -0,0 +1,243 @@
+//! Deduplication of retried writes: A client sends the same `Idempotency-Key` header
+//! with every attempt of a write, and every attempt after the first one that succeeded
+//! gets the response of that one, without writing again.
+//!
+//! The responses are stored as entries below `IDEMPOTENCY_PREFIX`, so deduplication
+//! survives restarts. They expire after `IDEMPOTENCY_TTL`, after which the expiry
+//! sweeper removes them like any other entry.
+
+use std::{future::Future, time::Duration};
+
+use actix_web::{
+    body,
+    http::{
+        header::{HeaderValue, CONTENT_TYPE, LOCATION},
+        StatusCode,
+    },
+    HttpRequest, HttpResponse, ResponseError,
+};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::write_queue::SetOutcome;
+use crate::{AppState, META_HEADER_PREFIX, X_KV_MERGE_TOKEN, X_KV_ON_CONFLICT, X_KV_TTL};
+use polling_test::auth::Identity;
+use polling_test::kv::{backend::StorageBackend, entry::Entry};
+
+/// Header naming the write a request is an attempt of.
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+/// Set on responses which were stored for an earlier attempt.
+const IDEMPOTENT_REPLAYED: &str = "idempotent-replayed";
+/// Responses are stored under this prefix, followed by the idempotency key.
+const IDEMPOTENCY_PREFIX: &str = "_idempotency/";
+/// How long retries of a write are deduplicated.
+const IDEMPOTENCY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Longest idempotency key accepted, which is plenty for a UUID or ULID.
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
+/// The response to the first successful attempt of a write.
+#[derive(Serialize, Deserialize)]
+struct StoredResponse {
+    /// Hash of the request, so that a key reused for a different request is noticed.
+    fingerprint: String,
+    status: u16,
+    content_type: Option<String>,
+    location: Option<String>,
+    /// Base64 encoded.
+    body: String,
+}
+
+impl StoredResponse {
+    fn to_response(&self) -> Result<HttpResponse, String> {
+        let status = StatusCode::from_u16(self.status).map_err(|e| e.to_string())?;
+        let body = BASE64_STANDARD
+            .decode(&self.body)
+            .map_err(|e| e.to_string())?;
+        let mut response = HttpResponse::build(status);
+        response.insert_header((IDEMPOTENT_REPLAYED, "true"));
+        if let Some(content_type) = &self.content_type {
+            response.insert_header((CONTENT_TYPE, content_type.as_str()));
+        }
+        if let Some(location) = &self.location {
+            response.insert_header((LOCATION, location.as_str()));
+        }
+        Ok(response.body(body))
+    }
+}
+
+/// Request headers which change what a write does, and so are part of its fingerprint,
+/// along with the `X-KV-Meta-*` headers.
+const FINGERPRINT_HEADERS: [&str; 5] = [
+    "if-match",
+    "content-type",
+    X_KV_TTL,
+    X_KV_ON_CONFLICT,
+    X_KV_MERGE_TOKEN,
+];
+
+/// A hash of what a request asks for and who asks for it: its method, path, query,
+/// the headers which change the write, the bearer token, and the body. The peer address
+/// isn't part of it, since retries may come from another connection.
+fn fingerprint(req: &HttpRequest, body: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(req.method().as_str());
+    hasher.update([0]);
+    hasher.update(req.uri().to_string());
+    hasher.update([0]);
+    let identity = Identity::from_request(req);
+    hasher.update(identity.bearer_token.unwrap_or_default());
+    hasher.update([0]);
+    let mut headers: Vec<_> = req
+        .headers()
+        .iter()
+        .filter(|(name, _)| {
+            FINGERPRINT_HEADERS.contains(&name.as_str())
+                || name.as_str().starts_with(META_HEADER_PREFIX)
+        })
+        .collect();
+    headers.sort_by(|(a, a_value), (b, b_value)| {
+        (a.as_str(), a_value.as_bytes()).cmp(&(b.as_str(), b_value.as_bytes()))
+    });
+    for (name, value) in headers {
+        hasher.update(name.as_str());
+        hasher.update([0]);
+        hasher.update(value.as_bytes());
+        hasher.update([0]);
+    }
+    hasher.update(body);
+    hasher.finalize()[..16]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// The idempotency key of the request, if it has one. Returns why it was rejected if
+/// it's invalid.
+fn idempotency_key(req: &HttpRequest) -> Result<Option<&str>, String> {
+    let Some(key) = req.headers().get(IDEMPOTENCY_KEY) else {
+        return Ok(None);
+    };
+    match key.to_str() {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_IDEMPOTENCY_KEY_LEN => Ok(Some(key)),
+        _ => Err(format!(
+            "Invalid Idempotency-Key: Must be 1 to {} visible ASCII characters",
+            MAX_IDEMPOTENCY_KEY_LEN
+        )),
+    }
+}
+
+/// Runs `handle` for the write in the request, unless an earlier attempt with the same
+/// `Idempotency-Key` succeeded, in which case that attempt's response is sent again.
+/// `body` is the request body, which is part of what makes attempts the same.
+///
+/// The callers authorize the request before, so that also attempts which are
+/// answered with a stored response are, and a client which may no longer write
+/// doesn't learn how earlier attempts went.
+///
+/// Only successful responses are stored, so failed attempts can be retried. Attempts
+/// which run at the same time aren't deduplicated.
+pub(crate) async fn once<B: StorageBackend>(
+    req: &HttpRequest,
+    data: &AppState<B>,
+    body: &[u8],
+    handle: impl Future<Output = HttpResponse>,
+) -> HttpResponse {
+    let key = match idempotency_key(req) {
+        Ok(Some(key)) => format!("{}{}", IDEMPOTENCY_PREFIX, key),
+        Ok(None) => return handle.await,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    let fingerprint = fingerprint(req, body);
+    let stored = data.store.peek(key.clone()).await;
+    match stored {
+        Ok(Some(entry)) => {
+            let replay = serde_json::from_slice::<StoredResponse>(&entry.value)
+                .map_err(|e| e.to_string())
+                .and_then(|stored| {
+                    if stored.fingerprint != fingerprint {
+                        return Ok(HttpResponse::UnprocessableEntity()
+                            .body("Idempotency-Key was already used for a different request"));
+                    }
+                    stored.to_response()
+                });
+            match replay {
+                Ok(response) => return response,
+                // The write is done again rather than failing every retry.
+                Err(e) => log::error!("Invalid stored response for {:?}: {}", key, e),
+            }
+        }
+        Ok(None) => {}
+        Err(e) => return e.error_response(),
+    }
+
+    let response = handle.await;
+    if !response.status().is_success() {
+        return response;
+    }
+    let (response, response_body) = response.into_parts();
+    let response_body = body::to_bytes(response_body)
+        .await
+        .expect("responses to writes are held in memory");
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value: &HeaderValue| value.to_str().ok())
+            .map(str::to_owned)
+    };
+    let stored = StoredResponse {
+        fingerprint,
+        status: response.status().as_u16(),
+        content_type: header(CONTENT_TYPE),
+        location: header(LOCATION),
+        body: BASE64_STANDARD.encode(&response_body),
+    };
+    let value = serde_json::to_vec(&stored).expect("StoredResponse always serializes");
+    let entry =
+        Entry::new(value, "application/json").with_expiry(data.clock.now() + IDEMPOTENCY_TTL);
+    // The write itself succeeded, so the client gets its response either way.
+    match data.writes.set(key.clone(), entry, |_| true).await {
+        Ok(SetOutcome::Set) => {}
+        Ok(SetOutcome::ConditionFailed(_)) => unreachable!("the condition always holds"),
+        Err(e) => log::error!("Error storing response for {:?}: {}", key, e),
+    }
+    response.set_body(response_body).map_into_boxed_body()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_fingerprint() {
+        let req = TestRequest::post().uri("/a").to_http_request();
+        assert_eq!(fingerprint(&req, b"1"), fingerprint(&req, b"1"));
+        assert_ne!(fingerprint(&req, b"1"), fingerprint(&req, b"2"));
+        let other = TestRequest::post().uri("/b").to_http_request();
+        assert_ne!(fingerprint(&req, b"1"), fingerprint(&other, b"1"));
+        let with_header = |header: (&str, &str)| {
+            let req = TestRequest::post()
+                .uri("/a")
+                .insert_header(header)
+                .to_http_request();
+            fingerprint(&req, b"1")
+        };
+        for header in [
+            ("authorization", "Bearer secret"),
+            ("if-match", "\"1\""),
+            ("content-type", "text/plain"),
+            ("x-kv-ttl", "60"),
+            ("x-kv-meta-owner", "alice"),
+        ] {
+            assert_ne!(fingerprint(&req, b"1"), with_header(header), "{:?}", header);
+        }
+        assert_eq!(with_header(("x-request-id", "1")), fingerprint(&req, b"1"));
+
+        let req = TestRequest::post()
+            .insert_header((IDEMPOTENCY_KEY, "x".repeat(MAX_IDEMPOTENCY_KEY_LEN + 1)))
+            .to_http_request();
+        assert!(idempotency_key(&req).is_err());
+    }
+}
@@ -0,0 +1,75 @@
+//! Tracking of when entries were last read.
+//!
+//! Like `relatime` does for files, a read only updates the access time of an entry if
+//! the recorded one is older than the granularity, so frequently read entries don't
+//! cause a write each. Updated access times are collected in memory, and persisted
+//! in batches by `KVStore::persist_access_times`.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+/// The access times which were updated since they were last persisted.
+#[derive(Default)]
+pub(crate) struct AccessTimes {
+    /// `None` while access times aren't tracked.
+    granularity: Option<Duration>,
+    pending: Mutex<HashMap<String, SystemTime>>,
+}
+
+impl AccessTimes {
+    pub(crate) fn set_granularity(&mut self, granularity: Option<Duration>) {
+        self.granularity = granularity;
+    }
+
+    /// Records that `key` was read at `now`, unless `last`, the access time recorded
+    /// before, is more recent than the granularity.
+    pub(crate) fn record(&self, key: &str, last: Option<SystemTime>, now: SystemTime) {
+        let Some(granularity) = self.granularity else {
+            return;
+        };
+        let mut pending = self.pending.lock().unwrap();
+        let last = pending.get(key).copied().or(last);
+        if last.is_some_and(|last| now < last + granularity) {
+            return;
+        }
+        pending.insert(key.to_owned(), now);
+    }
+
+    /// The access time of `key` which wasn't persisted yet, if there is one.
+    pub(crate) fn pending(&self, key: &str) -> Option<SystemTime> {
+        self.pending.lock().unwrap().get(key).copied()
+    }
+
+    /// Takes all access times which weren't persisted yet.
+    pub(crate) fn take(&mut self) -> HashMap<String, SystemTime> {
+        std::mem::take(self.pending.get_mut().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut access = AccessTimes::default();
+        access.record("a", None, start);
+        assert!(access.take().is_empty());
+
+        access.set_granularity(Some(Duration::from_secs(60)));
+        access.record("a", None, start);
+        access.record("a", None, start + Duration::from_secs(30));
+        assert_eq!(access.pending("a"), Some(start));
+        // Recent enough access times which were persisted before are kept as well.
+        access.record("b", Some(start), start + Duration::from_secs(59));
+        access.record("c", Some(start), start + Duration::from_secs(60));
+        let pending = access.take();
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending["c"], start + Duration::from_secs(60));
+        assert_eq!(access.pending("a"), None);
+    }
+}
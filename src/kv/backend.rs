@@ -0,0 +1,1523 @@
+//! Where a `KVStore` keeps its records.
+//!
+//! The store indexes the records, while a `StorageBackend` persists them and reads
+//! values back when they're requested. `FileBackend` keeps the records in a log file,
+//! `LogBackend` keeps them in the same format on any other stream, and `MemoryBackend`
+//! doesn't keep them at all. Other engines, like S3 or sled, plug in by implementing
+//! `StorageBackend`.
+
+use std::{
+    future::Future,
+    io::{self, SeekFrom},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use log::{debug, error, info, warn};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    sync::Mutex,
+};
+
+use super::{
+    codec::{self, Decoded, Limits, DEFAULT_COMPRESSION_LEVEL},
+    direct_io::DirectWriter,
+    entry::KVEntry,
+    header::{self, Header, FORMAT_VERSION},
+    migration::path_with_suffix,
+    result::{KVError, KVResult},
+};
+
+/// This trait exists to allow for the use of both `File` and `MemoryNoOpRWS` (as well as anything
+/// else that implements `AsyncRead`, `AsyncWrite`, `AsyncSeek`, etc. as the stream of a
+/// `LogBackend`.
+pub trait AsyncRWS:
+    tokio::io::AsyncRead + tokio::io::AsyncWrite + tokio::io::AsyncSeek + Unpin
+{
+}
+
+impl<T: tokio::io::AsyncRead + tokio::io::AsyncWrite + tokio::io::AsyncSeek + ?Sized + Unpin>
+    AsyncRWS for T
+{
+}
+
+/// Persists the records of a `KVStore`.
+///
+/// Records are addressed by the offsets `append` and `load_all` hand out, which stay
+/// valid until the storage is replaced by a compacted copy.
+pub trait StorageBackend: Send + Sync + Sized + 'static {
+    /// Reads the header and then every record, passing each record to `on_record`
+    /// along with its offset. Returns `None` for empty storage, which the store then
+    /// initializes with `create`.
+    ///
+    /// Storage in any format version other than `FORMAT_VERSION` is rejected with
+    /// `KVError::UnsupportedVersion`, and needs to be upgraded with
+    /// `migration::migrate_file` first.
+    fn load_all(
+        &mut self,
+        on_record: impl FnMut(u64, KVEntry) + Send,
+    ) -> impl Future<Output = KVResult<Option<Header>>> + Send;
+
+    /// Initializes empty storage with a header.
+    fn create(&mut self, header: &Header) -> impl Future<Output = KVResult<()>> + Send;
+
+    /// Appends a record, returning its offset. If appending fails midway, the next
+    /// append replaces whatever part of the record was written.
+    fn append(&mut self, record: &KVEntry) -> impl Future<Output = KVResult<u64>> + Send;
+
+    /// Appends several records, returning their offsets. Backends which can should
+    /// persist them all at once, instead of one at a time like `append`.
+    ///
+    /// If appending fails midway, the next append replaces the records which were
+    /// written, but a crash before that may leave them in the storage.
+    fn append_batch(
+        &mut self,
+        records: &[KVEntry],
+    ) -> impl Future<Output = KVResult<Vec<u64>>> + Send {
+        async move {
+            let mut offsets = Vec::with_capacity(records.len());
+            for record in records {
+                offsets.push(self.append(record).await?);
+            }
+            Ok(offsets)
+        }
+    }
+
+    /// Reads the record at `offset` back. Only called if `keeps_records`.
+    fn read(&self, offset: u64) -> impl Future<Output = KVResult<KVEntry>> + Send;
+
+    /// Whether records can be read back with `read`. If not, the store keeps all
+    /// values in memory.
+    fn keeps_records(&self) -> bool;
+
+    /// Makes sure that all appended records are persisted, which also shows whether
+    /// the storage still works.
+    fn sync(&mut self) -> impl Future<Output = KVResult<()>> + Send;
+
+    /// Checks that the storage still accepts writes, without changing what it
+    /// stores. By default, that's what `sync` shows.
+    fn probe(&mut self) -> impl Future<Output = KVResult<()>> + Send {
+        self.sync()
+    }
+
+    /// Size of the storage in bytes, which grows with every record until it's compacted.
+    fn size(&self) -> u64;
+
+    /// Creates empty storage of the same kind, which a compacted copy of the records
+    /// is written to before it replaces this storage with `replace`.
+    fn create_sibling(&self) -> impl Future<Output = KVResult<Self>> + Send;
+
+    /// Replaces this storage with a compacted copy created by `create_sibling`.
+    fn replace(&mut self, compacted: Self) -> impl Future<Output = KVResult<()>> + Send;
+
+    /// Opens the records appended so far for reading apart from this backend, which
+    /// goes on appending records meanwhile, see `KVStore::verify_sample`. Returns
+    /// `None` if the backend can't, which is the default.
+    fn reader(&self) -> impl Future<Output = KVResult<Option<RecordReader>>> + Send {
+        async { Ok(None) }
+    }
+
+    /// Whether `append` stores the record compressed.
+    fn compresses(&self, _record: &KVEntry) -> bool {
+        false
+    }
+
+    /// Values larger than this many bytes are compressed by `append`, which is
+    /// recorded in the header of new storage. `None` if values are never compressed.
+    /// Storage whose header records another threshold fails to load.
+    fn compression_threshold(&self) -> Option<u64> {
+        None
+    }
+
+    /// Compresses values larger than `bytes` from now on, see
+    /// `LogBackend::with_compression_threshold`. Backends which don't compress ignore
+    /// this.
+    fn set_compression_threshold(&mut self, _bytes: usize) {}
+
+    /// Compresses values at zstd `level` from now on, see
+    /// `LogBackend::with_compression_level`. Backends which don't compress ignore this.
+    fn set_compression_level(&mut self, _level: i32) {}
+
+    /// Longest keys, values, and MIME types of the records the storage reads back.
+    /// The store rejects longer ones before they're written.
+    fn limits(&self) -> Limits {
+        Limits::default()
+    }
+}
+
+/// Values larger than this many bytes are compressed, unless configured otherwise.
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+/// How many bytes are read at once when records are read one after another, so that
+/// reading records in the order they were written, e.g. scanning a compacted log in
+/// key order, doesn't need a read for each of them.
+const READ_AHEAD_LEN: usize = 256 * 1024;
+/// How many bytes are read at first when a record is read on its own, which is enough
+/// for most records, so that they're read at once.
+const RECORD_READ_LEN: usize = 4 * 1024;
+
+/// Reads the records a backend held when `StorageBackend::reader` was called.
+pub struct RecordReader {
+    stream: Box<dyn AsyncRead + Send + Unpin>,
+    /// The offset of the first record.
+    start: u64,
+    limits: Limits,
+}
+
+impl RecordReader {
+    /// Reads every record, passing each one to `on_record` along with its offset.
+    pub async fn for_each(self, on_record: impl FnMut(u64, KVEntry)) -> KVResult<()> {
+        replay_entries(self.stream, self.start, &self.limits, on_record).await?;
+        Ok(())
+    }
+}
+
+/// The bytes read ahead of the last record read by `LogBackend::read`.
+#[derive(Default)]
+struct ReadAhead {
+    /// The offset `buf` was read from.
+    start: u64,
+    buf: Vec<u8>,
+    /// Where the last record read ends. Reading the record there is sequential.
+    next: u64,
+}
+
+impl ReadAhead {
+    /// Decodes the record at `offset` from the buffer, if it's all in there.
+    fn take(&mut self, offset: u64, limits: &Limits) -> KVResult<Option<KVEntry>> {
+        let Some(at) = offset.checked_sub(self.start) else {
+            return Ok(None);
+        };
+        let Some(buf) = self.buf.get(at as usize..) else {
+            return Ok(None);
+        };
+        match codec::decode_limited(buf, limits)? {
+            Decoded::Complete(entry, len) => {
+                self.next = offset + len as u64;
+                Ok(Some(entry))
+            }
+            Decoded::Incomplete(_) => Ok(None),
+        }
+    }
+}
+
+/// Keeps the records in a log on a stream: A header, followed by the records in the
+/// order they were appended.
+pub struct LogBackend<T: AsyncRWS> {
+    /// Locked to read records, which `read` does with a shared reference, unless
+    /// they're read from `reader`.
+    stream: Mutex<T>,
+    /// A second handle on the log file of a `FileBackend`, which records are read from
+    /// at their offsets, so that reads neither wait for each other, nor move the
+    /// stream records are appended through.
+    reader: Option<Arc<std::fs::File>>,
+    /// What the last sequential read read ahead, see `read`.
+    read_ahead: std::sync::Mutex<ReadAhead>,
+    /// The offset the next record is appended at. Reading records moves the stream
+    /// away from the end.
+    end: u64,
+    /// Whether the stream gives back what's written to it, which `MemoryNoOpRWS`
+    /// doesn't.
+    readable: bool,
+    /// Values larger than this many bytes are compressed.
+    compression_threshold: usize,
+    /// The zstd level values are compressed at.
+    compression_level: i32,
+    /// Records longer than this are rejected when they're read.
+    limits: Limits,
+}
+
+impl<T: AsyncRWS + Send> LogBackend<T> {
+    pub fn new(stream: T) -> Self {
+        Self {
+            stream: Mutex::new(stream),
+            reader: None,
+            read_ahead: Default::default(),
+            end: 0,
+            readable: true,
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            compression_level: DEFAULT_COMPRESSION_LEVEL,
+            limits: Limits::default(),
+        }
+    }
+
+    /// Compresses values larger than `bytes` instead of `DEFAULT_COMPRESSION_THRESHOLD`.
+    /// Without the `zstd` feature, values are never compressed.
+    pub fn with_compression_threshold(mut self, bytes: usize) -> Self {
+        self.compression_threshold = bytes;
+        self
+    }
+
+    /// Compresses values at zstd `level` instead of `DEFAULT_COMPRESSION_LEVEL`. Higher
+    /// levels compress better, but take longer. Values compressed at any level are
+    /// read back alike, so the level can change at any time.
+    pub fn with_compression_level(mut self, level: i32) -> Self {
+        self.compression_level = level;
+        self
+    }
+
+    /// Reads records up to `limits` instead of the default ones. Storage which holds
+    /// a longer record fails to load.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Returns the underlying stream.
+    pub fn into_inner(self) -> T {
+        self.stream.into_inner()
+    }
+
+    async fn load(
+        &mut self,
+        on_record: impl FnMut(u64, KVEntry) + Send,
+    ) -> KVResult<Option<Header>> {
+        let stream = self.stream.get_mut();
+        stream.seek(SeekFrom::Start(0)).await?;
+        let header = match read_header(&mut *stream).await? {
+            None => return Ok(None),
+            Some(header) if header.version != FORMAT_VERSION => {
+                return Err(KVError::UnsupportedVersion(header.version))
+            }
+            Some(header) if header.encrypted => return Err(KVError::Encrypted),
+            Some(header) => header,
+        };
+        self.readable = true;
+        let end = replay_entries(
+            &mut *stream,
+            header.encoded_len() as u64,
+            &self.limits,
+            on_record,
+        )
+        .await?;
+        debug!("Finished reading all entries");
+        let len = stream.seek(SeekFrom::End(0)).await?;
+        if len > end {
+            // The next record is written over it.
+            warn!(
+                "Discarding a partially written record at offset {} ({} bytes), left behind by a crash",
+                end,
+                len - end
+            );
+        }
+        self.end = end;
+        Ok(Some(header))
+    }
+
+    async fn create(&mut self, header: &Header) -> KVResult<()> {
+        debug!("Empty backing storage, writing file header");
+        let stream = self.stream.get_mut();
+        stream.seek(SeekFrom::Start(0)).await?;
+        stream.write_all(&header::encode(header)).await?;
+        stream.flush().await?;
+        // Streams which don't give the header back won't give records back either.
+        stream.seek(SeekFrom::Start(0)).await?;
+        self.readable = read_header(&mut *stream).await?.is_some();
+        self.end = header.encoded_len() as u64;
+        Ok(())
+    }
+
+    async fn append(&mut self, record: &KVEntry) -> KVResult<u64> {
+        Ok(self.append_batch(std::slice::from_ref(record)).await?[0])
+    }
+
+    /// Writes the records one after another, and flushes once at the end.
+    async fn append_batch(&mut self, records: &[KVEntry]) -> KVResult<Vec<u64>> {
+        let start = self.end;
+        let stream = self.stream.get_mut();
+        let result: KVResult<(Vec<u64>, u64)> = async {
+            stream.seek(SeekFrom::Start(start)).await?;
+            let mut offsets = Vec::with_capacity(records.len());
+            let mut offset = start;
+            for record in records {
+                offsets.push(offset);
+                write_entry(
+                    &mut *stream,
+                    record,
+                    self.compression_threshold,
+                    self.compression_level,
+                )
+                .await?;
+                offset = stream.stream_position().await?;
+            }
+            // `tokio::fs::File` writes in the background, so errors only surface here.
+            stream.flush().await?;
+            Ok((offsets, offset))
+        }
+        .await;
+        match result {
+            Ok((offsets, end)) => {
+                self.end = end;
+                Ok(offsets)
+            }
+            Err(err) => {
+                warn!("Writing entries at offset {} failed: {}", start, err);
+                Err(err)
+            }
+        }
+    }
+
+    /// Reads ahead when the record at `offset` follows the one read before it. Records
+    /// are never changed once they're written, so what's read ahead stays valid.
+    async fn read(&self, offset: u64) -> KVResult<KVEntry> {
+        if let Some(reader) = &self.reader {
+            return self.read_at(reader, offset).await;
+        }
+        let mut stream = self.stream.lock().await;
+        if let Some(entry) = self.read_ahead.lock().unwrap().take(offset, &self.limits)? {
+            return Ok(entry);
+        }
+        let sequential = self.read_ahead.lock().unwrap().next == offset;
+        stream.seek(SeekFrom::Start(offset)).await?;
+        if sequential {
+            let len = READ_AHEAD_LEN.min(self.end.saturating_sub(offset) as usize);
+            let mut buf = vec![0; len];
+            stream.read_exact(&mut buf).await?;
+            let mut read_ahead = ReadAhead {
+                start: offset,
+                buf,
+                next: offset,
+            };
+            let entry = read_ahead.take(offset, &self.limits)?;
+            *self.read_ahead.lock().unwrap() = read_ahead;
+            if let Some(entry) = entry {
+                return Ok(entry);
+            }
+            // The record is larger than what's read ahead.
+            stream.seek(SeekFrom::Start(offset)).await?;
+        }
+        let (entry, len) = KVEntry::read_record(&mut *stream, &self.limits).await?;
+        self.read_ahead.lock().unwrap().next = offset + len as u64;
+        Ok(entry)
+    }
+
+    /// Like `read`, but reads from `file` at the offset, without locking the stream.
+    async fn read_at(&self, file: &Arc<std::fs::File>, offset: u64) -> KVResult<KVEntry> {
+        if let Some(entry) = self.read_ahead.lock().unwrap().take(offset, &self.limits)? {
+            return Ok(entry);
+        }
+        let sequential = self.read_ahead.lock().unwrap().next == offset;
+        let available = self.end.saturating_sub(offset) as usize;
+        let len = if sequential {
+            READ_AHEAD_LEN
+        } else {
+            RECORD_READ_LEN
+        };
+        let mut buf = read_exact_at(file, offset, len.min(available)).await?;
+        loop {
+            match codec::decode_limited(&buf, &self.limits)? {
+                Decoded::Complete(entry, len) => {
+                    let mut read_ahead = self.read_ahead.lock().unwrap();
+                    // Records larger than what's read ahead are read on their own.
+                    if sequential && buf.len() <= READ_AHEAD_LEN {
+                        *read_ahead = ReadAhead {
+                            start: offset,
+                            buf,
+                            next: offset,
+                        };
+                    }
+                    read_ahead.next = offset + len as u64;
+                    return Ok(entry);
+                }
+                Decoded::Incomplete(needed) if needed <= available => {
+                    let at = offset + buf.len() as u64;
+                    buf.extend(read_exact_at(file, at, needed - buf.len()).await?);
+                }
+                Decoded::Incomplete(_) => {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into())
+                }
+            }
+        }
+    }
+
+    async fn flush(&mut self) -> KVResult<()> {
+        self.stream.get_mut().flush().await?;
+        Ok(())
+    }
+
+    fn compresses(&self, record: &KVEntry) -> bool {
+        compresses(record, self.compression_threshold)
+    }
+
+    fn compression_threshold(&self) -> Option<u64> {
+        cfg!(feature = "zstd").then_some(self.compression_threshold as u64)
+    }
+}
+
+/// Logs on in-memory streams, such as `std::io::Cursor<Vec<u8>>`. Compacting one
+/// writes the records to a new, empty stream.
+impl<T: AsyncRWS + Default + Send + 'static> StorageBackend for LogBackend<T> {
+    async fn load_all(
+        &mut self,
+        on_record: impl FnMut(u64, KVEntry) + Send,
+    ) -> KVResult<Option<Header>> {
+        self.load(on_record).await
+    }
+
+    async fn create(&mut self, header: &Header) -> KVResult<()> {
+        LogBackend::create(self, header).await
+    }
+
+    async fn append(&mut self, record: &KVEntry) -> KVResult<u64> {
+        LogBackend::append(self, record).await
+    }
+
+    async fn append_batch(&mut self, records: &[KVEntry]) -> KVResult<Vec<u64>> {
+        LogBackend::append_batch(self, records).await
+    }
+
+    async fn read(&self, offset: u64) -> KVResult<KVEntry> {
+        LogBackend::read(self, offset).await
+    }
+
+    fn keeps_records(&self) -> bool {
+        self.readable
+    }
+
+    async fn sync(&mut self) -> KVResult<()> {
+        self.flush().await
+    }
+
+    fn size(&self) -> u64 {
+        self.end
+    }
+
+    async fn create_sibling(&self) -> KVResult<Self> {
+        Ok(Self::new(T::default())
+            .with_compression_threshold(self.compression_threshold)
+            .with_compression_level(self.compression_level)
+            .with_limits(self.limits))
+    }
+
+    async fn replace(&mut self, compacted: Self) -> KVResult<()> {
+        *self = compacted;
+        Ok(())
+    }
+
+    fn compresses(&self, record: &KVEntry) -> bool {
+        LogBackend::compresses(self, record)
+    }
+
+    fn compression_threshold(&self) -> Option<u64> {
+        LogBackend::compression_threshold(self)
+    }
+
+    fn set_compression_threshold(&mut self, bytes: usize) {
+        self.compression_threshold = bytes;
+    }
+
+    fn set_compression_level(&mut self, level: i32) {
+        self.compression_level = level;
+    }
+
+    fn limits(&self) -> Limits {
+        self.limits
+    }
+}
+
+/// Keeps the records in a log file.
+pub struct FileBackend {
+    log: LogBackend<File>,
+    path: PathBuf,
+    /// Set for compacted copies until they replace the original. Those which don't
+    /// are removed when they're dropped.
+    temporary: bool,
+    /// Whether the file is locked, see `open`. Compacted copies are locked as well
+    /// before they replace it.
+    locked: bool,
+    mirror: Option<Mirror>,
+    /// Set for compacted copies which are written with `O_DIRECT`, until they're
+    /// synced. They can't be read from before that.
+    direct: Option<DirectWriter>,
+    /// Set if the file was opened with `open_read_only`, so loading it doesn't
+    /// truncate it.
+    read_only: bool,
+}
+
+/// A copy of the log file which every record is written to as well, see
+/// `FileBackend::with_mirror`.
+struct Mirror {
+    path: PathBuf,
+    /// `None` while the mirror doesn't hold the same records as the log file, as
+    /// writing to it failed.
+    log: Option<LogBackend<File>>,
+}
+
+impl Mirror {
+    fn new(path: PathBuf) -> Self {
+        Self { path, log: None }
+    }
+
+    /// Stops writing to the mirror after `err`, until it's rewritten by compacting.
+    fn fail(&mut self, action: &str, err: &KVError) {
+        error!(
+            "{} mirror {:?} failed, not mirroring until the database is compacted: {}",
+            action, self.path, err
+        );
+        self.log = None;
+    }
+}
+
+/// Takes an advisory lock on the database file at `path`, which is released when the
+/// file is closed. Shared locks only keep out exclusive ones.
+async fn lock(file: File, path: &Path, shared: bool) -> KVResult<File> {
+    let file = file.into_std().await;
+    let locked = if shared {
+        file.try_lock_shared()
+    } else {
+        file.try_lock()
+    };
+    match locked {
+        Ok(()) => {}
+        Err(std::fs::TryLockError::WouldBlock) => return Err(KVError::Locked(path.to_owned())),
+        Err(std::fs::TryLockError::Error(err)) if err.kind() == io::ErrorKind::Unsupported => {
+            warn!("Can't lock {:?} on this platform: {}", path, err)
+        }
+        Err(std::fs::TryLockError::Error(err)) => return Err(err.into()),
+    }
+    Ok(File::from_std(file))
+}
+
+/// Clones a handle on `file` for `LogBackend::read_at`. Only files which nothing is
+/// being done with yet can be cloned, and only where reading at an offset leaves the
+/// position of the file alone.
+fn clone_reader(file: File) -> (File, Option<Arc<std::fs::File>>) {
+    if !cfg!(unix) {
+        return (file, None);
+    }
+    match file.try_into_std() {
+        Ok(file) => {
+            let reader = file.try_clone().ok().map(Arc::new);
+            (File::from_std(file), reader)
+        }
+        Err(file) => (file, None),
+    }
+}
+
+/// Reads `len` bytes at `offset` of `file` on the blocking thread pool.
+async fn read_exact_at(file: &Arc<std::fs::File>, offset: u64, len: usize) -> KVResult<Vec<u8>> {
+    let file = Arc::clone(file);
+    let read = tokio::task::spawn_blocking(move || {
+        let mut buf = vec![0; len];
+        read_exact_at_blocking(&file, &mut buf, offset).map(|()| buf)
+    });
+    Ok(read.await.map_err(io::Error::other)??)
+}
+
+#[cfg(unix)]
+fn read_exact_at_blocking(file: &std::fs::File, buf: &mut [u8], offset: u64) -> io::Result<()> {
+    std::os::unix::fs::FileExt::read_exact_at(file, buf, offset)
+}
+
+/// `clone_reader` doesn't clone handles on other platforms.
+#[cfg(not(unix))]
+fn read_exact_at_blocking(_: &std::fs::File, _: &mut [u8], _: u64) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Fails with `StorageFull` if fewer than `needed` bytes are left on the file system
+/// of `path`, for unprivileged processes.
+#[cfg(unix)]
+fn check_free_space(path: &Path, needed: u64) -> io::Result<()> {
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: `path` is a valid C string, and `stat` is only read if `statvfs`
+    // succeeded, which then filled it in.
+    let available = unsafe {
+        let mut stat: libc::statvfs = std::mem::zeroed();
+        if libc::statvfs(path.as_ptr(), &mut stat) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        stat.f_bavail as u64 * stat.f_frsize as u64
+    };
+    if available < needed {
+        return Err(io::Error::new(
+            io::ErrorKind::StorageFull,
+            format!("{} bytes left, {} needed", available, needed),
+        ));
+    }
+    Ok(())
+}
+
+/// Only the probe file shows whether there's room on other platforms.
+#[cfg(not(unix))]
+fn check_free_space(_: &Path, _: u64) -> io::Result<()> {
+    Ok(())
+}
+
+/// Opens the file at `path` for reading and writing, emptying it if it exists.
+async fn create_truncated(path: &Path) -> io::Result<File> {
+    File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .await
+}
+
+impl FileBackend {
+    /// Opens the database file at `path`, creating it if it doesn't exist.
+    ///
+    /// The file is locked until the backend is dropped, so that other processes
+    /// opening it fail with `KVError::Locked` rather than writing to it as well.
+    /// The lock is advisory, so it doesn't keep out anything which opens the file
+    /// otherwise.
+    pub async fn open(path: impl AsRef<Path>) -> KVResult<Self> {
+        let mut options = File::options();
+        options.create(true).truncate(false);
+        Self::open_with(path, options).await
+    }
+
+    /// Like `open`, but opens the file with `options`, which are always set to read
+    /// and write.
+    pub async fn open_with(path: impl AsRef<Path>, mut options: OpenOptions) -> KVResult<Self> {
+        let path = path.as_ref();
+        let file = options.read(true).write(true).open(path).await?;
+        let mut backend = Self::new(lock(file, path, false).await?, path);
+        backend.locked = true;
+        Ok(backend)
+    }
+
+    /// Opens the existing database file at `path` for reading only, for stores which
+    /// are built with `KVStoreBuilder::read_only`, e.g. to inspect a database without
+    /// changing it.
+    ///
+    /// The file is locked like with `open`, but shared, so that any number of
+    /// read-only backends can open it, while `open` fails with `KVError::Locked`.
+    pub async fn open_read_only(path: impl AsRef<Path>) -> KVResult<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).await?;
+        let mut backend = Self::new(lock(file, path, true).await?, path);
+        backend.locked = true;
+        backend.read_only = true;
+        Ok(backend)
+    }
+
+    /// Uses an already opened database file, which must be readable and writable.
+    /// `path` is where it's found, so it can be replaced when compacting.
+    pub fn new(file: File, path: impl Into<PathBuf>) -> Self {
+        let (file, reader) = clone_reader(file);
+        Self {
+            log: LogBackend {
+                reader,
+                ..LogBackend::new(file)
+            },
+            path: path.into(),
+            temporary: false,
+            locked: false,
+            mirror: None,
+            direct: None,
+            read_only: false,
+        }
+    }
+
+    /// Writes every record to a second file at `path` as well, e.g. on another disk,
+    /// so that losing one disk doesn't lose the database.
+    ///
+    /// Failing to write to the mirror doesn't fail the write, but stops mirroring
+    /// until the database is compacted, which rewrites the mirror. It's also rewritten
+    /// from the log file whenever that's loaded. Loading an empty log file fails if
+    /// the mirror isn't empty, rather than emptying the mirror as well; copying the
+    /// mirror over the log file restores the database.
+    pub fn with_mirror(mut self, path: impl Into<PathBuf>) -> Self {
+        self.mirror = Some(Mirror::new(path.into()));
+        self
+    }
+
+    /// Whether a mirror was configured with `with_mirror`, and holds the same
+    /// records as the log file.
+    pub fn mirror_in_sync(&self) -> bool {
+        self.mirror
+            .as_ref()
+            .is_some_and(|mirror| mirror.log.is_some())
+    }
+
+    /// See `LogBackend::with_compression_threshold`.
+    pub fn with_compression_threshold(mut self, bytes: usize) -> Self {
+        self.log.compression_threshold = bytes;
+        self
+    }
+
+    /// See `LogBackend::with_compression_level`.
+    pub fn with_compression_level(mut self, level: i32) -> Self {
+        self.log.compression_level = level;
+        self
+    }
+
+    /// See `LogBackend::with_limits`.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.log.limits = limits;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Makes the mirror a copy of the log file after it was loaded, or checks that
+    /// the mirror is empty if the log file is.
+    async fn load_mirror(&mut self, loaded: bool) -> KVResult<()> {
+        let Some(mirror) = &mut self.mirror else {
+            return Ok(());
+        };
+        if !loaded {
+            let len = tokio::fs::metadata(&mirror.path).await.map(|m| m.len());
+            if len.is_ok_and(|len| len > 0) {
+                return Err(KVError::InvalidData(format!(
+                    "{:?} is empty, but its mirror {:?} isn't. Copy the mirror over it to restore the database, or remove the mirror",
+                    self.path, mirror.path
+                )));
+            }
+            return Ok(());
+        }
+        let copying = path_with_suffix(&mirror.path, ".copying");
+        let copied = async {
+            tokio::fs::copy(&self.path, &copying).await?;
+            let file = File::options()
+                .read(true)
+                .write(true)
+                .open(&copying)
+                .await?;
+            file.sync_all().await?;
+            tokio::fs::rename(&copying, &mirror.path).await?;
+            Ok::<_, io::Error>(file)
+        }
+        .await;
+        match copied {
+            Ok(file) => {
+                let mut log = LogBackend::new(file)
+                    .with_compression_threshold(self.log.compression_threshold)
+                    .with_compression_level(self.log.compression_level)
+                    .with_limits(self.log.limits);
+                log.end = self.log.end;
+                mirror.log = Some(log);
+                info!("Mirroring {:?} to {:?}", self.path, mirror.path);
+            }
+            Err(err) => {
+                _ = tokio::fs::remove_file(&copying).await;
+                mirror.fail("Copying the database to", &err.into());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StorageBackend for FileBackend {
+    async fn load_all(
+        &mut self,
+        on_record: impl FnMut(u64, KVEntry) + Send,
+    ) -> KVResult<Option<Header>> {
+        let header = self.log.load(on_record).await?;
+        // Writing over a partially written record could leave some of it behind.
+        let file = self.log.stream.get_mut();
+        if !self.read_only && file.metadata().await?.len() > self.log.end {
+            file.set_len(self.log.end).await?;
+            file.sync_all().await?;
+            info!("Truncated {:?} to {} bytes", self.path, self.log.end);
+        }
+        self.load_mirror(header.is_some()).await?;
+        Ok(header)
+    }
+
+    async fn create(&mut self, header: &Header) -> KVResult<()> {
+        match &mut self.direct {
+            Some(direct) => {
+                direct.write(&header::encode(header)).await?;
+                self.log.end = header.encoded_len() as u64;
+            }
+            None => self.log.create(header).await?,
+        }
+        if let Some(mirror) = &mut self.mirror {
+            let created = async {
+                let file = create_truncated(&mirror.path).await?;
+                let mut log = LogBackend::new(file)
+                    .with_compression_threshold(self.log.compression_threshold)
+                    .with_compression_level(self.log.compression_level)
+                    .with_limits(self.log.limits);
+                log.create(header).await?;
+                Ok::<_, KVError>(log)
+            }
+            .await;
+            match created {
+                Ok(log) => mirror.log = Some(log),
+                Err(err) => mirror.fail("Creating", &err),
+            }
+        }
+        Ok(())
+    }
+
+    async fn append(&mut self, record: &KVEntry) -> KVResult<u64> {
+        Ok(self.append_batch(std::slice::from_ref(record)).await?[0])
+    }
+
+    /// Writes the records to the mirror once they're written to the log file, so that
+    /// a failed write leaves both of them unchanged.
+    async fn append_batch(&mut self, records: &[KVEntry]) -> KVResult<Vec<u64>> {
+        let offsets = match &mut self.direct {
+            Some(direct) => {
+                let mut buf = Vec::new();
+                let mut offsets = Vec::with_capacity(records.len());
+                for record in records {
+                    offsets.push(self.log.end + buf.len() as u64);
+                    let (threshold, level) =
+                        (self.log.compression_threshold, self.log.compression_level);
+                    write_entry(&mut buf, record, threshold, level).await?;
+                }
+                direct.write(&buf).await?;
+                self.log.end += buf.len() as u64;
+                offsets
+            }
+            None => self.log.append_batch(records).await?,
+        };
+        if let Some(mirror) = &mut self.mirror {
+            if let Some(log) = &mut mirror.log {
+                match log.append_batch(records).await {
+                    Ok(mirrored) if mirrored == offsets => {}
+                    Ok(_) => mirror.fail(
+                        "Writing to",
+                        &KVError::Corrupted("records were written at other offsets".to_owned()),
+                    ),
+                    Err(err) => mirror.fail("Writing to", &err),
+                }
+            }
+        }
+        Ok(offsets)
+    }
+
+    /// Reads the record from the mirror if reading it from the log file fails.
+    async fn read(&self, offset: u64) -> KVResult<KVEntry> {
+        if self.direct.is_some() {
+            return Err(KVError::IO(io::Error::other(
+                "records written with O_DIRECT can't be read before they're synced",
+            )));
+        }
+        let mirror = self.mirror.as_ref().and_then(|mirror| mirror.log.as_ref());
+        match (self.log.read(offset).await, mirror) {
+            (Err(KVError::IO(err)), Some(mirror)) => {
+                warn!(
+                    "Reading offset {} of {:?} failed, reading it from the mirror: {}",
+                    offset, self.path, err
+                );
+                mirror.read(offset).await
+            }
+            (result, _) => result,
+        }
+    }
+
+    fn keeps_records(&self) -> bool {
+        true
+    }
+
+    async fn sync(&mut self) -> KVResult<()> {
+        if let Some(direct) = self.direct.take() {
+            let len = direct.finish().await?;
+            debug_assert_eq!(len, self.log.end);
+        }
+        self.log.flush().await?;
+        self.log.stream.get_mut().sync_all().await?;
+        if let Some(mirror) = &mut self.mirror {
+            if let Some(log) = &mut mirror.log {
+                let synced = async {
+                    log.flush().await?;
+                    log.stream.get_mut().sync_all().await?;
+                    Ok::<_, KVError>(())
+                }
+                .await;
+                if let Err(err) = synced {
+                    mirror.fail("Syncing", &err);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Also writes a probe file next to the database file, as syncing alone doesn't
+    /// notice e.g. a file system which was remounted read-only, and checks that a
+    /// record with a value as large as the limits allow still fits, as a small probe
+    /// file may still fit onto a full disk. Files opened with `open_read_only` aren't
+    /// written to, only checked for still being there.
+    async fn probe(&mut self) -> KVResult<()> {
+        if self.read_only {
+            tokio::fs::metadata(&self.path).await?;
+            return Ok(());
+        }
+        self.sync().await?;
+        let limits = self.log.limits;
+        let needed = limits.max_key_len + limits.max_value_len + limits.max_mime_len;
+        check_free_space(&self.path, needed as u64)?;
+        let path = path_with_suffix(&self.path, ".probe");
+        let mut file = File::create(&path).await?;
+        let written = async {
+            file.write_all(b"probe").await?;
+            file.sync_all().await
+        }
+        .await;
+        _ = tokio::fs::remove_file(&path).await;
+        Ok(written?)
+    }
+
+    fn size(&self) -> u64 {
+        self.log.end
+    }
+
+    /// Opens the file once more, without locking it, as the lock is held already.
+    /// Records appended after this aren't read, so there's no partially written one.
+    async fn reader(&self) -> KVResult<Option<RecordReader>> {
+        // Compacted copies written with `O_DIRECT` aren't all in the file yet.
+        if self.direct.is_some() {
+            return Ok(None);
+        }
+        let mut file = File::open(&self.path).await?;
+        if read_header(&mut file).await?.is_none() {
+            return Ok(None);
+        }
+        let start = file.stream_position().await?;
+        Ok(Some(RecordReader {
+            stream: Box::new(file.take(self.log.end.saturating_sub(start))),
+            start,
+            limits: self.log.limits,
+        }))
+    }
+
+    /// The compacted copy is written to a temporary file next to the database file,
+    /// and one next to the mirror, even if the mirror stopped being written to. With
+    /// the `direct-io` feature, the copy of the database file is written with
+    /// `O_DIRECT` if its file system supports that, see `direct_io`.
+    async fn create_sibling(&self) -> KVResult<Self> {
+        let path = path_with_suffix(&self.path, ".compacting");
+        let mut file = create_truncated(&path).await?;
+        if self.locked {
+            file = lock(file, &path, false).await?;
+        }
+        let mut sibling = Self::new(file, path)
+            .with_compression_threshold(self.log.compression_threshold)
+            .with_compression_level(self.log.compression_level)
+            .with_limits(self.log.limits);
+        sibling.temporary = true;
+        sibling.locked = self.locked;
+        sibling.mirror = (self.mirror.as_ref())
+            .map(|mirror| Mirror::new(path_with_suffix(&mirror.path, ".compacting")));
+        sibling.direct = DirectWriter::open(&sibling.path).await?;
+        Ok(sibling)
+    }
+
+    /// Atomically renames the compacted copy over the database file. If anything fails
+    /// before that, the database file is left unchanged. The compacted mirror is
+    /// renamed over the mirror afterwards.
+    async fn replace(&mut self, mut compacted: Self) -> KVResult<()> {
+        compacted.sync().await?;
+        tokio::fs::rename(&compacted.path, &self.path).await?;
+        compacted.temporary = false;
+        std::mem::swap(&mut self.log, &mut compacted.log);
+        let (Some(mirror), Some(compacted_mirror)) = (&mut self.mirror, compacted.mirror.take())
+        else {
+            return Ok(());
+        };
+        // The records moved, so the old mirror is out of sync either way.
+        let was_in_sync = mirror.log.take().is_some();
+        let Some(log) = compacted_mirror.log else {
+            _ = tokio::fs::remove_file(&compacted_mirror.path).await;
+            return Ok(());
+        };
+        match tokio::fs::rename(&compacted_mirror.path, &mirror.path).await {
+            Ok(()) => {
+                if !was_in_sync {
+                    info!("Mirroring {:?} to {:?} again", self.path, mirror.path);
+                }
+                mirror.log = Some(log);
+            }
+            Err(err) => {
+                _ = tokio::fs::remove_file(&compacted_mirror.path).await;
+                mirror.fail("Replacing", &err.into());
+            }
+        }
+        Ok(())
+    }
+
+    fn compresses(&self, record: &KVEntry) -> bool {
+        self.log.compresses(record)
+    }
+
+    fn compression_threshold(&self) -> Option<u64> {
+        self.log.compression_threshold()
+    }
+
+    fn set_compression_threshold(&mut self, bytes: usize) {
+        self.log.compression_threshold = bytes;
+    }
+
+    fn set_compression_level(&mut self, level: i32) {
+        self.log.compression_level = level;
+    }
+
+    fn limits(&self) -> Limits {
+        self.log.limits
+    }
+}
+
+impl Drop for FileBackend {
+    fn drop(&mut self) {
+        if self.temporary {
+            _ = std::fs::remove_file(&self.path);
+            if let Some(mirror) = &self.mirror {
+                _ = std::fs::remove_file(&mirror.path);
+            }
+        }
+    }
+}
+
+/// Doesn't keep any records, for stores which don't need to persist anything. The
+/// store keeps all values in memory instead.
+#[derive(Debug, Default)]
+pub struct MemoryBackend;
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    async fn load_all(
+        &mut self,
+        _on_record: impl FnMut(u64, KVEntry) + Send,
+    ) -> KVResult<Option<Header>> {
+        Ok(None)
+    }
+
+    async fn create(&mut self, _header: &Header) -> KVResult<()> {
+        Ok(())
+    }
+
+    async fn append(&mut self, _record: &KVEntry) -> KVResult<u64> {
+        Ok(0)
+    }
+
+    async fn read(&self, offset: u64) -> KVResult<KVEntry> {
+        Err(KVError::InvalidData(format!(
+            "MemoryBackend doesn't keep records, can't read offset {}",
+            offset
+        )))
+    }
+
+    fn keeps_records(&self) -> bool {
+        false
+    }
+
+    async fn sync(&mut self) -> KVResult<()> {
+        Ok(())
+    }
+
+    fn size(&self) -> u64 {
+        0
+    }
+
+    async fn create_sibling(&self) -> KVResult<Self> {
+        Ok(Self)
+    }
+
+    async fn replace(&mut self, _compacted: Self) -> KVResult<()> {
+        Ok(())
+    }
+}
+
+/// Whether `write_entry` compresses the entry. Without the `zstd` feature, values are
+/// always written uncompressed.
+fn compresses(kv_entry: &KVEntry, threshold: usize) -> bool {
+    cfg!(feature = "zstd") && kv_entry.value.len() > threshold
+}
+
+/// Writes the entry to the stream, compressing it at zstd `level` if its value is
+/// larger than `threshold` bytes.
+async fn write_entry(
+    stream: impl AsyncWriteExt + Unpin,
+    kv_entry: &KVEntry,
+    threshold: usize,
+    #[cfg_attr(not(feature = "zstd"), allow(unused_variables))] level: i32,
+) -> KVResult<()> {
+    // For an in-memory KV store the underlying implementation is a no-op
+    // for the following lines which write to the stream.
+    if compresses(kv_entry, threshold) {
+        debug!(
+            "Value length exceeds {} bytes, compressing entry",
+            threshold
+        );
+        #[cfg(feature = "zstd")]
+        kv_entry.write_to_stream_compressed(stream, level).await?;
+    } else {
+        debug!("Value length is within limit, writing uncompressed entry");
+        kv_entry.write_to_stream(stream).await?;
+    }
+    Ok(())
+}
+
+/// Reads entries from the stream until the end, passing each one to `on_entry` along
+/// with its offset, counting from `offset` for the current position of the stream.
+///
+/// Returns the offset after the last complete entry. A partially written entry at the
+/// end of the stream, as a crash while writing it leaves behind, is ignored, so the
+/// stream may continue past that offset. So is a corrupt entry followed by nothing but
+/// zeros. Entries longer than `limits` are rejected.
+pub(crate) async fn replay_entries(
+    mut stream: impl AsyncReadExt + Unpin,
+    mut offset: u64,
+    limits: &Limits,
+    mut on_entry: impl FnMut(u64, KVEntry),
+) -> KVResult<u64> {
+    loop {
+        match KVEntry::read_record(&mut stream, limits).await {
+            Ok((entry, len)) => {
+                on_entry(offset, entry);
+                offset += len as u64;
+            }
+            Err(err) => match err {
+                KVError::IO(error) => match error.kind() {
+                    io::ErrorKind::UnexpectedEof => {
+                        debug!("Reached end of file");
+                        break;
+                    }
+                    _ => {
+                        debug!("IO Error of kind: {:?}", error.kind());
+                        return Err(KVError::IO(error));
+                    }
+                },
+                KVError::ChecksumMismatch { .. } | KVError::InvalidData(_)
+                    if only_zeros_remain(&mut stream).await? =>
+                {
+                    // Nothing valid follows, so it's the last record, which the crash
+                    // left partially written, or never wrote its bytes to disk.
+                    warn!(
+                        "Ignoring a corrupt record at offset {} at the end of the log: {}",
+                        offset, err
+                    );
+                    break;
+                }
+                _ => {
+                    debug!("Non-IO error: {:?}", err);
+                    return Err(err);
+                }
+            },
+        }
+    }
+    Ok(offset)
+}
+
+/// Reads the rest of the stream, and returns whether it's empty or only zeros, as
+/// space the file system allocated but didn't write to before a crash reads as.
+async fn only_zeros_remain(mut stream: impl AsyncReadExt + Unpin) -> KVResult<bool> {
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        match stream.read(&mut buf).await? {
+            0 => return Ok(true),
+            read if buf[..read].iter().any(|&byte| byte != 0) => return Ok(false),
+            _ => {}
+        }
+    }
+}
+
+/// Reads the file header, or returns `None` if the stream is empty.
+pub(crate) async fn read_header(mut stream: impl AsyncReadExt + Unpin) -> KVResult<Option<Header>> {
+    let mut buf = Vec::new();
+    loop {
+        match header::decode(&buf)? {
+            Decoded::Complete(header, _) => return Ok(Some(header)),
+            Decoded::Incomplete(needed) => {
+                let start = buf.len();
+                buf.resize(needed, 0);
+                match stream.read_exact(&mut buf[start..]).await {
+                    Ok(_) => {}
+                    Err(err) if err.kind() == io::ErrorKind::UnexpectedEof && start == 0 => {
+                        return Ok(None)
+                    }
+                    Err(err) => return Err(err.into()),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::memory_noop::MemoryNoOpRWS;
+
+    #[tokio::test]
+    async fn test_log_backend() -> KVResult<()> {
+        let mut backend = LogBackend::new(std::io::Cursor::new(Vec::new()));
+        assert!(backend.load_all(|_, _| {}).await?.is_none());
+        let header = Header::new(FORMAT_VERSION);
+        StorageBackend::create(&mut backend, &header).await?;
+        let record = || KVEntry::new("a".to_string(), b"value".to_vec(), "text/plain".to_string());
+        let first = StorageBackend::append(&mut backend, &record()).await?;
+        let second =
+            StorageBackend::append(&mut backend, &KVEntry::tombstone("a".to_string())).await?;
+        assert_eq!(first, header.encoded_len() as u64);
+        assert_eq!(StorageBackend::read(&backend, first).await?.value, b"value");
+        // Reading moved the stream, but appending continues at the end.
+        let third = StorageBackend::append(&mut backend, &record()).await?;
+        assert_eq!(backend.size() - third, second - first);
+
+        let mut offsets = Vec::new();
+        let loaded = backend.load_all(|offset, _| offsets.push(offset)).await?;
+        assert_eq!(loaded, Some(header));
+        assert_eq!(offsets, [first, second, third]);
+
+        // A crash while appending leaves part of a record behind, which is written over.
+        let torn = backend.size();
+        StorageBackend::append(&mut backend, &record()).await?;
+        let mut stream = backend.into_inner();
+        let len = stream.get_ref().len();
+        stream.get_mut().truncate(len - 3);
+        let mut backend = LogBackend::new(stream);
+        let mut offsets = Vec::new();
+        backend.load_all(|offset, _| offsets.push(offset)).await?;
+        assert_eq!(offsets, [first, second, third]);
+        assert_eq!(backend.size(), torn);
+        assert_eq!(StorageBackend::append(&mut backend, &record()).await?, torn);
+
+        // Or the whole record, with bytes which never made it to disk.
+        let mut stream = backend.into_inner();
+        let len = stream.get_ref().len();
+        stream.get_mut()[len - 3..].fill(0);
+        let mut backend = LogBackend::new(stream);
+        let mut offsets = Vec::new();
+        backend.load_all(|offset, _| offsets.push(offset)).await?;
+        assert_eq!(offsets, [first, second, third]);
+        assert_eq!(backend.size(), torn);
+        // Corrupt records followed by others aren't from a crash, and are reported.
+        let mut stream = backend.into_inner();
+        stream.get_mut()[second as usize - 3] ^= 0xff;
+        let mut backend = LogBackend::new(stream);
+        assert!(backend.load_all(|_, _| {}).await.is_err());
+
+        let mut no_op = LogBackend::new(MemoryNoOpRWS::new());
+        StorageBackend::create(&mut no_op, &header).await?;
+        assert!(!no_op.keeps_records());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_log_backend_read_ahead() -> KVResult<()> {
+        let mut backend = LogBackend::new(std::io::Cursor::new(Vec::new()));
+        StorageBackend::create(&mut backend, &Header::new(FORMAT_VERSION)).await?;
+        let record = |i: usize, len| KVEntry::new(i.to_string(), vec![i as u8; len], String::new());
+        let mut records = Vec::new();
+        for i in 0..20 {
+            // One record doesn't fit into what's read ahead.
+            let len = if i == 10 { READ_AHEAD_LEN } else { 1000 };
+            records.push(record(i, len));
+        }
+        let offsets = StorageBackend::append_batch(&mut backend, &records).await?;
+
+        for (record, offset) in records.iter().zip(&offsets) {
+            let read = StorageBackend::read(&backend, *offset).await?;
+            assert_eq!(read.key, record.key);
+            assert_eq!(read.value, record.value);
+        }
+        // Reading out of order still works, from what was read ahead or not.
+        for i in [3, 19, 2, 10, 11] {
+            assert_eq!(
+                StorageBackend::read(&backend, offsets[i]).await?.key,
+                i.to_string()
+            );
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_log_backend_limits() -> KVResult<()> {
+        let mut backend = LogBackend::new(std::io::Cursor::new(Vec::new()));
+        StorageBackend::create(&mut backend, &Header::new(FORMAT_VERSION)).await?;
+        let record = KVEntry::new("a".to_string(), vec![0; 100], "text/plain".to_string());
+        let offset = StorageBackend::append(&mut backend, &record).await?;
+
+        let limits = Limits {
+            max_value_len: 50,
+            ..Limits::default()
+        };
+        let mut backend = LogBackend::new(backend.into_inner()).with_limits(limits);
+        assert!(matches!(
+            backend.load_all(|_, _| {}).await,
+            Err(KVError::ValueTooLarge { len: 100, max: 50 })
+        ));
+        assert!(StorageBackend::read(&backend, offset).await.is_err());
+        assert_eq!(backend.create_sibling().await?.limits(), limits);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_backend_probe() -> KVResult<()> {
+        let path = std::env::temp_dir().join(format!("kv-probe-test-{}.db", std::process::id()));
+        let mut backend = FileBackend::open(&path).await?;
+        backend.probe().await?;
+        assert!(!path_with_suffix(&path, ".probe").exists());
+        drop(backend);
+        #[cfg(unix)]
+        {
+            check_free_space(&path, 0)?;
+            let full = check_free_space(&path, u64::MAX).unwrap_err();
+            assert!(KVError::from(full).is_storage_full());
+        }
+
+        // A read-only directory would fail probe files.
+        let mut backend = FileBackend::open_read_only(&path).await?;
+        let probe = path_with_suffix(&path, ".probe");
+        std::fs::create_dir(&probe)?;
+        backend.probe().await?;
+        std::fs::remove_dir(&probe)?;
+        drop(backend);
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_backend_mirror() -> KVResult<()> {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("kv-mirror-test-{}.db", std::process::id()));
+        let mirror = dir.join(format!("kv-mirror-test-{}.mirror.db", std::process::id()));
+        let same = || std::fs::read(&path).unwrap() == std::fs::read(&mirror).unwrap();
+        let record = |key: &str| KVEntry::new(key.to_string(), b"value".to_vec(), String::new());
+
+        let mut backend = FileBackend::open(&path).await?.with_mirror(&mirror);
+        assert!(backend.load_all(|_, _| {}).await?.is_none());
+        backend.create(&Header::new(FORMAT_VERSION)).await?;
+        backend.append(&record("a")).await?;
+        backend.sync().await?;
+        assert!(backend.mirror_in_sync());
+        assert!(same());
+
+        // Loading the log file rewrites the mirror, whatever it holds.
+        drop(backend);
+        std::fs::write(&mirror, b"stale")?;
+        let mut backend = FileBackend::open(&path).await?.with_mirror(&mirror);
+        backend.load_all(|_, _| {}).await?;
+        backend.append(&record("b")).await?;
+        backend.sync().await?;
+        assert!(same());
+
+        // Compacting rewrites both.
+        let mut compacted = backend.create_sibling().await?;
+        compacted.create(&Header::new(FORMAT_VERSION)).await?;
+        let offset = compacted.append(&record("b")).await?;
+        backend.replace(compacted).await?;
+        assert!(backend.mirror_in_sync());
+        assert!(same());
+        assert!(!path_with_suffix(&mirror, ".compacting").exists());
+
+        // A lost log file is restored from the mirror rather than emptying it.
+        drop(backend);
+        std::fs::write(&path, b"")?;
+        let mut backend = FileBackend::open(&path).await?.with_mirror(&mirror);
+        assert!(backend.load_all(|_, _| {}).await.is_err());
+        drop(backend);
+        std::fs::copy(&mirror, &path)?;
+        let mut backend = FileBackend::open(&path).await?.with_mirror(&mirror);
+        backend.load_all(|_, _| {}).await?;
+        assert_eq!(backend.read(offset).await?.key, "b");
+        drop(backend);
+        std::fs::remove_file(&path)?;
+        std::fs::remove_file(&mirror)?;
+
+        // Writes don't fail with the mirror.
+        let unwritable = dir.join("kv-mirror-test-missing").join("mirror.db");
+        let mut backend = FileBackend::open(&path).await?.with_mirror(&unwritable);
+        backend.load_all(|_, _| {}).await?;
+        backend.create(&Header::new(FORMAT_VERSION)).await?;
+        backend.append(&record("a")).await?;
+        backend.sync().await?;
+        assert!(!backend.mirror_in_sync());
+        drop(backend);
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_backend_positional_reads() -> KVResult<()> {
+        let path = std::env::temp_dir().join(format!("kv-read-test-{}.db", std::process::id()));
+        let mut backend = FileBackend::open(&path).await?;
+        backend.create(&Header::new(FORMAT_VERSION)).await?;
+        let records: Vec<KVEntry> = [10, RECORD_READ_LEN, READ_AHEAD_LEN, 10]
+            .into_iter()
+            .enumerate()
+            .map(|(i, len)| KVEntry::new(i.to_string(), vec![i as u8; len], String::new()))
+            .collect();
+        let offsets = backend.append_batch(&records).await?;
+        assert!(backend.log.reader.is_some());
+
+        // Reads run alongside each other, and leave the append position alone.
+        let reads = offsets.iter().rev().map(|offset| backend.read(*offset));
+        let read = futures_util::future::try_join_all(reads).await?;
+        for (record, read) in records.iter().rev().zip(read) {
+            assert_eq!(read.value, record.value);
+        }
+        let appended = backend.append(&records[0]).await?;
+        assert_eq!(backend.read(appended).await?.key, "0");
+        assert_eq!(std::fs::metadata(&path)?.len(), backend.size());
+        drop(backend);
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_backend_compacted_copy() -> KVResult<()> {
+        let path = std::env::temp_dir().join(format!("kv-compact-test-{}.db", std::process::id()));
+        let mut backend = FileBackend::open(&path).await?;
+        assert!(backend.load_all(|_, _| {}).await?.is_none());
+        backend.create(&Header::new(FORMAT_VERSION)).await?;
+
+        // Large enough to be written in several chunks with `O_DIRECT`.
+        let records: Vec<KVEntry> = (0..300)
+            .map(|i| {
+                let value = (0..5000).map(|_| rand::random()).collect();
+                KVEntry::new(i.to_string(), value, String::new())
+            })
+            .collect();
+        let mut compacted = backend.create_sibling().await?;
+        compacted.create(&Header::new(FORMAT_VERSION)).await?;
+        let mut offsets = compacted.append_batch(&records[..1]).await?;
+        offsets.extend(compacted.append_batch(&records[1..]).await?);
+        let size = compacted.size();
+        backend.replace(compacted).await?;
+        assert_eq!(std::fs::metadata(&path)?.len(), size);
+        for (record, offset) in records.iter().zip(offsets) {
+            assert_eq!(backend.read(offset).await?.value, record.value);
+        }
+        let appended = backend.append(&records[0]).await?;
+        assert_eq!(appended, size);
+        assert_eq!(backend.read(appended).await?.key, "0");
+        drop(backend);
+
+        let mut backend = FileBackend::open(&path).await?;
+        let mut loaded = 0;
+        backend.load_all(|_, _| loaded += 1).await?;
+        assert_eq!(loaded, 301);
+        drop(backend);
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[cfg(feature = "zstd")]
+    #[tokio::test]
+    async fn test_compression_threshold() -> KVResult<()> {
+        let mut backend =
+            LogBackend::new(std::io::Cursor::new(Vec::new())).with_compression_threshold(16);
+        StorageBackend::create(&mut backend, &Header::new(FORMAT_VERSION)).await?;
+        let small = KVEntry::new("a".to_string(), vec![b'a'; 16], "text/plain".to_string());
+        let large = KVEntry::new("b".to_string(), vec![b'b'; 64], "text/plain".to_string());
+        assert!(!StorageBackend::compresses(&backend, &small));
+        assert!(StorageBackend::compresses(&backend, &large));
+        let offset = StorageBackend::append(&mut backend, &large).await?;
+        assert!(backend.size() - offset < 64);
+        assert_eq!(
+            StorageBackend::read(&backend, offset).await?.value,
+            large.value
+        );
+        // The compacted copy compresses the same values.
+        let sibling = backend.create_sibling().await?;
+        assert!(StorageBackend::compresses(&sibling, &large));
+        Ok(())
+    }
+}
@@ -0,0 +1,113 @@
+use std::sync::Arc;
+
+use tokio::{
+    io::AsyncRead,
+    runtime::{Builder, Runtime},
+};
+
+use super::{
+    backend::{FileBackend, MemoryBackend, StorageBackend},
+    clock::Clock,
+    entry::{Entry, EntryInfo},
+    normalization::KeyNormalization,
+    result::KVResult,
+    store::KVStore,
+};
+
+/// Blocking counterpart of `FileBackedKVStore`.
+pub type FileBackedBlockingKVStore = BlockingKVStore<FileBackend>;
+/// Blocking counterpart of `MemoryBackedKVStore`.
+pub type MemoryBackedBlockingKVStore = BlockingKVStore<MemoryBackend>;
+
+/// A blocking facade over `KVStore`, for applications that don't use tokio.
+///
+/// The store is driven by its own single-threaded runtime, so none of these
+/// methods may be called from within an async context.
+pub struct BlockingKVStore<B>
+where
+    B: StorageBackend,
+{
+    runtime: Runtime,
+    store: KVStore<B>,
+}
+
+impl<B: StorageBackend> BlockingKVStore<B> {
+    /// Creates a new blocking store with the provided backend. See `KVStore::new`.
+    pub fn new(backend: B) -> KVResult<Self> {
+        let runtime = Builder::new_current_thread().enable_all().build()?;
+        let store = runtime.block_on(KVStore::new(backend))?;
+        Ok(Self { runtime, store })
+    }
+
+    /// Like `new`, but with a custom clock. See `KVStore::with_clock`.
+    pub fn with_clock(backend: B, clock: Arc<dyn Clock>) -> KVResult<Self> {
+        let runtime = Builder::new_current_thread().enable_all().build()?;
+        let store = runtime.block_on(KVStore::with_clock(backend, clock))?;
+        Ok(Self { runtime, store })
+    }
+
+    /// Like `new`, but with normalized keys. See `KVStore::with_key_normalization`.
+    pub fn with_key_normalization(
+        backend: B,
+        key_normalization: KeyNormalization,
+    ) -> KVResult<Self> {
+        let runtime = Builder::new_current_thread().enable_all().build()?;
+        let store =
+            runtime.block_on(KVStore::with_key_normalization(backend, key_normalization))?;
+        Ok(Self { runtime, store })
+    }
+
+    /// Like `new`, but on top of a read-only seed database. See `KVStore::with_seed`.
+    pub fn with_seed(backend: B, seed: impl AsyncRead + Unpin + Send) -> KVResult<Self> {
+        let runtime = Builder::new_current_thread().enable_all().build()?;
+        let store = runtime.block_on(KVStore::with_seed(backend, seed))?;
+        Ok(Self { runtime, store })
+    }
+
+    /// Get the value as an `Entry` for a given key, blocking until it was read from the
+    /// backing storage. See `KVStore::get`.
+    pub fn get(&self, key: &str) -> KVResult<Option<Entry>> {
+        self.runtime.block_on(self.store.get(key))
+    }
+
+    /// Set the value for a given key, blocking until it was written to the backing storage.
+    /// See `KVStore::set`.
+    pub fn set(&mut self, key: &str, value: Entry) -> KVResult<()> {
+        self.runtime.block_on(self.store.set(key, value))
+    }
+
+    /// Remove the value for a given key, blocking until the removal was written to the
+    /// backing storage. See `KVStore::remove`.
+    pub fn remove(&mut self, key: &str) -> KVResult<Option<EntryInfo>> {
+        self.runtime.block_on(self.store.remove(key))
+    }
+
+    /// Remove all keys starting with `prefix`. See `KVStore::remove_prefix`.
+    pub fn remove_prefix(&mut self, prefix: &str) -> KVResult<usize> {
+        self.runtime.block_on(self.store.remove_prefix(prefix))
+    }
+
+    /// Returns the underlying async store, for when the caller does adopt tokio after all.
+    pub fn into_inner(self) -> KVStore<B> {
+        self.store
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_blocking_set_and_get() -> KVResult<()> {
+        let mut store = MemoryBackedBlockingKVStore::new(MemoryBackend::new())?;
+        store.set(
+            "test_key",
+            Entry::new(b"test_value".to_vec(), "text/plain".to_string()),
+        )?;
+
+        let entry = store.get("test_key")?.unwrap();
+        assert_eq!(entry.value, b"test_value");
+        assert_eq!(&*entry.mime, "text/plain");
+        Ok(())
+    }
+}
@@ -0,0 +1,188 @@
+//! A bounded cache of values read from the backing storage, so that values which are
+//! read often, or were prefetched, aren't read from the storage every time.
+//!
+//! Values are cached under the offset of their record, which stays the same until
+//! the store is compacted. The least recently used values are evicted first, except
+//! that values of pinned entries are only evicted to make room for other pinned ones.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Mutex,
+};
+
+/// Values larger than this fraction of the capacity aren't cached, so that reading a
+/// single large value doesn't evict everything else.
+const MAX_VALUE_FRACTION: usize = 8;
+/// Values of pinned entries may take up this fraction of the capacity, so that pinning
+/// many entries leaves room for the others.
+const PINNED_FRACTION: usize = 4;
+
+#[derive(Default)]
+pub(crate) struct ValueCache {
+    // Values are read with a shared reference to the store.
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// In bytes, for the values of both pools. 0 turns caching off.
+    capacity: usize,
+    /// Incremented on every use, so the least recently used value has the lowest.
+    clock: u64,
+    values: Pool,
+    /// Values of pinned entries, which aren't evicted for the others.
+    pinned: Pool,
+}
+
+/// Cached values, evicted in the order they were last used.
+#[derive(Default)]
+struct Pool {
+    /// Sum of the lengths of the cached values.
+    size: usize,
+    values: HashMap<u64, (Vec<u8>, u64)>,
+    /// Offsets of the cached values by when they were last used.
+    by_use: BTreeMap<u64, u64>,
+}
+
+impl ValueCache {
+    /// Caches up to `capacity` bytes of values, evicting values if it shrank.
+    pub(crate) fn set_capacity(&self, capacity: usize) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.capacity = capacity;
+        let pinned_capacity = capacity / PINNED_FRACTION;
+        inner.pinned.evict(0, pinned_capacity);
+        let values_capacity = capacity - inner.pinned.size;
+        inner.values.evict(0, values_capacity);
+    }
+
+    pub(crate) fn get(&self, offset: u64) -> Option<Vec<u8>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.clock += 1;
+        let clock = inner.clock;
+        let Inner { values, pinned, .. } = &mut *inner;
+        values
+            .touch(offset, clock)
+            .or_else(|| pinned.touch(offset, clock))
+    }
+
+    pub(crate) fn contains(&self, offset: u64) -> bool {
+        let inner = self.inner.lock().unwrap();
+        inner.values.values.contains_key(&offset) || inner.pinned.values.contains_key(&offset)
+    }
+
+    /// Caches the value of the record at `offset`. `pinned` is whether the record's
+    /// entry is pinned, see `Entry::pinned`.
+    pub(crate) fn insert(&self, offset: u64, value: &[u8], pinned: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        if value.len() > inner.capacity / MAX_VALUE_FRACTION
+            || inner.values.values.contains_key(&offset)
+            || inner.pinned.values.contains_key(&offset)
+        {
+            return;
+        }
+        // Pinned values always fit into their budget, which is larger than any cached value.
+        let capacity = inner.capacity;
+        if pinned {
+            inner.pinned.evict(value.len(), capacity / PINNED_FRACTION);
+            let room = capacity - inner.pinned.size - value.len();
+            inner.values.evict(0, room);
+        } else {
+            let room = capacity - inner.pinned.size;
+            inner.values.evict(value.len(), room);
+        }
+        inner.clock += 1;
+        let clock = inner.clock;
+        let pool = if pinned {
+            &mut inner.pinned
+        } else {
+            &mut inner.values
+        };
+        pool.size += value.len();
+        pool.values.insert(offset, (value.to_vec(), clock));
+        pool.by_use.insert(clock, offset);
+    }
+
+    /// Forgets all values, as their offsets no longer point at the same records.
+    pub(crate) fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.values = Pool::default();
+        inner.pinned = Pool::default();
+    }
+}
+
+impl Pool {
+    /// Returns the value at `offset`, if it's cached, and marks it as used at `clock`.
+    fn touch(&mut self, offset: u64, clock: u64) -> Option<Vec<u8>> {
+        let (value, used) = self.values.get_mut(&offset)?;
+        let (value, last_used) = (value.clone(), std::mem::replace(used, clock));
+        self.by_use.remove(&last_used);
+        self.by_use.insert(clock, offset);
+        Some(value)
+    }
+
+    /// Evicts the least recently used values until another `len` bytes fit into
+    /// `capacity`.
+    fn evict(&mut self, len: usize, capacity: usize) {
+        while self.size + len > capacity {
+            let Some((_, offset)) = self.by_use.pop_first() else {
+                break;
+            };
+            if let Some((value, _)) = self.values.remove(&offset) {
+                self.size -= value.len();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_value_cache() {
+        let cache = ValueCache::default();
+        cache.insert(1, b"one", false);
+        assert_eq!(cache.get(1), None);
+
+        cache.set_capacity(80);
+        cache.insert(1, &[1; 10], false);
+        cache.insert(2, &[2; 10], false);
+        // Too large for the capacity.
+        cache.insert(3, &[3; 11], false);
+        assert!(!cache.contains(3));
+        assert_eq!(cache.get(1), Some(vec![1; 10]));
+
+        // Using 1 made 2 the least recently used.
+        cache.set_capacity(88);
+        for offset in 4..11 {
+            cache.insert(offset, &[0; 10], false);
+        }
+        assert!(cache.contains(1));
+        assert!(!cache.contains(2));
+        assert!(cache.contains(10));
+
+        cache.clear();
+        assert_eq!(cache.get(1), None);
+    }
+
+    #[test]
+    fn test_value_cache_pinned() {
+        let cache = ValueCache::default();
+        cache.set_capacity(80);
+        cache.insert(1, &[1; 10], true);
+        for offset in 2..20 {
+            cache.insert(offset, &[0; 10], false);
+        }
+        // Pinned values stay, even though they weren't used since.
+        assert_eq!(cache.get(1), Some(vec![1; 10]));
+        assert!(cache.contains(19));
+
+        // But only up to their own budget, within which they're evicted like others.
+        cache.insert(20, &[2; 10], true);
+        cache.get(1);
+        cache.insert(21, &[3; 10], true);
+        assert!(cache.contains(1));
+        assert!(!cache.contains(20));
+        assert!(cache.contains(21));
+    }
+}
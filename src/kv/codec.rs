@@ -0,0 +1,900 @@
+//! Sans-IO encoding and decoding of the on-disk entry format.
+//!
+//! Nothing in here depends on tokio, so the format can be parsed by tools which
+//! don't want the async stack (fuzzers, offline analyzers, etc.). The async
+//! `KVEntry` methods, as well as the synchronous `read_entry` and `write_entry`
+//! adapters, are thin wrappers around `encode` and `decode`.
+
+use std::io::{Read, Write};
+use std::ops::BitAnd;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::entry::{EntryKind, KVEntry, Metadata};
+use super::result::{KVError, KVResult};
+
+/// Flags stored before each entry, indicating different properties of the entry,
+/// such as whether the value is compressed.
+#[repr(u8)]
+pub(crate) enum Flags {
+    None = 0,
+    /// The entry deletes its key. Its value and MIME type are empty.
+    Tombstone = 0b00000001,
+    /// The entry deletes every key starting with its key. Its value and MIME type are empty.
+    PrefixTombstone = 0b00000010,
+    /// The body continues with the entry's metadata, after the MIME type.
+    HasMetadata = 0b00000100,
+    /// The body ends with the time the entry expires at, in milliseconds since the
+    /// Unix epoch, as a `u64`.
+    HasExpiry = 0b00001000,
+    /// The body ends with the time the entry was written at, in milliseconds since the
+    /// Unix epoch, as a `u64`, after the expiry if it has one.
+    HasModified = 0b00010000,
+    /// The entry records when its key was last read. Its value is that time, in
+    /// milliseconds since the Unix epoch, as a `u64`, and its MIME type is empty.
+    Touch = 0b00100000,
+    /// Another byte of flags follows, see `ExtendedFlags`. New flags go there, as
+    /// this byte has no bits left.
+    Extended = 0b01000000,
+    ZstdCompressed = 0b10000000,
+}
+
+/// Flags stored in the byte after the flags, if `Flags::Extended` is set. Only entries
+/// with any of these have that byte.
+#[repr(u8)]
+pub(crate) enum ExtendedFlags {
+    /// The entry is pinned, see `Entry::pinned`.
+    Pinned = 0b00000001,
+    /// The value is a delta against the value of an earlier record of the same key,
+    /// rather than the value itself.
+    Delta = 0b00000010,
+}
+
+/// All extended flags this version knows about. Entries with any other extended flag
+/// set were written by a newer version, and can't be read safely.
+const KNOWN_EXTENDED_FLAGS: u8 = ExtendedFlags::Pinned as u8 | ExtendedFlags::Delta as u8;
+
+/// How records are laid out, which changed between format versions. Files are only
+/// written in the current layout, older ones are only read to migrate them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Layout {
+    /// Before version 4: Lengths of values and compressed frames are `u32`.
+    V1,
+    /// Version 4: Every record ends with a checksum.
+    V4,
+    /// Since version 5: Lengths of values and compressed frames are `u64`.
+    V5,
+}
+
+impl Layout {
+    fn checked(self) -> bool {
+        self != Layout::V1
+    }
+
+    /// Writes the length of a value or compressed frame.
+    fn push_len(self, out: &mut Vec<u8>, len: usize) -> KVResult<()> {
+        if self == Layout::V5 {
+            out.extend_from_slice(&(len as u64).to_le_bytes());
+        } else {
+            let len = u32::try_from(len).map_err(|_| KVError::ValueTooLarge {
+                len: len as u64,
+                max: u32::MAX as u64,
+            })?;
+            out.extend_from_slice(&len.to_le_bytes());
+        }
+        Ok(())
+    }
+
+    /// Takes the length of a value or compressed frame, followed by that many bytes,
+    /// see `SliceReader::take_bounded`.
+    fn take_bounded<'a>(
+        self,
+        reader: &mut SliceReader<'a>,
+        max: usize,
+        too_large: impl FnOnce(u64) -> KVError,
+    ) -> KVResult<Option<&'a [u8]>> {
+        match self {
+            Layout::V5 => reader.take_bounded::<8>(max, too_large),
+            Layout::V1 | Layout::V4 => reader.take_bounded::<4>(max, too_large),
+        }
+    }
+}
+
+/// Longest key a record can hold, in bytes, as its length is stored as a `u16`.
+pub const MAX_KEY_LEN: usize = u16::MAX as usize;
+/// Longest MIME type a record can hold, in bytes, as its length is stored as a `u16`.
+pub const MAX_MIME_LEN: usize = u16::MAX as usize;
+/// Largest value decoded, in bytes, unless configured otherwise.
+pub const DEFAULT_MAX_VALUE_LEN: usize = 1 << 30;
+/// The zstd level values are compressed at, unless configured otherwise. 0 selects
+/// zstd's own default.
+pub const DEFAULT_COMPRESSION_LEVEL: i32 = 0;
+
+/// Room for the metadata, timestamps, and length prefixes of a compressed record, on
+/// top of its key, value and MIME type.
+const MAX_RECORD_OVERHEAD: usize = 1024 * 1024;
+
+/// Records are read this many bytes at a time, so that a corrupted length runs into
+/// the end of the stream before much is allocated for it.
+const READ_CHUNK_LEN: usize = 1024 * 1024;
+
+/// Longest keys, values, and MIME types of the records which are decoded, in bytes.
+/// Lengths are checked as soon as they're decoded, so a corrupted one is rejected
+/// instead of allocating however much it says.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    pub max_key_len: usize,
+    pub max_value_len: usize,
+    pub max_mime_len: usize,
+}
+
+impl Limits {
+    /// Longest body of a compressed record, before and after decompressing it.
+    fn max_body_len(&self) -> usize {
+        self.max_key_len
+            .saturating_add(self.max_value_len)
+            .saturating_add(self.max_mime_len)
+            .saturating_add(MAX_RECORD_OVERHEAD)
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_key_len: MAX_KEY_LEN,
+            max_value_len: DEFAULT_MAX_VALUE_LEN,
+            max_mime_len: MAX_MIME_LEN,
+        }
+    }
+}
+
+/// The outcome of trying to decode an entry from a buffer.
+#[derive(Debug)]
+pub enum Decoded<T> {
+    /// An entry was decoded, consuming the given amount of bytes from the buffer.
+    Complete(T, usize),
+    /// The buffer doesn't contain a full entry yet. The value is the total amount
+    /// of bytes the buffer needs to hold before decoding can make progress.
+    Incomplete(usize),
+}
+
+/// Serializes the entry into a new buffer, optionally compressing it with Zstd. The
+/// record ends with a CRC32 checksum of everything before it.
+pub fn encode(entry: &KVEntry, compress: bool) -> KVResult<Vec<u8>> {
+    encode_with(entry, compress, Layout::V5)
+}
+
+/// Like `encode`, but always compresses, at zstd `level` rather than at
+/// `DEFAULT_COMPRESSION_LEVEL`. Levels outside of zstd's range are clamped to it.
+pub fn encode_compressed(entry: &KVEntry, level: i32) -> KVResult<Vec<u8>> {
+    encode_at(entry, Some(level), Layout::V5)
+}
+
+/// Like `encode`, but in the layout of an older format version. Values which are too
+/// large for it are rejected with `KVError::ValueTooLarge`.
+pub(crate) fn encode_with(entry: &KVEntry, compress: bool, layout: Layout) -> KVResult<Vec<u8>> {
+    encode_at(entry, compress.then_some(DEFAULT_COMPRESSION_LEVEL), layout)
+}
+
+/// Encodes the entry in `layout`, compressing it at the zstd level, if any.
+fn encode_at(entry: &KVEntry, level: Option<i32>, layout: Layout) -> KVResult<Vec<u8>> {
+    if entry.key.len() > MAX_KEY_LEN {
+        return Err(KVError::KeyTooLarge {
+            len: entry.key.len(),
+            max: MAX_KEY_LEN,
+        });
+    }
+    let mut body = Vec::with_capacity(12 + entry.key.len() + entry.value.len() + entry.mime.len());
+    body.extend_from_slice(&(entry.key.len() as u16).to_le_bytes());
+    body.extend_from_slice(entry.key.as_bytes());
+    layout.push_len(&mut body, entry.value.len())?;
+    body.extend_from_slice(&entry.value);
+    push_short(&mut body, "MIME type", entry.mime.as_bytes())?;
+
+    if !entry.meta.is_empty() {
+        let count = u16::try_from(entry.meta.len())
+            .map_err(|_| KVError::InvalidData("Too many metadata entries".to_string()))?;
+        body.extend_from_slice(&count.to_le_bytes());
+        for (name, value) in &entry.meta {
+            push_short(&mut body, "Metadata name", name.as_bytes())?;
+            push_short(&mut body, "Metadata value", value.as_bytes())?;
+        }
+    }
+    if let Some(expires_at) = entry.expires_at {
+        body.extend_from_slice(&to_millis(expires_at).to_le_bytes());
+    }
+    if let Some(modified_at) = entry.modified_at {
+        body.extend_from_slice(&to_millis(modified_at).to_le_bytes());
+    }
+    let mut out = if let Some(level) = level {
+        let compressed = compress_body(&body, level)?;
+        let mut out = Vec::with_capacity(14 + compressed.len());
+        push_flags(&mut out, entry, true);
+        layout.push_len(&mut out, compressed.len())?;
+        out.extend_from_slice(&compressed);
+        out
+    } else {
+        let mut out = Vec::with_capacity(6 + body.len());
+        push_flags(&mut out, entry, false);
+        out.extend_from_slice(&body);
+        out
+    };
+    if layout.checked() {
+        let checksum = checksum(&out);
+        out.extend_from_slice(&checksum.to_le_bytes());
+    }
+    Ok(out)
+}
+
+/// Writes the flags byte, followed by the extended flags byte if there are any.
+fn push_flags(out: &mut Vec<u8>, entry: &KVEntry, compress: bool) {
+    let [flags, extended] = flags(entry, compress).to_le_bytes();
+    out.push(flags);
+    if flags & Flags::Extended as u8 != 0 {
+        out.push(extended);
+    }
+}
+
+/// The flags `encode` writes before the entry, with the extended flags in the high
+/// byte.
+pub fn flags(entry: &KVEntry, compress: bool) -> u16 {
+    let mut flags = match entry.kind {
+        EntryKind::Value => Flags::None as u8,
+        EntryKind::Tombstone => Flags::Tombstone as u8,
+        EntryKind::PrefixTombstone => Flags::PrefixTombstone as u8,
+        EntryKind::Touch => Flags::Touch as u8,
+    };
+    if !entry.meta.is_empty() {
+        flags |= Flags::HasMetadata as u8;
+    }
+    if entry.expires_at.is_some() {
+        flags |= Flags::HasExpiry as u8;
+    }
+    if entry.modified_at.is_some() {
+        flags |= Flags::HasModified as u8;
+    }
+    if compress {
+        flags |= Flags::ZstdCompressed as u8;
+    }
+    let mut extended = 0;
+    if entry.pinned {
+        extended |= ExtendedFlags::Pinned as u8;
+    }
+    if entry.delta {
+        extended |= ExtendedFlags::Delta as u8;
+    }
+    if extended != 0 {
+        flags |= Flags::Extended as u8;
+    }
+    u16::from_le_bytes([flags, extended])
+}
+
+/// Names of the flags set in `flags`, as returned by `flags`, lowest bit first, for
+/// people reading records.
+pub fn flag_names(flags: u16) -> Vec<&'static str> {
+    [
+        (Flags::Tombstone as u16, "tombstone"),
+        (Flags::PrefixTombstone as u16, "prefix_tombstone"),
+        (Flags::HasMetadata as u16, "metadata"),
+        (Flags::HasExpiry as u16, "expiry"),
+        (Flags::HasModified as u16, "modified"),
+        (Flags::Touch as u16, "touch"),
+        (Flags::Extended as u16, "extended"),
+        (Flags::ZstdCompressed as u16, "zstd"),
+        ((ExtendedFlags::Pinned as u16) << 8, "pinned"),
+        ((ExtendedFlags::Delta as u16) << 8, "delta"),
+    ]
+    .into_iter()
+    .filter(|(flag, _)| flags & flag != 0)
+    .map(|(_, name)| name)
+    .collect()
+}
+
+/// The checksum stored after each record, of the record's bytes.
+pub fn checksum(record: &[u8]) -> u32 {
+    crc32fast::hash(record)
+}
+
+/// Tries to decode one entry from the start of `buf`.
+///
+/// Returns `Decoded::Incomplete` if `buf` is too short, which tells the caller
+/// exactly how many bytes to read before trying again, so it never has to read
+/// past the end of the entry. Records whose checksum doesn't match, because they
+/// were corrupted or only partially written, are rejected with
+/// `KVError::ChecksumMismatch`, and records longer than the default `Limits` with
+/// `KVError::KeyTooLarge`, `KVError::ValueTooLarge`, or `KVError::MimeTooLarge`.
+pub fn decode(buf: &[u8]) -> KVResult<Decoded<KVEntry>> {
+    decode_limited(buf, &Limits::default())
+}
+
+/// Like `decode`, but with other limits than the default ones.
+pub fn decode_limited(buf: &[u8], limits: &Limits) -> KVResult<Decoded<KVEntry>> {
+    decode_record(buf, Layout::V5, limits)
+}
+
+/// Like `decode`, but for records in the layout of an older format version. Records
+/// from before version 4 have no checksum, so their corruption goes unnoticed.
+pub(crate) fn decode_with(buf: &[u8], layout: Layout) -> KVResult<Decoded<KVEntry>> {
+    decode_record(buf, layout, &Limits::default())
+}
+
+fn decode_record(buf: &[u8], layout: Layout, limits: &Limits) -> KVResult<Decoded<KVEntry>> {
+    let mut reader = SliceReader::new(buf);
+    let Some(flags) = reader.take(1) else {
+        return Ok(Decoded::Incomplete(reader.needed));
+    };
+    let flags = flags[0];
+    let mut extended = 0;
+    if flags & Flags::Extended as u8 != 0 {
+        let Some(byte) = reader.take(1) else {
+            return Ok(Decoded::Incomplete(reader.needed));
+        };
+        extended = byte[0];
+        if extended & !KNOWN_EXTENDED_FLAGS != 0 {
+            return Err(KVError::InvalidData(format!(
+                "Unknown extended entry flags: {:#010b}",
+                extended
+            )));
+        }
+    }
+    let compressed = flags.bitand(Flags::ZstdCompressed as u8) != 0;
+    if compressed {
+        let max_len = limits.max_body_len();
+        let Some(frame) = layout.take_bounded(&mut reader, max_len, |len| {
+            KVError::InvalidData(format!("Compressed record of {} bytes is too long", len))
+        })?
+        else {
+            return Ok(Decoded::Incomplete(reader.needed));
+        };
+        // Corrupted frames are caught before they're decompressed.
+        if layout.checked() && verify_checksum(&mut reader)?.is_none() {
+            return Ok(Decoded::Incomplete(reader.needed));
+        }
+        let body = decompress_body(frame, max_len)?;
+        match decode_body(
+            &mut SliceReader::new(&body),
+            flags,
+            extended,
+            layout,
+            limits,
+        )? {
+            Some(entry) => Ok(Decoded::Complete(entry, reader.pos)),
+            None => Err(KVError::InvalidData(
+                "Truncated compressed entry".to_string(),
+            )),
+        }
+    } else {
+        let Some(entry) = decode_body(&mut reader, flags, extended, layout, limits)? else {
+            return Ok(Decoded::Incomplete(reader.needed));
+        };
+        if layout.checked() && verify_checksum(&mut reader)?.is_none() {
+            return Ok(Decoded::Incomplete(reader.needed));
+        }
+        Ok(Decoded::Complete(entry, reader.pos))
+    }
+}
+
+/// Takes the checksum following the record which the reader has just read, and
+/// compares it to that of the record. Returns `None` if the reader runs out of data.
+fn verify_checksum(reader: &mut SliceReader) -> KVResult<Option<()>> {
+    let end = reader.pos;
+    let Some(stored) = reader.take(4) else {
+        return Ok(None);
+    };
+    let stored = u32::from_le_bytes(stored.try_into().unwrap());
+    let computed = checksum(&reader.buf[..end]);
+    if stored != computed {
+        return Err(KVError::ChecksumMismatch { stored, computed });
+    }
+    Ok(Some(()))
+}
+
+/// Writes the entry to a synchronous writer.
+pub fn write_entry(mut writer: impl Write, entry: &KVEntry, compress: bool) -> KVResult<()> {
+    writer.write_all(&encode(entry, compress)?)?;
+    Ok(())
+}
+
+/// Reads one entry from a synchronous reader, without reading past its end.
+pub fn read_entry(mut reader: impl Read) -> KVResult<KVEntry> {
+    let mut buf = Vec::new();
+    loop {
+        match decode(&buf)? {
+            Decoded::Complete(entry, _) => return Ok(entry),
+            Decoded::Incomplete(needed) => {
+                let start = buf.len();
+                buf.resize(next_read_len(start, needed), 0);
+                reader.read_exact(&mut buf[start..])?;
+            }
+        }
+    }
+}
+
+/// How long to make a buffer of `len` bytes which `decode` asked to hold `needed`
+/// bytes. Large records are read a chunk at a time, see `READ_CHUNK_LEN`.
+pub(crate) fn next_read_len(len: usize, needed: usize) -> usize {
+    needed.min(len.saturating_add(READ_CHUNK_LEN))
+}
+
+/// Writes `bytes` prefixed with their length as a `u16`, rejecting them if they're
+/// longer than that can describe.
+fn push_short(out: &mut Vec<u8>, what: &str, bytes: &[u8]) -> KVResult<()> {
+    let len = u16::try_from(bytes.len()).map_err(|_| {
+        KVError::InvalidData(format!("{} of {} bytes is too long", what, bytes.len()))
+    })?;
+    out.extend_from_slice(&len.to_le_bytes());
+    out.extend_from_slice(bytes);
+    Ok(())
+}
+
+/// Decodes the key, value, MIME type, metadata, expiry, and modification time, as
+/// described by the flags and extended flags before them. Returns `None` if the
+/// reader runs out of data.
+fn decode_body(
+    reader: &mut SliceReader,
+    flags: u8,
+    extended: u8,
+    layout: Layout,
+    limits: &Limits,
+) -> KVResult<Option<KVEntry>> {
+    let kind = if flags.bitand(Flags::Tombstone as u8) != 0 {
+        EntryKind::Tombstone
+    } else if flags.bitand(Flags::PrefixTombstone as u8) != 0 {
+        EntryKind::PrefixTombstone
+    } else if flags.bitand(Flags::Touch as u8) != 0 {
+        EntryKind::Touch
+    } else {
+        EntryKind::Value
+    };
+    let has_meta = flags.bitand(Flags::HasMetadata as u8) != 0;
+    let has_expiry = flags.bitand(Flags::HasExpiry as u8) != 0;
+    let has_modified = flags.bitand(Flags::HasModified as u8) != 0;
+    let pinned = extended & ExtendedFlags::Pinned as u8 != 0;
+    let delta = extended & ExtendedFlags::Delta as u8 != 0;
+    let Some(key) = reader.take_bounded::<2>(limits.max_key_len, |len| KVError::KeyTooLarge {
+        len: len as usize,
+        max: limits.max_key_len,
+    })?
+    else {
+        return Ok(None);
+    };
+    let Some(value) =
+        layout.take_bounded(reader, limits.max_value_len, |len| KVError::ValueTooLarge {
+            len,
+            max: limits.max_value_len as u64,
+        })?
+    else {
+        return Ok(None);
+    };
+    let Some(mime) =
+        reader.take_bounded::<2>(limits.max_mime_len, |len| KVError::MimeTooLarge {
+            len: len as usize,
+            max: limits.max_mime_len,
+        })?
+    else {
+        return Ok(None);
+    };
+    let mut meta = Metadata::new();
+    if has_meta {
+        let Some(count) = reader.take(2) else {
+            return Ok(None);
+        };
+        for _ in 0..u16::from_le_bytes([count[0], count[1]]) {
+            let Some(name) = reader.take_prefixed::<2>() else {
+                return Ok(None);
+            };
+            let Some(value) = reader.take_prefixed::<2>() else {
+                return Ok(None);
+            };
+            meta.insert(utf8(name, "metadata name")?, utf8(value, "metadata")?);
+        }
+    }
+    let mut expires_at = None;
+    if has_expiry {
+        let Some(millis) = reader.take(8) else {
+            return Ok(None);
+        };
+        expires_at = Some(from_millis(u64::from_le_bytes(millis.try_into().unwrap())));
+    }
+    let mut modified_at = None;
+    if has_modified {
+        let Some(millis) = reader.take(8) else {
+            return Ok(None);
+        };
+        modified_at = Some(from_millis(u64::from_le_bytes(millis.try_into().unwrap())));
+    }
+    Ok(Some(KVEntry {
+        key: String::from_utf8(key.to_vec())
+            .map_err(|_| KVError::InvalidData("Invalid UTF-8 in key".to_string()))?,
+        value: value.to_vec(),
+        mime: String::from_utf8(mime.to_vec())
+            .map_err(|_| KVError::InvalidData("Invalid UTF-8 in MIME".to_string()))?,
+        meta,
+        expires_at,
+        modified_at,
+        pinned,
+        delta,
+        kind,
+    }))
+}
+
+/// Milliseconds since the Unix epoch, as times are stored, and sent to clients by the
+/// server.
+pub fn to_millis(time: SystemTime) -> u64 {
+    // Times before the epoch have long passed, so they might as well be the epoch.
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+pub(crate) fn from_millis(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+fn utf8(bytes: &[u8], what: &str) -> KVResult<String> {
+    String::from_utf8(bytes.to_vec())
+        .map_err(|_| KVError::InvalidData(format!("Invalid UTF-8 in {}", what)))
+}
+
+#[cfg(feature = "zstd")]
+fn compress_body(body: &[u8], level: i32) -> KVResult<Vec<u8>> {
+    Ok(zstd::encode_all(body, level)?)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn compress_body(_body: &[u8], _level: i32) -> KVResult<Vec<u8>> {
+    Err(KVError::InvalidData(
+        "Cannot compress entry, zstd support is not enabled".to_string(),
+    ))
+}
+
+/// Decompresses the frame, rejecting it once it decompresses to more than `max_len`
+/// bytes.
+#[cfg(feature = "zstd")]
+fn decompress_body(frame: &[u8], max_len: usize) -> KVResult<Vec<u8>> {
+    let mut body = Vec::new();
+    zstd::stream::read::Decoder::new(frame)?
+        .take(max_len as u64 + 1)
+        .read_to_end(&mut body)?;
+    if body.len() > max_len {
+        return Err(KVError::InvalidData(format!(
+            "Compressed record decompresses to more than {} bytes",
+            max_len
+        )));
+    }
+    Ok(body)
+}
+
+#[cfg(not(feature = "zstd"))]
+fn decompress_body(_frame: &[u8], _max_len: usize) -> KVResult<Vec<u8>> {
+    Err(KVError::InvalidData(
+        "Entry is Zstd compressed, but zstd support is not enabled".to_string(),
+    ))
+}
+
+/// Cursor over a byte slice which remembers how many bytes it would have needed
+/// when it runs out.
+struct SliceReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    needed: usize,
+}
+
+impl<'a> SliceReader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self {
+            buf,
+            pos: 0,
+            needed: 0,
+        }
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let end = self.pos.saturating_add(len);
+        if end > self.buf.len() {
+            self.needed = end;
+            return None;
+        }
+        let bytes = &self.buf[self.pos..end];
+        self.pos = end;
+        Some(bytes)
+    }
+
+    /// Takes a little-endian length prefix of `N` bytes.
+    fn take_len<const N: usize>(&mut self) -> Option<u64> {
+        let len_bytes = self.take(N)?;
+        let mut len = [0u8; 8];
+        len[..N].copy_from_slice(len_bytes);
+        Some(u64::from_le_bytes(len))
+    }
+
+    /// Takes a little-endian length prefix of `N` bytes, followed by that many bytes.
+    fn take_prefixed<const N: usize>(&mut self) -> Option<&'a [u8]> {
+        let len = self.take_len::<N>()?;
+        self.take(len as usize)
+    }
+
+    /// Like `take_prefixed`, but lengths larger than `max` are rejected with the error
+    /// `too_large` returns for them, before asking for that many bytes.
+    fn take_bounded<const N: usize>(
+        &mut self,
+        max: usize,
+        too_large: impl FnOnce(u64) -> KVError,
+    ) -> KVResult<Option<&'a [u8]>> {
+        let Some(len) = self.take_len::<N>() else {
+            return Ok(None);
+        };
+        if len > max as u64 {
+            return Err(too_large(len));
+        }
+        Ok(self.take(len as usize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_entry(value: Vec<u8>) -> KVEntry {
+        KVEntry::new("test_key".to_string(), value, "text/plain".to_string())
+    }
+
+    #[test]
+    fn test_encode_and_decode() -> KVResult<()> {
+        let entry = test_entry(b"test_value".to_vec());
+        let buf = encode(&entry, false)?;
+
+        match decode(&buf)? {
+            Decoded::Complete(decoded, len) => {
+                assert_eq!(len, buf.len());
+                assert_eq!(decoded.key, entry.key);
+                assert_eq!(decoded.value, entry.value);
+                assert_eq!(decoded.mime, entry.mime);
+            }
+            Decoded::Incomplete(_) => panic!("expected a complete entry"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_and_decode_path_keys() -> KVResult<()> {
+        for key in ["users/42/avatar", "a//b", "/leading", "trailing/", "ü/ key"] {
+            let entry = KVEntry::new(key.to_string(), b"v".to_vec(), "text/plain".to_string());
+            let buf = encode(&entry, false)?;
+            assert!(
+                matches!(decode(&buf)?, Decoded::Complete(decoded, len) if decoded.key == key && len == buf.len())
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_corrupted() -> KVResult<()> {
+        let mut buf = encode(&test_entry(b"test_value".to_vec()), false)?;
+        let last = buf.len() - 5;
+        buf[last] ^= 0b00000100;
+        assert!(matches!(
+            decode(&buf),
+            Err(KVError::ChecksumMismatch { .. })
+        ));
+
+        // Records from before checksums decode as long as they aren't checked.
+        let buf = encode_with(&test_entry(b"test_value".to_vec()), false, Layout::V1)?;
+        assert!(
+            matches!(decode_with(&buf, Layout::V1)?, Decoded::Complete(_, len) if len == buf.len())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_and_decode_layouts() -> KVResult<()> {
+        let entry = test_entry(b"test_value".to_vec());
+        let v4 = encode_with(&entry, false, Layout::V4)?;
+        assert_eq!(v4.len() + 4, encode(&entry, false)?.len());
+        assert!(matches!(
+            decode_with(&v4, Layout::V4)?,
+            Decoded::Complete(decoded, len) if decoded.value == entry.value && len == v4.len()
+        ));
+
+        // Values of 4 GiB and more only fit the current layout.
+        let huge = u32::MAX as usize + 1;
+        assert!(matches!(
+            Layout::V4.push_len(&mut Vec::new(), huge),
+            Err(KVError::ValueTooLarge { len, .. }) if len == huge as u64
+        ));
+        let mut buf = Vec::new();
+        Layout::V5.push_len(&mut buf, huge)?;
+        assert_eq!(buf, (huge as u64).to_le_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_limits() -> KVResult<()> {
+        let mut buf = encode(&test_entry(b"test_value".to_vec()), false)?;
+        let limits = |max_key_len, max_value_len, max_mime_len| Limits {
+            max_key_len,
+            max_value_len,
+            max_mime_len,
+        };
+        assert!(matches!(
+            decode_limited(&buf, &limits(4, 100, 100)),
+            Err(KVError::KeyTooLarge { len: 8, max: 4 })
+        ));
+        assert!(matches!(
+            decode_limited(&buf, &limits(100, 4, 100)),
+            Err(KVError::ValueTooLarge { len: 10, max: 4 })
+        ));
+        assert!(matches!(
+            decode_limited(&buf, &limits(100, 100, 4)),
+            Err(KVError::MimeTooLarge { len: 10, max: 4 })
+        ));
+
+        // A corrupted length is rejected before the reader asks for that many bytes.
+        let at = 1 + 2 + "test_key".len();
+        buf[at..at + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(decode(&buf), Err(KVError::ValueTooLarge { .. })));
+        // One within the limits is read in chunks, until the reader runs out.
+        buf[at..at + 8].copy_from_slice(&(DEFAULT_MAX_VALUE_LEN as u64).to_le_bytes());
+        assert!(matches!(read_entry(&buf[..]), Err(KVError::IO(_))));
+        assert_eq!(next_read_len(0, DEFAULT_MAX_VALUE_LEN), READ_CHUNK_LEN);
+        Ok(())
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_decode_limits_compressed() -> KVResult<()> {
+        let buf = encode(&test_entry(vec![0; 4096]), true)?;
+        let limits = Limits {
+            max_value_len: 1024,
+            ..Limits::default()
+        };
+        assert!(matches!(
+            decode_limited(&buf, &limits),
+            Err(KVError::ValueTooLarge { len: 4096, .. })
+        ));
+        // Frames which decompress to more than any record could hold aren't
+        // decompressed all the way.
+        assert!(
+            decompress_body(&compress_body(&[0; 4096], DEFAULT_COMPRESSION_LEVEL)?, 1024).is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_rejects_long_fields() -> KVResult<()> {
+        let mut entry = test_entry(b"test_value".to_vec());
+        entry.key = "k".repeat(MAX_KEY_LEN);
+        encode(&entry, false)?;
+        entry.key.push('k');
+        assert!(matches!(
+            encode(&entry, false),
+            Err(KVError::KeyTooLarge { len, max }) if len == MAX_KEY_LEN + 1 && max == MAX_KEY_LEN
+        ));
+
+        // Other lengths used to be truncated, and the record written wrong.
+        let mut entry = test_entry(b"test_value".to_vec());
+        entry.mime = "m".repeat(MAX_KEY_LEN + 1);
+        assert!(matches!(
+            encode(&entry, false),
+            Err(KVError::InvalidData(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_decode_incomplete() -> KVResult<()> {
+        let buf = encode(&test_entry(b"test_value".to_vec()), false)?;
+        for len in 0..buf.len() {
+            match decode(&buf[..len])? {
+                Decoded::Incomplete(needed) => assert!(needed > len && needed <= buf.len()),
+                Decoded::Complete(..) => panic!("decoded an entry from {} bytes", len),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_and_decode_tombstones() -> KVResult<()> {
+        let mut buf = Vec::new();
+        write_entry(&mut buf, &KVEntry::tombstone("a".to_string()), false)?;
+        write_entry(
+            &mut buf,
+            &KVEntry::prefix_tombstone("b/".to_string()),
+            false,
+        )?;
+        let accessed_at = from_millis(1_700_000_000_000);
+        write_entry(
+            &mut buf,
+            &KVEntry::touch("c".to_string(), accessed_at),
+            false,
+        )?;
+
+        let mut reader = &buf[..];
+        let tombstone = read_entry(&mut reader)?;
+        assert_eq!(tombstone.kind, EntryKind::Tombstone);
+        assert_eq!(tombstone.key, "a");
+        let prefix_tombstone = read_entry(&mut reader)?;
+        assert_eq!(prefix_tombstone.kind, EntryKind::PrefixTombstone);
+        assert_eq!(prefix_tombstone.key, "b/");
+        let touch = read_entry(&mut reader)?;
+        assert_eq!(touch.kind, EntryKind::Touch);
+        assert_eq!(touch.touched_at(), Some(accessed_at));
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_and_decode_pinned() -> KVResult<()> {
+        let unpinned = encode(&test_entry(b"test_value".to_vec()), false)?;
+        let mut entry = test_entry(b"test_value".to_vec());
+        entry.pinned = true;
+        let mut buf = encode(&entry, false)?;
+        // Only pinned entries have the byte of extended flags.
+        assert_eq!(buf.len(), unpinned.len() + 1);
+        assert_eq!(flag_names(flags(&entry, false)), ["extended", "pinned"]);
+        match decode(&buf)? {
+            Decoded::Complete(decoded, len) => {
+                assert!(decoded.pinned);
+                assert_eq!(len, buf.len());
+            }
+            Decoded::Incomplete(_) => panic!("pinned entry is incomplete"),
+        }
+
+        buf[1] |= 0b10000000;
+        assert!(matches!(decode(&buf), Err(KVError::InvalidData(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_and_decode_metadata() -> KVResult<()> {
+        let mut entry = test_entry(b"test_value".to_vec());
+        entry
+            .meta
+            .insert("uploader".to_string(), "alice".to_string());
+        entry
+            .meta
+            .insert("source".to_string(), "import".to_string());
+        let buf = encode(&entry, false)?;
+        assert_eq!(flag_names(buf[0].into()), ["metadata"]);
+        for len in 0..buf.len() {
+            assert!(matches!(decode(&buf[..len])?, Decoded::Incomplete(_)));
+        }
+        let decoded = read_entry(&buf[..])?;
+        assert_eq!(decoded.meta, entry.meta);
+        assert_eq!(decoded.value, entry.value);
+        Ok(())
+    }
+
+    #[test]
+    fn test_encode_and_decode_expiry() -> KVResult<()> {
+        let mut entry = test_entry(b"test_value".to_vec());
+        entry.expires_at = Some(UNIX_EPOCH + Duration::from_millis(1_700_000_000_123));
+        entry.modified_at = Some(UNIX_EPOCH + Duration::from_millis(1_600_000_000_456));
+        let buf = encode(&entry, false)?;
+        assert_ne!(buf[0] & Flags::HasExpiry as u8, 0);
+        assert_ne!(buf[0] & Flags::HasModified as u8, 0);
+        for len in 0..buf.len() {
+            assert!(matches!(decode(&buf[..len])?, Decoded::Incomplete(_)));
+        }
+        let decoded = read_entry(&buf[..])?;
+        assert_eq!(decoded.expires_at, entry.expires_at);
+        assert_eq!(decoded.modified_at, entry.modified_at);
+        Ok(())
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn test_sync_read_and_write_compressed() -> KVResult<()> {
+        let entry = test_entry(vec![42u8; 4096]);
+        let mut buf = Vec::new();
+        write_entry(&mut buf, &entry, true)?;
+        write_entry(&mut buf, &entry, false)?;
+        assert!(buf.len() < 4096 * 2);
+
+        let mut reader = &buf[..];
+        let first = read_entry(&mut reader)?;
+        let second = read_entry(&mut reader)?;
+        assert!(reader.is_empty());
+        assert_eq!(first.value, entry.value);
+        assert_eq!(second.value, entry.value);
+        Ok(())
+    }
+}
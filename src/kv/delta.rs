@@ -0,0 +1,197 @@
+//! Sans-IO binary deltas, so that overwriting a large value which only changed in
+//! places stores just the changes against its previous version.
+//!
+//! A delta is a sequence of instructions, each of which either copies a range of
+//! the base, or inserts bytes which aren't in it. Matches are found on blocks of
+//! `BLOCK_SIZE` bytes, which keeps diffing linear, at the cost of missing changes
+//! closer together than that.
+
+use std::collections::HashMap;
+
+use super::result::{KVError, KVResult};
+
+/// Size of the blocks of the base which the target is matched against.
+const BLOCK_SIZE: usize = 32;
+
+/// Instruction to copy `len: u64` bytes starting at `offset: u64` of the base.
+const COPY: u8 = 0;
+/// Instruction to insert the following `len: u64` bytes.
+const INSERT: u8 = 1;
+
+/// Deltas are only stored on top of this many other deltas, after which the full
+/// value is stored again, so reading a value never resolves a longer chain.
+pub(crate) const MAX_DEPTH: u8 = 8;
+
+/// Length of the header `DeltaRecord::encode` writes before the instructions.
+const HEADER_LEN: usize = 8 + 8 + 16 + 1;
+
+/// The value of a record storing a delta, see `codec::ExtendedFlags::Delta`.
+#[derive(Debug, PartialEq)]
+pub(crate) struct DeltaRecord<'a> {
+    /// Offset of the record the delta is against, which is of the same key.
+    pub(crate) base: u64,
+    /// Length of the value the delta produces.
+    pub(crate) value_len: u64,
+    /// ETag digest of the value the delta produces, so it's known without resolving
+    /// the delta.
+    pub(crate) etag: [u8; 16],
+    /// How many deltas there are in the chain up to and including this one.
+    pub(crate) depth: u8,
+    /// The instructions, as returned by `diff`.
+    pub(crate) instructions: &'a [u8],
+}
+
+impl<'a> DeltaRecord<'a> {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_LEN + self.instructions.len());
+        out.extend_from_slice(&self.base.to_le_bytes());
+        out.extend_from_slice(&self.value_len.to_le_bytes());
+        out.extend_from_slice(&self.etag);
+        out.push(self.depth);
+        out.extend_from_slice(self.instructions);
+        out
+    }
+
+    pub(crate) fn decode(value: &'a [u8]) -> KVResult<Self> {
+        if value.len() < HEADER_LEN {
+            return Err(KVError::InvalidData("Truncated delta header".to_string()));
+        }
+        Ok(Self {
+            base: u64::from_le_bytes(value[0..8].try_into().unwrap()),
+            value_len: u64::from_le_bytes(value[8..16].try_into().unwrap()),
+            etag: value[16..32].try_into().unwrap(),
+            depth: value[32],
+            instructions: &value[HEADER_LEN..],
+        })
+    }
+}
+
+/// The instructions which turn `base` into `target`, for `apply`.
+pub(crate) fn diff(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut blocks: HashMap<&[u8], usize> = HashMap::new();
+    for (i, block) in base.chunks_exact(BLOCK_SIZE).enumerate() {
+        blocks.entry(block).or_insert(i * BLOCK_SIZE);
+    }
+    let mut out = Vec::new();
+    // Start of the bytes of the target which aren't covered by a copy yet.
+    let mut pending = 0;
+    let mut pos = 0;
+    while pos + BLOCK_SIZE <= target.len() {
+        let Some(&found) = blocks.get(&target[pos..pos + BLOCK_SIZE]) else {
+            pos += 1;
+            continue;
+        };
+        // The match may well start before the block and continue after it.
+        let before = (1..=found.min(pos - pending))
+            .take_while(|n| base[found - n] == target[pos - n])
+            .count();
+        let (start, at) = (found - before, pos - before);
+        let len = base[start..]
+            .iter()
+            .zip(&target[at..])
+            .take_while(|(a, b)| a == b)
+            .count();
+        push_insert(&mut out, &target[pending..at]);
+        out.push(COPY);
+        out.extend_from_slice(&(start as u64).to_le_bytes());
+        out.extend_from_slice(&(len as u64).to_le_bytes());
+        pos = at + len;
+        pending = pos;
+    }
+    push_insert(&mut out, &target[pending..]);
+    out
+}
+
+fn push_insert(out: &mut Vec<u8>, bytes: &[u8]) {
+    if !bytes.is_empty() {
+        out.push(INSERT);
+        out.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+        out.extend_from_slice(bytes);
+    }
+}
+
+/// Applies instructions returned by `diff` to `base`, producing `value_len` bytes.
+pub(crate) fn apply(base: &[u8], instructions: &[u8], value_len: u64) -> KVResult<Vec<u8>> {
+    let invalid = || KVError::InvalidData("Invalid delta instructions".to_string());
+    // The length is only a hint, which is as untrusted as the rest of the record.
+    let mut out = Vec::with_capacity((value_len as usize).min(base.len() + instructions.len()));
+    let mut rest = instructions;
+    while let Some((&op, remaining)) = rest.split_first() {
+        rest = remaining;
+        match op {
+            COPY => {
+                let start = u64::from_le_bytes(take(&mut rest, 8)?.try_into().unwrap());
+                let len = u64::from_le_bytes(take(&mut rest, 8)?.try_into().unwrap());
+                let range = usize::try_from(start)
+                    .ok()
+                    .zip(usize::try_from(len).ok())
+                    .and_then(|(start, len)| base.get(start..start.checked_add(len)?))
+                    .ok_or_else(invalid)?;
+                out.extend_from_slice(range);
+            }
+            INSERT => {
+                let len = u64::from_le_bytes(take(&mut rest, 8)?.try_into().unwrap());
+                let len = usize::try_from(len).map_err(|_| invalid())?;
+                out.extend_from_slice(take(&mut rest, len)?);
+            }
+            _ => return Err(invalid()),
+        }
+    }
+    if out.len() as u64 != value_len {
+        return Err(KVError::InvalidData(format!(
+            "Delta produced {} bytes instead of {}",
+            out.len(),
+            value_len
+        )));
+    }
+    Ok(out)
+}
+
+/// Takes the first `len` bytes off `rest`.
+fn take<'a>(rest: &mut &'a [u8], len: usize) -> KVResult<&'a [u8]> {
+    let (taken, remaining) = rest
+        .split_at_checked(len)
+        .ok_or_else(|| KVError::InvalidData("Truncated delta instructions".to_string()))?;
+    *rest = remaining;
+    Ok(taken)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn test_diff_and_apply() {
+        let mut rng = rand::thread_rng();
+        let base: Vec<u8> = (0..64 * 1024).map(|_| rng.gen()).collect();
+        let mut target = base.clone();
+        target[1000..1010].copy_from_slice(b"0123456789");
+        target.splice(30_000..30_000, b"inserted".iter().copied());
+        target.drain(50_000..50_100);
+        target.extend_from_slice(b"appended");
+
+        let delta = diff(&base, &target);
+        assert!(delta.len() < 200, "delta is {} bytes", delta.len());
+        assert_eq!(apply(&base, &delta, target.len() as u64).unwrap(), target);
+
+        // Unrelated values still round trip, only without saving anything.
+        let other: Vec<u8> = (0..1000).map(|_| rng.gen()).collect();
+        assert_eq!(apply(&base, &diff(&base, &other), 1000).unwrap(), other);
+        assert!(apply(&base, &diff(&base, &other), 999).is_err());
+        assert!(apply(&base, &[COPY, 1, 2], 0).is_err());
+    }
+
+    #[test]
+    fn test_delta_record() {
+        let record = DeltaRecord {
+            base: 42,
+            value_len: 4096,
+            etag: [7; 16],
+            depth: 3,
+            instructions: &[INSERT, 1, 0, 0, 0, 0, 0, 0, 0, b'a'],
+        };
+        assert_eq!(DeltaRecord::decode(&record.encode()).unwrap(), record);
+        assert!(DeltaRecord::decode(&[0; HEADER_LEN - 1]).is_err());
+    }
+}
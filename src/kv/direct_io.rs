@@ -0,0 +1,151 @@
+//! Writing files with `O_DIRECT`, which bypasses the page cache, for files which are
+//! written all at once, like compacted copies of the database file. Writing those
+//! through the page cache would evict the pages reads are served from.
+//!
+//! Only built on Linux with the `direct-io` feature. Otherwise, and on file systems
+//! which don't support `O_DIRECT`, `DirectWriter::open` returns `None`, and the file
+//! is written through the page cache as usual.
+
+#[cfg(all(feature = "direct-io", target_os = "linux"))]
+pub(crate) use linux::DirectWriter;
+
+#[cfg(not(all(feature = "direct-io", target_os = "linux")))]
+pub(crate) use fallback::DirectWriter;
+
+#[cfg(all(feature = "direct-io", target_os = "linux"))]
+mod linux {
+    use std::{
+        fs::{File, OpenOptions},
+        io,
+        os::unix::fs::{FileExt, OpenOptionsExt},
+        path::Path,
+        sync::Arc,
+    };
+
+    use log::debug;
+
+    /// What offsets, lengths, and buffers of direct writes need to be aligned to. The
+    /// logical block size of most devices divides it.
+    const ALIGN: usize = 4096;
+    /// How many bytes are collected before they're written.
+    const CHUNK_LEN: usize = 256 * ALIGN;
+
+    /// Writes a file from the start, with `O_DIRECT`.
+    pub(crate) struct DirectWriter {
+        file: Arc<File>,
+        /// Longer than `CHUNK_LEN` by `ALIGN`, so that it holds an aligned chunk at
+        /// `start`.
+        buf: Vec<u8>,
+        start: usize,
+        /// How many bytes of the chunk are filled.
+        len: usize,
+        /// How many bytes were written to the file, a multiple of `ALIGN`.
+        written: u64,
+    }
+
+    impl DirectWriter {
+        /// Opens the file at `path` for writing with `O_DIRECT`, or returns `None` if
+        /// its file system doesn't support that. The file should be empty.
+        pub(crate) async fn open(path: &Path) -> io::Result<Option<Self>> {
+            let path = path.to_owned();
+            let opened = tokio::task::spawn_blocking(move || {
+                OpenOptions::new()
+                    .write(true)
+                    .custom_flags(libc::O_DIRECT)
+                    .open(&path)
+            })
+            .await?;
+            let file = match opened {
+                Ok(file) => file,
+                Err(err) if err.raw_os_error() == Some(libc::EINVAL) => {
+                    debug!("File system doesn't support O_DIRECT: {}", err);
+                    return Ok(None);
+                }
+                Err(err) => return Err(err),
+            };
+            let buf = vec![0; CHUNK_LEN + ALIGN];
+            let start = buf.as_ptr().align_offset(ALIGN);
+            Ok(Some(Self {
+                file: Arc::new(file),
+                buf,
+                start,
+                len: 0,
+                written: 0,
+            }))
+        }
+
+        /// Appends `bytes` to what was written before.
+        pub(crate) async fn write(&mut self, mut bytes: &[u8]) -> io::Result<()> {
+            while !bytes.is_empty() {
+                let taken = bytes.len().min(CHUNK_LEN - self.len);
+                let at = self.start + self.len;
+                self.buf[at..at + taken].copy_from_slice(&bytes[..taken]);
+                self.len += taken;
+                bytes = &bytes[taken..];
+                if self.len == CHUNK_LEN {
+                    self.write_chunk().await?;
+                }
+            }
+            Ok(())
+        }
+
+        /// Writes the filled part of the chunk, padded to `ALIGN`.
+        async fn write_chunk(&mut self) -> io::Result<()> {
+            let padded = self.len.next_multiple_of(ALIGN);
+            let range = self.start..self.start + padded;
+            self.buf[self.start + self.len..range.end].fill(0);
+            let file = self.file.clone();
+            let buf = std::mem::take(&mut self.buf);
+            let offset = self.written;
+            let (buf, written) = tokio::task::spawn_blocking(move || {
+                let written = file.write_all_at(&buf[range], offset);
+                (buf, written)
+            })
+            .await?;
+            self.buf = buf;
+            written?;
+            self.written += padded as u64;
+            self.len = 0;
+            Ok(())
+        }
+
+        /// Writes what's left, and makes sure that everything is persisted. Returns
+        /// the length of the file.
+        pub(crate) async fn finish(mut self) -> io::Result<u64> {
+            let len = self.written + self.len as u64;
+            if self.len > 0 {
+                self.write_chunk().await?;
+            }
+            let file = self.file.clone();
+            tokio::task::spawn_blocking(move || {
+                // The last chunk was padded.
+                file.set_len(len)?;
+                file.sync_all()
+            })
+            .await??;
+            Ok(len)
+        }
+    }
+}
+
+#[cfg(not(all(feature = "direct-io", target_os = "linux")))]
+mod fallback {
+    use std::{convert::Infallible, io, path::Path};
+
+    /// Never opened without `O_DIRECT`.
+    pub(crate) struct DirectWriter(Infallible);
+
+    impl DirectWriter {
+        pub(crate) async fn open(_path: &Path) -> io::Result<Option<Self>> {
+            Ok(None)
+        }
+
+        pub(crate) async fn write(&mut self, _bytes: &[u8]) -> io::Result<()> {
+            match self.0 {}
+        }
+
+        pub(crate) async fn finish(self) -> io::Result<u64> {
+            match self.0 {}
+        }
+    }
+}
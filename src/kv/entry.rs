@@ -1,28 +1,97 @@
-use async_compression::tokio::write::ZstdEncoder;
-use std::ops::BitAnd;
-use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWriteExt};
+use std::{collections::BTreeMap, sync::Arc, time::SystemTime};
 
-use super::result::{KVError, KVResult};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-/// Internal representation of a key-value store entry.
-pub(crate) struct KVEntry {
-    pub(crate) key: String,
-    pub(crate) value: Vec<u8>,
-    pub(crate) mime: String,
+use super::codec::{self, Decoded, Limits};
+use super::delta::DeltaRecord;
+use super::result::KVResult;
+
+/// What an entry in the backing storage does to its key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EntryKind {
+    /// Sets the key to the entry's value.
+    Value,
+    /// Deletes the key.
+    Tombstone,
+    /// Deletes every key starting with the entry's key.
+    ///
+    /// Like any other entry, it only applies to the entries before it in the log,
+    /// so keys written after it under the same prefix are unaffected.
+    PrefixTombstone,
+    /// Records when the key was last read, see `KVEntry::touch`.
+    Touch,
 }
 
-/// Flags stored before each entry, indicating different properties of the entry,
-/// such as whether the value is compressed.
-#[repr(u8)]
-enum Flags {
-    None = 0,
-    ZstdCompressed = 0b10000000,
+/// User-supplied annotations of an entry, such as who uploaded it. Stored and
+/// returned alongside the value, but otherwise not interpreted by the store.
+pub type Metadata = BTreeMap<String, String>;
+
+/// Internal representation of a key-value store entry.
+pub struct KVEntry {
+    pub key: String,
+    pub value: Vec<u8>,
+    pub mime: String,
+    pub meta: Metadata,
+    /// When the entry expires, if ever.
+    pub expires_at: Option<SystemTime>,
+    /// When the entry was written. Records written before this was tracked don't
+    /// have it.
+    pub modified_at: Option<SystemTime>,
+    /// Whether the value is pinned, see `Entry::pinned`.
+    pub pinned: bool,
+    /// Whether the value is a binary delta against the value of an earlier record of
+    /// the same key, rather than the value itself. Only the store writes these.
+    pub delta: bool,
+    pub kind: EntryKind,
 }
 
 impl KVEntry {
     /// Creates a new KVEntry with the given key, value, and MIME type.
     pub fn new(key: String, value: Vec<u8>, mime: String) -> Self {
-        Self { key, value, mime }
+        Self {
+            key,
+            value,
+            mime,
+            meta: Metadata::new(),
+            expires_at: None,
+            modified_at: None,
+            pinned: false,
+            delta: false,
+            kind: EntryKind::Value,
+        }
+    }
+
+    /// Creates a KVEntry which deletes the given key.
+    pub fn tombstone(key: String) -> Self {
+        Self {
+            kind: EntryKind::Tombstone,
+            ..Self::new(key, Vec::new(), String::new())
+        }
+    }
+
+    /// Creates a KVEntry which deletes all keys starting with the given prefix.
+    pub fn prefix_tombstone(prefix: String) -> Self {
+        Self {
+            kind: EntryKind::PrefixTombstone,
+            ..Self::new(prefix, Vec::new(), String::new())
+        }
+    }
+
+    /// Creates a KVEntry which records that the given key was last read at
+    /// `accessed_at`. It doesn't change the key's value.
+    pub fn touch(key: String, accessed_at: SystemTime) -> Self {
+        let millis = codec::to_millis(accessed_at).to_le_bytes().to_vec();
+        Self {
+            kind: EntryKind::Touch,
+            ..Self::new(key, millis, String::new())
+        }
+    }
+
+    /// The time a `touch` entry records, or `None` for other entries.
+    pub fn touched_at(&self) -> Option<SystemTime> {
+        let millis = self.value.as_slice().try_into().ok()?;
+        (self.kind == EntryKind::Touch).then(|| codec::from_millis(u64::from_le_bytes(millis)))
     }
 
     /// Writes the KVEntry to the provided stream, without compressing the value.
@@ -32,121 +101,52 @@ impl KVEntry {
     pub(crate) async fn write_to_stream(
         &self,
         mut stream: impl AsyncWriteExt + Unpin,
-    ) -> Result<(), io::Error> {
-        stream.write_all(&(Flags::None as u8).to_le_bytes()).await?;
-        stream
-            .write_all(&(self.key.len() as u16).to_le_bytes())
-            .await?;
-        stream.write_all(self.key.as_bytes()).await?;
-        stream
-            .write_all(&(self.value.len() as u32).to_le_bytes())
-            .await?;
-        stream.write_all(&self.value).await?;
-        stream
-            .write_all(&(self.mime.len() as u16).to_le_bytes())
-            .await?;
-        stream.write_all(self.mime.as_bytes()).await?;
+    ) -> KVResult<()> {
+        stream.write_all(&codec::encode(self, false)?).await?;
         Ok(())
     }
 
-    /// Writes the KVEntry to the provided stream, compressing the value with Zstd.
+    /// Writes the KVEntry to the provided stream, compressing the value with Zstd at
+    /// `level`.
     ///
     /// This method serializes the key, value, and MIME type of the KVEntry
     /// and writes them to the given stream.
+    #[cfg(feature = "zstd")]
     pub(crate) async fn write_to_stream_compressed(
         &self,
-        mut stream: impl AsyncWriteExt + AsyncSeek + Unpin,
-    ) -> Result<(), io::Error> {
-        stream
-            .write_all(&(Flags::ZstdCompressed as u8).to_le_bytes())
-            .await?;
-        let pos = stream.stream_position().await?;
-        // this is a placeholder
-        stream.write_all(&0u32.to_le_bytes()).await?;
-        let start = stream.stream_position().await?;
-        let mut encoder = ZstdEncoder::new(stream);
-        encoder
-            .write_all(&(self.key.len() as u16).to_le_bytes())
-            .await?;
-        encoder.write_all(self.key.as_bytes()).await?;
-        encoder
-            .write_all(&(self.value.len() as u32).to_le_bytes())
-            .await?;
-        encoder.write_all(&self.value).await?;
-        encoder
-            .write_all(&(self.mime.len() as u16).to_le_bytes())
-            .await?;
-        encoder.write_all(self.mime.as_bytes()).await?;
-        encoder.flush().await?;
-        let mut stream = encoder.into_inner();
-        let end = stream.stream_position().await?;
-        stream.seek(io::SeekFrom::Start(pos)).await?;
+        mut stream: impl AsyncWriteExt + Unpin,
+        level: i32,
+    ) -> KVResult<()> {
         stream
-            .write_all(&((end - start) as u32).to_le_bytes())
+            .write_all(&codec::encode_compressed(self, level)?)
             .await?;
-        stream.seek(io::SeekFrom::Start(end)).await?;
         Ok(())
     }
 
-    /// Reads the key, value, and MIME type of the KVEntry from the given stream.
-    /// The stream is assumed to be at the start of the KVEntry, and the flags byte
-    /// has already been read.
-    pub(crate) async fn read_from_stream_impl<T: AsyncRead + Unpin>(
-        mut stream: T,
-    ) -> KVResult<Self> {
-        let mut key_len_bytes = [0u8; 2];
-        stream.read_exact(&mut key_len_bytes).await?;
-        let key_len = u16::from_le_bytes(key_len_bytes) as usize;
-        let mut key = vec![0u8; key_len];
-        stream.read_exact(&mut key).await?;
-
-        let mut value_len_bytes = [0u8; 4];
-        stream.read_exact(&mut value_len_bytes).await?;
-        let value_len = u32::from_le_bytes(value_len_bytes) as usize;
-        let mut value = vec![0u8; value_len];
-        stream.read_exact(&mut value).await?;
-
-        let mut mime_len_bytes = [0u8; 2];
-        stream.read_exact(&mut mime_len_bytes).await?;
-        let mime_len = u16::from_le_bytes(mime_len_bytes) as usize;
-        let mut mime = vec![0u8; mime_len];
-        stream.read_exact(&mut mime).await?;
-
-        Ok(Self {
-            key: String::from_utf8(key)
-                .map_err(|_| KVError::InvalidData("Invalid UTF-8 in key".to_string()))?,
-            value,
-            mime: String::from_utf8(mime)
-                .map_err(|_| KVError::InvalidData("Invalid UTF-8 in MIME".to_string()))?,
-        })
-    }
-
-    /// Reads a KVEntry from the given stream, decompressing the value with Zstd if necessary.
-    /// Delegates to `read_from_stream_impl` to read the key, value, and MIME type after
-    /// decompression (if applicable).
-    pub(crate) async fn read_from_stream(mut stream: impl AsyncReadExt + Unpin) -> KVResult<Self> {
-        let flags = stream.read_u8().await?;
-        let compressed = flags.bitand(Flags::ZstdCompressed as u8) != 0;
-        if compressed {
-            let in_len = stream.read_u32_le().await? as usize;
-            let mut in_stream = vec![0u8; in_len];
-            stream.read_exact(&mut in_stream).await?;
-
-            let mut decomp_stream = Vec::new();
-            tokio::io::copy(&mut &in_stream[..], &mut decomp_stream).await?;
-
-            let entry = Self::read_from_stream_impl(&mut &decomp_stream[..]).await?;
-            Ok(entry)
-        } else {
-            Self::read_from_stream_impl(stream).await
+    /// Reads a KVEntry from the given stream, decompressing the value with Zstd if necessary,
+    /// along with the length of its record. Only reads as many bytes as `codec::decode` asks
+    /// for, so the stream is left at the start of the next entry. Records longer than
+    /// `limits` are rejected.
+    pub(crate) async fn read_record(
+        mut stream: impl AsyncReadExt + Unpin,
+        limits: &Limits,
+    ) -> KVResult<(Self, usize)> {
+        let mut buf = Vec::new();
+        loop {
+            match codec::decode_limited(&buf, limits)? {
+                Decoded::Complete(entry, len) => return Ok((entry, len)),
+                Decoded::Incomplete(needed) => {
+                    let start = buf.len();
+                    buf.resize(codec::next_read_len(start, needed), 0);
+                    stream.read_exact(&mut buf[start..]).await?;
+                }
+            }
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::io::Cursor;
-
     use super::*;
     use tokio::io::BufReader;
     use tokio::io::BufWriter;
@@ -167,7 +167,7 @@ mod tests {
         }
 
         let mut reader = BufReader::new(&buffer[..]);
-        let read_entry = KVEntry::read_from_stream(&mut reader).await?;
+        let (read_entry, _) = KVEntry::read_record(&mut reader, &Limits::default()).await?;
 
         assert_eq!(entry.key, read_entry.key);
         assert_eq!(entry.value, read_entry.value);
@@ -176,9 +176,11 @@ mod tests {
         Ok(())
     }
 
+    #[cfg(feature = "zstd")]
     #[tokio::test]
     async fn test_write_and_read_compressed_entry() -> KVResult<()> {
         use rand::Rng;
+        use std::io::Cursor;
 
         let mut rng = rand::thread_rng();
         // enough bytes to cause the compression to kick in
@@ -192,12 +194,14 @@ mod tests {
         let mut buffer = Vec::new();
         {
             let mut cursor = Cursor::new(&mut buffer);
-            entry.write_to_stream(&mut cursor).await?;
+            entry
+                .write_to_stream_compressed(&mut cursor, codec::DEFAULT_COMPRESSION_LEVEL)
+                .await?;
             cursor.flush().await?;
         }
 
         let mut reader = BufReader::new(&buffer[..]);
-        let read_entry = KVEntry::read_from_stream(&mut reader).await?;
+        let (read_entry, _) = KVEntry::read_record(&mut reader, &Limits::default()).await?;
 
         assert_eq!(entry.key, read_entry.key);
         assert_eq!(entry.value, read_entry.value);
@@ -212,14 +216,158 @@ mod tests {
 /// This struct is used to represent the value and MIME type of a KVEntry,
 /// which it gets converted to. It is used to abstract away the underlying
 /// storage mechanism of the KVEntry.
+///
+/// The MIME type is shared between all entries of the same type in a store.
 #[derive(Clone, Debug)]
 pub struct Entry {
     pub value: Vec<u8>,
-    pub mime: String,
+    pub mime: Arc<str>,
+    pub meta: Metadata,
+    /// When the entry expires, if ever. Expired entries are no longer returned by the
+    /// store, and removed from it eventually.
+    pub expires_at: Option<SystemTime>,
+    /// When the entry was last set, which the store records on `set`. `None` for new
+    /// entries, and for entries written before this was tracked.
+    pub modified_at: Option<SystemTime>,
+    /// Pinned entries never expire, and are kept by anything which removes entries
+    /// on its own, until they're unpinned with `KVStore::set_pinned`. Setting a new
+    /// value keeps a key pinned.
+    pub pinned: bool,
 }
 impl Entry {
-    pub fn new(value: Vec<u8>, mime: String) -> Self {
-        Self { value, mime }
+    pub fn new(value: Vec<u8>, mime: impl Into<Arc<str>>) -> Self {
+        Self {
+            value,
+            mime: mime.into(),
+            meta: Metadata::new(),
+            expires_at: None,
+            modified_at: None,
+            pinned: false,
+        }
+    }
+
+    /// Attaches the given metadata to the entry, replacing any it had.
+    pub fn with_meta(mut self, meta: Metadata) -> Self {
+        self.meta = meta;
+        self
+    }
+
+    /// Makes the entry expire at the given time.
+    pub fn with_expiry(mut self, expires_at: SystemTime) -> Self {
+        self.expires_at = Some(expires_at);
+        self
+    }
+
+    /// Whether the entry has expired at the time `now`.
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        !self.pinned && self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// A hash of the value and MIME type, as a hex string. Suitable as a strong HTTP ETag.
+    ///
+    /// Metadata isn't part of the hash, as it's not part of the value's representation.
+    pub fn etag(&self) -> String {
+        to_hex(&etag_digest(&self.mime, &self.value))
+    }
+}
+
+/// The hash `Entry::etag` is made of.
+pub(crate) fn etag_digest(mime: &str, value: &[u8]) -> [u8; 16] {
+    let mut hasher = Sha256::new();
+    hasher.update(mime.as_bytes());
+    hasher.update([0]);
+    hasher.update(value);
+    hasher.finalize()[..16].try_into().unwrap()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Where the value of an indexed entry is kept.
+#[derive(Clone, Debug)]
+pub(crate) enum ValueLocation {
+    /// In the record starting at this offset of the backing storage.
+    Stored(u64),
+    /// In memory, for entries from a seed, and for backing storage which doesn't
+    /// keep what's written to it.
+    Loaded(Vec<u8>),
+}
+
+/// What the store's index keeps about an entry: Everything but the value, which is
+/// only read from the backing storage when the entry is requested.
+#[derive(Clone, Debug)]
+pub struct EntryInfo {
+    pub mime: Arc<str>,
+    pub meta: Metadata,
+    pub expires_at: Option<SystemTime>,
+    pub modified_at: Option<SystemTime>,
+    /// When the entry was last read, if access times are tracked. This is only as
+    /// precise as the granularity they're tracked with, see `KVStore::accessed_at`.
+    pub accessed_at: Option<SystemTime>,
+    pub pinned: bool,
+    /// Length of the value in bytes.
+    pub value_len: usize,
+    /// Kept so the ETag is known without reading the value.
+    etag: [u8; 16],
+    pub(crate) location: ValueLocation,
+    /// How many delta records have to be resolved to read the value, see
+    /// `KVStore::set_delta_threshold`.
+    pub(crate) delta_depth: u8,
+}
+
+impl EntryInfo {
+    pub(crate) fn new(entry: &Entry, location: ValueLocation) -> Self {
+        Self {
+            mime: entry.mime.clone(),
+            meta: entry.meta.clone(),
+            expires_at: entry.expires_at,
+            modified_at: entry.modified_at,
+            accessed_at: None,
+            pinned: entry.pinned,
+            value_len: entry.value.len(),
+            etag: etag_digest(&entry.mime, &entry.value),
+            location,
+            delta_depth: 0,
+        }
+    }
+
+    /// Like `new`, but for an entry whose value is the delta `record`, which describes
+    /// the value it produces.
+    pub(crate) fn for_delta(entry: &Entry, record: &DeltaRecord, location: ValueLocation) -> Self {
+        Self {
+            value_len: record.value_len as usize,
+            etag: record.etag,
+            delta_depth: record.depth,
+            ..Self::new(entry, location)
+        }
+    }
+
+    /// The ETag of the entry, see `Entry::etag`.
+    pub fn etag(&self) -> String {
+        to_hex(&self.etag)
+    }
+
+    /// Whether the entry has expired at the time `now`.
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        !self.pinned && self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// When the entry expires, unless it's pinned.
+    pub(crate) fn expiry(&self) -> Option<SystemTime> {
+        self.expires_at.filter(|_| !self.pinned)
+    }
+
+    /// The full entry, with the value read from wherever it's kept.
+    pub(crate) fn with_value(&self, value: Vec<u8>) -> Entry {
+        Entry {
+            value,
+            mime: self.mime.clone(),
+            meta: self.meta.clone(),
+            expires_at: self.expires_at,
+            modified_at: self.modified_at,
+            pinned: self.pinned,
+        }
     }
 }
 
@@ -227,7 +375,11 @@ impl From<KVEntry> for Entry {
     fn from(value: KVEntry) -> Self {
         Self {
             value: value.value,
-            mime: value.mime,
+            mime: value.mime.into(),
+            meta: value.meta,
+            expires_at: value.expires_at,
+            modified_at: value.modified_at,
+            pinned: value.pinned,
         }
     }
 }
@@ -0,0 +1,149 @@
+use log::warn;
+use tokio::sync::broadcast;
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    Stream, StreamExt,
+};
+
+use super::codec;
+use super::entry::{Entry, EntryKind, KVEntry};
+
+/// How many events a subscriber may fall behind before it starts missing events.
+const EVENT_CHANNEL_CAPACITY: usize = 1024;
+
+/// A mutation of the store, as seen by subscribers.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub enum ChangeEvent {
+    /// A key was set to a new value.
+    Set { key: String, entry: Entry },
+    /// A key was removed.
+    Removed { key: String },
+    /// All keys starting with the prefix were removed.
+    PrefixRemoved { prefix: String },
+}
+
+impl ChangeEvent {
+    /// The key affected by this event, or the prefix of all affected keys.
+    pub fn key(&self) -> &str {
+        match self {
+            ChangeEvent::Set { key, .. } | ChangeEvent::Removed { key } => key,
+            ChangeEvent::PrefixRemoved { prefix } => prefix,
+        }
+    }
+
+    /// Whether this event affects any key starting with `prefix`.
+    pub fn affects(&self, prefix: &str) -> bool {
+        match self {
+            ChangeEvent::Set { key, .. } | ChangeEvent::Removed { key } => key.starts_with(prefix),
+            ChangeEvent::PrefixRemoved { prefix: removed } => {
+                removed.starts_with(prefix) || prefix.starts_with(removed.as_str())
+            }
+        }
+    }
+}
+
+/// Publishes `ChangeEvent`s to any number of subscribers. Events which are costly to
+/// build, like those carrying values, are only built if `has_subscribers`.
+pub(crate) struct EventBus {
+    sender: broadcast::Sender<ChangeEvent>,
+}
+
+impl EventBus {
+    pub(crate) fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        Self { sender }
+    }
+
+    pub(crate) fn has_subscribers(&self) -> bool {
+        self.sender.receiver_count() > 0
+    }
+
+    pub(crate) fn publish(&self, event: ChangeEvent) {
+        // An error only means that nobody is subscribed right now.
+        _ = self.sender.send(event);
+    }
+
+    /// Subscribes to all events for keys starting with `prefix`. Subscribers which
+    /// fall too far behind skip the events they missed, which is logged.
+    pub(crate) fn subscribe(&self, prefix: &str) -> impl Stream<Item = ChangeEvent> {
+        let prefix = prefix.to_owned();
+        BroadcastStream::new(self.sender.subscribe()).filter_map(move |event| match event {
+            Ok(event) if event.affects(&prefix) => Some(event),
+            Ok(_) => None,
+            Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                warn!(
+                    "Subscriber for prefix {:?} lagged behind, missed {} events",
+                    prefix, missed
+                );
+                None
+            }
+        })
+    }
+}
+
+/// A record as it was appended to the backing storage, as seen by `KVStore::tail`.
+/// Only describes the value, which isn't included.
+#[derive(Clone, Debug)]
+pub struct LogRecord {
+    /// Counts the records appended since the store was opened, starting at 0. Gaps
+    /// show that a subscriber missed records.
+    pub sequence: u64,
+    /// Where the record starts in the backing storage.
+    pub offset: u64,
+    pub key: String,
+    pub kind: EntryKind,
+    pub mime: String,
+    /// Size of the uncompressed value, in bytes.
+    pub value_len: usize,
+    /// The flags stored before the record, see `codec::flag_names`.
+    pub flags: u16,
+}
+
+/// Publishes every appended record as a `LogRecord`, like `EventBus` does for changes.
+pub(crate) struct RecordTap {
+    sender: broadcast::Sender<LogRecord>,
+    next_sequence: u64,
+}
+
+impl RecordTap {
+    pub(crate) fn new() -> Self {
+        let (sender, _) = broadcast::channel(EVENT_CHANNEL_CAPACITY);
+        Self {
+            sender,
+            next_sequence: 0,
+        }
+    }
+
+    /// Publishes the record appended at `offset`, which the backend stored compressed
+    /// if `compressed`.
+    pub(crate) fn publish(&mut self, offset: u64, record: &KVEntry, compressed: bool) {
+        let sequence = self.next_sequence;
+        self.next_sequence += 1;
+        // Records are only described when somebody is watching.
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        _ = self.sender.send(LogRecord {
+            sequence,
+            offset,
+            key: record.key.clone(),
+            kind: record.kind,
+            mime: record.mime.clone(),
+            value_len: record.value.len(),
+            flags: codec::flags(record, compressed),
+        });
+    }
+
+    /// Subscribes to all records appended from now on. Subscribers which fall too far
+    /// behind skip the records they missed, which is logged.
+    pub(crate) fn subscribe(&self) -> impl Stream<Item = LogRecord> {
+        BroadcastStream::new(self.sender.subscribe()).filter_map(|record| match record {
+            Ok(record) => Some(record),
+            Err(BroadcastStreamRecvError::Lagged(missed)) => {
+                warn!("Log tail lagged behind, missed {} records", missed);
+                None
+            }
+        })
+    }
+}
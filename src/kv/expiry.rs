@@ -0,0 +1,64 @@
+//! The times entries expire at, in order, so that expired and soon expiring entries
+//! are found without scanning every key.
+
+use std::{collections::BTreeSet, time::SystemTime};
+
+#[derive(Debug, Default)]
+pub(crate) struct ExpiryIndex {
+    by_time: BTreeSet<(SystemTime, String)>,
+}
+
+impl ExpiryIndex {
+    pub(crate) fn insert(&mut self, key: &str, expires_at: SystemTime) {
+        self.by_time.insert((expires_at, key.to_owned()));
+    }
+
+    pub(crate) fn remove(&mut self, key: &str, expires_at: SystemTime) {
+        self.by_time.remove(&(expires_at, key.to_owned()));
+    }
+
+    /// The keys which expire at or before `until`, soonest first, along with when.
+    /// Without `until`, that's every key which expires.
+    pub(crate) fn until(
+        &self,
+        until: Option<SystemTime>,
+    ) -> impl Iterator<Item = (&str, SystemTime)> {
+        self.by_time
+            .iter()
+            .take_while(move |(expires_at, _)| until.is_none_or(|until| *expires_at <= until))
+            .map(|(expires_at, key)| (key.as_str(), *expires_at))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_until() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut index = ExpiryIndex::default();
+        index.insert("b", start + Duration::from_secs(10));
+        index.insert("a", start + Duration::from_secs(20));
+        index.insert("c", start + Duration::from_secs(10));
+        let keys = |index: &ExpiryIndex, until| {
+            index
+                .until(Some(until))
+                .map(|(key, _)| key.to_owned())
+                .collect::<Vec<_>>()
+        };
+        assert!(keys(&index, start).is_empty());
+        assert_eq!(keys(&index, start + Duration::from_secs(10)), ["b", "c"]);
+        assert_eq!(
+            keys(&index, start + Duration::from_secs(60)),
+            ["b", "c", "a"]
+        );
+
+        // Removing needs the time the key expires at.
+        index.remove("b", start + Duration::from_secs(10));
+        index.remove("a", start);
+        assert_eq!(keys(&index, start + Duration::from_secs(60)), ["c", "a"]);
+        assert_eq!(index.until(None).count(), 2);
+    }
+}
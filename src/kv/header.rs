@@ -0,0 +1,209 @@
+//! The header at the start of every database file, identifying the format version
+//! and recording the settings the database was created with.
+//!
+//! Files written before the header was introduced start directly with the first
+//! entry. Those are format version 1, and are upgraded by `migration`.
+
+use std::time::{Duration, SystemTime};
+
+use super::codec::Decoded;
+use super::normalization::KeyNormalization;
+use super::result::{KVError, KVResult};
+
+/// Magic bytes at the start of a database file. Version 1 files start with an entry's
+/// flags byte instead, which can never be `b'K'`.
+pub const MAGIC: &[u8; 4] = b"KVDB";
+
+/// The format version written by this build.
+pub const FORMAT_VERSION: u16 = 6;
+
+/// The version of files which don't have a header.
+pub const LEGACY_FORMAT_VERSION: u16 = 1;
+
+/// Length of the magic bytes and the version, which every header starts with.
+const PREAMBLE_LEN: usize = MAGIC.len() + 2;
+
+/// Length of the longest header any known version has: The preamble, the key
+/// normalization, the flags, the compression threshold, and the creation time.
+pub const MAX_HEADER_LEN: usize = PREAMBLE_LEN + 1 + 1 + 8 + 8;
+
+/// Set in the flags of headers of encrypted databases.
+const FLAG_ENCRYPTED: u8 = 1;
+
+/// Stands for a compression threshold which isn't recorded.
+const UNKNOWN_THRESHOLD: u64 = u64::MAX;
+
+/// The settings stored in a database file's header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Header {
+    pub version: u16,
+    /// Since version 3. Earlier versions don't normalize keys.
+    pub key_normalization: KeyNormalization,
+    /// Since version 6. Values larger than this many bytes are compressed. `None` for
+    /// databases created before it was recorded, or by backends which don't compress.
+    pub compression_threshold: Option<u64>,
+    /// Since version 6. No build encrypts databases yet, and encrypted ones are
+    /// rejected when they're loaded.
+    pub encrypted: bool,
+    /// Since version 6. `None` for databases created before it was recorded.
+    pub created_at: Option<SystemTime>,
+}
+
+impl Header {
+    /// A header for the given version, with default settings.
+    pub fn new(version: u16) -> Self {
+        Self {
+            version,
+            key_normalization: KeyNormalization::None,
+            compression_threshold: None,
+            encrypted: false,
+            created_at: None,
+        }
+    }
+
+    /// Length of the encoded header in bytes.
+    pub fn encoded_len(&self) -> usize {
+        match self.version {
+            LEGACY_FORMAT_VERSION => 0,
+            2 => PREAMBLE_LEN,
+            3..=5 => PREAMBLE_LEN + 1,
+            _ => MAX_HEADER_LEN,
+        }
+    }
+}
+
+/// Serializes the header. Settings which its version doesn't support are left out.
+pub fn encode(header: &Header) -> Vec<u8> {
+    let mut out = Vec::with_capacity(header.encoded_len());
+    if header.version == LEGACY_FORMAT_VERSION {
+        return out;
+    }
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&header.version.to_le_bytes());
+    if header.version >= 3 {
+        out.push(header.key_normalization as u8);
+    }
+    if header.version >= 6 {
+        out.push(if header.encrypted { FLAG_ENCRYPTED } else { 0 });
+        let threshold = header.compression_threshold.unwrap_or(UNKNOWN_THRESHOLD);
+        out.extend_from_slice(&threshold.to_le_bytes());
+        // Zero stands for an unknown creation time.
+        let created_at = header
+            .created_at
+            .and_then(|created_at| created_at.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .map_or(0, |since_epoch| since_epoch.as_secs());
+        out.extend_from_slice(&created_at.to_le_bytes());
+    }
+    out
+}
+
+/// Tries to decode the header from the start of a database file.
+///
+/// Returns a `LEGACY_FORMAT_VERSION` header without consuming anything if the file
+/// doesn't start with a header. An empty buffer is reported as incomplete. Headers
+/// of versions newer than `FORMAT_VERSION` are only decoded up to their version.
+pub fn decode(buf: &[u8]) -> KVResult<Decoded<Header>> {
+    if buf.is_empty() {
+        return Ok(Decoded::Incomplete(1));
+    }
+    if buf[0] != MAGIC[0] {
+        return Ok(Decoded::Complete(Header::new(LEGACY_FORMAT_VERSION), 0));
+    }
+    if buf.len() < PREAMBLE_LEN {
+        return Ok(Decoded::Incomplete(PREAMBLE_LEN));
+    }
+    if &buf[..MAGIC.len()] != MAGIC {
+        return Err(KVError::InvalidData("Invalid file header".to_string()));
+    }
+    let mut header = Header::new(u16::from_le_bytes([buf[MAGIC.len()], buf[MAGIC.len() + 1]]));
+    if header.version > FORMAT_VERSION {
+        return Ok(Decoded::Complete(header, PREAMBLE_LEN));
+    }
+    if buf.len() < header.encoded_len() {
+        return Ok(Decoded::Incomplete(header.encoded_len()));
+    }
+    if header.version >= 3 {
+        header.key_normalization =
+            KeyNormalization::from_u8(buf[PREAMBLE_LEN]).ok_or_else(|| {
+                KVError::InvalidData(format!(
+                    "Unknown key normalization in header: {}",
+                    buf[PREAMBLE_LEN]
+                ))
+            })?;
+    }
+    if header.version >= 6 {
+        let flags = buf[PREAMBLE_LEN + 1];
+        if flags & !FLAG_ENCRYPTED != 0 {
+            return Err(KVError::InvalidData(format!(
+                "Unknown flags in header: {:#04x}",
+                flags
+            )));
+        }
+        header.encrypted = flags & FLAG_ENCRYPTED != 0;
+        let u64_at = |at: usize| u64::from_le_bytes(buf[at..at + 8].try_into().unwrap());
+        let threshold = u64_at(PREAMBLE_LEN + 2);
+        header.compression_threshold = (threshold != UNKNOWN_THRESHOLD).then_some(threshold);
+        let created_at = u64_at(PREAMBLE_LEN + 10);
+        header.created_at =
+            (created_at != 0).then(|| SystemTime::UNIX_EPOCH + Duration::from_secs(created_at));
+    }
+    Ok(Decoded::Complete(header, header.encoded_len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_and_decode() -> KVResult<()> {
+        let header = Header {
+            version: FORMAT_VERSION,
+            key_normalization: KeyNormalization::Lowercase,
+            compression_threshold: Some(4096),
+            encrypted: false,
+            created_at: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+        };
+        let buf = encode(&header);
+        assert_eq!(buf.len(), MAX_HEADER_LEN);
+        assert!(matches!(
+            decode(&buf[..1])?,
+            Decoded::Incomplete(PREAMBLE_LEN)
+        ));
+        assert!(matches!(
+            decode(&buf[..PREAMBLE_LEN])?,
+            Decoded::Incomplete(MAX_HEADER_LEN)
+        ));
+        assert!(matches!(
+            decode(&buf)?,
+            Decoded::Complete(decoded, MAX_HEADER_LEN) if decoded == header
+        ));
+        // Settings which aren't recorded are decoded as such.
+        let unknown = encode(&Header::new(FORMAT_VERSION));
+        assert!(matches!(
+            decode(&unknown)?,
+            Decoded::Complete(decoded, _) if decoded == Header::new(FORMAT_VERSION)
+        ));
+        let mut unknown_flags = unknown.clone();
+        unknown_flags[PREAMBLE_LEN + 1] = 0x80;
+        assert!(decode(&unknown_flags).is_err());
+        let v5 = encode(&Header::new(5));
+        assert_eq!(v5.len(), PREAMBLE_LEN + 1);
+        let v2 = encode(&Header::new(2));
+        assert!(matches!(
+            decode(&v2)?,
+            Decoded::Complete(Header { version: 2, .. }, PREAMBLE_LEN)
+        ));
+        // The flags byte of an uncompressed version 1 entry.
+        assert!(matches!(
+            decode(&[0u8])?,
+            Decoded::Complete(
+                Header {
+                    version: LEGACY_FORMAT_VERSION,
+                    ..
+                },
+                0
+            )
+        ));
+        Ok(())
+    }
+}
@@ -0,0 +1,523 @@
+/// An ordered map from string keys to values, which stores shared key prefixes
+/// only once (a radix tree).
+///
+/// Hierarchical keys like `users/42/avatar` and `users/42/name` tend to share
+/// long prefixes, so this uses a lot less memory than a `HashMap<String, V>` for
+/// large stores. Iteration yields keys in lexicographic (byte) order.
+#[derive(Clone)]
+pub struct RadixIndex<V> {
+    root: Node<V>,
+    len: usize,
+}
+
+#[derive(Clone)]
+struct Node<V> {
+    /// The part of the key this node adds to its parent's. Only empty for the root.
+    prefix: Box<[u8]>,
+    value: Option<V>,
+    /// Sorted by the first byte of their prefix, which is unique among siblings.
+    children: Vec<Node<V>>,
+}
+
+impl<V> Node<V> {
+    fn new(prefix: &[u8], value: Option<V>) -> Self {
+        Self {
+            prefix: prefix.into(),
+            value,
+            children: Vec::new(),
+        }
+    }
+
+    fn child_index(&self, byte: u8) -> Result<usize, usize> {
+        self.children
+            .binary_search_by_key(&byte, |child| child.prefix[0])
+    }
+
+    fn get(&self, mut key: &[u8]) -> Option<&Self> {
+        let mut node = self;
+        while !key.is_empty() {
+            let child = &node.children[node.child_index(key[0]).ok()?];
+            key = key.strip_prefix(&*child.prefix)?;
+            node = child;
+        }
+        Some(node)
+    }
+
+    fn get_mut(&mut self, mut key: &[u8]) -> Option<&mut Self> {
+        let mut node = self;
+        while !key.is_empty() {
+            let index = node.child_index(key[0]).ok()?;
+            let child = &mut node.children[index];
+            key = key.strip_prefix(&*child.prefix)?;
+            node = child;
+        }
+        Some(node)
+    }
+
+    fn insert(&mut self, key: &[u8], value: V) -> Option<V> {
+        if key.is_empty() {
+            return self.value.replace(value);
+        }
+        match self.child_index(key[0]) {
+            Err(index) => {
+                self.children.insert(index, Node::new(key, Some(value)));
+                None
+            }
+            Ok(index) => {
+                let child = &mut self.children[index];
+                let common = common_prefix_len(&child.prefix, key);
+                if common < child.prefix.len() {
+                    // Split the child, so that the shared part becomes its own node.
+                    let mut old = std::mem::replace(child, Node::new(&key[..common], None));
+                    old.prefix = old.prefix[common..].into();
+                    child.children.push(old);
+                }
+                child.insert(&key[common..], value)
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Option<V> {
+        if key.is_empty() {
+            return self.value.take();
+        }
+        let index = self.child_index(key[0]).ok()?;
+        let child = &mut self.children[index];
+        let rest = key.strip_prefix(&*child.prefix)?;
+        let removed = child.remove(rest)?;
+        self.compact_child(index);
+        Some(removed)
+    }
+
+    /// Removes every value whose key starts with `prefix`, which must not be empty.
+    /// Returns how many values were removed.
+    fn remove_prefix(&mut self, prefix: &[u8]) -> usize {
+        let Ok(index) = self.child_index(prefix[0]) else {
+            return 0;
+        };
+        let child = &mut self.children[index];
+        if child.prefix.starts_with(prefix) {
+            return self.children.remove(index).count();
+        }
+        let Some(rest) = prefix.strip_prefix(&*child.prefix) else {
+            return 0;
+        };
+        let removed = child.remove_prefix(rest);
+        self.compact_child(index);
+        removed
+    }
+
+    /// Removes the child at `index` if it became empty, or merges it with its only
+    /// child if it no longer holds a value itself.
+    fn compact_child(&mut self, index: usize) {
+        let child = &mut self.children[index];
+        if child.value.is_some() {
+            return;
+        }
+        match child.children.len() {
+            0 => {
+                self.children.remove(index);
+            }
+            1 => {
+                let grandchild = child.children.pop().unwrap();
+                let mut prefix = child.prefix.to_vec();
+                prefix.extend_from_slice(&grandchild.prefix);
+                *child = Node {
+                    prefix: prefix.into(),
+                    ..grandchild
+                };
+            }
+            _ => {}
+        }
+    }
+
+    /// Number of values in this subtree.
+    fn count(&self) -> usize {
+        self.value.is_some() as usize + self.children.iter().map(Node::count).sum::<usize>()
+    }
+}
+
+impl<V> RadixIndex<V> {
+    pub fn new() -> Self {
+        Self {
+            root: Node::new(&[], None),
+            len: 0,
+        }
+    }
+
+    /// Number of keys in the index.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, key: &str) -> Option<&V> {
+        self.root.get(key.as_bytes())?.value.as_ref()
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut V> {
+        self.root.get_mut(key.as_bytes())?.value.as_mut()
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Inserts the value, returning the previous value for this key, if any.
+    pub fn insert(&mut self, key: &str, value: V) -> Option<V> {
+        let previous = self.root.insert(key.as_bytes(), value);
+        if previous.is_none() {
+            self.len += 1;
+        }
+        previous
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<V> {
+        let removed = self.root.remove(key.as_bytes());
+        if removed.is_some() {
+            self.len -= 1;
+        }
+        removed
+    }
+
+    /// Removes all keys starting with `prefix`, returning how many were removed.
+    /// Matching subtrees are detached as a whole rather than removed key by key.
+    pub fn remove_prefix(&mut self, prefix: &str) -> usize {
+        let removed = if prefix.is_empty() {
+            let removed = self.len;
+            self.root = Node::new(&[], None);
+            removed
+        } else {
+            self.root.remove_prefix(prefix.as_bytes())
+        };
+        self.len -= removed;
+        removed
+    }
+
+    /// Iterates over all keys and values, in key order.
+    pub fn iter(&self) -> Iter<'_, V> {
+        Iter::new(&self.root, Vec::new())
+    }
+
+    /// Iterates over all keys starting with `prefix` and their values, in key order.
+    pub fn iter_prefix(&self, prefix: &str) -> Iter<'_, V> {
+        let mut node = &self.root;
+        let mut key = prefix.as_bytes();
+        let mut path = Vec::with_capacity(prefix.len());
+        while !key.is_empty() {
+            let Ok(index) = node.child_index(key[0]) else {
+                return Iter::empty();
+            };
+            let child = &node.children[index];
+            if child.prefix.starts_with(key) {
+                // The prefix ends within this node, so everything below it matches.
+                return Iter::new(child, path);
+            }
+            let Some(rest) = key.strip_prefix(&*child.prefix) else {
+                return Iter::empty();
+            };
+            path.extend_from_slice(&child.prefix);
+            key = rest;
+            node = child;
+        }
+        // Starting from `node` would add its prefix a second time.
+        path.truncate(path.len() - node.prefix.len());
+        Iter::new(node, path)
+    }
+
+    /// Iterates over all keys which are greater than or equal to `start` and their
+    /// values, in key order. Finding the first key takes time proportional to the
+    /// length of `start`, not to the number of keys before it.
+    pub fn range_from(&self, start: &str) -> Iter<'_, V> {
+        let mut stack = Vec::new();
+        let mut node = &self.root;
+        let mut path = Vec::with_capacity(start.len());
+        let mut rest = start.as_bytes();
+        // `node`'s key is always a prefix of `start`, and `path` is that key.
+        while !rest.is_empty() {
+            let index = match node.child_index(rest[0]) {
+                Ok(index) => index,
+                Err(index) => index,
+            };
+            // Siblings after the one `start` continues in are greater than `start`.
+            // They're pushed first, so they're visited after anything pushed below.
+            let greater = node.children[index..]
+                .iter()
+                .filter(|child| child.prefix[0] > rest[0]);
+            stack.extend(greater.rev().map(|child| (child, path.len())));
+            let Some(child) = node
+                .children
+                .get(index)
+                .filter(|child| child.prefix[0] == rest[0])
+            else {
+                return Iter { stack, key: path };
+            };
+            let common = common_prefix_len(&child.prefix, rest);
+            if common == child.prefix.len() {
+                path.extend_from_slice(&child.prefix);
+                rest = &rest[common..];
+                node = child;
+            } else {
+                // The child's key diverges from `start` (or `start` ends within it),
+                // so either its whole subtree comes after `start`, or none of it does.
+                if common == rest.len() || child.prefix[common] > rest[common] {
+                    stack.push((child, path.len()));
+                }
+                return Iter { stack, key: path };
+            }
+        }
+        stack.push((node, path.len() - node.prefix.len()));
+        Iter { stack, key: path }
+    }
+
+    /// Iterates over all keys and values, in descending key order.
+    pub fn iter_rev(&self) -> RevIter<'_, V> {
+        RevIter {
+            stack: vec![(&self.root, 0, false)],
+            key: Vec::new(),
+        }
+    }
+
+    /// Iterates over all keys which are less than `end` and their values, in
+    /// descending key order. Like with `range_from`, finding the first key takes time
+    /// proportional to the length of `end`.
+    pub fn range_rev_before(&self, end: &str) -> RevIter<'_, V> {
+        let mut stack = Vec::new();
+        let mut node = &self.root;
+        let mut path = Vec::with_capacity(end.len());
+        let mut rest = end.as_bytes();
+        // `node`'s key is always a proper prefix of `end`, and `path` is that key.
+        while !rest.is_empty() {
+            // Less than `end`, and less than everything below it, so it comes last.
+            stack.push((node, path.len() - node.prefix.len(), true));
+            let index = match node.child_index(rest[0]) {
+                Ok(index) => index,
+                Err(index) => index,
+            };
+            // Siblings before the one `end` continues in are less than `end`.
+            stack.extend(
+                node.children[..index]
+                    .iter()
+                    .map(|child| (child, path.len(), false)),
+            );
+            let Some(child) = node
+                .children
+                .get(index)
+                .filter(|child| child.prefix[0] == rest[0])
+            else {
+                break;
+            };
+            let common = common_prefix_len(&child.prefix, rest);
+            if common == child.prefix.len() {
+                path.extend_from_slice(&child.prefix);
+                rest = &rest[common..];
+                node = child;
+            } else {
+                // The child's key diverges from `end` (or `end` ends within it), so
+                // either its whole subtree comes before `end`, or none of it does.
+                if common < rest.len() && child.prefix[common] < rest[common] {
+                    stack.push((child, path.len(), false));
+                }
+                break;
+            }
+        }
+        // Otherwise `node`'s key is `end`, which is excluded along with everything
+        // below it.
+        RevIter { stack, key: path }
+    }
+}
+
+impl<V> Default for RadixIndex<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Iterator over the keys and values of a `RadixIndex`, in key order.
+pub struct Iter<'a, V> {
+    /// Nodes still to visit, together with the length of their parent's key.
+    stack: Vec<(&'a Node<V>, usize)>,
+    key: Vec<u8>,
+}
+
+impl<'a, V> Iter<'a, V> {
+    /// `path` is the full key of `node`'s parent.
+    fn new(node: &'a Node<V>, path: Vec<u8>) -> Self {
+        Self {
+            stack: vec![(node, path.len())],
+            key: path,
+        }
+    }
+
+    fn empty() -> Self {
+        Self {
+            stack: Vec::new(),
+            key: Vec::new(),
+        }
+    }
+}
+
+impl<'a, V> Iterator for Iter<'a, V> {
+    type Item = (String, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((node, parent_len)) = self.stack.pop() {
+            self.key.truncate(parent_len);
+            self.key.extend_from_slice(&node.prefix);
+            let len = self.key.len();
+            self.stack
+                .extend(node.children.iter().rev().map(|child| (child, len)));
+            if let Some(value) = &node.value {
+                let key = String::from_utf8(self.key.clone())
+                    .expect("keys are only ever inserted as valid UTF-8");
+                return Some((key, value));
+            }
+        }
+        None
+    }
+}
+
+/// Iterator over the keys and values of a `RadixIndex`, in descending key order.
+pub struct RevIter<'a, V> {
+    /// Nodes still to visit, together with the length of their parent's key, and
+    /// whether only their own value is left, as their children were visited.
+    stack: Vec<(&'a Node<V>, usize, bool)>,
+    key: Vec<u8>,
+}
+
+impl<'a, V> Iterator for RevIter<'a, V> {
+    type Item = (String, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((node, parent_len, visited)) = self.stack.pop() {
+            self.key.truncate(parent_len);
+            self.key.extend_from_slice(&node.prefix);
+            if !visited && !node.children.is_empty() {
+                // A node's key is less than the keys below it, so its value comes
+                // after theirs.
+                let len = self.key.len();
+                self.stack.push((node, parent_len, true));
+                self.stack
+                    .extend(node.children.iter().map(|child| (child, len, false)));
+                continue;
+            }
+            if let Some(value) = &node.value {
+                let key = String::from_utf8(self.key.clone())
+                    .expect("keys are only ever inserted as valid UTF-8");
+                return Some((key, value));
+            }
+        }
+        None
+    }
+}
+
+fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index_of(keys: &[&str]) -> RadixIndex<usize> {
+        let mut index = RadixIndex::new();
+        for (i, key) in keys.iter().enumerate() {
+            index.insert(key, i);
+        }
+        index
+    }
+
+    fn keys<'a>(iter: impl Iterator<Item = (String, &'a usize)>) -> Vec<String> {
+        iter.map(|(key, _)| key).collect()
+    }
+
+    #[test]
+    fn test_insert_get_remove() {
+        let mut index = index_of(&["users/42/name", "users/42/avatar", "users/4", "", "x"]);
+        assert_eq!(index.len(), 5);
+        assert_eq!(index.get("users/42/avatar"), Some(&1));
+        assert_eq!(index.get("users/4"), Some(&2));
+        assert_eq!(index.get(""), Some(&3));
+        assert_eq!(index.get("users/42"), None);
+        assert_eq!(index.get("users/42/avatarx"), None);
+
+        assert_eq!(index.insert("users/4", 10), Some(2));
+        assert_eq!(index.len(), 5);
+
+        assert_eq!(index.remove("users/42/name"), Some(0));
+        assert_eq!(index.remove("users/42/name"), None);
+        assert_eq!(index.remove("users/42"), None);
+        assert_eq!(index.len(), 4);
+        assert_eq!(index.get("users/42/avatar"), Some(&1));
+        assert_eq!(index.get("users/4"), Some(&10));
+    }
+
+    #[test]
+    fn test_iter_is_ordered() {
+        let mut input = vec!["b", "a", "ab", "abc", "aa", "ü", "u", "abd", "ba"];
+        let index = index_of(&input);
+        input.sort();
+        assert_eq!(keys(index.iter()), input);
+    }
+
+    #[test]
+    fn test_prefix_iter_and_remove() {
+        let mut index = index_of(&["tmp/a", "tmp/b/c", "tmpfile", "tm", "other"]);
+        assert_eq!(keys(index.iter_prefix("tmp/")), ["tmp/a", "tmp/b/c"]);
+        assert_eq!(
+            keys(index.iter_prefix("tmp")),
+            ["tmp/a", "tmp/b/c", "tmpfile"]
+        );
+        assert_eq!(keys(index.iter_prefix("t")).len(), 4);
+        assert_eq!(keys(index.iter_prefix("tmp/b/c")), ["tmp/b/c"]);
+        assert!(keys(index.iter_prefix("nope")).is_empty());
+
+        assert_eq!(index.remove_prefix("tmp/"), 2);
+        assert_eq!(keys(index.iter()), ["other", "tm", "tmpfile"]);
+        assert_eq!(index.remove_prefix(""), 3);
+        assert!(index.is_empty());
+    }
+
+    #[test]
+    fn test_range_from() {
+        let mut input = vec!["b", "a", "ab", "abc", "aa", "ü", "u", "abd", "ba", "abcd"];
+        let index = index_of(&input);
+        input.sort();
+        for start in [
+            "", "a", "ab", "abc", "abca", "abe", "b", "c", "ü", "üü", "aaa", "0",
+        ] {
+            let expected: Vec<_> = input.iter().copied().filter(|key| *key >= start).collect();
+            assert_eq!(
+                keys(index.range_from(start)),
+                expected,
+                "start = {:?}",
+                start
+            );
+        }
+    }
+
+    #[test]
+    fn test_range_rev_before() {
+        let mut input = vec![
+            "b", "a", "ab", "abc", "aa", "ü", "u", "abd", "ba", "abcd", "",
+        ];
+        let index = index_of(&input);
+        input.sort();
+        input.reverse();
+        assert_eq!(keys(index.iter_rev()), input);
+        for end in [
+            "", "a", "ab", "abc", "abca", "abe", "b", "c", "ü", "üü", "aaa", "0", "abcd",
+        ] {
+            let expected: Vec<_> = input.iter().copied().filter(|key| *key < end).collect();
+            assert_eq!(
+                keys(index.range_rev_before(end)),
+                expected,
+                "end = {:?}",
+                end
+            );
+        }
+    }
+}
@@ -2,6 +2,7 @@ use std::io;
 
 use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite};
 
+#[derive(Default)]
 pub struct MemoryNoOpRWS {
 }
 impl MemoryNoOpRWS {
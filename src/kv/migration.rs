@@ -0,0 +1,334 @@
+//! Upgrades database files between format versions.
+//!
+//! Each `Migration` upgrades a file by exactly one version. They are applied in
+//! order on a copy of the file, which then atomically replaces the original. The
+//! original file is kept next to it as a backup.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use log::info;
+
+use super::{
+    codec::{self, Decoded, Flags, Layout},
+    delta::DeltaRecord,
+    entry::{EntryKind, KVEntry},
+    header::{self, Header, FORMAT_VERSION},
+    result::{KVError, KVResult},
+};
+
+/// Upgrades a database from version `from` to version `from + 1`. The reader is
+/// positioned at the start of the old file, after its header (if it has one).
+struct Migration {
+    from: u16,
+    description: &'static str,
+    apply: fn(&mut dyn Read, &mut dyn Write) -> KVResult<()>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 1,
+        description: "add file header",
+        apply: migrate_v1_to_v2,
+    },
+    Migration {
+        from: 2,
+        description: "record key normalization in header",
+        apply: migrate_v2_to_v3,
+    },
+    Migration {
+        from: 3,
+        description: "add record checksums",
+        apply: migrate_v3_to_v4,
+    },
+    Migration {
+        from: 4,
+        description: "widen value lengths to 64 bits",
+        apply: migrate_v4_to_v5,
+    },
+    Migration {
+        from: 5,
+        description: "record settings and creation time in header",
+        apply: migrate_v5_to_v6,
+    },
+];
+
+/// Version 2 only adds the header; the entries themselves are unchanged.
+fn migrate_v1_to_v2(old: &mut dyn Read, new: &mut dyn Write) -> KVResult<()> {
+    io::copy(old, new)?;
+    Ok(())
+}
+
+/// Version 3 only adds the key normalization to the header. Older files don't
+/// normalize keys, which is what the default header records.
+fn migrate_v2_to_v3(old: &mut dyn Read, new: &mut dyn Write) -> KVResult<()> {
+    io::copy(old, new)?;
+    Ok(())
+}
+
+/// Version 4 ends every record with a checksum. The records are copied as they are,
+/// followed by their checksum.
+fn migrate_v3_to_v4(old: &mut dyn Read, new: &mut dyn Write) -> KVResult<()> {
+    for_each_record(old, Layout::V1, |_, record| {
+        new.write_all(record)?;
+        new.write_all(&codec::checksum(record).to_le_bytes())?;
+        Ok(())
+    })
+}
+
+/// Version 5 stores the lengths of values and compressed frames as `u64`, so the
+/// records are encoded again.
+fn migrate_v4_to_v5(old: &mut dyn Read, new: &mut dyn Write) -> KVResult<()> {
+    move_records(old, new, 4, Layout::V4, true)
+}
+
+/// Version 6 adds the compression threshold, flags, and creation time to the header.
+/// They aren't known for existing files, so the new header leaves them unrecorded.
+fn migrate_v5_to_v6(old: &mut dyn Read, new: &mut dyn Write) -> KVResult<()> {
+    move_records(old, new, 5, Layout::V5, false)
+}
+
+/// Copies the records of a file of version `from` in `layout` to one of version
+/// `from + 1`, whose header may be longer. Deltas are pointed at where the records
+/// they're against moved to, which encodes them again, as are all records if
+/// `encode_all`.
+fn move_records(
+    old: &mut dyn Read,
+    new: &mut dyn Write,
+    from: u16,
+    layout: Layout,
+    encode_all: bool,
+) -> KVResult<()> {
+    let mut old_offset = Header::new(from).encoded_len() as u64;
+    let mut new_offset = Header::new(from + 1).encoded_len() as u64;
+    let mut moved = HashMap::new();
+    for_each_record(old, layout, |mut entry, record| {
+        if entry.kind == EntryKind::Value {
+            moved.insert(old_offset, new_offset);
+        }
+        old_offset += record.len() as u64;
+        if !entry.delta && !encode_all {
+            new.write_all(record)?;
+            new_offset += record.len() as u64;
+            return Ok(());
+        }
+        if entry.delta {
+            let delta = DeltaRecord::decode(&entry.value)?;
+            let base = *moved.get(&delta.base).ok_or_else(|| {
+                KVError::InvalidData(format!(
+                    "Delta record of key {:?} against missing offset {}",
+                    entry.key, delta.base
+                ))
+            })?;
+            entry.value = DeltaRecord { base, ..delta }.encode();
+        }
+        let compressed = record[0] & Flags::ZstdCompressed as u8 != 0;
+        let encoded = codec::encode(&entry, compressed)?;
+        new.write_all(&encoded)?;
+        new_offset += encoded.len() as u64;
+        Ok(())
+    })
+}
+
+/// Calls `f` with every record from `old` in the given layout, decoded and as read.
+fn for_each_record(
+    old: &mut dyn Read,
+    layout: Layout,
+    mut f: impl FnMut(KVEntry, &[u8]) -> KVResult<()>,
+) -> KVResult<()> {
+    let mut buf = Vec::new();
+    loop {
+        match codec::decode_with(&buf, layout)? {
+            Decoded::Complete(entry, len) => {
+                f(entry, &buf[..len])?;
+                buf.clear();
+            }
+            Decoded::Incomplete(needed) => {
+                let start = buf.len();
+                buf.resize(codec::next_read_len(start, needed), 0);
+                match old.read_exact(&mut buf[start..]) {
+                    Ok(()) => {}
+                    Err(err) if err.kind() == io::ErrorKind::UnexpectedEof && start == 0 => {
+                        return Ok(())
+                    }
+                    Err(err) => return Err(err.into()),
+                }
+            }
+        }
+    }
+}
+
+/// Reads the format version of a database file and the length of its header.
+/// Returns `None` for an empty file.
+pub fn read_version(reader: impl Read) -> KVResult<Option<(u16, usize)>> {
+    Ok(read_header(reader)?.map(|(header, len)| (header.version, len)))
+}
+
+/// Like `read_version`, but returns the whole header.
+pub fn read_header(reader: impl Read) -> KVResult<Option<(Header, usize)>> {
+    let mut buf = Vec::new();
+    let mut reader = reader.take(header::MAX_HEADER_LEN as u64);
+    reader.read_to_end(&mut buf)?;
+    if buf.is_empty() {
+        return Ok(None);
+    }
+    match header::decode(&buf)? {
+        Decoded::Complete(header, len) => Ok(Some((header, len))),
+        Decoded::Incomplete(_) => Err(KVError::InvalidData("Truncated file header".to_string())),
+    }
+}
+
+/// Upgrades the database file at `path` to `FORMAT_VERSION`, if it isn't already.
+/// The original file is kept as `<path>.v<version>.bak`.
+///
+/// Returns the version the file had before, if it was migrated. Missing and empty
+/// files are left alone.
+pub fn migrate_file(path: impl AsRef<Path>) -> KVResult<Option<u16>> {
+    let path = path.as_ref();
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let Some((original_version, _)) = read_version(file)? else {
+        return Ok(None);
+    };
+    if original_version == FORMAT_VERSION {
+        return Ok(None);
+    }
+    if original_version > FORMAT_VERSION {
+        return Err(KVError::UnsupportedVersion(original_version));
+    }
+
+    let backup = path_with_suffix(path, &format!(".v{}.bak", original_version));
+    fs::copy(path, &backup)?;
+    info!(
+        "Migrating {:?} from format version {} to {}, backup at {:?}",
+        path, original_version, FORMAT_VERSION, backup
+    );
+
+    let temp = path_with_suffix(path, ".migrating");
+    let mut version = original_version;
+    while version < FORMAT_VERSION {
+        let migration = MIGRATIONS
+            .iter()
+            .find(|migration| migration.from == version)
+            .ok_or(KVError::UnsupportedVersion(version))?;
+        info!(
+            "Applying migration {} -> {}: {}",
+            version,
+            version + 1,
+            migration.description
+        );
+
+        let mut old = BufReader::new(File::open(path)?);
+        let (old_header, header_len) = read_header(&mut old)?.unwrap_or((Header::new(version), 0));
+        // Headerless files have had their first entry's bytes read already.
+        old.seek(SeekFrom::Start(header_len as u64))?;
+
+        let mut new = BufWriter::new(File::create(&temp)?);
+        // Settings the new version doesn't change are kept.
+        let new_header = Header {
+            version: version + 1,
+            ..old_header
+        };
+        new.write_all(&header::encode(&new_header))?;
+        (migration.apply)(&mut old, &mut new)?;
+        new.into_inner()
+            .map_err(|err| err.into_error())?
+            .sync_all()?;
+        fs::rename(&temp, path)?;
+        version += 1;
+    }
+    Ok(Some(original_version))
+}
+
+pub(super) fn path_with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::{backend::FileBackend, entry::KVEntry, store::KVStore};
+
+    #[tokio::test]
+    async fn test_migrate_v1_file() -> KVResult<()> {
+        let path =
+            std::env::temp_dir().join(format!("kv-migration-test-{}.db", std::process::id()));
+        {
+            let mut file = File::create(&path)?;
+            let entry = KVEntry::new(
+                "test_key".to_string(),
+                b"test_value".to_vec(),
+                "text/plain".to_string(),
+            );
+            file.write_all(&codec::encode_with(&entry, false, Layout::V1)?)?;
+        }
+
+        assert_eq!(migrate_file(&path)?, Some(1));
+        assert_eq!(migrate_file(&path)?, None);
+        let backup = path_with_suffix(&path, ".v1.bak");
+        assert_eq!(read_version(File::open(&backup)?)?, Some((1, 0)));
+
+        let store = KVStore::new(FileBackend::open(&path).await?).await?;
+        assert_eq!(store.get("test_key").await?.unwrap().value, b"test_value");
+
+        fs::remove_file(&path)?;
+        fs::remove_file(&backup)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_migrate_v4_file() -> KVResult<()> {
+        use crate::kv::{delta, entry::etag_digest, normalization::KeyNormalization};
+
+        let path =
+            std::env::temp_dir().join(format!("kv-migration-v4-test-{}.db", std::process::id()));
+        let first = vec![1u8; 4096];
+        let mut second = first.clone();
+        second[100] = 2;
+        {
+            let mut file = File::create(&path)?;
+            let header = Header {
+                key_normalization: KeyNormalization::Lowercase,
+                ..Header::new(4)
+            };
+            file.write_all(&header::encode(&header))?;
+            let other = KVEntry::new("b".to_string(), b"other".to_vec(), "text/plain".to_string());
+            let other = codec::encode_with(&other, false, Layout::V4)?;
+            file.write_all(&other)?;
+            let full = KVEntry::new("a".to_string(), first.clone(), "text/plain".to_string());
+            file.write_all(&codec::encode_with(&full, false, Layout::V4)?)?;
+            let instructions = delta::diff(&first, &second);
+            let record = DeltaRecord {
+                base: (header.encoded_len() + other.len()) as u64,
+                value_len: second.len() as u64,
+                etag: etag_digest("text/plain", &second),
+                depth: 1,
+                instructions: &instructions,
+            };
+            let delta = KVEntry {
+                delta: true,
+                ..KVEntry::new("a".to_string(), record.encode(), "text/plain".to_string())
+            };
+            file.write_all(&codec::encode_with(&delta, false, Layout::V4)?)?;
+        }
+
+        assert_eq!(migrate_file(&path)?, Some(4));
+        let store = KVStore::new(FileBackend::open(&path).await?).await?;
+        assert_eq!(store.key_normalization(), KeyNormalization::Lowercase);
+        assert_eq!(store.get("A").await?.unwrap().value, second);
+        assert_eq!(store.get("b").await?.unwrap().value, b"other");
+
+        fs::remove_file(&path)?;
+        fs::remove_file(path_with_suffix(&path, ".v4.bak"))?;
+        Ok(())
+    }
+}
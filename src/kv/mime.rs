@@ -0,0 +1,75 @@
+use std::{collections::HashSet, sync::Arc};
+
+/// Don't bother pruning the table until it holds at least this many MIME types.
+const MIN_PRUNE_LEN: usize = 64;
+
+/// Deduplicates MIME type strings, so that entries with the same MIME type
+/// share a single allocation.
+///
+/// MIME types which are no longer referenced by any entry are dropped once the
+/// table has doubled in size since it was last pruned.
+///
+/// Only the index is deduplicated. Records on disk still spell out their MIME type
+/// rather than referring to an ID in a table, as every record has to decode on its
+/// own: values are read at their offsets, see `LogBackend::read`, and neither
+/// `RecordReader` nor offline tools using `codec` replay the file first. A table
+/// would also have to be written again by every compaction and kept by mirrors, for
+/// saving a few dozen bytes per record, which zstd already saves for compressed ones.
+pub(crate) struct MimeInterner {
+    table: HashSet<Arc<str>>,
+    prune_at: usize,
+}
+
+impl MimeInterner {
+    pub(crate) fn new() -> Self {
+        Self {
+            table: HashSet::new(),
+            prune_at: MIN_PRUNE_LEN,
+        }
+    }
+
+    /// Returns the shared copy of `mime`, adding it to the table if it's new.
+    pub(crate) fn intern(&mut self, mime: &str) -> Arc<str> {
+        if let Some(interned) = self.table.get(mime) {
+            return interned.clone();
+        }
+        if self.table.len() >= self.prune_at {
+            self.table.retain(|mime| Arc::strong_count(mime) > 1);
+            self.prune_at = (self.table.len() * 2).max(MIN_PRUNE_LEN);
+        }
+        let interned: Arc<str> = Arc::from(mime);
+        self.table.insert(interned.clone());
+        interned
+    }
+
+    /// Number of distinct MIME types currently in the table.
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.table.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intern_shares_allocation() {
+        let mut interner = MimeInterner::new();
+        let a = interner.intern("text/plain");
+        let b = interner.intern(&String::from("text/plain"));
+        assert!(Arc::ptr_eq(&a, &b));
+        assert_eq!(interner.len(), 1);
+    }
+
+    #[test]
+    fn test_intern_prunes_unused() {
+        let mut interner = MimeInterner::new();
+        let kept = interner.intern("text/plain");
+        for i in 0..MIN_PRUNE_LEN * 4 {
+            interner.intern(&format!("application/x-test-{}", i));
+        }
+        assert!(interner.len() < MIN_PRUNE_LEN * 2);
+        assert!(Arc::ptr_eq(&kept, &interner.intern("text/plain")));
+    }
+}
@@ -1,4 +1,20 @@
-pub mod store;
-pub mod result;
+mod access;
+pub mod backend;
+pub mod blocking;
+mod cache;
+pub mod clock;
+pub mod codec;
+mod delta;
+mod direct_io;
 pub mod entry;
+pub mod events;
+mod expiry;
+pub mod header;
+pub mod index;
 pub mod memory_noop;
+pub mod migration;
+mod mime;
+pub mod normalization;
+pub mod result;
+pub mod store;
+pub mod usage;
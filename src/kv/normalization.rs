@@ -0,0 +1,129 @@
+//! Optional normalization of keys, for databases which should treat keys that only
+//! differ in case (or in their Unicode representation) as the same key.
+//!
+//! The mode is chosen when a database is created and stored in its header, since
+//! changing it later would make existing keys unreachable.
+
+use std::{borrow::Cow, fmt, str::FromStr};
+
+use unicode_normalization::{is_nfc, UnicodeNormalization};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[repr(u8)]
+pub enum KeyNormalization {
+    /// Keys are used exactly as given.
+    #[default]
+    None = 0,
+    /// Keys are lowercased, which makes them case-insensitive.
+    Lowercase = 1,
+    /// Keys are converted to Unicode Normalization Form C, so that e.g. a precomposed
+    /// `é` and an `e` followed by a combining accent are the same key.
+    Nfc = 2,
+    /// Keys are lowercased and converted to NFC.
+    NfcLowercase = 3,
+}
+
+impl KeyNormalization {
+    /// Normalizes a key, or a prefix of keys. Only allocates if the key changes.
+    ///
+    /// Prefixes which end right before a combining character of a key won't match
+    /// that key in the NFC modes, as the character is composed in the normalized key.
+    pub fn normalize<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        match self {
+            KeyNormalization::None => Cow::Borrowed(key),
+            KeyNormalization::Lowercase => lowercase(key),
+            KeyNormalization::Nfc => nfc(key),
+            KeyNormalization::NfcLowercase => match lowercase(key) {
+                Cow::Borrowed(key) => nfc(key),
+                Cow::Owned(key) => Cow::Owned(nfc(&key).into_owned()),
+            },
+        }
+    }
+
+    pub(crate) fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(KeyNormalization::None),
+            1 => Some(KeyNormalization::Lowercase),
+            2 => Some(KeyNormalization::Nfc),
+            3 => Some(KeyNormalization::NfcLowercase),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for KeyNormalization {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            KeyNormalization::None => "none",
+            KeyNormalization::Lowercase => "lowercase",
+            KeyNormalization::Nfc => "NFC",
+            KeyNormalization::NfcLowercase => "NFC + lowercase",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Parses `none`, `lowercase`, `nfc`, or `nfc_lowercase`.
+impl FromStr for KeyNormalization {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "none" => Ok(KeyNormalization::None),
+            "lowercase" => Ok(KeyNormalization::Lowercase),
+            "nfc" => Ok(KeyNormalization::Nfc),
+            "nfc_lowercase" => Ok(KeyNormalization::NfcLowercase),
+            _ => Err(format!(
+                "Invalid key normalization {:?}: Must be none, lowercase, nfc, or nfc_lowercase",
+                mode
+            )),
+        }
+    }
+}
+
+fn lowercase(key: &str) -> Cow<'_, str> {
+    if key.chars().any(char::is_uppercase) {
+        Cow::Owned(key.to_lowercase())
+    } else {
+        Cow::Borrowed(key)
+    }
+}
+
+fn nfc(key: &str) -> Cow<'_, str> {
+    if is_nfc(key) {
+        Cow::Borrowed(key)
+    } else {
+        Cow::Owned(key.nfc().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        let decomposed = "Cafe\u{301}";
+        assert_eq!(KeyNormalization::None.normalize(decomposed), decomposed);
+        assert_eq!(
+            KeyNormalization::Lowercase.normalize(decomposed),
+            "cafe\u{301}"
+        );
+        assert_eq!(KeyNormalization::Nfc.normalize(decomposed), "Caf\u{e9}");
+        assert_eq!(
+            KeyNormalization::NfcLowercase.normalize(decomposed),
+            "caf\u{e9}"
+        );
+        assert!(matches!(
+            KeyNormalization::NfcLowercase.normalize("already/normal"),
+            Cow::Borrowed(_)
+        ));
+    }
+
+    #[test]
+    fn test_from_str() {
+        assert_eq!("none".parse(), Ok(KeyNormalization::None));
+        assert_eq!("nfc_lowercase".parse(), Ok(KeyNormalization::NfcLowercase));
+        assert!("NFC".parse::<KeyNormalization>().is_err());
+    }
+}
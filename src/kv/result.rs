@@ -1,4 +1,9 @@
-use std::io;
+use std::{io, path::PathBuf};
+
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use serde::{Deserialize, Serialize};
+
+use super::normalization::KeyNormalization;
 
 #[derive(Debug, thiserror::Error)]
 pub enum KVError {
@@ -6,6 +11,134 @@ pub enum KVError {
     IO(io::Error),
     #[error("Invalid Data: {0}")]
     InvalidData(String),
+    /// The backing storage holds records which decode, but contradict the index.
+    #[error("Corrupted Data: {0}")]
+    Corrupted(String),
+    #[error("Checksum mismatch: stored {stored:#010x}, computed {computed:#010x}")]
+    ChecksumMismatch { stored: u32, computed: u32 },
+    #[error("Key {0:?} does not exist")]
+    NotFound(String),
+    #[error("Key of {len} bytes exceeds the limit of {max} bytes")]
+    KeyTooLarge { len: usize, max: usize },
+    #[error("Value of {len} bytes exceeds the limit of {max} bytes")]
+    ValueTooLarge { len: u64, max: u64 },
+    #[error("MIME type of {len} bytes exceeds the limit of {max} bytes")]
+    MimeTooLarge { len: usize, max: usize },
+    /// The database file is in use by another store, see `FileBackend::open`.
+    #[error("Database {0:?} is already opened by another store")]
+    Locked(PathBuf),
+    /// Writes are rejected before they reach the backing storage, see
+    /// `KVStoreBuilder::read_only`.
+    #[error("Store is read-only")]
+    ReadOnly,
+    #[error("Snapshot was taken before the store was compacted")]
+    SnapshotInvalidated,
+    /// Another compaction hasn't finished yet, see `KVStore::begin_compaction`.
+    #[error("Store is already being compacted")]
+    CompactionRunning,
+    #[error("Unsupported format version: {0}")]
+    UnsupportedVersion(u16),
+    #[error("Database normalizes keys with {stored}, but {requested} was requested")]
+    KeyNormalizationMismatch {
+        stored: KeyNormalization,
+        requested: KeyNormalization,
+    },
+    #[error("Database compresses values larger than {stored} bytes, but {requested} bytes were configured")]
+    CompressionThresholdMismatch { stored: u64, requested: u64 },
+    #[error("Database is encrypted, which this build doesn't support")]
+    Encrypted,
+}
+
+impl KVError {
+    /// Identifies the kind of error in error responses, see `ResponseError`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            KVError::IO(_) if self.is_storage_full() => "storage_full",
+            KVError::IO(_) => "io_error",
+            KVError::InvalidData(_) => "invalid_data",
+            KVError::Corrupted(_) => "corrupted",
+            KVError::ChecksumMismatch { .. } => "checksum_mismatch",
+            KVError::NotFound(_) => "not_found",
+            KVError::KeyTooLarge { .. } => "key_too_large",
+            KVError::ValueTooLarge { .. } => "value_too_large",
+            KVError::MimeTooLarge { .. } => "mime_too_large",
+            KVError::Locked(_) => "locked",
+            KVError::ReadOnly => "read_only",
+            KVError::SnapshotInvalidated => "snapshot_invalidated",
+            KVError::CompactionRunning => "compaction_running",
+            KVError::UnsupportedVersion(_) => "unsupported_version",
+            KVError::KeyNormalizationMismatch { .. } => "key_normalization_mismatch",
+            KVError::CompressionThresholdMismatch { .. } => "compression_threshold_mismatch",
+            KVError::Encrypted => "encrypted",
+        }
+    }
+
+    /// The key the error is about, if it's about a single one.
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            KVError::NotFound(key) => Some(key),
+            _ => None,
+        }
+    }
+
+    /// Whether the error means that the backing storage ran out of space.
+    pub fn is_storage_full(&self) -> bool {
+        match self {
+            KVError::IO(error) => matches!(
+                error.kind(),
+                io::ErrorKind::StorageFull | io::ErrorKind::QuotaExceeded
+            ),
+            _ => false,
+        }
+    }
+}
+
+/// Copies errors for each of several writes which failed together. `io::Error` can't
+/// be cloned, so copies of I/O errors only keep their kind, which decides how they're
+/// reported, and their message.
+impl Clone for KVError {
+    fn clone(&self) -> Self {
+        match self {
+            KVError::IO(error) => KVError::IO(io::Error::new(error.kind(), error.to_string())),
+            KVError::InvalidData(message) => KVError::InvalidData(message.clone()),
+            KVError::Corrupted(message) => KVError::Corrupted(message.clone()),
+            KVError::ChecksumMismatch { stored, computed } => KVError::ChecksumMismatch {
+                stored: *stored,
+                computed: *computed,
+            },
+            KVError::NotFound(key) => KVError::NotFound(key.clone()),
+            KVError::KeyTooLarge { len, max } => KVError::KeyTooLarge {
+                len: *len,
+                max: *max,
+            },
+            KVError::ValueTooLarge { len, max } => KVError::ValueTooLarge {
+                len: *len,
+                max: *max,
+            },
+            KVError::MimeTooLarge { len, max } => KVError::MimeTooLarge {
+                len: *len,
+                max: *max,
+            },
+            KVError::Locked(path) => KVError::Locked(path.clone()),
+            KVError::ReadOnly => KVError::ReadOnly,
+            KVError::SnapshotInvalidated => KVError::SnapshotInvalidated,
+            KVError::CompactionRunning => KVError::CompactionRunning,
+            KVError::UnsupportedVersion(version) => KVError::UnsupportedVersion(*version),
+            KVError::KeyNormalizationMismatch { stored, requested } => {
+                KVError::KeyNormalizationMismatch {
+                    stored: *stored,
+                    requested: *requested,
+                }
+            }
+            KVError::CompressionThresholdMismatch { stored, requested } => {
+                KVError::CompressionThresholdMismatch {
+                    stored: *stored,
+                    requested: *requested,
+                }
+            }
+            KVError::Encrypted => KVError::Encrypted,
+        }
+    }
 }
 
 impl From<io::Error> for KVError {
@@ -14,4 +147,91 @@ impl From<io::Error> for KVError {
     }
 }
 
+/// The body of error responses, like the ones `KVError` turns into.
+#[derive(Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ErrorBody {
+    pub error: String,
+    pub code: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+}
+
+/// Lets HTTP handlers return `KVError`s with `?`. Errors caused by the request are
+/// described in the response, while those of the backing storage are only logged, and
+/// answered with a generic message.
+impl ResponseError for KVError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            KVError::NotFound(_) => StatusCode::NOT_FOUND,
+            KVError::KeyTooLarge { .. } | KVError::MimeTooLarge { .. } => StatusCode::BAD_REQUEST,
+            KVError::ValueTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            KVError::SnapshotInvalidated => StatusCode::GONE,
+            KVError::CompactionRunning => StatusCode::CONFLICT,
+            KVError::ReadOnly => StatusCode::FORBIDDEN,
+            KVError::IO(_) if self.is_storage_full() => StatusCode::INSUFFICIENT_STORAGE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let status = self.status_code();
+        let error = match self {
+            KVError::IO(_) if self.is_storage_full() => "Storage is full".to_owned(),
+            _ if status.is_server_error() => {
+                log::error!("Error accessing storage: {}", self);
+                "Error accessing storage".to_owned()
+            }
+            _ => self.to_string(),
+        };
+        HttpResponse::build(status).json(ErrorBody {
+            error,
+            code: self.code().to_owned(),
+            key: self.key().map(str::to_owned),
+        })
+    }
+}
+
 pub type KVResult<T> = std::result::Result<T, KVError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[actix_web::test]
+    async fn test_error_response() {
+        let response = KVError::NotFound("a".to_owned()).error_response();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let body = actix_web::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["code"], "not_found");
+        assert_eq!(body["error"], "Key \"a\" does not exist");
+        assert_eq!(body["key"], "a");
+
+        let full = KVError::IO(io::ErrorKind::StorageFull.into());
+        assert_eq!(full.status_code(), StatusCode::INSUFFICIENT_STORAGE);
+        assert_eq!(full.code(), "storage_full");
+        // Details of the backing storage aren't sent to clients.
+        let corrupted = KVError::Corrupted("record at offset 42".to_owned());
+        let body = actix_web::body::to_bytes(corrupted.error_response().into_body())
+            .await
+            .unwrap();
+        assert!(!String::from_utf8_lossy(&body).contains("offset 42"));
+    }
+
+    #[test]
+    fn test_clone() {
+        // Copies are reported like the original.
+        for err in [
+            KVError::ReadOnly,
+            KVError::ValueTooLarge { len: 17, max: 16 },
+            KVError::IO(io::ErrorKind::StorageFull.into()),
+        ] {
+            let copy = err.clone();
+            assert_eq!(copy.code(), err.code());
+            assert_eq!(copy.status_code(), err.status_code());
+            assert_eq!(copy.to_string(), err.to_string());
+        }
+    }
+}
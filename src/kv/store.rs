@@ -1,148 +1,2938 @@
 use std::{
-    collections::HashMap,
-    io::{self, SeekFrom},
+    borrow::Cow,
+    collections::{HashMap, HashSet},
+    fmt,
+    ops::{Bound, RangeBounds},
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Weak},
+    time::{Duration, SystemTime},
 };
 
-use log::debug;
-use tokio::{fs::File, io::AsyncSeekExt};
+use log::{debug, info, warn};
+use rand::seq::IteratorRandom;
+use tokio::{fs::OpenOptions, io::AsyncRead};
+use tokio_stream::Stream;
 
-use crate::kv::{entry::KVEntry, result::KVError};
+use crate::kv::{
+    entry::{EntryKind, KVEntry},
+    result::KVError,
+};
+
+use super::{
+    access::AccessTimes,
+    backend::{
+        read_header, replay_entries, FileBackend, MemoryBackend, RecordReader, StorageBackend,
+    },
+    cache::ValueCache,
+    clock::{Clock, SystemClock},
+    codec,
+    delta::{self, DeltaRecord},
+    entry::{etag_digest, Entry, EntryInfo, ValueLocation},
+    events::{ChangeEvent, EventBus, LogRecord, RecordTap},
+    expiry::ExpiryIndex,
+    header::{Header, FORMAT_VERSION},
+    index::RadixIndex,
+    mime::MimeInterner,
+    normalization::KeyNormalization,
+    result::KVResult,
+    usage::UsageTree,
+};
 
-use super::{entry::Entry, memory_noop::MemoryNoOpRWS, result::KVResult};
+/// KVStore backed by a log file on disk.
+pub type FileBackedKVStore = KVStore<FileBackend>;
+/// In-memory KVStore, using `MemoryBackend`, which is not persistent.
+pub type MemoryBackedKVStore = KVStore<MemoryBackend>;
 
-/// This trait exists to allow for the use of both `File` and `MemoryNoOpRWS` (as well as anything
-/// else that implements `AsyncRead`, `AsyncWrite`, `AsyncSeek`, etc. as the backing storage
-/// for the KVStore.
-pub trait AsyncRWS:
-    tokio::io::AsyncRead + tokio::io::AsyncWrite + tokio::io::AsyncSeek + Unpin
-{
+/// About how many bytes of values `KVStore::compaction_batch` reads at a time, so that
+/// reads and writes only wait for one batch while the store is compacted.
+const COMPACTION_BATCH_LEN: usize = 4 * 1024 * 1024;
+
+/// One of the writes `KVStore::write_many` applies together.
+#[derive(Clone, Debug)]
+pub enum Write {
+    Set(String, Entry),
+    Remove(String),
 }
 
-impl<T: tokio::io::AsyncRead + tokio::io::AsyncWrite + tokio::io::AsyncSeek + ?Sized + Unpin>
-    AsyncRWS for T
-{
+/// When writes are synced to the backing storage, trading durability for throughput.
+/// Until they're synced, writes can be lost if the machine crashes, though not if only
+/// the process does.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// After every write, before it's reported as done.
+    Always,
+    /// Whenever `KVStore::sync_pending` is called, which is meant to happen every
+    /// this many milliseconds.
+    EveryNMillis(u64),
+    /// Only when the store is flushed or compacted.
+    #[default]
+    Never,
+}
+
+impl SyncPolicy {
+    /// How often `KVStore::sync_pending` needs to be called, if at all.
+    pub fn interval(&self) -> Option<Duration> {
+        match self {
+            SyncPolicy::EveryNMillis(millis) => Some(Duration::from_millis(*millis)),
+            _ => None,
+        }
+    }
+}
+
+/// Parses `always`, `never`, or an interval like `100ms`.
+impl FromStr for SyncPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy {
+            "always" => Ok(SyncPolicy::Always),
+            "never" => Ok(SyncPolicy::Never),
+            _ => match policy.strip_suffix("ms").map(str::parse) {
+                Some(Ok(millis)) if millis > 0 => Ok(SyncPolicy::EveryNMillis(millis)),
+                _ => Err(format!(
+                    "Invalid sync policy {:?}: Must be always, never, or an interval like 100ms",
+                    policy
+                )),
+            },
+        }
+    }
+}
+
+impl fmt::Display for SyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyncPolicy::Always => write!(f, "always"),
+            SyncPolicy::EveryNMillis(millis) => write!(f, "{}ms", millis),
+            SyncPolicy::Never => write!(f, "never"),
+        }
+    }
+}
+
+/// Outcome of `KVStore::verify`.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// How many keys were compared.
+    pub checked: usize,
+    /// Keys whose latest record in the backing storage differs from the index.
+    pub mismatched: Vec<String>,
+    /// Keys which are in the index, but have no record in the backing storage.
+    pub missing: Vec<String>,
+}
+
+/// Outcome of `KVStore::compact`.
+#[derive(Debug)]
+pub struct CompactionReport {
+    /// Size of the database file before compacting, in bytes.
+    pub before: u64,
+    /// Size of the database file after compacting, in bytes.
+    pub after: u64,
+    /// How many entries were kept.
+    pub entries: usize,
+}
+
+/// A compaction begun with `KVStore::begin_compaction`, which copies the live entries
+/// to new storage while the store goes on serving reads and writes.
+pub struct Compaction<B: StorageBackend> {
+    target: B,
+    snapshot: Snapshot,
+    /// Keys of the snapshot whose entries are yet to be copied, the next one last.
+    remaining: Vec<String>,
+    /// New offsets of the entries whose values are read from the backend, where later
+    /// ones replace earlier ones.
+    offsets: Vec<(String, u64)>,
+    before: u64,
+    /// Tells the store whether the compaction was dropped before it finished.
+    alive: Arc<()>,
+}
+
+/// Records `KVStore::compaction_batch` read for `Compaction::write`.
+pub struct CompactionBatch {
+    /// Along with whether the index is to be pointed at the record once the storage is
+    /// replaced, as its value is read from the backend.
+    records: Vec<(KVEntry, bool)>,
+}
+
+/// A compaction which is running, see `KVStore::begin_compaction`.
+struct Running {
+    alive: Weak<()>,
+    /// Keys written since the compaction began, whose entries it copies at the end.
+    keys: HashSet<String>,
+    /// Prefixes removed since the compaction began, in the order they were removed.
+    prefixes: Vec<String>,
+}
+
+impl<B: StorageBackend> Compaction<B> {
+    /// Appends the records to the compacted copy. This doesn't need the store, so it
+    /// can be done without holding on to it.
+    pub async fn write(&mut self, batch: CompactionBatch) -> KVResult<()> {
+        let (records, relocated): (Vec<KVEntry>, Vec<bool>) = batch.records.into_iter().unzip();
+        let offsets = self.target.append_batch(&records).await?;
+        for ((record, relocated), offset) in records.into_iter().zip(relocated).zip(offsets) {
+            if relocated {
+                self.offsets.push((record.key, offset));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl CompactionBatch {
+    /// Whether all live entries were copied, so the compaction can be finished.
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+}
+
+/// Keys picked by `KVStore::verify_sample`, along with the records they're compared
+/// against.
+pub struct VerifySample {
+    sample: Sample,
+    reader: RecordReader,
+}
+
+/// The entries of the keys `KVStore::verify` compares, and whether the latest record
+/// read so far matches them.
+struct Sample {
+    expected: HashMap<String, (EntryInfo, Option<bool>)>,
+}
+
+impl VerifySample {
+    /// Reads through the records which were in the backing storage when the sample
+    /// was picked, and reports any drift from the index, like `KVStore::verify`.
+    pub async fn check(self) -> KVResult<VerifyReport> {
+        let mut sample = self.sample;
+        self.reader
+            .for_each(|offset, entry| sample.compare(offset, entry))
+            .await?;
+        Ok(sample.report())
+    }
+}
+
+impl Sample {
+    fn compare(&mut self, offset: u64, entry: KVEntry) {
+        match entry.kind {
+            EntryKind::Value => {
+                if let Some((expected, matches)) = self.expected.get_mut(&entry.key) {
+                    let value_matches = match &expected.location {
+                        ValueLocation::Stored(stored) => *stored == offset,
+                        ValueLocation::Loaded(value) => *value == entry.value,
+                    };
+                    let value_len = if entry.delta {
+                        DeltaRecord::decode(&entry.value)
+                            .map_or(0, |record| record.value_len as usize)
+                    } else {
+                        entry.value.len()
+                    };
+                    *matches = Some(
+                        value_matches
+                            && expected.value_len == value_len
+                            && *expected.mime == entry.mime
+                            && expected.meta == entry.meta
+                            && expected.expires_at == entry.expires_at
+                            && expected.modified_at == entry.modified_at
+                            && expected.pinned == entry.pinned,
+                    );
+                }
+            }
+            EntryKind::Tombstone => {
+                if let Some((_, matches)) = self.expected.get_mut(&entry.key) {
+                    *matches = None;
+                }
+            }
+            EntryKind::PrefixTombstone => {
+                for (key, (_, matches)) in self.expected.iter_mut() {
+                    if key.starts_with(&entry.key) {
+                        *matches = None;
+                    }
+                }
+            }
+            EntryKind::Touch => {}
+        }
+    }
+
+    fn report(self) -> VerifyReport {
+        let mut report = VerifyReport {
+            checked: self.expected.len(),
+            ..Default::default()
+        };
+        for (key, (_, matches)) in self.expected {
+            match matches {
+                Some(true) => {}
+                Some(false) => report.mismatched.push(key),
+                None => report.missing.push(key),
+            }
+        }
+        report.mismatched.sort();
+        report.missing.sort();
+        report
+    }
+}
+
+impl VerifyReport {
+    pub fn is_consistent(&self) -> bool {
+        self.mismatched.is_empty() && self.missing.is_empty()
+    }
 }
 
-/// KVStore backed by a file on disk, using `tokio::fs::File` as the backing storage.
-pub type FileBackedKVStore = KVStore<File>;
-/// In-memory KVStore, using `MemoryNoOpRWS` as the backing storage, which is not persistent.
-pub type MemoryBackedKVStore = KVStore<MemoryNoOpRWS>;
+/// The entries of a store as they were at one point in time, see `KVStore::snapshot`.
+pub struct Snapshot {
+    entries: RadixIndex<EntryInfo>,
+    key_normalization: KeyNormalization,
+    taken_at: SystemTime,
+    /// The `KVStore::generation` the offsets in `entries` point into.
+    generation: u64,
+}
+
+impl Snapshot {
+    /// When the snapshot was taken, according to the store's clock.
+    pub fn taken_at(&self) -> SystemTime {
+        self.taken_at
+    }
+}
 
 /// A key-value store
-pub struct KVStore<T>
+pub struct KVStore<B>
 where
-    T: AsyncRWS,
+    B: StorageBackend,
 {
-    entries: HashMap<String, Entry>,
-    stream: Box<T>,
-}
-
-impl<T: AsyncRWS> KVStore<T> {
-    /// Creates a new KVStore with the provided backing storage. This method will read all entries
-    /// from the backing storage and store them in memory, if any exist. If you don't need a
-    /// persistent store, consider using `MemoryBackedKVStore` instead.
-    pub async fn new(mut backing_stream: Box<T>) -> KVResult<KVStore<T>> {
-        let mut entries = HashMap::new();
-        backing_stream.seek(SeekFrom::Start(0)).await?;
-        loop {
-            match KVEntry::read_from_stream(&mut backing_stream).await {
-                Ok(entry) => {
-                    _ = entries.insert(entry.key.clone(), Entry::from(entry));
-                }
-                Err(err) => match err {
-                    KVError::IO(error) => match error.kind() {
-                        io::ErrorKind::UnexpectedEof => {
-                            debug!("Reached end of file");
-                            break;
-                        }
-                        _ => {
-                            debug!("IO Error of kind: {:?}", error.kind());
-                            return Err(KVError::IO(error));
-                        }
-                    },
-                    _ => {
-                        debug!("Non-IO error: {:?}", err);
-                        return Err(err);
+    entries: RadixIndex<EntryInfo>,
+    backend: B,
+    /// Whether values are read back from the backend when requested, rather than kept
+    /// in memory. Only backends which keep the records allow that.
+    lazy: bool,
+    events: EventBus,
+    tap: RecordTap,
+    mimes: MimeInterner,
+    clock: Arc<dyn Clock>,
+    header: Header,
+    /// Keys loaded from the seed passed to `with_seed`, and whether the overlay has
+    /// set or removed them since.
+    seed_keys: RadixIndex<bool>,
+    usage: UsageTree,
+    access: AccessTimes,
+    expiries: ExpiryIndex,
+    sync_policy: SyncPolicy,
+    /// Whether anything was written since the backend was last synced.
+    unsynced: bool,
+    /// Values larger than this which overwrite values larger than this are stored as
+    /// deltas, see `set_delta_threshold`.
+    delta_threshold: Option<usize>,
+    /// Largest value `set` accepts, see `set_max_value_size`.
+    max_value_size: Option<usize>,
+    cache: ValueCache,
+    /// How often the store was compacted, which moves every record, and so
+    /// invalidates the snapshots taken before.
+    generation: u64,
+    /// Whether writes are rejected, see `KVStoreBuilder::read_only`.
+    read_only: bool,
+    compaction: Option<Running>,
+}
+
+impl<B: StorageBackend> KVStore<B> {
+    /// Creates a new KVStore with the provided backend. This method will read all records
+    /// from the backend and index them, if any exist. Only the keys and what's needed
+    /// to find and describe their values are kept in memory, while the values themselves are
+    /// read from the backend on `get`. If you don't need a persistent store, consider
+    /// using `MemoryBackedKVStore` instead, which keeps the values in memory.
+    ///
+    /// Empty storage is initialized with a file header. Storage in any other format
+    /// version is rejected with `KVError::UnsupportedVersion`, and needs to be
+    /// upgraded with `migration::migrate_file` first.
+    pub async fn new(backend: B) -> KVResult<KVStore<B>> {
+        Self::with_clock(backend, Arc::new(SystemClock)).await
+    }
+
+    /// Like `new`, but the store takes the current time from `clock` instead of the
+    /// system clock.
+    pub async fn with_clock(backend: B, clock: Arc<dyn Clock>) -> KVResult<KVStore<B>> {
+        Self::load(backend, clock, None, None, false).await
+    }
+
+    /// Like `new`, but keys are normalized with `key_normalization` before they're
+    /// written or looked up.
+    ///
+    /// The mode is recorded in the header of empty storage. Storage which already
+    /// records a different mode is rejected with `KVError::KeyNormalizationMismatch`,
+    /// as its keys were normalized differently. `new` simply uses whatever mode the
+    /// storage records.
+    pub async fn with_key_normalization(
+        backend: B,
+        key_normalization: KeyNormalization,
+    ) -> KVResult<KVStore<B>> {
+        Self::load(
+            backend,
+            Arc::new(SystemClock),
+            Some(key_normalization),
+            None,
+            false,
+        )
+        .await
+    }
+
+    /// Like `new`, but layers the backend as a writable overlay on top of a read-only
+    /// `seed` database, e.g. a default dataset shipped with the application.
+    ///
+    /// Reads see the overlay's entries where it has them, and the seed's otherwise.
+    /// All writes, including removals of keys from the seed, only go to the overlay,
+    /// so the seed can be replaced by a newer version later. Empty storage adopts the
+    /// seed's key normalization.
+    pub async fn with_seed(
+        backend: B,
+        mut seed: impl AsyncRead + Unpin + Send,
+    ) -> KVResult<KVStore<B>> {
+        Self::load(backend, Arc::new(SystemClock), None, Some(&mut seed), false).await
+    }
+
+    /// Configures a store before loading it from `backend`, see `KVStoreBuilder`.
+    pub fn builder(backend: B) -> KVStoreBuilder<B> {
+        KVStoreBuilder {
+            backend,
+            clock: Arc::new(SystemClock),
+            key_normalization: None,
+            seed: None,
+            compression_threshold: None,
+            compression_level: None,
+            sync_policy: SyncPolicy::default(),
+            max_value_size: None,
+            read_only: false,
+        }
+    }
+
+    async fn load(
+        mut backend: B,
+        clock: Arc<dyn Clock>,
+        mut key_normalization: Option<KeyNormalization>,
+        seed: Option<&mut (dyn AsyncRead + Unpin + Send)>,
+        read_only: bool,
+    ) -> KVResult<KVStore<B>> {
+        let mut entries = RadixIndex::new();
+        let mut mimes = MimeInterner::new();
+        let now = clock.now();
+        let mut seed_keys = RadixIndex::new();
+        if let Some(seed) = seed {
+            let seed_header = match read_header(&mut *seed).await? {
+                None => return Err(KVError::InvalidData("Seed database is empty".to_string())),
+                Some(header) if header.version != FORMAT_VERSION => {
+                    return Err(KVError::UnsupportedVersion(header.version))
+                }
+                Some(header) => header,
+            };
+            // The seed can't be read from later, so its values are kept in memory.
+            let mut applied = Ok(());
+            replay_entries(seed, 0, &backend.limits(), |_, entry| {
+                if applied.is_ok() {
+                    applied = apply_entry(&mut entries, &mut mimes, now, entry, None);
+                }
+            })
+            .await?;
+            applied?;
+            for (key, _) in entries.iter() {
+                seed_keys.insert(&key, false);
+            }
+            debug!("Loaded {} entries from seed", seed_keys.len());
+            match key_normalization {
+                Some(requested) if requested != seed_header.key_normalization => {
+                    return Err(KVError::KeyNormalizationMismatch {
+                        stored: seed_header.key_normalization,
+                        requested,
+                    })
+                }
+                _ => key_normalization = Some(seed_header.key_normalization),
+            }
+        }
+        let lazy = backend.keeps_records();
+        let mut applied = Ok(());
+        let loaded = backend
+            .load_all(|offset, entry| {
+                override_seed(&mut seed_keys, &entry.key, entry.kind);
+                let offset = lazy.then_some(offset);
+                if applied.is_ok() {
+                    applied = apply_entry(&mut entries, &mut mimes, now, entry, offset);
+                }
+            })
+            .await?;
+        applied?;
+        let header = match loaded {
+            None => {
+                let header = Header {
+                    key_normalization: key_normalization.unwrap_or_default(),
+                    compression_threshold: backend.compression_threshold(),
+                    created_at: Some(now),
+                    ..Header::new(FORMAT_VERSION)
+                };
+                if !read_only {
+                    backend.create(&header).await?;
+                }
+                header
+            }
+            Some(header) => {
+                if let Some(requested) = key_normalization {
+                    if requested != header.key_normalization {
+                        return Err(KVError::KeyNormalizationMismatch {
+                            stored: header.key_normalization,
+                            requested,
+                        });
+                    }
+                }
+                if let (Some(stored), Some(requested)) = (
+                    header.compression_threshold,
+                    backend.compression_threshold(),
+                ) {
+                    if stored != requested {
+                        return Err(KVError::CompressionThresholdMismatch { stored, requested });
                     }
-                },
+                }
+                header
+            }
+        };
+        let mut usage = UsageTree::new();
+        let mut expiries = ExpiryIndex::default();
+        for (key, info) in entries.iter() {
+            usage.add(&key, usage_bytes(&key, info));
+            if let Some(expires_at) = info.expiry() {
+                expiries.insert(&key, expires_at);
             }
         }
-        debug!("Finished reading all entries");
         Ok(KVStore {
             entries,
-            stream: backing_stream,
+            // Creating the storage finds out whether it keeps what's written to it.
+            lazy: backend.keeps_records(),
+            backend,
+            events: EventBus::new(),
+            tap: RecordTap::new(),
+            mimes,
+            clock,
+            header,
+            seed_keys,
+            usage,
+            access: AccessTimes::default(),
+            expiries,
+            sync_policy: SyncPolicy::default(),
+            unsynced: false,
+            delta_threshold: None,
+            max_value_size: None,
+            cache: ValueCache::default(),
+            generation: 0,
+            read_only,
+            compaction: None,
         })
     }
 
-    /// Get the value as an `Entry` for a given key.
-    pub fn get(&self, key: &str) -> Option<&Entry> {
-        if let Some(entry) = self.entries.get(key) {
-            Some(entry)
-        } else {
-            None
+    /// Whether every write is rejected with `KVError::ReadOnly`, see
+    /// `KVStoreBuilder::read_only`.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Fails with `KVError::ReadOnly` if the store is read-only.
+    fn check_writable(&self) -> KVResult<()> {
+        if self.read_only {
+            return Err(KVError::ReadOnly);
         }
+        Ok(())
+    }
+
+    /// How keys are normalized, as recorded in the backing storage's header.
+    pub fn key_normalization(&self) -> KeyNormalization {
+        self.header.key_normalization
+    }
+
+    fn normalize<'a>(&self, key: &'a str) -> Cow<'a, str> {
+        self.header.key_normalization.normalize(key)
+    }
+
+    /// How many values and bytes are stored under each prefix. See `UsageTree`.
+    pub fn usage(&self) -> &UsageTree {
+        &self.usage
+    }
+
+    /// The current time, according to the store's clock.
+    pub fn now(&self) -> SystemTime {
+        self.clock.now()
     }
 
-    /// Set the value for a given key. This will write the entry to the backing storage.
+    /// The store's clock, for computing times the store compares against it, like
+    /// expiry times.
+    pub fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
+    }
+
+    /// Starts tracking when entries are read by `get`, or stops with `None`. A read
+    /// only updates the access time if the recorded one is older than `granularity`,
+    /// and updated access times are only persisted by `persist_access_times`.
+    pub fn track_access_times(&mut self, granularity: Option<Duration>) {
+        self.access.set_granularity(granularity);
+    }
+
+    /// Syncs writes to the backing storage according to `policy`, instead of only
+    /// when the store is flushed or compacted.
+    pub fn set_sync_policy(&mut self, policy: SyncPolicy) {
+        self.sync_policy = policy;
+    }
+
+    /// Stores only what changed when a value larger than `threshold` bytes overwrites
+    /// another one, as a binary delta against it, or stops with `None`. This cuts the
+    /// growth of the backing storage for large values which are edited in place.
     ///
-    /// If the value is large enough, it will be compressed before being written.
+    /// Deltas are only stored if they're less than half the size of the value, and
+    /// only on backends which keep the records. The full value is stored again after
+    /// a few deltas in a row, so reading a value never resolves a long chain, and
+    /// `compact` stores every value in full.
+    pub fn set_delta_threshold(&mut self, threshold: Option<usize>) {
+        self.delta_threshold = threshold;
+    }
+
+    /// Rejects values larger than `bytes` in `set` and `set_many`, or stops with `None`.
+    /// Values are only limited by the memory they take up by default.
+    pub fn set_max_value_size(&mut self, bytes: Option<usize>) {
+        self.max_value_size = bytes;
+    }
+
+    /// Keeps up to `bytes` of the values read from the backing storage in memory, so
+    /// values which are read often don't have to be read from there every time. The
+    /// cache is off by default, and turned off again with 0.
+    pub fn set_cache_size(&mut self, bytes: usize) {
+        self.cache.set_capacity(bytes);
+    }
+
+    /// When the entry of `key` was last read, as far as access times were tracked.
+    pub fn accessed_at(&self, key: &str) -> Option<SystemTime> {
+        let key = self.normalize(key);
+        self.access
+            .pending(&key)
+            .or_else(|| self.entries.get(&key)?.accessed_at)
+    }
+
+    /// Persists the access times updated since they were last persisted, returning
+    /// how many. They're appended to the backing storage all at once.
     ///
     /// # Errors
     ///
-    /// std::io::Error: If there is an error writing to the backing storage.
+    /// KVError::ReadOnly: If the store is read-only.
     ///
-    pub async fn set(&mut self, key: &str, value: Entry) -> KVResult<()> {
-        let kv_entry = KVEntry {
-            key: key.to_owned(),
-            value: value.value.clone(),
-            mime: value.mime.clone(),
-        };
-        debug!(
-            "Setting entry: key = {:?}, value length = {}, mime = {:?}",
-            key,
-            value.value.len(),
-            value.mime
-        );
-        // For an in-memory KV store the underlying implementation is a no-op
-        // for the following lines which write to the stream.
-        if value.value.len() > 1024 {
-            debug!("Value length exceeds 1024 bytes, compressing entry");
-            kv_entry
-                .write_to_stream_compressed(&mut *self.stream)
-                .await?;
-        } else {
-            debug!("Value length is within limit, writing uncompressed entry");
-            kv_entry.write_to_stream(&mut *self.stream).await?;
+    /// std::io::Error: If there is an error writing to the backing storage. The access
+    /// times which were to be persisted are dropped, as they're only a hint.
+    ///
+    pub async fn persist_access_times(&mut self) -> KVResult<usize> {
+        self.check_writable()?;
+        let mut records: Vec<KVEntry> = self
+            .access
+            .take()
+            .into_iter()
+            // Entries removed since they were read don't need an access time anymore.
+            .filter(|(key, _)| self.entries.contains_key(key))
+            .map(|(key, accessed_at)| KVEntry::touch(key, accessed_at))
+            .collect();
+        if records.is_empty() {
+            return Ok(0);
         }
-        self.entries.insert(key.to_owned(), value);
-        debug!("Entry set successfully: key = {:?}", key);
-        Ok(())
+        records.sort_by(|a, b| a.key.cmp(&b.key));
+        let offsets = self.backend.append_batch(&records).await?;
+        self.sync_after_write().await?;
+        for (record, offset) in records.iter().zip(&offsets) {
+            self.published(*offset, record);
+            if let Some(info) = self.entries.get_mut(&record.key) {
+                info.accessed_at = record.touched_at();
+            }
+        }
+        Ok(records.len())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::kv::memory_noop::MemoryNoOpRWS;
-    use crate::kv::entry::Entry;
-    use crate::kv::result::KVResult;
+    /// What the index knows about the entry of a given key, without reading its value.
+    /// Expired entries are treated as if they were removed already.
+    pub fn info(&self, key: &str) -> Option<&EntryInfo> {
+        self.entries
+            .get(&self.normalize(key))
+            .filter(|info| !info.is_expired(self.now()))
+    }
 
-    #[tokio::test]
-    async fn test_kvstore_set_and_get() -> KVResult<()> {
-        let memory_stream = Box::new(MemoryNoOpRWS::new());
-        let mut kv_store = KVStore::new(memory_stream).await?;
+    /// Whether there's an entry for `key`, like `info`, so without reading its value.
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.info(key).is_some()
+    }
 
-        let key = "test_key";
-        let value = Entry {
-            value: b"test_value".to_vec(),
-            mime: "text/plain".to_string(),
+    /// The number of entries. Expired entries are treated as if they were removed
+    /// already.
+    pub fn len(&self) -> usize {
+        let expired = self.expiries.until(Some(self.now())).count();
+        self.entries.len() - expired
+    }
+
+    /// Whether there are no entries, see `len`.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterates over all keys and what the index knows about their entries, in key
+    /// order, like `scan_prefix` with an empty prefix. The values aren't kept in
+    /// memory, so they have to be read with `get`.
+    pub fn iter(&self) -> impl Iterator<Item = (String, &EntryInfo)> + '_ {
+        self.scan_prefix("")
+    }
+
+    /// Iterates over all keys in order, see `iter`.
+    pub fn keys(&self) -> impl Iterator<Item = String> + '_ {
+        self.iter().map(|(key, _)| key)
+    }
+
+    /// Get the value as an `Entry` for a given key, reading it from the backing storage.
+    /// Expired entries are treated as if they were removed already.
+    ///
+    /// This counts as reading the entry if access times are tracked, see
+    /// `track_access_times`.
+    ///
+    /// # Errors
+    ///
+    /// std::io::Error: If there is an error reading the value from the backing storage.
+    ///
+    pub async fn get(&self, key: &str) -> KVResult<Option<Entry>> {
+        let entry = self.peek(key).await?;
+        if entry.is_some() {
+            let key = self.normalize(key);
+            let last = self.entries.get(&key).and_then(|info| info.accessed_at);
+            self.access.record(&key, last, self.now());
+        }
+        Ok(entry)
+    }
+
+    /// Like `get`, but doesn't count as reading the entry, e.g. to check the current
+    /// value before changing it.
+    pub async fn peek(&self, key: &str) -> KVResult<Option<Entry>> {
+        let key = self.normalize(key);
+        match self.entries.get(&key) {
+            Some(info) if !info.is_expired(self.now()) => {
+                let value = match &info.location {
+                    ValueLocation::Stored(offset) => self.read_value(&key, *offset).await?,
+                    ValueLocation::Loaded(value) => value.clone(),
+                };
+                Ok(Some(info.with_value(value)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Reads the values of `keys` into the cache, so reading them later doesn't have to
+    /// wait for the backing storage, returning how many were read. Keys which don't
+    /// exist or are already cached are skipped, as are values kept in memory anyway.
+    ///
+    /// Without a cache, see `set_cache_size`, this only warms the operating system's
+    /// cache of the backing storage.
+    pub async fn prefetch(&self, keys: &[String]) -> KVResult<usize> {
+        let now = self.now();
+        let mut read = 0;
+        for key in keys {
+            let key = self.normalize(key);
+            match self.entries.get(&key) {
+                Some(info) if !info.is_expired(now) => {
+                    if let ValueLocation::Stored(offset) = info.location {
+                        if !self.cache.contains(offset) {
+                            self.read_value(&key, offset).await?;
+                            read += 1;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(read)
+    }
+
+    /// Reads the value of `key` from the record at `offset` in the backing storage,
+    /// resolving it against the records before it if it's a delta. Values are taken
+    /// from the cache if they're in there, and put there otherwise.
+    async fn read_value(&self, key: &str, offset: u64) -> KVResult<Vec<u8>> {
+        if let Some(value) = self.cache.get(offset) {
+            return Ok(value);
+        }
+        let value = self.read_record_value(key, offset).await?;
+        let pinned = self.entries.get(key).is_some_and(|info| info.pinned);
+        self.cache.insert(offset, &value, pinned);
+        Ok(value)
+    }
+
+    /// Like `read_value`, but always reads from the backing storage.
+    async fn read_record_value(&self, key: &str, mut offset: u64) -> KVResult<Vec<u8>> {
+        let mut deltas = Vec::new();
+        let value = loop {
+            let kv_entry = self.backend.read(offset).await?;
+            if kv_entry.key != key {
+                return Err(KVError::Corrupted(format!(
+                    "Expected the record of key {:?} at offset {}, found key {:?}",
+                    key, offset, kv_entry.key
+                )));
+            }
+            if !kv_entry.delta {
+                break kv_entry.value;
+            }
+            offset = DeltaRecord::decode(&kv_entry.value)?.base;
+            deltas.push(kv_entry.value);
         };
+        deltas.iter().rev().try_fold(value, |base, record| {
+            let record = DeltaRecord::decode(record)?;
+            delta::apply(&base, record.instructions, record.value_len)
+        })
+    }
 
-        kv_store.set(key, value.clone()).await?;
-        let retrieved_value = kv_store.get(key).unwrap();
+    /// The record setting `key` to `value` as a delta against its current value, if
+    /// deltas are stored and this one pays off.
+    async fn delta_record(&self, key: &str, value: &Entry) -> KVResult<Option<KVEntry>> {
+        let Some(threshold) = self.delta_threshold else {
+            return Ok(None);
+        };
+        let current = match self.entries.get(key) {
+            Some(current) if !current.is_expired(self.now()) => current,
+            _ => return Ok(None),
+        };
+        let ValueLocation::Stored(base) = current.location else {
+            return Ok(None);
+        };
+        if value.value.len() <= threshold
+            || current.value_len <= threshold
+            || current.delta_depth >= delta::MAX_DEPTH
+        {
+            return Ok(None);
+        }
+        let instructions = delta::diff(&self.read_value(key, base).await?, &value.value);
+        if instructions.len() >= value.value.len() / 2 {
+            return Ok(None);
+        }
+        let record = DeltaRecord {
+            base,
+            value_len: value.value.len() as u64,
+            etag: etag_digest(&value.mime, &value.value),
+            depth: current.delta_depth + 1,
+            instructions: &instructions,
+        };
+        Ok(Some(KVEntry {
+            value: record.encode(),
+            delta: true,
+            ..to_kv_entry(key, value)
+        }))
+    }
 
-        assert_eq!(retrieved_value.value, value.value);
-        assert_eq!(retrieved_value.mime, value.mime);
+    /// Checks that `value` can be set for `key`, which `set` and `set_many` do for every
+    /// entry before writing any of them.
+    ///
+    /// # Errors
+    ///
+    /// KVError::KeyTooLarge: If the key is longer than the backend reads back, see
+    /// `StorageBackend::limits`, once it's normalized.
+    ///
+    /// KVError::ValueTooLarge: If the value is larger than the backend reads back, or
+    /// than `set_max_value_size` allows.
+    ///
+    /// KVError::MimeTooLarge: If the MIME type is longer than the backend reads back.
+    ///
+    pub fn validate(&self, key: &str, value: &Entry) -> KVResult<()> {
+        let limits = self.backend.limits();
+        let len = self.normalize(key).len();
+        if len > limits.max_key_len {
+            return Err(KVError::KeyTooLarge {
+                len,
+                max: limits.max_key_len,
+            });
+        }
+        let max_value_len = self
+            .max_value_size
+            .map_or(limits.max_value_len, |max| max.min(limits.max_value_len));
+        if value.value.len() > max_value_len {
+            return Err(KVError::ValueTooLarge {
+                len: value.value.len() as u64,
+                max: max_value_len as u64,
+            });
+        }
+        if value.mime.len() > limits.max_mime_len {
+            return Err(KVError::MimeTooLarge {
+                len: value.mime.len(),
+                max: limits.max_mime_len,
+            });
+        }
+        Ok(())
+    }
+
+    /// Set the value for a given key. This will write the entry to the backing storage,
+    /// recording the current time as its `modified_at`.
+    ///
+    /// If the value is large enough, it will be compressed before being written
+    /// (only when the `zstd` feature is enabled).
+    ///
+    /// # Errors
+    ///
+    /// std::io::Error: If there is an error writing to the backing storage. The store
+    /// is left unchanged in that case.
+    ///
+    /// KVError::KeyTooLarge, KVError::ValueTooLarge, KVError::MimeTooLarge: See
+    /// `validate`.
+    ///
+    pub async fn set(&mut self, key: &str, value: Entry) -> KVResult<()> {
+        self.set_many(vec![(key.to_owned(), value)]).await
+    }
+
+    /// Like `set`, but for several keys at once. All records are written to the backing
+    /// storage before they're flushed once, which is much cheaper than setting the keys
+    /// one by one. Later entries for the same key win.
+    ///
+    /// # Errors
+    ///
+    /// std::io::Error: If there is an error writing to the backing storage. The store
+    /// is left unchanged in that case.
+    ///
+    /// KVError::KeyTooLarge, KVError::ValueTooLarge, KVError::MimeTooLarge: See
+    /// `validate`. None of the entries are written if one of them is rejected.
+    ///
+    pub async fn set_many(&mut self, entries: Vec<(String, Entry)>) -> KVResult<()> {
+        let writes = entries
+            .into_iter()
+            .map(|(key, value)| Write::Set(key, value))
+            .collect();
+        self.write_many(writes).await
+    }
+
+    /// Like `set_many`, but the writes may also remove keys. They're applied in order,
+    /// so a later write of the same key wins, and removing a key which doesn't exist by
+    /// then is skipped.
+    ///
+    /// # Errors
+    ///
+    /// std::io::Error: If there is an error writing to the backing storage. The store
+    /// is left unchanged in that case.
+    ///
+    /// KVError::KeyTooLarge, KVError::ValueTooLarge, KVError::MimeTooLarge: See
+    /// `validate`. None of the writes are applied if one of them is rejected.
+    ///
+    pub async fn write_many(&mut self, writes: Vec<Write>) -> KVResult<()> {
+        self.apply_writes(writes, false).await
+    }
+
+    /// Like `set_many`, for bulk imports which write many entries in a row, e.g. an
+    /// initial data load. Values are always stored in full rather than as deltas, which
+    /// would have to read the current values, and the records aren't synced whatever
+    /// the sync policy is. Call `flush` once the import is done.
+    ///
+    /// # Errors
+    ///
+    /// Like `set_many`.
+    ///
+    pub async fn import(&mut self, entries: Vec<(String, Entry)>) -> KVResult<()> {
+        let writes = entries
+            .into_iter()
+            .map(|(key, value)| Write::Set(key, value))
+            .collect();
+        self.apply_writes(writes, true).await
+    }
+
+    /// See `write_many`, and `import` for `bulk`.
+    async fn apply_writes(&mut self, writes: Vec<Write>, bulk: bool) -> KVResult<()> {
+        self.check_writable()?;
+        for write in &writes {
+            if let Write::Set(key, value) = write {
+                self.validate(key, value)?;
+            }
+        }
+        let now = self.now();
+        // Records keep times in milliseconds, so the index does as well.
+        let to_record_precision = |time| codec::from_millis(codec::to_millis(time));
+        // Whether each key written exists after the writes so far.
+        let mut exists = HashMap::new();
+        let writes: Vec<Write> = writes
+            .into_iter()
+            .filter_map(|write| match write {
+                Write::Set(key, mut value) => {
+                    let key = self.normalize(&key).into_owned();
+                    value.modified_at = Some(to_record_precision(now));
+                    value.expires_at = value.expires_at.map(to_record_precision);
+                    value.pinned |= self.entries.get(&key).is_some_and(|info| info.pinned);
+                    exists.insert(key.clone(), true);
+                    Some(Write::Set(key, value))
+                }
+                Write::Remove(key) => {
+                    let key = self.normalize(&key).into_owned();
+                    let existed = exists
+                        .insert(key.clone(), false)
+                        .unwrap_or_else(|| self.entries.contains_key(&key));
+                    existed.then_some(Write::Remove(key))
+                }
+            })
+            .collect();
+        if writes.is_empty() {
+            return Ok(());
+        }
+        let mut records = Vec::with_capacity(writes.len());
+        // A delta is against the current value, not one set earlier in the batch.
+        let mut batched = HashSet::new();
+        for write in &writes {
+            match write {
+                Write::Set(key, value) => {
+                    debug!(
+                        "Setting entry: key = {:?}, value length = {}, mime = {:?}",
+                        key,
+                        value.value.len(),
+                        value.mime
+                    );
+                    let delta = if !bulk && batched.insert(key) {
+                        self.delta_record(key, value).await?
+                    } else {
+                        None
+                    };
+                    records.push(delta.unwrap_or_else(|| to_kv_entry(key, value)));
+                }
+                Write::Remove(key) => {
+                    debug!("Removing entry: key = {:?}", key);
+                    batched.insert(key);
+                    records.push(KVEntry::tombstone(key.to_owned()));
+                }
+            }
+        }
+        let offsets = self.backend.append_batch(&records).await?;
+        if bulk {
+            self.unsynced = true;
+        } else {
+            self.sync_after_write().await?;
+        }
+        for (record, offset) in records.iter().zip(&offsets) {
+            self.published(*offset, record);
+        }
+        for ((write, offset), record) in writes.into_iter().zip(offsets).zip(&records) {
+            match write {
+                Write::Set(key, value) => self.index_set(key, value, offset, record.delta),
+                Write::Remove(key) => {
+                    self.index_removal(&key);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Indexes the value which was just written at `offset`.
+    fn index_set(&mut self, key: String, mut value: Entry, offset: u64, delta: bool) {
+        override_seed(&mut self.seed_keys, &key, EntryKind::Value);
+        value.mime = self.mimes.intern(&value.mime);
+        // Subscribers get the value, so it's only copied into the index if there are
+        // any.
+        let subscribed = self.events.has_subscribers();
+        let mut info = EntryInfo::new(&value, ValueLocation::Stored(offset));
+        if !self.lazy {
+            info.location = ValueLocation::Loaded(if subscribed {
+                value.value.clone()
+            } else {
+                std::mem::take(&mut value.value)
+            });
+        }
+        if delta {
+            info.delta_depth = self.entries.get(&key).map_or(0, |info| info.delta_depth) + 1;
+        }
+        info.accessed_at = self.entries.get(&key).and_then(|info| info.accessed_at);
+        self.usage.add(&key, usage_bytes(&key, &info));
+        let expires_at = info.expiry();
+        if let Some(previous) = self.entries.insert(&key, info) {
+            self.usage.remove(&key, usage_bytes(&key, &previous));
+            if let Some(expired_at) = previous.expiry() {
+                self.expiries.remove(&key, expired_at);
+            }
+        }
+        if let Some(expires_at) = expires_at {
+            self.expiries.insert(&key, expires_at);
+        }
+        debug!("Entry set successfully: key = {:?}", key);
+        if subscribed {
+            self.events.publish(ChangeEvent::Set { key, entry: value });
+        }
+    }
+
+    /// Remove the value for a given key, returning what the index knew about it if there
+    /// was one. This writes a tombstone to the backing storage, so the removal persists.
+    ///
+    /// # Errors
+    ///
+    /// std::io::Error: If there is an error writing to the backing storage. The store
+    /// is left unchanged in that case.
+    ///
+    pub async fn remove(&mut self, key: &str) -> KVResult<Option<EntryInfo>> {
+        let key = &*self.normalize(key).into_owned();
+        if !self.entries.contains_key(key) {
+            return Ok(None);
+        }
+        debug!("Removing entry: key = {:?}", key);
+        self.append(&KVEntry::tombstone(key.to_owned())).await?;
+        Ok(self.index_removal(key))
+    }
+
+    /// Removes the key, whose tombstone was just written, from the index.
+    fn index_removal(&mut self, key: &str) -> Option<EntryInfo> {
+        override_seed(&mut self.seed_keys, key, EntryKind::Tombstone);
+        let removed = self.entries.remove(key);
+        if let Some(removed) = &removed {
+            self.usage.remove(key, usage_bytes(key, removed));
+            if let Some(expires_at) = removed.expiry() {
+                self.expiries.remove(key, expires_at);
+            }
+        }
+        self.events.publish(ChangeEvent::Removed {
+            key: key.to_owned(),
+        });
+        removed
+    }
+
+    /// Remove all keys starting with `prefix`, returning how many were removed.
+    ///
+    /// Only a single tombstone is written for the whole prefix, no matter how many
+    /// keys it covers.
+    ///
+    /// # Errors
+    ///
+    /// std::io::Error: If there is an error writing to the backing storage. The store
+    /// is left unchanged in that case.
+    ///
+    pub async fn remove_prefix(&mut self, prefix: &str) -> KVResult<usize> {
+        let prefix = &*self.normalize(prefix).into_owned();
+        if self.entries.iter_prefix(prefix).next().is_none() {
+            return Ok(0);
+        }
+        debug!("Removing all entries with prefix {:?}", prefix);
+        self.append(&KVEntry::prefix_tombstone(prefix.to_owned()))
+            .await?;
+        override_seed(&mut self.seed_keys, prefix, EntryKind::PrefixTombstone);
+        for (key, info) in self.entries.iter_prefix(prefix) {
+            self.usage.remove(&key, usage_bytes(&key, info));
+            if let Some(expires_at) = info.expiry() {
+                self.expiries.remove(&key, expires_at);
+            }
+        }
+        let removed = self.entries.remove_prefix(prefix);
+        self.events.publish(ChangeEvent::PrefixRemoved {
+            prefix: prefix.to_owned(),
+        });
+        debug!("Removed {} entries with prefix {:?}", removed, prefix);
+        Ok(removed)
+    }
+
+    /// Pins or unpins the entry of `key`, see `Entry::pinned`. Returns whether there
+    /// is an entry.
+    ///
+    /// # Errors
+    ///
+    /// std::io::Error: If there is an error writing to the backing storage. The store
+    /// is left unchanged in that case.
+    ///
+    pub async fn set_pinned(&mut self, key: &str, pinned: bool) -> KVResult<bool> {
+        let key = &*self.normalize(key).into_owned();
+        let Some(mut entry) = self.peek(key).await? else {
+            return Ok(false);
+        };
+        if entry.pinned == pinned {
+            return Ok(true);
+        }
+        debug!("Setting pinned = {} for key = {:?}", pinned, key);
+        // The value is written again, as records only hold whole entries.
+        entry.pinned = pinned;
+        let offset = self.append(&to_kv_entry(key, &entry)).await?;
+        override_seed(&mut self.seed_keys, key, EntryKind::Value);
+        let info = self.entries.get_mut(key).expect("peek found the entry");
+        if let Some(expires_at) = info.expires_at {
+            if pinned {
+                self.expiries.remove(key, expires_at);
+            } else {
+                self.expiries.insert(key, expires_at);
+            }
+        }
+        info.pinned = pinned;
+        if self.lazy {
+            info.location = ValueLocation::Stored(offset);
+            info.delta_depth = 0;
+        }
+        Ok(true)
+    }
+
+    /// Removes every expired entry, returning how many were removed. A tombstone is
+    /// written for each, just like for `remove`.
+    ///
+    /// # Errors
+    ///
+    /// std::io::Error: If there is an error writing to the backing storage. Entries
+    /// removed before the error stay removed.
+    ///
+    pub async fn remove_expired(&mut self) -> KVResult<usize> {
+        let now = self.now();
+        let expired: Vec<String> = self
+            .expiries
+            .until(Some(now))
+            .map(|(key, _)| key.to_owned())
+            .collect();
+        for key in &expired {
+            debug!("Entry expired: key = {:?}", key);
+            self.remove(key).await?;
+        }
+        Ok(expired.len())
+    }
+
+    /// The keys starting with `prefix` which expire within `within` from now, soonest
+    /// first, along with when they expire. Keys which already expired aren't included,
+    /// and a `within` too long to add to the current time includes all others.
+    pub fn expiring<'a>(
+        &'a self,
+        prefix: &str,
+        within: Duration,
+    ) -> impl Iterator<Item = (&'a str, SystemTime)> + 'a {
+        let now = self.now();
+        let prefix = self.normalize(prefix).into_owned();
+        self.expiries
+            .until(now.checked_add(within))
+            .skip_while(move |(_, expires_at)| *expires_at <= now)
+            .filter(move |(key, _)| key.starts_with(&prefix))
+    }
+
+    /// Appends the entry to the backend, and returns the offset it starts at.
+    async fn append(&mut self, kv_entry: &KVEntry) -> KVResult<u64> {
+        self.check_writable()?;
+        let offset = self.backend.append(kv_entry).await?;
+        self.sync_after_write().await?;
+        self.published(offset, kv_entry);
+        Ok(offset)
+    }
+
+    /// Passes a record which was just appended at `offset` on to the tap, and notes
+    /// what it changed for a running compaction, which hasn't copied that yet.
+    fn published(&mut self, offset: u64, record: &KVEntry) {
+        self.tap
+            .publish(offset, record, self.backend.compresses(record));
+        if let Some(running) = &mut self.compaction {
+            match record.kind {
+                EntryKind::PrefixTombstone => running.prefixes.push(record.key.clone()),
+                _ => {
+                    running.keys.insert(record.key.clone());
+                }
+            }
+        }
+    }
+
+    /// Syncs the backend after a write if the sync policy says so, and otherwise
+    /// remembers that there's a write to sync.
+    async fn sync_after_write(&mut self) -> KVResult<()> {
+        match self.sync_policy {
+            SyncPolicy::Always => self.backend.sync().await,
+            SyncPolicy::EveryNMillis(_) | SyncPolicy::Never => {
+                self.unsynced = true;
+                Ok(())
+            }
+        }
+    }
+
+    /// Syncs the backend if anything was written since it was last synced, returning
+    /// whether it was. See `SyncPolicy::EveryNMillis`.
+    pub async fn sync_pending(&mut self) -> KVResult<bool> {
+        if !self.unsynced {
+            return Ok(false);
+        }
+        self.flush().await?;
+        Ok(true)
+    }
+
+    /// Makes sure that everything written so far is persisted, e.g. before shutting
+    /// down. Access times which weren't persisted yet aren't, see
+    /// `persist_access_times`.
+    pub async fn flush(&mut self) -> KVResult<()> {
+        self.backend.sync().await?;
+        self.unsynced = false;
+        Ok(())
+    }
+
+    /// Checks that the backend still accepts writes, see `StorageBackend::probe`.
+    pub async fn check_storage(&mut self) -> KVResult<()> {
+        self.backend.probe().await
+    }
+
+    /// The size of the backing storage in bytes, which grows with every write until
+    /// the store is compacted.
+    pub fn log_len(&self) -> u64 {
+        self.backend.size()
+    }
+
+    /// Compacts the store: Writes the current entry of every live key to new storage
+    /// created by the backend, which then replaces the current storage. Stale records
+    /// and tombstones are left behind.
+    ///
+    /// This does all of `begin_compaction`, `compaction_batch` and `finish_compaction`
+    /// at once. If anything fails before the storage is replaced, the store is left
+    /// unchanged.
+    pub async fn compact(&mut self) -> KVResult<CompactionReport> {
+        let compaction = self.begin_compaction().await?;
+        self.finish_compaction(compaction).await
+    }
+
+    /// Begins compacting the store, see `compact`. The live entries are copied from a
+    /// snapshot in batches read with `compaction_batch`, which are written with
+    /// `Compaction::write` without holding on to the store in between. Then
+    /// `finish_compaction` copies what was written since, and replaces the storage.
+    ///
+    /// Only one compaction runs at a time, others fail with
+    /// `KVError::CompactionRunning`. Dropping the `Compaction` abandons it.
+    pub async fn begin_compaction(&mut self) -> KVResult<Compaction<B>> {
+        self.check_writable()?;
+        if self
+            .compaction
+            .as_ref()
+            .is_some_and(|running| running.alive.strong_count() > 0)
+        {
+            return Err(KVError::CompactionRunning);
+        }
+        // The compacted copy persists all access times.
+        for (key, accessed_at) in self.access.take() {
+            if let Some(info) = self.entries.get_mut(&key) {
+                info.accessed_at = Some(accessed_at);
+            }
+        }
+        let result = async {
+            let mut target = self.backend.create_sibling().await?;
+            target.create(&self.header).await?;
+            // Removals of keys from the seed have to be kept, or the keys would reappear.
+            let tombstones: Vec<KVEntry> = self
+                .seed_keys
+                .iter()
+                .filter(|(key, overridden)| **overridden && !self.entries.contains_key(key))
+                .map(|(key, _)| KVEntry::tombstone(key))
+                .collect();
+            if !tombstones.is_empty() {
+                target.append_batch(&tombstones).await?;
+            }
+            Ok(target)
+        }
+        .await;
+        let target = match result {
+            Ok(target) => target,
+            Err(err) => {
+                warn!("Compacting failed: {}", err);
+                return Err(err);
+            }
+        };
+        let snapshot = self.snapshot();
+        let mut remaining: Vec<String> = snapshot.entries.iter().map(|(key, _)| key).collect();
+        remaining.reverse();
+        let alive = Arc::new(());
+        self.compaction = Some(Running {
+            alive: Arc::downgrade(&alive),
+            keys: HashSet::new(),
+            prefixes: Vec::new(),
+        });
+        Ok(Compaction {
+            target,
+            snapshot,
+            remaining,
+            offsets: Vec::new(),
+            before: self.log_len(),
+            alive,
+        })
+    }
+
+    /// Reads the next live entries of the snapshot `compaction` copies, up to about
+    /// `COMPACTION_BATCH_LEN` bytes of values. The batch is empty once all of them
+    /// were read.
+    pub async fn compaction_batch(
+        &self,
+        compaction: &mut Compaction<B>,
+    ) -> KVResult<CompactionBatch> {
+        let mut records = Vec::new();
+        let mut len = 0;
+        while len < COMPACTION_BATCH_LEN {
+            let Some(key) = compaction.remaining.pop() else {
+                break;
+            };
+            // The records of the snapshot's offsets stay until the storage is replaced.
+            let info = compaction.snapshot.entries.get(&key).unwrap();
+            len += self.live_entry_records(&key, info, &mut records).await?;
+        }
+        Ok(CompactionBatch { records })
+    }
+
+    /// Finishes `compaction`: Copies whatever is left, and what was written since it
+    /// began, and replaces the storage with the copy.
+    ///
+    /// If anything fails before the storage is replaced, the store is left unchanged,
+    /// and the compaction is abandoned.
+    pub async fn finish_compaction(
+        &mut self,
+        mut compaction: Compaction<B>,
+    ) -> KVResult<CompactionReport> {
+        let running = match self.compaction.take() {
+            Some(running) if Weak::ptr_eq(&running.alive, &Arc::downgrade(&compaction.alive)) => {
+                running
+            }
+            running => {
+                self.compaction = running;
+                return Err(KVError::InvalidData(
+                    "The compaction wasn't begun by this store".to_owned(),
+                ));
+            }
+        };
+        let before = compaction.before;
+        let result = async {
+            loop {
+                let batch = self.compaction_batch(&mut compaction).await?;
+                if batch.is_empty() {
+                    break;
+                }
+                compaction.write(batch).await?;
+            }
+            let records = self.written_since(&compaction.snapshot, running).await?;
+            compaction.write(records).await?;
+            self.backend.replace(compaction.target).await?;
+            Ok(compaction.offsets)
+        }
+        .await;
+        let offsets = match result {
+            Ok(offsets) => offsets,
+            Err(err) => {
+                warn!("Compacting failed: {}", err);
+                return Err(err);
+            }
+        };
+        self.relocate(offsets);
+        self.generation += 1;
+        let after = self.log_len();
+        info!(
+            "Compacted from {} to {} bytes, {} live entries",
+            before,
+            after,
+            self.entries.len()
+        );
+        Ok(CompactionReport {
+            before,
+            after,
+            entries: self.entries.len(),
+        })
+    }
+
+    /// The records which bring a copy of `snapshot` up to date with what `running`
+    /// noted was written since.
+    async fn written_since(
+        &self,
+        snapshot: &Snapshot,
+        running: Running,
+    ) -> KVResult<CompactionBatch> {
+        let mut records: Vec<(KVEntry, bool)> = running
+            .prefixes
+            .into_iter()
+            .map(|prefix| (KVEntry::prefix_tombstone(prefix), false))
+            .collect();
+        let mut keys: Vec<String> = running.keys.into_iter().collect();
+        keys.sort();
+        for key in keys {
+            match self.entries.get(&key) {
+                Some(info) => {
+                    self.live_entry_records(&key, info, &mut records).await?;
+                }
+                None if snapshot.entries.contains_key(&key)
+                    || self.seed_keys.contains_key(&key) =>
+                {
+                    records.push((KVEntry::tombstone(key), false));
+                }
+                None => {}
+            }
+        }
+        Ok(CompactionBatch { records })
+    }
+
+    /// Adds the records which hold the entry `info` of `key` in compacted storage to
+    /// `records`, and returns the length of the value read from the backend, if any.
+    async fn live_entry_records(
+        &self,
+        key: &str,
+        info: &EntryInfo,
+        records: &mut Vec<(KVEntry, bool)>,
+    ) -> KVResult<usize> {
+        let mut len = 0;
+        // Entries from the seed which weren't overridden stay in the seed.
+        if self.seed_keys.get(key) != Some(&false) {
+            match &info.location {
+                ValueLocation::Stored(offset) => {
+                    let value = self.read_value(key, *offset).await?;
+                    len = value.len();
+                    records.push((to_kv_entry(key, &info.with_value(value)), true));
+                }
+                ValueLocation::Loaded(value) => {
+                    let record = to_kv_entry(key, &info.with_value(value.clone()));
+                    records.push((record, false));
+                }
+            }
+        }
+        if let Some(accessed_at) = info.accessed_at {
+            records.push((KVEntry::touch(key.to_owned(), accessed_at), false));
+        }
+        Ok(len)
+    }
+
+    /// Points the index at the records of the compacted copy.
+    fn relocate(&mut self, offsets: Vec<(String, u64)>) {
+        self.cache.clear();
+        for (key, offset) in offsets {
+            if let Some(info) = self.entries.get_mut(&key) {
+                info.location = ValueLocation::Stored(offset);
+                info.delta_depth = 0;
+            }
+        }
+    }
+
+    /// Compares a random sample of up to `sample_size` keys in the index against their
+    /// latest records in the backing storage, reporting any drift between the two.
+    ///
+    /// This reads through the whole backing storage, so it should be run sparingly,
+    /// or with `verify_sample` where the backend allows.
+    pub async fn verify(&mut self, sample_size: usize) -> KVResult<VerifyReport> {
+        let mut sample = self.pick_sample(sample_size);
+        self.backend
+            .load_all(|offset, entry| sample.compare(offset, entry))
+            .await?;
+        Ok(sample.report())
+    }
+
+    /// Like `verify`, but only picks the sample, which `VerifySample::check` then
+    /// compares against the records without holding on to the store. Returns `None`
+    /// if the backend can't be read from apart from the store, see
+    /// `StorageBackend::reader`.
+    pub async fn verify_sample(&self, sample_size: usize) -> KVResult<Option<VerifySample>> {
+        let Some(reader) = self.backend.reader().await? else {
+            return Ok(None);
+        };
+        Ok(Some(VerifySample {
+            sample: self.pick_sample(sample_size),
+            reader,
+        }))
+    }
+
+    fn pick_sample(&self, sample_size: usize) -> Sample {
+        let expected = self
+            .entries
+            .iter()
+            // Entries from the seed aren't in the backing storage.
+            .filter(|(key, _)| self.seed_keys.get(key) != Some(&false))
+            .choose_multiple(&mut rand::thread_rng(), sample_size)
+            .into_iter()
+            .map(|(key, info)| (key, (info.clone(), None)))
+            .collect();
+        Sample { expected }
+    }
+
+    /// Iterates over all keys starting with `prefix` and their entries, in key order.
+    /// With `after`, iteration starts at the first such key greater than `after`, so
+    /// a long scan can be continued in batches.
+    ///
+    /// The keys are yielded as stored, i.e. normalized. Expired entries are skipped.
+    pub fn scan<'a>(
+        &'a self,
+        prefix: &str,
+        after: Option<&str>,
+    ) -> impl Iterator<Item = (String, &'a EntryInfo)> + 'a {
+        let normalization = self.key_normalization();
+        scan_index(&self.entries, normalization, self.now(), prefix, after)
+    }
+
+    /// Iterates over all keys starting with `prefix` and their entries, in key order,
+    /// like `scan` from the start.
+    pub fn scan_prefix<'a>(
+        &'a self,
+        prefix: &str,
+    ) -> impl Iterator<Item = (String, &'a EntryInfo)> + 'a {
+        self.scan(prefix, None)
+    }
+
+    /// Iterates over the keys within `range` and their entries, in ascending key
+    /// order, e.g. `store.range("2024-01".."2024-03")` for time-ordered keys. Bounds
+    /// are compared by the bytes of the keys, like the keys are ordered, and a range
+    /// which ends before it starts is empty.
+    pub fn range<'a, 'k>(
+        &'a self,
+        range: impl RangeBounds<&'k str>,
+    ) -> impl Iterator<Item = (String, &'a EntryInfo)> + 'a {
+        let (start, end) = self.normalize_range(&range);
+        let entries = match &start {
+            Bound::Included(start) | Bound::Excluded(start) => self.entries.range_from(start),
+            Bound::Unbounded => self.entries.iter(),
+        };
+        let now = self.now();
+        entries
+            .skip_while(move |(key, _)| matches!(&start, Bound::Excluded(start) if key == start))
+            .take_while(move |(key, _)| match &end {
+                Bound::Included(end) => key <= end,
+                Bound::Excluded(end) => key < end,
+                Bound::Unbounded => true,
+            })
+            .filter(move |(_, entry)| !entry.is_expired(now))
+    }
+
+    /// Like `range`, but in descending key order, starting from the end of `range`.
+    pub fn range_rev<'a, 'k>(
+        &'a self,
+        range: impl RangeBounds<&'k str>,
+    ) -> impl Iterator<Item = (String, &'a EntryInfo)> + 'a {
+        let (start, end) = self.normalize_range(&range);
+        let (last, entries) = match end {
+            Bound::Included(end) => {
+                let last = self.entries.get(&end).map(|entry| (end.clone(), entry));
+                (last, self.entries.range_rev_before(&end))
+            }
+            Bound::Excluded(end) => (None, self.entries.range_rev_before(&end)),
+            Bound::Unbounded => (None, self.entries.iter_rev()),
+        };
+        let now = self.now();
+        last.into_iter()
+            .chain(entries)
+            .take_while(move |(key, _)| match &start {
+                Bound::Included(start) => key >= start,
+                Bound::Excluded(start) => key > start,
+                Bound::Unbounded => true,
+            })
+            .filter(move |(_, entry)| !entry.is_expired(now))
+    }
+
+    fn normalize_range<'k>(
+        &self,
+        range: &impl RangeBounds<&'k str>,
+    ) -> (Bound<String>, Bound<String>) {
+        let normalization = self.key_normalization();
+        let normalize =
+            |bound: Bound<&&str>| bound.map(|key| normalization.normalize(key).into_owned());
+        (normalize(range.start_bound()), normalize(range.end_bound()))
+    }
+
+    /// Takes a snapshot of the entries, which `info_at`, `peek_at` and `scan_at` read
+    /// from as if nothing was written since. This copies the index, but none of the
+    /// values, which stay in the records they were in when the snapshot was taken.
+    ///
+    /// Compacting the store moves those records, so snapshots can't be read from
+    /// anymore afterwards.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            entries: self.entries.clone(),
+            key_normalization: self.key_normalization(),
+            taken_at: self.now(),
+            generation: self.generation,
+        }
+    }
+
+    /// Fails with `KVError::SnapshotInvalidated` if the store was compacted since the
+    /// snapshot was taken.
+    fn check_snapshot(&self, snapshot: &Snapshot) -> KVResult<()> {
+        if snapshot.generation != self.generation {
+            return Err(KVError::SnapshotInvalidated);
+        }
+        Ok(())
+    }
+
+    /// Like `info`, but as of when the snapshot was taken. Entries which had expired by
+    /// then are treated as if they were removed.
+    pub fn info_at<'a>(
+        &self,
+        snapshot: &'a Snapshot,
+        key: &str,
+    ) -> KVResult<Option<&'a EntryInfo>> {
+        self.check_snapshot(snapshot)?;
+        let key = snapshot.key_normalization.normalize(key);
+        Ok(snapshot
+            .entries
+            .get(&key)
+            .filter(|info| !info.is_expired(snapshot.taken_at)))
+    }
+
+    /// Like `peek`, but reads the value the key had when the snapshot was taken.
+    pub async fn peek_at(&self, snapshot: &Snapshot, key: &str) -> KVResult<Option<Entry>> {
+        let Some(info) = self.info_at(snapshot, key)? else {
+            return Ok(None);
+        };
+        let value = match &info.location {
+            ValueLocation::Stored(offset) => self.read_value(&self.normalize(key), *offset).await?,
+            ValueLocation::Loaded(value) => value.clone(),
+        };
+        Ok(Some(info.with_value(value)))
+    }
+
+    /// Like `scan`, but over the entries as they were when the snapshot was taken.
+    pub fn scan_at<'a>(
+        &self,
+        snapshot: &'a Snapshot,
+        prefix: &str,
+        after: Option<&str>,
+    ) -> KVResult<impl Iterator<Item = (String, &'a EntryInfo)> + 'a> {
+        self.check_snapshot(snapshot)?;
+        let Snapshot {
+            entries,
+            key_normalization,
+            taken_at,
+            ..
+        } = snapshot;
+        Ok(scan_index(
+            entries,
+            *key_normalization,
+            *taken_at,
+            prefix,
+            after,
+        ))
+    }
+
+    /// Subscribe to changes of all keys starting with `prefix`. Pass an empty prefix
+    /// to receive every change.
+    ///
+    /// The stream only yields changes made after subscribing. A subscriber which
+    /// doesn't keep up will skip the events it missed.
+    pub fn subscribe(&self, prefix: &str) -> impl Stream<Item = ChangeEvent> {
+        self.events.subscribe(&self.normalize(prefix))
+    }
+
+    /// Follows the records appended to the backing storage, like `tail -f` does for a
+    /// file. Unlike `subscribe`, this describes the records themselves, including the
+    /// tombstones, but not their values.
+    ///
+    /// The stream only yields records appended after subscribing. Records written by
+    /// `compact` aren't included, as they only rewrite what's already there.
+    pub fn tail(&self) -> impl Stream<Item = LogRecord> {
+        self.tap.subscribe()
+    }
+}
+
+impl KVStore<FileBackend> {
+    /// Opens the database file at `path`, creating it if it doesn't exist, and loads
+    /// it like `new`, which discards what a crash left of a partially written record.
+    ///
+    /// The file is locked while the store is open, so opening it again, e.g. from
+    /// another process, fails with `KVError::Locked` instead of corrupting it.
+    pub async fn open(path: impl AsRef<Path>) -> KVResult<Self> {
+        Self::new(FileBackend::open(path).await?).await
+    }
+
+    /// Like `open`, but opens the file with `options`, e.g. to fail if it doesn't
+    /// exist, or to create it with other permissions. The file is always opened for
+    /// reading and writing.
+    pub async fn open_with(path: impl AsRef<Path>, options: OpenOptions) -> KVResult<Self> {
+        Self::new(FileBackend::open_with(path, options).await?).await
+    }
+}
+
+/// Configures a `KVStore` before it's loaded, for settings which the constructors of
+/// `KVStore` don't take. Created with `KVStore::builder`.
+pub struct KVStoreBuilder<B: StorageBackend> {
+    backend: B,
+    clock: Arc<dyn Clock>,
+    key_normalization: Option<KeyNormalization>,
+    seed: Option<Box<dyn AsyncRead + Unpin + Send>>,
+    compression_threshold: Option<usize>,
+    compression_level: Option<i32>,
+    sync_policy: SyncPolicy,
+    max_value_size: Option<usize>,
+    read_only: bool,
+}
+
+impl<B: StorageBackend> KVStoreBuilder<B> {
+    /// Takes the current time from `clock` instead of the system clock, see
+    /// `KVStore::with_clock`.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Normalizes keys with `key_normalization`, see `KVStore::with_key_normalization`.
+    pub fn key_normalization(mut self, key_normalization: KeyNormalization) -> Self {
+        self.key_normalization = Some(key_normalization);
+        self
+    }
+
+    /// Layers the backend on top of a read-only `seed` database, see
+    /// `KVStore::with_seed`.
+    pub fn seed(mut self, seed: impl AsyncRead + Unpin + Send + 'static) -> Self {
+        self.seed = Some(Box::new(seed));
+        self
+    }
+
+    /// Compresses values larger than `bytes`, see
+    /// `LogBackend::with_compression_threshold`. The threshold is recorded in the
+    /// header of new storage, and storage which records another one fails to load with
+    /// `KVError::CompressionThresholdMismatch`. Without this, the backend's threshold
+    /// is used.
+    pub fn compression_threshold(mut self, bytes: usize) -> Self {
+        self.compression_threshold = Some(bytes);
+        self
+    }
+
+    /// Compresses values at zstd `level`, see `LogBackend::with_compression_level`.
+    /// Without this, the backend's level is used.
+    pub fn compression_level(mut self, level: i32) -> Self {
+        self.compression_level = Some(level);
+        self
+    }
+
+    /// See `KVStore::set_sync_policy`.
+    pub fn sync_policy(mut self, policy: SyncPolicy) -> Self {
+        self.sync_policy = policy;
+        self
+    }
+
+    /// See `KVStore::set_max_value_size`.
+    pub fn max_value_size(mut self, bytes: usize) -> Self {
+        self.max_value_size = Some(bytes);
+        self
+    }
+
+    /// Rejects every write with `KVError::ReadOnly`, e.g. to inspect a database
+    /// without changing it. Empty storage isn't initialized either.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Loads the store from the backend, like `KVStore::new`.
+    pub async fn build(self) -> KVResult<KVStore<B>> {
+        let KVStoreBuilder {
+            mut backend,
+            clock,
+            key_normalization,
+            mut seed,
+            compression_threshold,
+            compression_level,
+            sync_policy,
+            max_value_size,
+            read_only,
+        } = self;
+        if let Some(bytes) = compression_threshold {
+            backend.set_compression_threshold(bytes);
+        }
+        if let Some(level) = compression_level {
+            backend.set_compression_level(level);
+        }
+        let seed = seed.as_deref_mut().map(|seed| seed as _);
+        let mut store = KVStore::load(backend, clock, key_normalization, seed, read_only).await?;
+        store.sync_policy = sync_policy;
+        store.max_value_size = max_value_size;
+        Ok(store)
+    }
+}
+
+/// The live entries of `entries` whose keys start with `prefix`, after `after` if
+/// given, see `KVStore::scan`.
+fn scan_index<'a>(
+    entries: &'a RadixIndex<EntryInfo>,
+    normalization: KeyNormalization,
+    now: SystemTime,
+    prefix: &str,
+    after: Option<&str>,
+) -> impl Iterator<Item = (String, &'a EntryInfo)> + 'a {
+    let prefix = normalization.normalize(prefix).into_owned();
+    let after = after.map(|after| normalization.normalize(after).into_owned());
+    let start = match &after {
+        Some(after) if *after > prefix => after,
+        _ => &prefix,
+    };
+    entries
+        .range_from(start)
+        .skip_while(move |(key, _)| Some(key) == after.as_ref())
+        .take_while(move |(key, _)| key.starts_with(&prefix))
+        .filter(move |(_, entry)| !entry.is_expired(now))
+}
+
+/// Applies an entry read from the backing storage to the index. With the `offset` the
+/// entry was read from, its value is read from there again when requested, and is
+/// kept in memory otherwise.
+///
+/// Fails if the entry is a delta which can't be made sense of.
+fn apply_entry(
+    entries: &mut RadixIndex<EntryInfo>,
+    mimes: &mut MimeInterner,
+    now: SystemTime,
+    entry: KVEntry,
+    offset: Option<u64>,
+) -> KVResult<()> {
+    match entry.kind {
+        EntryKind::Value => {
+            let key = entry.key.clone();
+            let delta = entry.delta;
+            let mut value = Entry::from(entry);
+            // Values kept in memory are resolved right away, which needs the base to
+            // still be in the index.
+            let base = match entries.get(&key).map(|info| &info.location) {
+                Some(ValueLocation::Loaded(base)) if delta && offset.is_none() => Some(base),
+                _ => None,
+            };
+            if let Some(base) = base {
+                let record = DeltaRecord::decode(&value.value)?;
+                value.value = delta::apply(base, record.instructions, record.value_len)?;
+            } else if delta && offset.is_none() {
+                return Err(KVError::InvalidData(format!(
+                    "Delta record of key {:?} without the value it's against",
+                    key
+                )));
+            }
+            if value.is_expired(now) {
+                _ = entries.remove(&key);
+                return Ok(());
+            }
+            value.mime = mimes.intern(&value.mime);
+            let mut info = match offset {
+                Some(offset) if delta => EntryInfo::for_delta(
+                    &value,
+                    &DeltaRecord::decode(&value.value)?,
+                    ValueLocation::Stored(offset),
+                ),
+                Some(offset) => EntryInfo::new(&value, ValueLocation::Stored(offset)),
+                None => EntryInfo::new(&value, ValueLocation::Loaded(value.value.clone())),
+            };
+            // Setting a value doesn't count as reading it.
+            info.accessed_at = entries.get(&key).and_then(|info| info.accessed_at);
+            _ = entries.insert(&key, info);
+        }
+        EntryKind::Tombstone => _ = entries.remove(&entry.key),
+        EntryKind::PrefixTombstone => _ = entries.remove_prefix(&entry.key),
+        EntryKind::Touch => {
+            if let Some(info) = entries.get_mut(&entry.key) {
+                info.accessed_at = entry.touched_at();
+            }
+        }
+    }
+    Ok(())
+}
+
+/// How many bytes an entry counts with in the `UsageTree`.
+fn usage_bytes(key: &str, info: &EntryInfo) -> u64 {
+    (key.len() + info.value_len) as u64
+}
+
+/// Marks the seed's keys which an entry of the given kind overrides.
+fn override_seed(seed_keys: &mut RadixIndex<bool>, key: &str, kind: EntryKind) {
+    if seed_keys.is_empty() {
+        return;
+    }
+    let keys: Vec<String> = match kind {
+        EntryKind::Value | EntryKind::Tombstone => vec![key.to_owned()],
+        EntryKind::PrefixTombstone => seed_keys.iter_prefix(key).map(|(key, _)| key).collect(),
+        // Reading a key doesn't change it.
+        EntryKind::Touch => return,
+    };
+    for key in keys {
+        if let Some(overridden) = seed_keys.get_mut(&key) {
+            *overridden = true;
+        }
+    }
+}
+
+/// The record which sets `key` to `entry` in the backing storage.
+fn to_kv_entry(key: &str, entry: &Entry) -> KVEntry {
+    KVEntry {
+        meta: entry.meta.clone(),
+        expires_at: entry.expires_at,
+        modified_at: entry.modified_at,
+        pinned: entry.pinned,
+        ..KVEntry::new(key.to_owned(), entry.value.clone(), entry.mime.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::backend::LogBackend;
+    use crate::kv::memory_noop::MemoryNoOpRWS;
+    use crate::kv::entry::Entry;
+    use crate::kv::result::KVResult;
+
+    #[tokio::test]
+    async fn test_kvstore_set_and_get() -> KVResult<()> {
+        let mut kv_store = KVStore::new(MemoryBackend::new()).await?;
+
+        let key = "test_key";
+        let value = Entry::new(b"test_value".to_vec(), "text/plain");
+
+        kv_store.set(key, value.clone()).await?;
+        let retrieved_value = kv_store.get(key).await?.unwrap();
+
+        assert_eq!(retrieved_value.value, value.value);
+        assert_eq!(retrieved_value.mime, value.mime);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kvstore_remove_persists() -> KVResult<()> {
+        let mut kv_store = KVStore::new(LogBackend::new(std::io::Cursor::new(Vec::new()))).await?;
+        let value = Entry::new(b"test_value".to_vec(), "text/plain");
+        for key in ["a", "tmp/1", "tmp/2", "tmpfile"] {
+            kv_store.set(key, value.clone()).await?;
+        }
+        assert!(kv_store.remove("a").await?.is_some());
+        assert!(kv_store.remove("a").await?.is_none());
+        assert_eq!(kv_store.remove_prefix("tmp/").await?, 2);
+        assert!(kv_store.verify(10).await?.is_consistent());
+        kv_store.set("tmp/3", value).await?;
+
+        let reopened = KVStore::new(kv_store.backend).await?;
+        assert!(reopened.get("a").await?.is_none());
+        assert!(reopened.get("tmp/1").await?.is_none());
+        assert!(reopened.get("tmp/3").await?.is_some());
+        assert!(reopened.get("tmpfile").await?.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kvstore_path_keys_persist() -> KVResult<()> {
+        let mut kv_store = KVStore::new(LogBackend::new(std::io::Cursor::new(Vec::new()))).await?;
+        let keys = ["users/42/avatar", "users/42/", "a//b", "/leading", "ü/ key"];
+        for key in keys {
+            kv_store
+                .set(key, Entry::new(key.as_bytes().to_vec(), "text/plain"))
+                .await?;
+        }
+
+        let reopened = KVStore::new(kv_store.backend).await?;
+        for key in keys {
+            assert_eq!(reopened.get(key).await?.unwrap().value, key.as_bytes());
+        }
+        let scanned: Vec<String> = reopened
+            .scan_prefix("users/42/")
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(scanned, ["users/42/", "users/42/avatar"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kvstore_set_many() -> KVResult<()> {
+        let mut kv_store = KVStore::new(LogBackend::new(std::io::Cursor::new(Vec::new()))).await?;
+        let value = |value: &str| Entry::new(value.as_bytes().to_vec(), "text/plain");
+        kv_store
+            .set_many(vec![
+                ("a".to_string(), value("first")),
+                ("b".to_string(), value("second")),
+                ("a".to_string(), value("third")),
+            ])
+            .await?;
+        assert_eq!(kv_store.get("a").await?.unwrap().value, b"third");
+        assert_eq!(kv_store.usage().total().values, 2);
+        assert!(kv_store.verify(10).await?.is_consistent());
+
+        let reopened = KVStore::new(kv_store.backend).await?;
+        assert_eq!(reopened.get("a").await?.unwrap().value, b"third");
+        assert_eq!(reopened.get("b").await?.unwrap().value, b"second");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kvstore_write_many() -> KVResult<()> {
+        let mut kv_store = KVStore::new(LogBackend::new(std::io::Cursor::new(Vec::new()))).await?;
+        let value = |value: &str| Entry::new(value.as_bytes().to_vec(), "text/plain");
+        kv_store.set("b", value("old")).await?;
+        let size = kv_store.backend.size();
+        kv_store
+            .write_many(vec![Write::Remove("a".to_string())])
+            .await?;
+        assert_eq!(kv_store.backend.size(), size);
+
+        kv_store
+            .write_many(vec![
+                Write::Set("a".to_string(), value("new")),
+                Write::Remove("b".to_string()),
+                Write::Set("c".to_string(), value("gone")),
+                Write::Remove("c".to_string()),
+                Write::Remove("missing".to_string()),
+            ])
+            .await?;
+        assert_eq!(kv_store.get("a").await?.unwrap().value, b"new");
+        assert!(kv_store.get("b").await?.is_none());
+        assert!(kv_store.get("c").await?.is_none());
+        assert_eq!(kv_store.usage().total().values, 1);
+
+        let reopened = KVStore::new(kv_store.backend).await?;
+        assert_eq!(reopened.get("a").await?.unwrap().value, b"new");
+        assert!(reopened.get("b").await?.is_none());
+        assert!(reopened.get("c").await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kvstore_rejects_oversized() -> KVResult<()> {
+        let mut kv_store = KVStore::new(MemoryBackend::new()).await?;
+        kv_store.set_max_value_size(Some(4));
+        let value = |value: &str| Entry::new(value.as_bytes().to_vec(), "text/plain");
+        let long_key = "k".repeat(codec::MAX_KEY_LEN + 1);
+        assert!(matches!(
+            kv_store.set(&long_key, value("v")).await,
+            Err(KVError::KeyTooLarge { len, .. }) if len == long_key.len()
+        ));
+        assert!(matches!(
+            kv_store
+                .set_many(vec![
+                    ("a".to_string(), value("fine")),
+                    ("b".to_string(), value("too long")),
+                ])
+                .await,
+            Err(KVError::ValueTooLarge { len: 8, max: 4 })
+        ));
+        assert!(kv_store.get("a").await?.is_none());
+        assert_eq!(kv_store.usage().total().values, 0);
+
+        kv_store.set("a", value("fine")).await?;
+        assert_eq!(kv_store.get("a").await?.unwrap().value, b"fine");
+
+        // Nothing is written which the backend wouldn't read back.
+        let limits = codec::Limits {
+            max_mime_len: 4,
+            ..Default::default()
+        };
+        let backend = LogBackend::new(std::io::Cursor::new(Vec::new())).with_limits(limits);
+        let mut kv_store = KVStore::new(backend).await?;
+        assert!(matches!(
+            kv_store.set("a", value("v")).await,
+            Err(KVError::MimeTooLarge { len: 10, max: 4 })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kvstore_lazy_values() -> KVResult<()> {
+        let mut kv_store = KVStore::new(LogBackend::new(std::io::Cursor::new(Vec::new()))).await?;
+        kv_store
+            .set("a", Entry::new(b"first".to_vec(), "text/plain"))
+            .await?;
+        assert_eq!(kv_store.get("a").await?.unwrap().value, b"first");
+        // Reading moved the stream, but the next record is still appended at the end.
+        kv_store
+            .set("b", Entry::new(b"second".to_vec(), "text/plain"))
+            .await?;
+        assert_eq!(kv_store.get("a").await?.unwrap().value, b"first");
+        assert_eq!(kv_store.get("b").await?.unwrap().value, b"second");
+        assert!(matches!(
+            kv_store.entries.get("b").unwrap().location,
+            ValueLocation::Stored(_)
+        ));
+
+        kv_store.check_storage().await?;
+
+        let reopened = KVStore::new(kv_store.backend).await?;
+        assert_eq!(reopened.entries.get("a").unwrap().value_len, 5);
+        assert_eq!(reopened.get("b").await?.unwrap().value, b"second");
+
+        // Storage which doesn't keep anything can't be read from.
+        let mut in_memory = KVStore::new(LogBackend::new(MemoryNoOpRWS::new())).await?;
+        in_memory
+            .set("a", Entry::new(b"first".to_vec(), "text/plain"))
+            .await?;
+        assert_eq!(in_memory.get("a").await?.unwrap().value, b"first");
+        in_memory.check_storage().await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kvstore_deltas() -> KVResult<()> {
+        use rand::Rng;
+
+        let mut kv_store = KVStore::new(LogBackend::new(std::io::Cursor::new(Vec::new()))).await?;
+        kv_store.set_delta_threshold(Some(1024));
+        let mut value: Vec<u8> = (0..16 * 1024).map(|_| rand::thread_rng().gen()).collect();
+        let mut versions = Vec::new();
+        for i in 0..10 {
+            value[i * 100] = b'x';
+            let entry = Entry::new(value.clone(), "application/octet-stream");
+            kv_store.set("a", entry.clone()).await?;
+            versions.push(entry);
+        }
+        // The first value and the one after the longest chain are stored in full.
+        assert!(kv_store.log_len() < 3 * 16 * 1024);
+        assert_eq!(kv_store.entries.get("a").unwrap().delta_depth, 0);
+        kv_store.set("a", versions[5].clone()).await?;
+        let info = kv_store.info("a").unwrap();
+        assert_eq!(info.delta_depth, 1);
+        assert_eq!(info.value_len, value.len());
+        assert_eq!(info.etag(), versions[5].etag());
+        assert_eq!(kv_store.get("a").await?.unwrap().value, versions[5].value);
+        assert!(kv_store.verify(10).await?.is_consistent());
+
+        // Small values are always stored in full.
+        kv_store
+            .set("b", Entry::new(vec![1; 100], "text/plain"))
+            .await?;
+        kv_store
+            .set("b", Entry::new(vec![2; 100], "text/plain"))
+            .await?;
+        assert_eq!(kv_store.entries.get("b").unwrap().delta_depth, 0);
+
+        let mut reopened = KVStore::new(kv_store.backend).await?;
+        assert_eq!(reopened.info("a").unwrap().etag(), versions[5].etag());
+        assert_eq!(reopened.get("a").await?.unwrap().value, versions[5].value);
+        reopened.compact().await?;
+        assert_eq!(reopened.entries.get("a").unwrap().delta_depth, 0);
+        assert_eq!(reopened.get("a").await?.unwrap().value, versions[5].value);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kvstore_import() -> KVResult<()> {
+        let mut kv_store = KVStore::new(LogBackend::new(std::io::Cursor::new(Vec::new()))).await?;
+        kv_store.set_sync_policy(SyncPolicy::Always);
+        kv_store.set_delta_threshold(Some(1024));
+        let value = |byte| Entry::new(vec![byte; 16 * 1024], "application/octet-stream");
+        kv_store.set("a", value(1)).await?;
+        assert!(!kv_store.sync_pending().await?);
+        kv_store
+            .import(vec![
+                ("a".to_string(), value(2)),
+                ("b".to_string(), value(3)),
+            ])
+            .await?;
+        assert_eq!(kv_store.entries.get("a").unwrap().delta_depth, 0);
+        assert_eq!(kv_store.get("a").await?.unwrap().value, value(2).value);
+        assert!(kv_store.sync_pending().await?);
+
+        let reopened = KVStore::new(kv_store.backend).await?;
+        assert_eq!(reopened.get("b").await?.unwrap().value, value(3).value);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kvstore_cache() -> KVResult<()> {
+        let mut kv_store = KVStore::new(LogBackend::new(std::io::Cursor::new(Vec::new()))).await?;
+        kv_store.set_cache_size(1024 * 1024);
+        for key in ["a", "b", "c"] {
+            kv_store
+                .set(key, Entry::new(key.as_bytes().to_vec(), "text/plain"))
+                .await?;
+        }
+        let offset = |store: &KVStore<_>, key| match store.entries.get(key).unwrap().location {
+            ValueLocation::Stored(offset) => offset,
+            ValueLocation::Loaded(_) => panic!("value of {} is in memory", key),
+        };
+        assert!(!kv_store.cache.contains(offset(&kv_store, "a")));
+        assert_eq!(kv_store.get("a").await?.unwrap().value, b"a");
+        assert!(kv_store.cache.contains(offset(&kv_store, "a")));
+
+        let keys = ["a", "b", "missing"].map(str::to_owned);
+        assert_eq!(kv_store.prefetch(&keys).await?, 1);
+        assert!(kv_store.cache.contains(offset(&kv_store, "b")));
+        assert!(!kv_store.cache.contains(offset(&kv_store, "c")));
+
+        // Compacting moves the records.
+        kv_store.compact().await?;
+        assert!(!kv_store.cache.contains(offset(&kv_store, "a")));
+        assert_eq!(kv_store.get("b").await?.unwrap().value, b"b");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kvstore_scan() -> KVResult<()> {
+        let mut kv_store = KVStore::new(MemoryBackend::new()).await?;
+        let value = Entry::new(b"test_value".to_vec(), "text/plain");
+        for key in ["a", "b/1", "b/2", "b/3", "c"] {
+            kv_store.set(key, value.clone()).await?;
+        }
+        let scan = |prefix, after| -> Vec<String> {
+            kv_store.scan(prefix, after).map(|(key, _)| key).collect()
+        };
+        assert_eq!(scan("b/", None), ["b/1", "b/2", "b/3"]);
+        assert_eq!(scan("b/", Some("b/1")), ["b/2", "b/3"]);
+        assert_eq!(scan("b/", Some("a")), ["b/1", "b/2", "b/3"]);
+        assert!(scan("b/", Some("b/3")).is_empty());
+        assert_eq!(scan("", Some("b/3")), ["c"]);
+        assert_eq!(kv_store.scan_prefix("b/").count(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kvstore_iteration() -> KVResult<()> {
+        let mut kv_store = KVStore::new(MemoryBackend::new()).await?;
+        assert!(kv_store.is_empty());
+        let value = |mime| Entry::new(b"test_value".to_vec(), mime);
+        kv_store.set("b", value("text/plain")).await?;
+        kv_store.set("a", value("application/json")).await?;
+        kv_store.set("c", value("text/plain")).await?;
+        kv_store.remove("c").await?;
+        assert_eq!(kv_store.len(), 2);
+        assert!(!kv_store.is_empty());
+        assert!(kv_store.contains_key("a"));
+        assert!(!kv_store.contains_key("c"));
+        assert_eq!(kv_store.keys().collect::<Vec<_>>(), ["a", "b"]);
+        let mimes: Vec<_> = kv_store
+            .iter()
+            .map(|(key, info)| (key, info.mime.to_string()))
+            .collect();
+        assert_eq!(
+            mimes,
+            [
+                ("a".to_owned(), "application/json".to_owned()),
+                ("b".to_owned(), "text/plain".to_owned())
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kvstore_range() -> KVResult<()> {
+        let mut kv_store = KVStore::new(MemoryBackend::new()).await?;
+        for key in [
+            "2024-01-31",
+            "2024-02-01",
+            "2024-02-15",
+            "2024-03-01",
+            "2024-03",
+        ] {
+            kv_store
+                .set(key, Entry::new(b"test_value".to_vec(), "text/plain"))
+                .await?;
+        }
+        let keys = |iter: &mut dyn Iterator<Item = (String, &EntryInfo)>| -> Vec<String> {
+            iter.map(|(key, _)| key).collect()
+        };
+        assert_eq!(
+            keys(&mut kv_store.range("2024-02".."2024-03")),
+            ["2024-02-01", "2024-02-15"]
+        );
+        assert_eq!(
+            keys(&mut kv_store.range_rev("2024-02".."2024-03")),
+            ["2024-02-15", "2024-02-01"]
+        );
+        assert_eq!(
+            keys(&mut kv_store.range_rev("2024-02-01"..="2024-03")),
+            ["2024-03", "2024-02-15", "2024-02-01"]
+        );
+        assert_eq!(
+            keys(&mut kv_store.range((Bound::Excluded("2024-02-15"), Bound::Unbounded))),
+            ["2024-03", "2024-03-01"]
+        );
+        assert_eq!(
+            keys(&mut kv_store.range_rev(..)),
+            [
+                "2024-03-01",
+                "2024-03",
+                "2024-02-15",
+                "2024-02-01",
+                "2024-01-31"
+            ]
+        );
+        assert_eq!(keys(&mut kv_store.range(..="2024-01-31")), ["2024-01-31"]);
+        assert!(keys(&mut kv_store.range("2024-03".."2024-02")).is_empty());
+        assert!(keys(&mut kv_store.range_rev("2024-03".."2024-02")).is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kvstore_usage() -> KVResult<()> {
+        use crate::kv::usage::Usage;
+
+        let mut kv_store = KVStore::new(LogBackend::new(std::io::Cursor::new(Vec::new()))).await?;
+        let value = |len| Entry::new(vec![0; len], "application/octet-stream");
+        kv_store.set("a/1", value(7)).await?;
+        kv_store.set("a/1", value(17)).await?;
+        kv_store.set("a/2", value(7)).await?;
+        kv_store.set("b/1", value(7)).await?;
+        kv_store.remove("a/2").await?;
+        kv_store.remove_prefix("b/").await?;
+        let expected = Usage {
+            values: 1,
+            bytes: 20,
+        };
+        assert_eq!(kv_store.usage().total(), expected);
+        assert_eq!(
+            kv_store.usage().breakdown(1)[1],
+            ("a/".to_string(), expected)
+        );
+
+        let reopened = KVStore::new(kv_store.backend).await?;
+        assert_eq!(reopened.usage().breakdown(1).len(), 2);
+        assert_eq!(reopened.usage().total(), expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kvstore_key_normalization() -> KVResult<()> {
+        let mut kv_store = KVStore::with_key_normalization(
+            LogBackend::new(std::io::Cursor::new(Vec::new())),
+            KeyNormalization::Lowercase,
+        )
+        .await?;
+        let value = Entry::new(b"test_value".to_vec(), "text/plain");
+        kv_store.set("Users/Alice", value.clone()).await?;
+        kv_store.set("users/BOB", value).await?;
+        assert!(kv_store.get("USERS/alice").await?.is_some());
+        let keys: Vec<String> = kv_store.scan_prefix("USERS/").map(|(key, _)| key).collect();
+        assert_eq!(keys, ["users/alice", "users/bob"]);
+        assert!(kv_store.remove("users/bob").await?.is_some());
+        assert!(kv_store.verify(10).await?.is_consistent());
+
+        // The mode sticks with the storage, and can't be changed on reopening.
+        let mut backend = kv_store.backend;
+        let reopened = KVStore::new(backend).await?;
+        assert_eq!(reopened.key_normalization(), KeyNormalization::Lowercase);
+        assert!(reopened.get("users/ALICE").await?.is_some());
+        backend = reopened.backend;
+        assert!(matches!(
+            KVStore::with_key_normalization(backend, KeyNormalization::Nfc).await,
+            Err(KVError::KeyNormalizationMismatch { .. })
+        ));
+        Ok(())
+    }
+
+    #[cfg(feature = "zstd")]
+    #[tokio::test]
+    async fn test_kvstore_header_settings() -> KVResult<()> {
+        let backend =
+            LogBackend::new(std::io::Cursor::new(Vec::new())).with_compression_threshold(16);
+        let kv_store = KVStore::new(backend).await?;
+        let stream = kv_store.backend.into_inner();
+        let header = read_header(std::io::Cursor::new(stream.get_ref()))
+            .await?
+            .unwrap();
+        assert_eq!(header.compression_threshold, Some(16));
+        assert!(header.created_at.is_some());
+
+        let backend = LogBackend::new(stream).with_compression_threshold(32);
+        assert!(matches!(
+            KVStore::new(backend).await,
+            Err(KVError::CompressionThresholdMismatch {
+                stored: 16,
+                requested: 32
+            })
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kvstore_builder() -> KVResult<()> {
+        let value = |len| Entry::new(vec![b'a'; len], "text/plain");
+        let mut kv_store = KVStore::builder(LogBackend::new(std::io::Cursor::new(Vec::new())))
+            .compression_threshold(16)
+            .compression_level(19)
+            .sync_policy(SyncPolicy::Always)
+            .max_value_size(1024)
+            .build()
+            .await?;
+        assert_eq!(
+            kv_store.backend.compression_threshold(),
+            cfg!(feature = "zstd").then_some(16)
+        );
+        kv_store.set("a", value(1024)).await?;
+        assert!(matches!(
+            kv_store.set("b", value(1025)).await,
+            Err(KVError::ValueTooLarge { len: 1025, .. })
+        ));
+        assert!(!kv_store.sync_pending().await?);
+        #[cfg(feature = "zstd")]
+        assert!(kv_store.log_len() < 1024);
+
+        let mut read_only = KVStore::builder(kv_store.backend)
+            .read_only(true)
+            .build()
+            .await?;
+        assert!(read_only.is_read_only());
+        assert_eq!(read_only.get("a").await?.unwrap().value, value(1024).value);
+        let size = read_only.log_len();
+        assert!(matches!(
+            read_only.set("b", value(1)).await,
+            Err(KVError::ReadOnly)
+        ));
+        assert!(matches!(
+            read_only.remove("a").await,
+            Err(KVError::ReadOnly)
+        ));
+        assert!(matches!(read_only.compact().await, Err(KVError::ReadOnly)));
+        read_only.track_access_times(Some(std::time::Duration::from_secs(60)));
+        read_only.get("a").await?;
+        assert!(matches!(
+            read_only.persist_access_times().await,
+            Err(KVError::ReadOnly)
+        ));
+        assert_eq!(read_only.log_len(), size);
+
+        // Empty storage isn't initialized.
+        let read_only = KVStore::builder(LogBackend::new(std::io::Cursor::new(Vec::new())))
+            .read_only(true)
+            .build()
+            .await?;
+        assert!(read_only.backend.into_inner().get_ref().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kvstore_compact() -> KVResult<()> {
+        let mut kv_store = KVStore::new(LogBackend::new(std::io::Cursor::new(Vec::new()))).await?;
+        let value = Entry::new(b"test_value".to_vec(), "text/plain");
+        for _ in 0..10 {
+            kv_store.set("a", value.clone()).await?;
+        }
+        kv_store
+            .set(
+                "b",
+                value.clone().with_meta([("k".into(), "v".into())].into()),
+            )
+            .await?;
+        kv_store.set("c", value.clone()).await?;
+        kv_store.remove("c").await?;
+        let before = kv_store.log_len();
+
+        let report = kv_store.compact().await?;
+        assert_eq!(report.before, before);
+        assert_eq!(report.after, kv_store.log_len());
+        assert!(report.after < before);
+        assert!(kv_store.verify(10).await?.is_consistent());
+        assert_eq!(kv_store.get("b").await?.unwrap().value, b"test_value");
+        kv_store.set("d", value).await?;
+
+        let reopened = KVStore::new(kv_store.backend).await?;
+        assert_eq!(reopened.entries.len(), 3);
+        assert_eq!(reopened.get("b").await?.unwrap().meta["k"], "v");
+        assert!(reopened.get("c").await?.is_none());
+        assert!(reopened.get("d").await?.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kvstore_compact_while_writing() -> KVResult<()> {
+        let mut kv_store = KVStore::new(LogBackend::new(std::io::Cursor::new(Vec::new()))).await?;
+        let value = |value: &str| Entry::new(value.as_bytes().to_vec(), "text/plain");
+        for key in ["a", "b", "c/1", "c/2", "d"] {
+            kv_store.set(key, value("old")).await?;
+        }
+        let mut compaction = kv_store.begin_compaction().await?;
+        assert!(matches!(
+            kv_store.begin_compaction().await,
+            Err(KVError::CompactionRunning)
+        ));
+        let batch = kv_store.compaction_batch(&mut compaction).await?;
+        compaction.write(batch).await?;
+
+        // Written after the entries were copied.
+        kv_store.set("a", value("new")).await?;
+        kv_store.remove("b").await?;
+        kv_store.remove_prefix("c/").await?;
+        kv_store.set("c/3", value("new")).await?;
+        kv_store.set("e", value("new")).await?;
+        assert!(kv_store.compaction_batch(&mut compaction).await?.is_empty());
+        let report = kv_store.finish_compaction(compaction).await?;
+        assert_eq!(report.entries, 4);
+        assert!(kv_store.verify(10).await?.is_consistent());
+        assert_eq!(kv_store.get("a").await?.unwrap().value, b"new");
+
+        let reopened = KVStore::new(kv_store.backend).await?;
+        let keys: Vec<String> = reopened.keys().collect();
+        assert_eq!(keys, ["a", "c/3", "d", "e"]);
+        assert_eq!(reopened.get("a").await?.unwrap().value, b"new");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kvstore_abandoned_compaction() -> KVResult<()> {
+        let mut kv_store = KVStore::new(LogBackend::new(std::io::Cursor::new(Vec::new()))).await?;
+        kv_store
+            .set("a", Entry::new(b"a".to_vec(), "text/plain"))
+            .await?;
+        drop(kv_store.begin_compaction().await?);
+        let report = kv_store.compact().await?;
+        assert_eq!(report.entries, 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kvstore_snapshot() -> KVResult<()> {
+        let mut kv_store = KVStore::new(LogBackend::new(std::io::Cursor::new(Vec::new()))).await?;
+        kv_store
+            .set("a", Entry::new(b"old".to_vec(), "text/plain"))
+            .await?;
+        kv_store
+            .set("b", Entry::new(b"b".to_vec(), "text/plain"))
+            .await?;
+        let snapshot = kv_store.snapshot();
+        kv_store
+            .set("a", Entry::new(b"new".to_vec(), "text/plain"))
+            .await?;
+        kv_store.remove("b").await?;
+        kv_store
+            .set("c", Entry::new(b"c".to_vec(), "text/plain"))
+            .await?;
+
+        assert_eq!(
+            kv_store.peek_at(&snapshot, "a").await?.unwrap().value,
+            b"old"
+        );
+        assert_eq!(kv_store.peek_at(&snapshot, "b").await?.unwrap().value, b"b");
+        assert!(kv_store.info_at(&snapshot, "c")?.is_none());
+        let keys: Vec<_> = kv_store
+            .scan_at(&snapshot, "", None)?
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, ["a", "b"]);
+        assert_eq!(kv_store.get("a").await?.unwrap().value, b"new");
+
+        // Compacting drops the records the snapshot points to.
+        kv_store.compact().await?;
+        assert!(matches!(
+            kv_store.peek_at(&snapshot, "a").await,
+            Err(KVError::SnapshotInvalidated)
+        ));
+        let snapshot = kv_store.snapshot();
+        assert_eq!(
+            kv_store.peek_at(&snapshot, "a").await?.unwrap().value,
+            b"new"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_backed_kvstore_compact() -> KVResult<()> {
+        let path =
+            std::env::temp_dir().join(format!("kv-compaction-test-{}.db", std::process::id()));
+        _ = std::fs::remove_file(&path);
+        let mut kv_store = KVStore::new(FileBackend::open(&path).await?).await?;
+        let value = Entry::new(b"test_value".to_vec(), "text/plain");
+        for _ in 0..10 {
+            kv_store.set("a", value.clone()).await?;
+        }
+        let report = kv_store.compact().await?;
+        assert_eq!(report.entries, 1);
+        assert!(report.after < report.before);
+        assert_eq!(std::fs::metadata(&path)?.len(), report.after);
+        kv_store.set("b", value.clone()).await?;
+
+        // The sample is checked against the file as it was when it was picked.
+        let sample = kv_store.verify_sample(10).await?.unwrap();
+        kv_store.set("a", value).await?;
+        let report = sample.check().await?;
+        assert_eq!(report.checked, 2);
+        assert!(report.is_consistent());
+        drop(kv_store);
+
+        let reopened = KVStore::new(FileBackend::open(&path).await?).await?;
+        assert!(reopened.get("a").await?.is_some());
+        assert!(reopened.get("b").await?.is_some());
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_file_backed_kvstore_open() -> KVResult<()> {
+        let path = std::env::temp_dir().join(format!("kv-open-test-{}.db", std::process::id()));
+        _ = std::fs::remove_file(&path);
+        let mut options = OpenOptions::new();
+        options.create(false);
+        assert!(matches!(
+            KVStore::open_with(&path, options).await,
+            Err(KVError::IO(err)) if err.kind() == std::io::ErrorKind::NotFound
+        ));
+
+        let mut kv_store = FileBackedKVStore::open(&path).await?;
+        kv_store.set("a", Entry::new(b"1".to_vec(), "")).await?;
+        assert!(matches!(
+            KVStore::open(&path).await,
+            Err(KVError::Locked(locked)) if locked == path
+        ));
+        // The compacted file replacing the original is locked as well.
+        kv_store.compact().await?;
+        assert!(matches!(
+            KVStore::open(&path).await,
+            Err(KVError::Locked(_))
+        ));
+        drop(kv_store);
+
+        let reopened = KVStore::open(&path).await?;
+        assert!(reopened.get("a").await?.is_some());
+        assert!(matches!(
+            FileBackend::open_read_only(&path).await,
+            Err(KVError::Locked(_))
+        ));
+        drop(reopened);
+
+        // Any number of read-only stores share the file, but keep out writers.
+        let read_only = || async {
+            KVStore::builder(FileBackend::open_read_only(&path).await?)
+                .read_only(true)
+                .build()
+                .await
+        };
+        let (first, second) = (read_only().await?, read_only().await?);
+        assert!(second.get("a").await?.is_some());
+        assert!(matches!(
+            KVStore::open(&path).await,
+            Err(KVError::Locked(_))
+        ));
+        drop((first, second));
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kvstore_expiry() -> KVResult<()> {
+        use crate::kv::clock::ManualClock;
+        use std::time::Duration;
+
+        let clock = Arc::new(ManualClock::default());
+        let mut kv_store = KVStore::with_clock(
+            LogBackend::new(std::io::Cursor::new(Vec::new())),
+            clock.clone(),
+        )
+        .await?;
+        let value = Entry::new(b"test_value".to_vec(), "text/plain");
+        let expires_at = clock.now() + Duration::from_secs(60);
+        kv_store
+            .set("a", value.clone().with_expiry(expires_at))
+            .await?;
+        kv_store.set("b", value).await?;
+        assert!(kv_store.verify(10).await?.is_consistent());
+
+        let reopened = KVStore::with_clock(kv_store.backend, clock.clone()).await?;
+        assert_eq!(
+            reopened.get("a").await?.unwrap().expires_at,
+            Some(expires_at)
+        );
+        assert_eq!(reopened.info("b").unwrap().modified_at, Some(clock.now()));
+        let expiring: Vec<_> = reopened.expiring("", Duration::from_secs(60)).collect();
+        assert_eq!(expiring, [("a", expires_at)]);
+        assert_eq!(reopened.expiring("", Duration::from_secs(59)).count(), 0);
+        assert_eq!(reopened.expiring("", Duration::MAX).count(), 1);
+
+        clock.advance(Duration::from_secs(60));
+        assert!(reopened.get("a").await?.is_none());
+        assert_eq!(reopened.len(), 1);
+
+        // Expired entries are skipped when loading, even before they were removed.
+        let mut reopened = KVStore::with_clock(reopened.backend, clock.clone()).await?;
+        assert!(reopened.entries.get("a").is_none());
+
+        let expires_at = clock.now() + Duration::from_secs(1);
+        let value = Entry::new(b"test_value".to_vec(), "text/plain");
+        // Setting a key again replaces when it expires.
+        reopened
+            .set(
+                "c",
+                value
+                    .clone()
+                    .with_expiry(expires_at + Duration::from_secs(1)),
+            )
+            .await?;
+        reopened.set("c", value.with_expiry(expires_at)).await?;
+        assert_eq!(reopened.expiring("", Duration::from_secs(60)).count(), 1);
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(reopened.remove_expired().await?, 1);
+        assert_eq!(reopened.remove_expired().await?, 0);
+        assert!(reopened.entries.get("c").is_none());
+        assert!(reopened.get("b").await?.is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kvstore_pinning() -> KVResult<()> {
+        use crate::kv::clock::ManualClock;
+        use std::time::Duration;
+
+        let clock = Arc::new(ManualClock::default());
+        let mut kv_store = KVStore::with_clock(
+            LogBackend::new(std::io::Cursor::new(Vec::new())),
+            clock.clone(),
+        )
+        .await?;
+        let value = Entry::new(b"test_value".to_vec(), "text/plain");
+        let expires_at = clock.now() + Duration::from_secs(60);
+        kv_store
+            .set("a", value.clone().with_expiry(expires_at))
+            .await?;
+        assert!(!kv_store.set_pinned("b", true).await?);
+        assert!(kv_store.set_pinned("a", true).await?);
+        assert_eq!(kv_store.expiring("", Duration::from_secs(60)).count(), 0);
+
+        clock.advance(Duration::from_secs(120));
+        assert_eq!(kv_store.remove_expired().await?, 0);
+        assert!(kv_store.get("a").await?.unwrap().pinned);
+
+        // Pins survive setting a new value, compaction, and reopening the store.
+        kv_store
+            .set("a", value.clone().with_expiry(expires_at))
+            .await?;
+        kv_store.compact().await?;
+        assert!(kv_store.verify(10).await?.is_consistent());
+        let mut reopened = KVStore::with_clock(kv_store.backend, clock.clone()).await?;
+        assert!(reopened.info("a").unwrap().pinned);
+
+        assert!(reopened.set_pinned("a", false).await?);
+        assert!(reopened.get("a").await?.is_none());
+        assert_eq!(reopened.remove_expired().await?, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sync_policy_parse() {
+        for policy in [
+            SyncPolicy::Always,
+            SyncPolicy::EveryNMillis(250),
+            SyncPolicy::Never,
+        ] {
+            assert_eq!(policy.to_string().parse(), Ok(policy));
+        }
+        assert!("0ms".parse::<SyncPolicy>().is_err());
+        assert!("100".parse::<SyncPolicy>().is_err());
+        assert!("sometimes".parse::<SyncPolicy>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_kvstore_sync_pending() -> KVResult<()> {
+        let mut kv_store = KVStore::new(MemoryBackend::new()).await?;
+        kv_store.set_sync_policy(SyncPolicy::EveryNMillis(100));
+        assert!(!kv_store.sync_pending().await?);
+        let value = Entry::new(b"test_value".to_vec(), "text/plain");
+        kv_store.set("a", value.clone()).await?;
+        assert!(kv_store.sync_pending().await?);
+        assert!(!kv_store.sync_pending().await?);
+
+        // Writes which were synced right away leave nothing pending.
+        kv_store.set_sync_policy(SyncPolicy::Always);
+        kv_store.set("b", value).await?;
+        kv_store.remove("a").await?;
+        assert!(!kv_store.sync_pending().await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kvstore_access_times() -> KVResult<()> {
+        use crate::kv::clock::ManualClock;
+        use std::time::Duration;
+
+        let clock = Arc::new(ManualClock::default());
+        let mut kv_store = KVStore::with_clock(
+            LogBackend::new(std::io::Cursor::new(Vec::new())),
+            clock.clone(),
+        )
+        .await?;
+        let value = Entry::new(b"test_value".to_vec(), "text/plain");
+        kv_store.set("a", value.clone()).await?;
+        kv_store.set("b", value.clone()).await?;
+        kv_store.get("a").await?;
+        assert_eq!(kv_store.accessed_at("a"), None);
+
+        kv_store.track_access_times(Some(Duration::from_secs(60)));
+        let read_at = clock.now();
+        kv_store.get("a").await?;
+        kv_store.peek("b").await?;
+        clock.advance(Duration::from_secs(30));
+        kv_store.get("a").await?;
+        assert_eq!(kv_store.accessed_at("a"), Some(read_at));
+        assert_eq!(kv_store.accessed_at("b"), None);
+        let len = kv_store.log_len();
+        assert_eq!(kv_store.persist_access_times().await?, 1);
+        assert_eq!(kv_store.persist_access_times().await?, 0);
+        assert!(kv_store.log_len() > len);
+
+        // Setting the value again keeps the access time, as does compacting.
+        kv_store.set("a", value).await?;
+        kv_store.compact().await?;
+        let mut reopened = KVStore::with_clock(kv_store.backend, clock.clone()).await?;
+        assert_eq!(reopened.accessed_at("a"), Some(read_at));
+        assert_eq!(reopened.accessed_at("b"), None);
+        assert!(reopened.verify(10).await?.is_consistent());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kvstore_with_seed() -> KVResult<()> {
+        use std::io::Cursor;
+
+        let value = |value: &str| Entry::new(value.as_bytes().to_vec(), "text/plain");
+        let mut seed = KVStore::new(LogBackend::new(Cursor::new(Vec::new()))).await?;
+        for key in ["a", "b", "c/1", "c/2", "e"] {
+            seed.set(key, value("seed")).await?;
+        }
+        let seed = seed.backend.into_inner().into_inner();
+
+        let mut kv_store =
+            KVStore::with_seed(LogBackend::new(Cursor::new(Vec::new())), &seed[..]).await?;
+        assert_eq!(kv_store.get("a").await?.unwrap().value, b"seed");
+        kv_store.set("a", value("overlay")).await?;
+        kv_store.remove("b").await?;
+        kv_store.remove_prefix("c/").await?;
+        kv_store.set("d", value("overlay")).await?;
+        assert!(kv_store.verify(10).await?.is_consistent());
+
+        // The seed stays as it was, and the overlay is compacted without its entries.
+        kv_store.compact().await?;
+        let reopened = KVStore::with_seed(kv_store.backend, &seed[..]).await?;
+        let keys: Vec<String> = reopened.keys().collect();
+        assert_eq!(keys, ["a", "d", "e"]);
+        assert_eq!(reopened.get("a").await?.unwrap().value, b"overlay");
+
+        let overlay_only = KVStore::new(reopened.backend).await?;
+        assert!(overlay_only.get("a").await?.is_some());
+        assert!(overlay_only.get("e").await?.is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kvstore_verify() -> KVResult<()> {
+        let mut kv_store = KVStore::new(LogBackend::new(std::io::Cursor::new(Vec::new()))).await?;
+        let value = Entry::new(b"test_value".to_vec(), "text/plain");
+        kv_store.set("a", value.clone()).await?;
+        kv_store.set("b", value.clone()).await?;
+        kv_store.set("a", value).await?;
+        assert!(kv_store.verify(10).await?.is_consistent());
+
+        // The index points "b" at the record of "a".
+        kv_store.entries.get_mut("b").unwrap().location = ValueLocation::Stored(7);
+        let report = kv_store.verify(10).await?;
+        assert_eq!(report.checked, 2);
+        assert_eq!(report.mismatched, ["b"]);
+
+        // Verifying must not move the append position.
+        kv_store
+            .set("c", Entry::new(b"c".to_vec(), "text/plain"))
+            .await?;
+        let reopened = KVStore::new(kv_store.backend).await?;
+        assert_eq!(reopened.get("c").await?.unwrap().value, b"c");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kvstore_subscribe() -> KVResult<()> {
+        use tokio_stream::StreamExt;
+
+        let mut kv_store = KVStore::new(MemoryBackend::new()).await?;
+        let events = kv_store.subscribe("users/");
+        tokio::pin!(events);
+
+        let value = Entry::new(b"test_value".to_vec(), "text/plain".to_string());
+        kv_store.set("other", value.clone()).await?;
+        kv_store.set("users/42", value).await?;
+
+        match events.next().await.unwrap() {
+            ChangeEvent::Set { key, entry } => {
+                assert_eq!(key, "users/42");
+                assert_eq!(entry.value, b"test_value");
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+
+        kv_store.remove_prefix("users/4").await?;
+        assert!(matches!(
+            events.next().await.unwrap(),
+            ChangeEvent::PrefixRemoved { .. }
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_kvstore_tail() -> KVResult<()> {
+        use std::io::Cursor;
+        use tokio_stream::StreamExt;
+
+        let mut kv_store = KVStore::new(LogBackend::new(Cursor::new(Vec::new()))).await?;
+        kv_store
+            .set("a", Entry::new(b"1".to_vec(), "text/plain"))
+            .await?;
+        let records = kv_store.tail();
+        tokio::pin!(records);
+
+        let start = kv_store.log_len();
+        kv_store
+            .set("b", Entry::new(b"22".to_vec(), "text/plain"))
+            .await?;
+        kv_store.remove("a").await?;
+
+        let set = records.next().await.unwrap();
+        assert_eq!(
+            (set.sequence, set.offset, set.key.as_str(), set.kind),
+            (1, start, "b", EntryKind::Value)
+        );
+        assert_eq!(set.value_len, 2);
+        assert_eq!(codec::flag_names(set.flags), ["modified"]);
+        let removed = records.next().await.unwrap();
+        assert_eq!(removed.sequence, 2);
+        assert!(removed.offset > start);
+        assert_eq!(codec::flag_names(removed.flags), ["tombstone"]);
 
         Ok(())
     }
@@ -0,0 +1,151 @@
+//! Storage usage by key prefix, like `du` for the key space.
+//!
+//! Keys are split into levels at every `/`, so `users/alice/avatar` counts towards
+//! the prefixes `users/` and `users/alice/`. The usage of every such prefix is kept
+//! up to date as keys are set and removed, so it can be reported without a scan.
+
+use std::collections::BTreeMap;
+
+/// How much a prefix holds.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// Number of values whose keys start with the prefix.
+    pub values: usize,
+    /// Size of those keys and values in bytes.
+    pub bytes: u64,
+}
+
+/// The usage of every prefix ending in `/`, as a tree with one level per `/`.
+#[derive(Debug, Default)]
+pub struct UsageTree {
+    usage: Usage,
+    /// Keyed by the next level of the prefix, including its `/`.
+    children: BTreeMap<String, UsageTree>,
+}
+
+impl UsageTree {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The usage of all keys.
+    pub fn total(&self) -> Usage {
+        self.usage
+    }
+
+    /// Counts a value of `bytes` bytes (including the key) under `key`.
+    pub fn add(&mut self, key: &str, bytes: u64) {
+        self.usage.values += 1;
+        self.usage.bytes += bytes;
+        if let Some((level, rest)) = split_level(key) {
+            self.children
+                .entry(level.to_owned())
+                .or_default()
+                .add(rest, bytes);
+        }
+    }
+
+    /// Stops counting a value previously added with `add`. Prefixes without any values
+    /// left are dropped.
+    pub fn remove(&mut self, key: &str, bytes: u64) {
+        self.usage.values -= 1;
+        self.usage.bytes -= bytes;
+        if let Some((level, rest)) = split_level(key) {
+            if let Some(child) = self.children.get_mut(level) {
+                child.remove(rest, bytes);
+                if child.usage.values == 0 {
+                    self.children.remove(level);
+                }
+            }
+        }
+    }
+
+    /// The usage of `prefix`, which is empty or ends in `/`, like `users/alice/`.
+    /// Other prefixes aren't tracked, so they hold nothing.
+    pub fn get(&self, prefix: &str) -> Usage {
+        match split_level(prefix) {
+            Some((level, rest)) => self
+                .children
+                .get(level)
+                .map_or_else(Usage::default, |child| child.get(rest)),
+            None if prefix.is_empty() => self.usage,
+            None => Usage::default(),
+        }
+    }
+
+    /// The usage of every prefix with up to `depth` levels, in key order, starting
+    /// with the empty prefix for all keys.
+    pub fn breakdown(&self, depth: usize) -> Vec<(String, Usage)> {
+        let mut out = Vec::new();
+        self.collect(String::new(), depth, &mut out);
+        out
+    }
+
+    fn collect(&self, prefix: String, depth: usize, out: &mut Vec<(String, Usage)>) {
+        out.push((prefix.clone(), self.usage));
+        if depth == 0 {
+            return;
+        }
+        for (level, child) in &self.children {
+            child.collect(format!("{}{}", prefix, level), depth - 1, out);
+        }
+    }
+}
+
+/// Splits the first level including its `/` off the key, unless the key has no more
+/// levels.
+fn split_level(key: &str) -> Option<(&str, &str)> {
+    key.find('/').map(|index| key.split_at(index + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usage_tree() {
+        let mut tree = UsageTree::new();
+        tree.add("users/alice/avatar", 100);
+        tree.add("users/alice/name", 10);
+        tree.add("users/bob/name", 10);
+        tree.add("config", 5);
+        assert_eq!(
+            tree.total(),
+            Usage {
+                values: 4,
+                bytes: 125
+            }
+        );
+
+        let breakdown = |tree: &UsageTree, depth| -> Vec<(String, usize, u64)> {
+            tree.breakdown(depth)
+                .into_iter()
+                .map(|(prefix, usage)| (prefix, usage.values, usage.bytes))
+                .collect()
+        };
+        assert_eq!(
+            breakdown(&tree, 2),
+            [
+                ("".to_string(), 4, 125),
+                ("users/".to_string(), 3, 120),
+                ("users/alice/".to_string(), 2, 110),
+                ("users/bob/".to_string(), 1, 10),
+            ]
+        );
+        assert_eq!(breakdown(&tree, 0), [("".to_string(), 4, 125)]);
+        assert_eq!(tree.get("users/alice/").bytes, 110);
+        assert_eq!(tree.get("").values, 4);
+        assert_eq!(tree.get("users/carol/"), Usage::default());
+        assert_eq!(tree.get("users/al"), Usage::default());
+
+        tree.remove("users/bob/name", 10);
+        assert_eq!(
+            breakdown(&tree, 2),
+            [
+                ("".to_string(), 3, 115),
+                ("users/".to_string(), 2, 110),
+                ("users/alice/".to_string(), 2, 110),
+            ]
+        );
+    }
+}
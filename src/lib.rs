@@ -0,0 +1,22 @@
+//! A key-value store which persists its entries to an append-only log, for embedding
+//! into other projects without the HTTP server of the `polling-test` binary. Where the
+//! log is kept is up to a `StorageBackend`.
+//!
+//! The store is async and runs on tokio; `BlockingKVStore` wraps it for applications
+//! which don't use tokio.
+//!
+//! `auth` has the types deciding what requests to the server may do, so embedders can
+//! plug in their own `Authorizer`.
+
+pub mod auth;
+pub mod kv;
+
+pub use auth::{AllowAll, Authorizer, Decision, Grant, Identity, Operation, TokenAuthorizer};
+pub use kv::{
+    backend::{AsyncRWS, FileBackend, LogBackend, MemoryBackend, StorageBackend},
+    blocking::{BlockingKVStore, FileBackedBlockingKVStore, MemoryBackedBlockingKVStore},
+    entry::{Entry, EntryInfo, Metadata},
+    normalization::KeyNormalization,
+    result::{KVError, KVResult},
+    store::{FileBackedKVStore, KVStore, MemoryBackedKVStore, SyncPolicy},
+};
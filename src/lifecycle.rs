@@ -0,0 +1,290 @@
+//! Lifecycle rules for key prefixes, which remove values once they weren't set for a
+//! number of days, like the expiration rules of S3.
+//!
+//! Every rule is stored as an entry below `LIFECYCLE_PREFIX`, so rules survive
+//! restarts. A key is governed by the rule of the longest prefix of it which has one,
+//! and the server's own keys, which start with `_`, only by rules whose prefix does as
+//! well, e.g. `_cas/`. Pinned values are kept, as are values written before their
+//! modification time was recorded.
+//!
+//! Unlike S3, there are no rules moving values to colder storage or pruning older
+//! versions, as the store has a single tier and only keeps the latest value of a key.
+
+use std::{
+    collections::BTreeMap,
+    ops::Bound,
+    sync::{atomic::Ordering, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+
+use crate::write_queue::{RemoveOutcome, SetOutcome, WriteError};
+use crate::{authorize, check_writable, write_error_response, AppState};
+use polling_test::auth::Operation;
+use polling_test::kv::{backend::StorageBackend, entry::Entry, result::KVResult, store::KVStore};
+
+/// Lifecycle rules are stored under this prefix, followed by the key prefix they
+/// apply to.
+const LIFECYCLE_PREFIX: &str = "_lifecycle/";
+/// Most values a single rule removes per sweep, so that a sweep doesn't hold up
+/// writes for long. The rest are removed by the following sweeps.
+const MAX_REMOVALS_PER_SWEEP: usize = 1000;
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// What happens to the values under a prefix as they age.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct LifecycleRule {
+    /// Values are removed once they weren't set for this many days.
+    expire_after_days: u32,
+}
+
+impl LifecycleRule {
+    /// Whether a value set at `modified_at` is due to be removed at `now`.
+    fn expires(&self, modified_at: SystemTime, now: SystemTime) -> bool {
+        let age = Duration::from_secs(self.expire_after_days as u64 * SECONDS_PER_DAY);
+        modified_at + age <= now
+    }
+}
+
+/// The rule governing `key` and its prefix, if any, see the module documentation.
+fn rule_for<'a>(
+    rules: &'a BTreeMap<String, LifecycleRule>,
+    key: &str,
+) -> Option<(&'a str, &'a LifecycleRule)> {
+    // Prefixes of `key` sort before it, and longer ones after shorter ones.
+    rules
+        .range::<str, _>((Bound::Unbounded, Bound::Included(key)))
+        .rev()
+        .find(|(prefix, _)| key.starts_with(prefix.as_str()))
+        .filter(|(prefix, _)| !key.starts_with('_') || prefix.starts_with('_'))
+        .map(|(prefix, rule)| (prefix.as_str(), rule))
+}
+
+/// The lifecycle rules by key prefix.
+pub(crate) struct Lifecycle {
+    rules: RwLock<BTreeMap<String, LifecycleRule>>,
+}
+
+impl Lifecycle {
+    /// Loads the rules stored in the store. Entries which aren't valid rules are
+    /// skipped with an error.
+    pub(crate) async fn load<B: StorageBackend>(store: &KVStore<B>) -> KVResult<Self> {
+        let keys: Vec<String> = store
+            .scan_prefix(LIFECYCLE_PREFIX)
+            .map(|(key, _)| key)
+            .collect();
+        let mut rules = BTreeMap::new();
+        for key in keys {
+            let Some(entry) = store.peek(&key).await? else {
+                continue;
+            };
+            let prefix = &key[LIFECYCLE_PREFIX.len()..];
+            match serde_json::from_slice(&entry.value) {
+                Ok(rule) => _ = rules.insert(prefix.to_owned(), rule),
+                Err(e) => log::error!("Skipping lifecycle rule of prefix {:?}: {}", prefix, e),
+            }
+        }
+        Ok(Self {
+            rules: RwLock::new(rules),
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct PrefixRule {
+    prefix: String,
+    #[serde(flatten)]
+    rule: LifecycleRule,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct LifecycleQuery {
+    prefix: String,
+}
+
+/// Lists the lifecycle rules.
+pub(crate) async fn list_rules<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+) -> impl Responder {
+    if let Some(response) = authorize(&req, &data, Operation::Admin, "").await {
+        return response;
+    }
+    let rules = data.lifecycle.rules.read().unwrap();
+    let list: Vec<PrefixRule> = rules
+        .iter()
+        .map(|(prefix, rule)| PrefixRule {
+            prefix: prefix.clone(),
+            rule: *rule,
+        })
+        .collect();
+    HttpResponse::Ok().json(list)
+}
+
+/// Sets the rule of `prefix` to the one in the body, replacing any it had.
+pub(crate) async fn set_rule<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    query: web::Query<LifecycleQuery>,
+    rule: web::Json<LifecycleRule>,
+) -> impl Responder {
+    if let Some(response) = authorize(&req, &data, Operation::Admin, "").await {
+        return response;
+    }
+    if let Some(response) = check_writable(&data) {
+        return response;
+    }
+    let rule = rule.into_inner();
+    if rule.expire_after_days == 0 {
+        return HttpResponse::BadRequest().body("expire_after_days must be at least 1");
+    }
+    let value = serde_json::to_vec(&rule).expect("LifecycleRule always serializes");
+    let key = format!("{}{}", LIFECYCLE_PREFIX, query.prefix);
+    match data
+        .writes
+        .set(key, Entry::new(value, "application/json"), |_| true)
+        .await
+    {
+        Ok(SetOutcome::Set) => data.write_guard.record_success(),
+        Ok(SetOutcome::ConditionFailed(_)) => unreachable!("the condition always holds"),
+        Err(e) => {
+            log::error!("Error setting lifecycle rule of {:?}: {}", query.prefix, e);
+            return write_error_response(&data, &e);
+        }
+    }
+    let prefix = query.into_inner().prefix;
+    data.lifecycle
+        .rules
+        .write()
+        .unwrap()
+        .insert(prefix.clone(), rule);
+    HttpResponse::Ok().json(PrefixRule { prefix, rule })
+}
+
+/// Removes the rule of `prefix`.
+pub(crate) async fn delete_rule<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    query: web::Query<LifecycleQuery>,
+) -> impl Responder {
+    if let Some(response) = authorize(&req, &data, Operation::Admin, "").await {
+        return response;
+    }
+    if let Some(response) = check_writable(&data) {
+        return response;
+    }
+    let key = format!("{}{}", LIFECYCLE_PREFIX, query.prefix);
+    match data.writes.remove(key, |_| true).await {
+        Ok(RemoveOutcome::Removed) => data.write_guard.record_success(),
+        Ok(RemoveOutcome::NotFound) => return HttpResponse::NotFound().finish(),
+        Ok(RemoveOutcome::ConditionFailed(_)) => unreachable!("the condition always holds"),
+        Err(e) => {
+            log::error!("Error removing lifecycle rule of {:?}: {}", query.prefix, e);
+            return write_error_response(&data, &e);
+        }
+    }
+    data.lifecycle.rules.write().unwrap().remove(&query.prefix);
+    HttpResponse::NoContent().finish()
+}
+
+/// The keys which their rules say are due to be removed at `now`, along with when
+/// they were last set.
+async fn expired_keys<B: StorageBackend>(
+    data: &AppState<B>,
+    now: SystemTime,
+) -> Result<Vec<(String, SystemTime)>, WriteError> {
+    let rules = data.lifecycle.rules.read().unwrap().clone();
+    data.store
+        .run(move |store| {
+            Box::pin(async move {
+                let mut expired = Vec::new();
+                for (prefix, rule) in &rules {
+                    let governed = |key: &str| {
+                        rule_for(&rules, key).is_some_and(|(governing, _)| governing == prefix)
+                    };
+                    let due = store
+                        .scan_prefix(prefix)
+                        .filter(|(_, info)| !info.pinned)
+                        .filter_map(|(key, info)| Some((key, info.modified_at?)))
+                        .filter(|(key, modified_at)| {
+                            rule.expires(*modified_at, now) && governed(key)
+                        })
+                        .take(MAX_REMOVALS_PER_SWEEP);
+                    expired.extend(due);
+                }
+                expired
+            })
+        })
+        .await
+}
+
+/// Removes values once their lifecycle rules say so, checking them every `interval`.
+pub(crate) async fn apply_rules_periodically<B: StorageBackend>(
+    data: web::Data<AppState<B>>,
+    interval: Duration,
+) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        if !data.write_guard.allows_write() || data.frozen.load(Ordering::SeqCst) {
+            continue;
+        }
+        let expired = match expired_keys(&data, SystemTime::now()).await {
+            Ok(expired) => expired,
+            Err(e) => {
+                log::error!("Error applying lifecycle rules: {}", e);
+                continue;
+            }
+        };
+        let mut removed = 0;
+        for (key, modified_at) in expired {
+            // Values set again since they were found are younger than the rule allows.
+            let unchanged =
+                move |current: &Entry| current.modified_at == Some(modified_at) && !current.pinned;
+            match data.writes.remove(key.clone(), unchanged).await {
+                Ok(RemoveOutcome::Removed) => removed += 1,
+                Ok(_) => {}
+                Err(e) => {
+                    log::error!("Error removing {:?} by its lifecycle rule: {}", key, e);
+                    break;
+                }
+            }
+        }
+        if removed > 0 {
+            log::info!("Removed {} values by their lifecycle rules", removed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rule_for() {
+        let days = |expire_after_days| LifecycleRule { expire_after_days };
+        let rules = BTreeMap::from([
+            ("logs/".to_owned(), days(30)),
+            ("logs/debug/".to_owned(), days(1)),
+            ("".to_owned(), days(365)),
+            ("_cas/".to_owned(), days(7)),
+        ]);
+        assert_eq!(
+            rule_for(&rules, "logs/debug/1"),
+            Some(("logs/debug/", &days(1)))
+        );
+        assert_eq!(rule_for(&rules, "logs/2"), Some(("logs/", &days(30))));
+        assert_eq!(rule_for(&rules, "users/1"), Some(("", &days(365))));
+        assert_eq!(rule_for(&rules, "_cas/ab"), Some(("_cas/", &days(7))));
+        // The server's own keys aren't governed by rules for everything.
+        assert_eq!(rule_for(&rules, "_schemas/logs/"), None);
+
+        let now = SystemTime::now();
+        let day = Duration::from_secs(SECONDS_PER_DAY);
+        assert!(days(1).expires(now - day, now));
+        assert!(!days(2).expires(now - day, now));
+    }
+}
@@ -0,0 +1,112 @@
+//! Middleware configured per listener, so that one process can serve e.g. a public
+//! listener which requires a token next to a local one which doesn't.
+
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::{
+        header::{ALLOW, WWW_AUTHENTICATE},
+        Method,
+    },
+    middleware::Next,
+    web, HttpResponse,
+};
+use sha2::{Digest, Sha256};
+
+use crate::config::ListenerConfig;
+use polling_test::auth::Identity;
+
+/// Rejects the requests the listener they arrived on doesn't handle. The listener's
+/// configuration is taken from the app data.
+pub(crate) async fn enforce(
+    listener: web::Data<ListenerConfig>,
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<EitherBody<impl MessageBody>>, actix_web::Error> {
+    if let Some(token) = &listener.token {
+        let identity = Identity::from_request(req.request());
+        if !token_matches(identity.bearer_token.as_deref(), token) {
+            let response = HttpResponse::Unauthorized()
+                .insert_header((WWW_AUTHENTICATE, "Bearer"))
+                .body("Unauthorized: Missing or wrong bearer token");
+            return Ok(req.into_response(response).map_into_right_body());
+        }
+    }
+    if listener.read_only && ![Method::GET, Method::HEAD].contains(req.method()) {
+        let response = HttpResponse::MethodNotAllowed()
+            .insert_header((ALLOW, "GET, HEAD"))
+            .body("Method Not Allowed: This listener is read-only");
+        return Ok(req.into_response(response).map_into_right_body());
+    }
+    Ok(next.call(req).await?.map_into_left_body())
+}
+
+/// Compares digests of the tokens byte by byte, all of them, so that how long it takes
+/// doesn't tell how much of the token a request got right.
+fn token_matches(given: Option<&str>, token: &str) -> bool {
+    let Some(given) = given else {
+        return false;
+    };
+    let (given, token) = (Sha256::digest(given), Sha256::digest(token));
+    let diff = given
+        .iter()
+        .zip(token.iter())
+        .fold(0, |diff, (a, b)| diff | (a ^ b));
+    diff == 0
+}
+
+/// Loads the certificate chain and private key for a listener serving HTTPS.
+#[cfg(feature = "tls")]
+pub(crate) fn tls_config(tls: &crate::config::TlsConfig) -> std::io::Result<rustls::ServerConfig> {
+    use std::{fs::File, io::BufReader};
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(&tls.cert)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(&tls.key)?))?
+        .ok_or_else(|| std::io::Error::other(format!("No private key in {}", tls.key.display())))?;
+    rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(std::io::Error::other)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{http::StatusCode, middleware::from_fn, test, App};
+
+    #[actix_web::test]
+    async fn test_enforce() {
+        let listener = ListenerConfig {
+            bind: "127.0.0.1:0".to_owned(),
+            tls: None,
+            token: Some("secret".to_owned()),
+            read_only: true,
+        };
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(listener))
+                .wrap(from_fn(enforce))
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+        let status = |req: test::TestRequest| {
+            let app = &app;
+            async move { test::call_service(app, req.to_request()).await.status() }
+        };
+        let authorized =
+            || test::TestRequest::default().insert_header(("Authorization", "Bearer secret"));
+
+        assert_eq!(status(authorized()).await, StatusCode::OK);
+        assert_eq!(
+            status(test::TestRequest::default()).await,
+            StatusCode::UNAUTHORIZED
+        );
+        let wrong = test::TestRequest::default().insert_header(("Authorization", "Bearer secrets"));
+        assert_eq!(status(wrong).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            status(authorized().method(Method::POST)).await,
+            StatusCode::METHOD_NOT_ALLOWED
+        );
+    }
+}
@@ -1,15 +1,296 @@
-use kv::entry::Entry;
-use tokio::fs::File;
+use polling_test::auth::{Authorizer, Decision, Identity, Operation};
+use polling_test::kv::{
+    self,
+    backend::{FileBackend, StorageBackend},
+    clock::Clock,
+    codec::{self, to_millis},
+    entry::{Entry, EntryInfo, EntryKind, Metadata},
+    events::LogRecord,
+    normalization::KeyNormalization,
+    result::{KVError, KVResult},
+    store::{CompactionReport, KVStore, Snapshot},
+};
+use tokio::{fs::File, io::BufReader};
 
-mod kv;
+mod buckets;
+mod bulk_import;
+mod capabilities;
+mod cas;
+#[cfg(feature = "chaos")]
+mod chaos;
+mod charset;
+mod config;
+mod contention;
+mod debug;
+mod errors;
+mod health;
+mod idempotency;
+mod lifecycle;
+mod listeners;
+mod mime_policy;
+mod namespaces;
+mod prefetch;
+mod retention;
+mod scan;
+mod schemas;
+mod snapshots;
+mod soft_limits;
+mod throttle;
+mod transactions;
+mod write_guard;
+mod write_queue;
 
 use actix_web::{
-    http::header::ACCEPT, web, App, HttpMessage, HttpRequest, HttpResponse, HttpServer, Responder,
+    body::{BodySize, MessageBody},
+    http::{
+        header::{
+            ETag, EntityTag, Expires, HeaderName, HeaderValue, IfMatch, IfNoneMatch, LastModified,
+            ACCEPT, ACCEPT_CHARSET, IF_MATCH, LINK, LOCATION, RETRY_AFTER, VARY, WARNING,
+        },
+        Method,
+    },
+    middleware::from_fn,
+    web, App, HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder, HttpServer, Responder,
+    ResponseError,
+};
+use base64::prelude::{Engine, BASE64_STANDARD, BASE64_URL_SAFE_NO_PAD};
+use buckets::Buckets;
+use bulk_import::BulkImports;
+use capabilities::{check_capability, Action, Capabilities};
+use charset::{Charset, NotAcceptable};
+use clap::Parser;
+use config::ServerConfig;
+use contention::StoreActivity;
+use health::{HealthReport, WriteLatency, X_HEALTH_SCORE};
+use lifecycle::Lifecycle;
+use mime_policy::{check_content_type, MimePolicies};
+use retention::Retention;
+use schemas::SchemaRegistry;
+use serde::{Deserialize, Serialize};
+use snapshots::Snapshots;
+use soft_limits::SoftLimits;
+use std::{
+    convert::Infallible,
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime},
 };
-use std::sync::Mutex;
+use throttle::{throttle, Throttles};
+use tokio::sync::mpsc;
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use transactions::Transactions;
+use ulid::Ulid;
+use uuid::Uuid;
+use write_guard::{CircuitState, WriteGuard};
+use write_queue::{
+    Conflict, RemoveOutcome, SetOutcome, StoreHandle, StoreJobs, WriteCommands, WriteError,
+    WriteQueue, CONFLICT_VALUE_LIMIT, STORE_JOB_CAPACITY,
+};
+
+struct AppState<B: StorageBackend> {
+    /// The store is owned by the store task, which reads and maintenance like
+    /// compaction are handed to through this, see `write_queue::run_store`.
+    store: StoreHandle<B>,
+    /// The store's clock, which expiry times of written values are computed from.
+    clock: Arc<dyn Clock>,
+    /// While set, writes are rejected so that snapshots, migrations, or restores
+    /// can run against a store that doesn't change underneath them.
+    frozen: AtomicBool,
+    /// Puts the server into read-only mode while writes to the backing storage fail.
+    write_guard: WriteGuard,
+    /// How long writes to the backing storage take, reported by `/healthz`.
+    write_latency: WriteLatency,
+    /// What holds the store task, and what held it before, see `contention`.
+    store_activity: StoreActivity,
+    /// All writes go through this queue, see `write_queue::run_store`.
+    writes: WriteQueue,
+    /// Asked before every operation on the store, see `authorize`.
+    authorizer: Arc<dyn Authorizer>,
+    /// Protobuf schemas of key prefixes, see `schemas`.
+    schemas: SchemaRegistry,
+    /// Tokens confirming destructive operations, see `capabilities`.
+    capabilities: Capabilities,
+    /// Write throttles of namespaces during compaction, see `throttle`.
+    throttles: Throttles,
+    /// Lifecycle rules of key prefixes, see `lifecycle`.
+    lifecycle: Lifecycle,
+    /// Which Content-Types values of namespaces may have, see `mime_policy`.
+    mime_policies: MimePolicies,
+    /// How many keys or bytes namespaces may hold, see `retention`.
+    retention: Retention,
+    /// How close writes to namespaces may come to the limits without a warning, see
+    /// `soft_limits`.
+    soft_limits: SoftLimits,
+    /// Key spaces addressed as `/_buckets/{bucket}/{key}`, see `buckets`.
+    buckets: Buckets,
+    /// Writes buffered by open transactions, see `transactions`.
+    transactions: Transactions,
+    /// Snapshots clients read from, see `snapshots`.
+    snapshots: Snapshots,
+    /// The bulk import which is running, if any, see `bulk_import`.
+    imports: BulkImports,
+    /// Whether the store was opened read-only, so that every write is rejected, see
+    /// `ServerConfig::read_only`.
+    read_only: bool,
+    /// Largest value accepted, in bytes.
+    max_value_size: usize,
+    /// About how many bytes a listing may take up, see `ServerConfig::max_response_size`.
+    max_response_size: usize,
+    /// Whether `DELETE /_keys` needs a capability token, see
+    /// `ServerConfig::confirm_delete_prefix`.
+    confirm_delete_prefix: bool,
+}
+
+/// Asks the authorizer whether the request may perform `operation` on `key`.
+/// Returns the response to send if it may not.
+async fn authorize<B: StorageBackend>(
+    req: &HttpRequest,
+    data: &AppState<B>,
+    operation: Operation,
+    key: &str,
+) -> Option<HttpResponse> {
+    let identity = Identity::from_request(req);
+    match data
+        .authorizer
+        .authorize(identity, operation, key.to_owned())
+        .await
+    {
+        Decision::Allow => None,
+        Decision::Deny => Some(HttpResponse::Forbidden().body("Forbidden")),
+    }
+}
+
+/// Request headers starting with this set metadata on the stored entry, and are
+/// returned on reads. The rest of the header name is the metadata name.
+const META_HEADER_PREFIX: &str = "x-kv-meta-";
+/// Most metadata entries a value may have.
+const MAX_META_ENTRIES: usize = 32;
+/// Most bytes all metadata names and values of an entry may add up to.
+const MAX_META_SIZE: usize = 4096;
+
+/// Describes each limit the written value and metadata used more than `percent` of.
+/// The writes succeed, but are answered with a `Warning` header for each, so clients
+/// notice before they hit the limits, see `soft_limits`.
+fn soft_limit_warnings(
+    value_len: usize,
+    max_value_size: usize,
+    meta: &Metadata,
+    percent: u8,
+) -> Vec<String> {
+    let near = |used: usize, limit: usize| used * 100 > limit * percent as usize;
+    let meta_size: usize = meta
+        .iter()
+        .map(|(name, value)| name.len() + value.len())
+        .sum();
+    let mut warnings = Vec::new();
+    if near(value_len, max_value_size) {
+        warnings.push(format!(
+            "Value size of {} bytes is close to the limit of {} bytes",
+            value_len, max_value_size
+        ));
+    }
+    if near(meta.len(), MAX_META_ENTRIES) {
+        warnings.push(format!(
+            "{} metadata entries are close to the limit of {}",
+            meta.len(),
+            MAX_META_ENTRIES
+        ));
+    }
+    if near(meta_size, MAX_META_SIZE) {
+        warnings.push(format!(
+            "Metadata size of {} bytes is close to the limit of {} bytes",
+            meta_size, MAX_META_SIZE
+        ));
+    }
+    warnings
+}
+
+fn add_warnings(response: &mut HttpResponseBuilder, warnings: &[String]) {
+    for warning in warnings {
+        // 199 is the code for miscellaneous warnings, without an agent name.
+        response.append_header((WARNING, format!("199 - {:?}", warning)));
+    }
+}
+
+/// Collects the `X-KV-Meta-*` headers of the request. Returns why they were rejected
+/// if they're invalid or too large.
+fn metadata_from_headers(req: &HttpRequest) -> Result<Metadata, String> {
+    let mut meta = Metadata::new();
+    let mut size = 0;
+    for (name, value) in req.headers() {
+        let Some(meta_name) = name.as_str().strip_prefix(META_HEADER_PREFIX) else {
+            continue;
+        };
+        let Ok(value) = value.to_str() else {
+            return Err(format!(
+                "Invalid value for metadata {:?}: Must be visible ASCII",
+                meta_name
+            ));
+        };
+        size += meta_name.len() + value.len();
+        meta.insert(meta_name.to_owned(), value.to_owned());
+    }
+    if meta.len() > MAX_META_ENTRIES || size > MAX_META_SIZE {
+        return Err(format!(
+            "Too much metadata: At most {} entries and {} bytes are allowed",
+            MAX_META_ENTRIES, MAX_META_SIZE
+        ));
+    }
+    Ok(meta)
+}
+
+/// Header with the number of seconds after which a written value expires.
+const X_KV_TTL: &str = "x-kv-ttl";
+
+/// Longest `X-KV-TTL` accepted, 100 years, which keeps expiry times far from the
+/// largest time that can be stored.
+const MAX_TTL_SECS: u64 = 100 * 365 * 24 * 60 * 60;
+
+/// Parses the `X-KV-TTL` header of the request, if it has one.
+fn ttl_from_headers(req: &HttpRequest) -> Result<Option<Duration>, String> {
+    let Some(ttl) = req.headers().get(X_KV_TTL) else {
+        return Ok(None);
+    };
+    match ttl.to_str().ok().and_then(|ttl| ttl.parse::<u64>().ok()) {
+        Some(seconds) if seconds > 0 && seconds <= MAX_TTL_SECS => {
+            Ok(Some(Duration::from_secs(seconds)))
+        }
+        _ => Err(format!(
+            "Invalid X-KV-TTL: Must be a positive number of seconds, at most {}",
+            MAX_TTL_SECS
+        )),
+    }
+}
+
+/// When a value written now with a time to live of `ttl` expires, according to the
+/// store's clock.
+fn expiry_after<B: StorageBackend>(
+    data: &AppState<B>,
+    ttl: Duration,
+) -> Result<SystemTime, String> {
+    data.clock
+        .now()
+        .checked_add(ttl)
+        .ok_or_else(|| "Invalid X-KV-TTL: Out of range".to_owned())
+}
 
-struct AppState {
-    store: Mutex<kv::store::FileBackedKVStore>,
+/// Parses a duration like `90s`, `30m`, `1h` or `7d`. A number without a unit is a
+/// number of seconds.
+fn parse_duration(duration: &str) -> Option<Duration> {
+    let (number, unit_secs) = match duration.char_indices().last()? {
+        (i, 's') => (&duration[..i], 1),
+        (i, 'm') => (&duration[..i], 60),
+        (i, 'h') => (&duration[..i], 60 * 60),
+        (i, 'd') => (&duration[..i], 24 * 60 * 60),
+        _ => (duration, 1),
+    };
+    let secs = number.parse::<u64>().ok()?.checked_mul(unit_secs)?;
+    Some(Duration::from_secs(secs))
 }
 
 fn accept_header_matches(header: &str, mime_type: &str) -> bool {
@@ -27,6 +308,8 @@ fn accept_header_matches(header: &str, mime_type: &str) -> bool {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use actix_web::http::{header::CONTENT_TYPE, StatusCode};
+    use polling_test::kv::backend::MemoryBackend;
 
     #[test]
     fn test_accept_header_matches() {
@@ -42,86 +325,2881 @@ mod tests {
         assert!(!accept_header_matches("text/*", "application/json"));
         assert!(!accept_header_matches("text/html", "application/html"));
     }
+
+    #[test]
+    fn test_metadata_from_headers() {
+        let req = actix_web::test::TestRequest::default()
+            .insert_header(("X-KV-Meta-Uploader", "alice"))
+            .insert_header(("Content-Type", "text/plain"))
+            .to_http_request();
+        let meta = metadata_from_headers(&req).unwrap();
+        assert_eq!(meta.len(), 1);
+        assert_eq!(meta["uploader"], "alice");
+
+        let req = actix_web::test::TestRequest::default()
+            .insert_header(("X-KV-Meta-Notes", "x".repeat(MAX_META_SIZE)))
+            .to_http_request();
+        assert!(metadata_from_headers(&req).is_err());
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("90s"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30m"), Some(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_duration("1h"), Some(Duration::from_secs(60 * 60)));
+        assert_eq!(
+            parse_duration("7d"),
+            Some(Duration::from_secs(7 * 24 * 60 * 60))
+        );
+        for invalid in ["", "h", "1.5h", "-1m", "1w", "1 h"] {
+            assert_eq!(parse_duration(invalid), None);
+        }
+    }
+
+    #[test]
+    fn test_ttl_from_headers() {
+        let req = actix_web::test::TestRequest::default().to_http_request();
+        assert_eq!(ttl_from_headers(&req), Ok(None));
+        let req = actix_web::test::TestRequest::default()
+            .insert_header((X_KV_TTL, "90"))
+            .to_http_request();
+        assert_eq!(ttl_from_headers(&req), Ok(Some(Duration::from_secs(90))));
+        let req = actix_web::test::TestRequest::default()
+            .insert_header((X_KV_TTL, MAX_TTL_SECS.to_string()))
+            .to_http_request();
+        assert_eq!(
+            ttl_from_headers(&req),
+            Ok(Some(Duration::from_secs(MAX_TTL_SECS)))
+        );
+        let too_long = (MAX_TTL_SECS + 1).to_string();
+        for invalid in ["0", "-1", "1.5", "soon", &too_long, "18446744073709551615"] {
+            let req = actix_web::test::TestRequest::default()
+                .insert_header((X_KV_TTL, invalid))
+                .to_http_request();
+            assert!(ttl_from_headers(&req).is_err());
+        }
+    }
+
+    #[test]
+    fn test_etag_matches() {
+        let entry = Entry::new(b"test_value".to_vec(), "text/plain");
+        let if_match = |etag: EntityTag| IfMatch::Items(vec![etag]);
+        assert!(etag_matches(&IfMatch::Any, &entry));
+        assert!(etag_matches(
+            &if_match(EntityTag::new_strong(entry.etag())),
+            &entry
+        ));
+        assert!(!etag_matches(
+            &if_match(EntityTag::new_weak(entry.etag())),
+            &entry
+        ));
+        assert!(!etag_matches(
+            &if_match(EntityTag::new_strong("stale".to_string())),
+            &entry
+        ));
+    }
+
+    #[test]
+    fn test_write_precondition() {
+        let req = actix_web::test::TestRequest::default().to_http_request();
+        let precondition = write_precondition(&req).unwrap();
+        assert!(precondition.if_match.is_none() && !precondition.merge);
+
+        let req = actix_web::test::TestRequest::default()
+            .insert_header((X_KV_MERGE_TOKEN, "0123abcd"))
+            .to_http_request();
+        let precondition = write_precondition(&req).unwrap();
+        assert!(precondition.merge);
+        let entry = Entry::new(b"test_value".to_vec(), "text/plain");
+        assert!(!etag_matches(&precondition.if_match.unwrap(), &entry));
+
+        for (name, value) in [
+            (X_KV_ON_CONFLICT, "overwrite"),
+            (X_KV_MERGE_TOKEN, "\"quoted\""),
+        ] {
+            let req = actix_web::test::TestRequest::default()
+                .insert_header((name, value))
+                .to_http_request();
+            assert!(write_precondition(&req).is_err());
+        }
+        let req = actix_web::test::TestRequest::default()
+            .insert_header((X_KV_MERGE_TOKEN, "0123abcd"))
+            .insert_header((IF_MATCH, "\"0123abcd\""))
+            .to_http_request();
+        assert!(write_precondition(&req).is_err());
+    }
+
+    #[test]
+    fn test_default_value() {
+        let no_default = GetQuery {
+            default: None,
+            include: None,
+            snapshot: None,
+        };
+        let req = actix_web::test::TestRequest::default().to_http_request();
+        assert_eq!(default_value(&req, &no_default), Ok(None));
+
+        let req = actix_web::test::TestRequest::default()
+            .insert_header((X_KV_DEFAULT, "aGVhZGVy"))
+            .to_http_request();
+        assert_eq!(
+            default_value(&req, &no_default),
+            Ok(Some(b"header".to_vec()))
+        );
+        let query = GetQuery {
+            default: Some("cXVlcnk=".to_string()),
+            include: None,
+            snapshot: None,
+        };
+        assert_eq!(default_value(&req, &query), Ok(Some(b"query".to_vec())));
+        // "\xfb\xff" is "+/8=" in the standard alphabet.
+        let url_safe = GetQuery {
+            default: Some("-_8".to_string()),
+            include: None,
+            snapshot: None,
+        };
+        assert_eq!(default_value(&req, &url_safe), Ok(Some(vec![0xfb, 0xff])));
+
+        let invalid = GetQuery {
+            default: Some("not base64!".to_string()),
+            include: None,
+            snapshot: None,
+        };
+        assert!(default_value(&req, &invalid).is_err());
+    }
+
+    #[test]
+    fn test_include() {
+        assert_eq!(Include::parse(None), Ok(Include::Value));
+        assert_eq!(Include::parse(Some("value")), Ok(Include::Value));
+        assert_eq!(Include::parse(Some("metadata")), Ok(Include::Metadata));
+        assert_eq!(Include::parse(Some("metadata, value")), Ok(Include::Both));
+        assert!(Include::parse(Some("body")).is_err());
+    }
+
+    #[test]
+    fn test_soft_limit_warnings() {
+        let mut meta = Metadata::new();
+        let max = config::DEFAULT_MAX_VALUE_SIZE;
+        let percent = soft_limits::DEFAULT_SOFT_LIMIT_PERCENT;
+        assert!(soft_limit_warnings(1024, max, &meta, percent).is_empty());
+        assert_eq!(soft_limit_warnings(max, max, &meta, percent).len(), 1);
+        assert_eq!(soft_limit_warnings(max / 2 + 1, max, &meta, 50).len(), 1);
+        assert!(soft_limit_warnings(max, max, &meta, 100).is_empty());
+
+        meta.insert("notes".to_string(), "x".repeat(MAX_META_SIZE - 100));
+        assert_eq!(soft_limit_warnings(1024, max, &meta, percent).len(), 1);
+    }
+
+    /// The state of a server around an empty store in memory, with its store task
+    /// running.
+    async fn test_state() -> web::Data<AppState<MemoryBackend>> {
+        test_state_with(Arc::new(polling_test::auth::AllowAll)).await
+    }
+
+    /// Like `test_state`, with requests authorized by `authorizer`.
+    async fn test_state_with(
+        authorizer: Arc<dyn Authorizer>,
+    ) -> web::Data<AppState<MemoryBackend>> {
+        test_state_from(authorizer, &ServerConfig::default()).await
+    }
+
+    /// Like `test_state_with`, configured by `config`.
+    async fn test_state_from(
+        authorizer: Arc<dyn Authorizer>,
+        config: &ServerConfig,
+    ) -> web::Data<AppState<MemoryBackend>> {
+        let store = KVStore::new(MemoryBackend::new()).await.unwrap();
+        let (data, commands, jobs) = app_state(&store, authorizer, config).await.unwrap();
+        let task_data = data.clone();
+        actix_web::rt::spawn(async move {
+            let (latency, activity) = (&task_data.write_latency, &task_data.store_activity);
+            write_queue::run_store(store, latency, activity, commands, jobs).await
+        });
+        data
+    }
+
+    #[actix_web::test]
+    async fn test_path_keys() {
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(test_state().await)
+                .configure(routes::<MemoryBackend>),
+        )
+        .await;
+        let call = |req: actix_web::test::TestRequest| {
+            actix_web::test::call_service(&app, req.to_request())
+        };
+        let post = |uri: &str, body: &'static str| {
+            actix_web::test::TestRequest::post()
+                .uri(uri)
+                .insert_header((CONTENT_TYPE, "text/plain"))
+                .set_payload(body)
+        };
+        let get = |uri: &str| actix_web::test::TestRequest::get().uri(uri);
+
+        assert_eq!(call(post("/a", "parent")).await.status(), StatusCode::OK);
+        // Keys are the rest of the path, without any buckets to create first.
+        assert_eq!(
+            call(post("/users/42/avatar", "png")).await.status(),
+            StatusCode::OK
+        );
+        let res = call(get("/users/42/avatar")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(actix_web::test::read_body(res).await, "png");
+        // Whether their `/` are percent-encoded or not.
+        let res = call(get("/users%2F42%2Favatar")).await;
+        assert_eq!(actix_web::test::read_body(res).await, "png");
+        assert_eq!(call(post("/b%2F1", "plain")).await.status(), StatusCode::OK);
+        let res = call(get("/b/1")).await;
+        assert_eq!(actix_web::test::read_body(res).await, "plain");
+        // Keys ending like the pinning endpoints used to are keys like any other.
+        assert_eq!(call(post("/a/pin", "child")).await.status(), StatusCode::OK);
+        let res = call(get("/a/pin")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(actix_web::test::read_body(res).await, "child");
+
+        // The keys of buckets have paths of their own.
+        assert_eq!(
+            call(post("/_buckets/a/b", "bucket")).await.status(),
+            StatusCode::NOT_FOUND
+        );
+        let res = call(actix_web::test::TestRequest::post().uri("/_admin/buckets/a")).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(
+            call(post("/_buckets/a/b", "bucket")).await.status(),
+            StatusCode::OK
+        );
+        let res = call(get("/_buckets/a/b")).await;
+        assert_eq!(actix_web::test::read_body(res).await, "bucket");
+
+        let res = call(actix_web::test::TestRequest::post().uri("/_pin/a/pin")).await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = call(get("/a/pin?include=metadata")).await;
+        let envelope: serde_json::Value = actix_web::test::read_body_json(res).await;
+        assert_eq!(envelope["pinned"], true);
+        let res = call(get("/a?include=metadata")).await;
+        let envelope: serde_json::Value = actix_web::test::read_body_json(res).await;
+        assert_eq!(envelope["pinned"], false);
+    }
+
+    #[actix_web::test]
+    async fn test_buckets() {
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(test_state().await)
+                .configure(routes::<MemoryBackend>),
+        )
+        .await;
+        let call = |req: actix_web::test::TestRequest| {
+            actix_web::test::call_service(&app, req.to_request())
+        };
+        let post = |uri: &str, body: &'static str| {
+            actix_web::test::TestRequest::post()
+                .uri(uri)
+                .insert_header((CONTENT_TYPE, "text/plain"))
+                .set_payload(body)
+        };
+        let get = |uri: &str| actix_web::test::TestRequest::get().uri(uri);
+        let delete = |uri: &str| actix_web::test::TestRequest::delete().uri(uri);
+        let capability = |action: &str, target: &str| {
+            actix_web::test::TestRequest::post().uri(&format!(
+                "/_admin/capabilities?action={}&target={}",
+                action, target
+            ))
+        };
+
+        assert_eq!(
+            call(post("/photos/a", "outside")).await.status(),
+            StatusCode::OK
+        );
+        let res = call(post("/_admin/buckets/photos", "")).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let bucket: serde_json::Value = actix_web::test::read_body_json(res).await;
+        // Keys stored with the bucket's name as their prefix aren't its keys.
+        assert_eq!(bucket["values"], 0);
+        let res = call(post("/_admin/buckets/photos", "")).await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let res = call(post("/_admin/buckets/_keys", "")).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        assert_eq!(
+            call(post("/_buckets/photos/a", "inside")).await.status(),
+            StatusCode::OK
+        );
+        let res = call(get("/_buckets/photos/a")).await;
+        assert_eq!(actix_web::test::read_body(res).await, "inside");
+        let res = call(get("/photos/a")).await;
+        assert_eq!(actix_web::test::read_body(res).await, "outside");
+        // The keys of buckets are only written through the bucket.
+        assert_eq!(
+            call(post("/_bucket_keys/photos/a", "evil")).await.status(),
+            StatusCode::FORBIDDEN
+        );
+
+        let res = call(get("/_admin/buckets")).await;
+        let buckets: serde_json::Value = actix_web::test::read_body_json(res).await;
+        assert_eq!(buckets[0]["name"], "photos");
+        assert_eq!(buckets[0]["values"], 1);
+        let res = call(get("/_admin/buckets/photos")).await;
+        let bucket: serde_json::Value = actix_web::test::read_body_json(res).await;
+        assert_eq!(bucket["values"], 1);
+        assert!(bucket["bytes"].as_u64().unwrap() > 0);
+        let res = call(get("/_admin/buckets/videos")).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+        // Removing a namespace of the same name leaves the bucket alone.
+        let res = call(post("/_namespaces/photos", "").insert_header((X_KV_TTL, "60"))).await;
+        assert!(res.status().is_success());
+        let res = call(capability("delete_namespace", "photos")).await;
+        let token: serde_json::Value = actix_web::test::read_body_json(res).await;
+        let res = call(
+            delete("/_namespaces/photos")
+                .insert_header(("x-kv-capability", token["token"].as_str().unwrap())),
+        )
+        .await;
+        assert!(res.status().is_success());
+        assert_eq!(call(get("/photos/a")).await.status(), StatusCode::NOT_FOUND);
+        let res = call(get("/_buckets/photos/a")).await;
+        assert_eq!(actix_web::test::read_body(res).await, "inside");
+
+        // And the other way round.
+        assert_eq!(
+            call(post("/photos/a", "outside")).await.status(),
+            StatusCode::OK
+        );
+        let res = call(delete("/_admin/buckets/photos")).await;
+        assert_eq!(res.status(), StatusCode::PRECONDITION_REQUIRED);
+        let res = call(capability("delete_bucket", "photos")).await;
+        let token: serde_json::Value = actix_web::test::read_body_json(res).await;
+        let token = token["token"].as_str().unwrap().to_owned();
+        let with_token =
+            || delete("/_admin/buckets/photos").insert_header(("x-kv-capability", token.clone()));
+        assert_eq!(call(with_token()).await.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            call(get("/_buckets/photos/a")).await.status(),
+            StatusCode::NOT_FOUND
+        );
+        let res = call(get("/photos/a")).await;
+        assert_eq!(actix_web::test::read_body(res).await, "outside");
+        // The token was used up.
+        assert_eq!(call(with_token()).await.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn test_freeze_writes() {
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(test_state().await)
+                .configure(routes::<MemoryBackend>),
+        )
+        .await;
+        let call = |req: actix_web::test::TestRequest| {
+            actix_web::test::call_service(&app, req.to_request())
+        };
+        let post = |uri: &str| {
+            actix_web::test::TestRequest::post()
+                .uri(uri)
+                .insert_header((CONTENT_TYPE, "text/plain"))
+                .set_payload("value")
+        };
+        let get = |uri: &str| actix_web::test::TestRequest::get().uri(uri);
+
+        assert_eq!(call(post("/a")).await.status(), StatusCode::OK);
+        assert_eq!(call(post("/_admin/freeze")).await.status(), StatusCode::OK);
+        assert_eq!(
+            call(post("/b")).await.status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        let res = call(get("/a")).await;
+        assert_eq!(actix_web::test::read_body(res).await, "value");
+        // Compacting would replace the file while it's frozen.
+        assert_eq!(
+            call(post("/_admin/compact")).await.status(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+        let res = call(get("/readyz")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            actix_web::test::read_body(res).await,
+            "ready, writes are frozen"
+        );
+        let res = call(get("/_admin/metrics")).await;
+        let metrics = actix_web::test::read_body(res).await;
+        assert!(std::str::from_utf8(&metrics)
+            .unwrap()
+            .contains("\nkv_writes_frozen 1\n"));
+
+        assert_eq!(
+            call(post("/_admin/unfreeze")).await.status(),
+            StatusCode::OK
+        );
+        assert_eq!(call(post("/b")).await.status(), StatusCode::OK);
+        let res = call(get("/readyz")).await;
+        assert_eq!(actix_web::test::read_body(res).await, "ready");
+        let res = call(get("/_admin/metrics")).await;
+        let metrics = actix_web::test::read_body(res).await;
+        assert!(std::str::from_utf8(&metrics)
+            .unwrap()
+            .contains("\nkv_writes_frozen 0\n"));
+    }
+
+    #[actix_web::test]
+    async fn test_generated_keys() {
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(test_state().await)
+                .configure(routes::<MemoryBackend>),
+        )
+        .await;
+        let call = |req: actix_web::test::TestRequest| {
+            actix_web::test::call_service(&app, req.to_request())
+        };
+        let post = |uri: &str| {
+            actix_web::test::TestRequest::post()
+                .uri(uri)
+                .insert_header((CONTENT_TYPE, "text/plain"))
+                .set_payload("generated")
+        };
+
+        let res = call(post("/")).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let location = res
+            .headers()
+            .get(LOCATION)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_owned();
+        let created: serde_json::Value = actix_web::test::read_body_json(res).await;
+        assert_eq!(location, format!("/{}", created["key"].as_str().unwrap()));
+        let res = call(actix_web::test::TestRequest::get().uri(&location)).await;
+        assert_eq!(actix_web::test::read_body(res).await, "generated");
+
+        // Below a prefix, the Location resolves without creating anything first.
+        let res = call(post("/users/")).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let location = res
+            .headers()
+            .get(LOCATION)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_owned();
+        assert!(location.starts_with("/users/"));
+        let res = call(actix_web::test::TestRequest::get().uri(&location)).await;
+        assert_eq!(actix_web::test::read_body(res).await, "generated");
+
+        let res = call(post("/users/?id=uuid")).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let created: serde_json::Value = actix_web::test::read_body_json(res).await;
+        let key = created["key"].as_str().unwrap();
+        let id = Uuid::parse_str(key.strip_prefix("users/").unwrap()).unwrap();
+        assert_eq!(id.get_version_num(), 7);
+        assert!(created["created_at"].as_u64().unwrap() > 0);
+        assert_eq!(
+            call(post("/?id=counter")).await.status(),
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_list_keys_cut_short() {
+        let config = ServerConfig {
+            max_response_size: 150,
+            ..ServerConfig::default()
+        };
+        let state = test_state_from(Arc::new(polling_test::auth::AllowAll), &config).await;
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(state)
+                .configure(routes::<MemoryBackend>),
+        )
+        .await;
+        for key in ["a", "b", "c", "d"] {
+            let req = actix_web::test::TestRequest::post()
+                .uri(&format!("/{}", key))
+                .insert_header((CONTENT_TYPE, "text/plain"))
+                .set_payload("value");
+            actix_web::test::call_service(&app, req.to_request()).await;
+        }
+
+        let req = actix_web::test::TestRequest::get().uri("/_keys");
+        let res = actix_web::test::call_service(&app, req.to_request()).await;
+        assert_eq!(
+            res.headers().get(LINK).unwrap(),
+            "</_keys?after=b>; rel=\"next\""
+        );
+        let keys: serde_json::Value = actix_web::test::read_body_json(res).await;
+        assert_eq!(keys.as_array().unwrap().len(), 2);
+
+        // Streams are cut short at the same key, and end with where to continue.
+        let req = actix_web::test::TestRequest::get().uri("/_keys?stream=1");
+        let res = actix_web::test::call_service(&app, req.to_request()).await;
+        let body = actix_web::test::read_body(res).await;
+        let lines: Vec<serde_json::Value> = body
+            .split(|byte| *byte == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1]["key"], "b");
+        assert_eq!(lines[2]["next_cursor"], "b");
+        assert_eq!(lines[2]["next"], "/_keys?stream=1&after=b");
+
+        // The rest fits, so it isn't followed by a cursor.
+        let req = actix_web::test::TestRequest::get().uri("/_keys?stream=1&after=b");
+        let res = actix_web::test::call_service(&app, req.to_request()).await;
+        let body = actix_web::test::read_body(res).await;
+        assert_eq!(body.split(|byte| *byte == b'\n').count(), 3);
+    }
+
+    #[actix_web::test]
+    async fn test_reserved_keys() {
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(test_state().await)
+                .configure(routes::<MemoryBackend>),
+        )
+        .await;
+        let call = |req: actix_web::test::TestRequest| {
+            actix_web::test::call_service(&app, req.to_request())
+        };
+        let post = |uri: &str, body: &'static str| {
+            actix_web::test::TestRequest::post()
+                .uri(uri)
+                .insert_header((CONTENT_TYPE, "text/plain"))
+                .set_payload(body)
+        };
+        let hash = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        let res = call(post("/_cas", "hello")).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let blob = format!("/_cas/{}", hash);
+        assert_eq!(
+            call(post(&blob, "evil")).await.status(),
+            StatusCode::FORBIDDEN
+        );
+        for uri in ["/_idempotency/x", "/_throttles/users"] {
+            assert_eq!(call(post(uri, "{}")).await.status(), StatusCode::FORBIDDEN);
+        }
+        let delete = actix_web::test::TestRequest::delete().uri(&blob);
+        assert_eq!(call(delete).await.status(), StatusCode::FORBIDDEN);
+        let delete = actix_web::test::TestRequest::delete().uri("/_keys?prefix=_cas/");
+        assert_eq!(call(delete).await.status(), StatusCode::FORBIDDEN);
+        let batch = actix_web::test::TestRequest::post()
+            .uri("/_batch")
+            .set_payload(format!(
+                r#"[{{"key": "_cas/{}", "mime": "text/plain", "value": "ZXZpbA=="}}]"#,
+                hash
+            ));
+        assert_eq!(call(batch).await.status(), StatusCode::FORBIDDEN);
+
+        let res = call(actix_web::test::TestRequest::get().uri(&blob)).await;
+        assert_eq!(actix_web::test::read_body(res).await, "hello");
+    }
+
+    #[actix_web::test]
+    async fn test_idempotent_retries() {
+        let revoked = Arc::new(AtomicBool::new(false));
+        let authorizer = {
+            let revoked = revoked.clone();
+            move |_, operation, _| {
+                let denied = operation == Operation::Write && revoked.load(Ordering::Relaxed);
+                async move {
+                    if denied {
+                        Decision::Deny
+                    } else {
+                        Decision::Allow
+                    }
+                }
+            }
+        };
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(test_state_with(Arc::new(authorizer)).await)
+                .configure(routes::<MemoryBackend>),
+        )
+        .await;
+        let call = |req: actix_web::test::TestRequest| {
+            actix_web::test::call_service(&app, req.to_request())
+        };
+        let post = |ttl: &str| {
+            actix_web::test::TestRequest::post()
+                .uri("/a")
+                .insert_header((CONTENT_TYPE, "text/plain"))
+                .insert_header(("idempotency-key", "1"))
+                .insert_header((X_KV_TTL, ttl))
+                .set_payload("value")
+        };
+
+        assert_eq!(call(post("60")).await.status(), StatusCode::OK);
+        let res = call(post("60")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers().get("idempotent-replayed").unwrap(), "true");
+        // Headers which change the write make it a different request.
+        assert_eq!(
+            call(post("120")).await.status(),
+            StatusCode::UNPROCESSABLE_ENTITY
+        );
+        // Retries are authorized like the first attempt.
+        revoked.store(true, Ordering::Relaxed);
+        assert_eq!(call(post("60")).await.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn test_idempotent_writes_authorized_once() {
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let authorizer = {
+            let calls = calls.clone();
+            move |_, _, _| {
+                calls.fetch_add(1, Ordering::Relaxed);
+                async { Decision::Allow }
+            }
+        };
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(test_state_with(Arc::new(authorizer)).await)
+                .configure(routes::<MemoryBackend>),
+        )
+        .await;
+        let call = |req: actix_web::test::TestRequest| {
+            actix_web::test::call_service(&app, req.to_request())
+        };
+        let keyed = |req: actix_web::test::TestRequest, key: &str| {
+            req.insert_header((CONTENT_TYPE, "text/plain"))
+                .insert_header(("idempotency-key", key.to_owned()))
+        };
+        let authorized = |uri, req: actix_web::test::TestRequest| {
+            let before = calls.load(Ordering::Relaxed);
+            let res = call(req.uri(uri));
+            let calls = calls.clone();
+            async move {
+                assert!(res.await.status().is_success());
+                calls.load(Ordering::Relaxed) - before
+            }
+        };
+
+        let post = || keyed(actix_web::test::TestRequest::post(), "1").set_payload("value");
+        assert_eq!(authorized("/a", post()).await, 1);
+        // Retries are authorized once as well, before they're answered.
+        assert_eq!(authorized("/a", post()).await, 1);
+        let create = keyed(actix_web::test::TestRequest::post(), "2").set_payload("value");
+        assert_eq!(authorized("/users/", create).await, 1);
+        let delete = keyed(actix_web::test::TestRequest::delete(), "3");
+        assert_eq!(authorized("/a", delete).await, 1);
+        // Once for each key of a batch.
+        let batch = keyed(actix_web::test::TestRequest::post(), "4").set_payload(
+            r#"[{"key": "b", "mime": "text/plain", "value": "Yg=="},
+                {"key": "c", "mime": "text/plain", "value": "Yw=="}]"#,
+        );
+        assert_eq!(authorized("/_batch", batch).await, 2);
+    }
+
+    #[actix_web::test]
+    async fn test_merge_conflict() {
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(test_state().await)
+                .configure(routes::<MemoryBackend>),
+        )
+        .await;
+        let call = |req: actix_web::test::TestRequest| {
+            actix_web::test::call_service(&app, req.to_request())
+        };
+        let post = |value: Vec<u8>| {
+            actix_web::test::TestRequest::post()
+                .uri("/a")
+                .insert_header((CONTENT_TYPE, "application/octet-stream"))
+                .set_payload(value)
+        };
+        let merge = |value: &[u8]| {
+            post(value.to_vec())
+                .insert_header((IF_MATCH, "\"stale\""))
+                .insert_header((X_KV_ON_CONFLICT, "merge"))
+        };
+
+        assert_eq!(call(post(vec![1; 16])).await.status(), StatusCode::OK);
+        let res = call(merge(b"proposed")).await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let conflict: serde_json::Value = actix_web::test::read_body_json(res).await;
+        assert_eq!(
+            conflict["current"]["value"],
+            BASE64_STANDARD.encode([1; 16])
+        );
+        assert_eq!(conflict["proposed"]["size"], 8);
+
+        // Large values are left out, the rest is still there to merge with.
+        let large = vec![2; CONFLICT_VALUE_LIMIT + 1];
+        assert_eq!(call(post(large.clone())).await.status(), StatusCode::OK);
+        let res = call(merge(&large)).await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let conflict: serde_json::Value = actix_web::test::read_body_json(res).await;
+        for version in ["current", "proposed"] {
+            assert!(conflict[version].get("value").is_none());
+            assert_eq!(conflict[version]["size"], large.len());
+        }
+        assert!(conflict["merge_token"].is_string());
+    }
+
+    #[actix_web::test]
+    async fn test_echo_request_needs_admin() {
+        let authorizer = |_, operation, _| async move {
+            if operation == Operation::Admin {
+                Decision::Deny
+            } else {
+                Decision::Allow
+            }
+        };
+        let app = actix_web::test::init_service(
+            App::new()
+                .app_data(test_state_with(Arc::new(authorizer)).await)
+                .configure(routes::<MemoryBackend>),
+        )
+        .await;
+        let req = actix_web::test::TestRequest::get()
+            .uri("/_debug/request")
+            .to_request();
+        let res = actix_web::test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    }
+}
+
+/// Header with a base64 encoded value to return instead of 404 if the key doesn't
+/// exist, like the `default` query parameter.
+const X_KV_DEFAULT: &str = "x-kv-default";
+/// Set on responses which contain the default value instead of a stored one.
+const X_KV_DEFAULT_USED: &str = "x-kv-default-used";
+
+#[derive(Deserialize)]
+struct GetQuery {
+    /// Base64 encoded value to return if the key doesn't exist.
+    default: Option<String>,
+    /// Which parts of the entry to respond with, see `Include`.
+    include: Option<String>,
+    /// Token of the snapshot to read from, see `snapshots`.
+    snapshot: Option<String>,
+}
+
+/// The parts of an entry `GET /{key}` responds with: `value` (the default) for just
+/// the value, `metadata` for a JSON `EntryEnvelope` without the value, which doesn't
+/// read the value at all, or `value,metadata` for an envelope with both.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Include {
+    Value,
+    Metadata,
+    Both,
+}
+
+impl Include {
+    fn parse(include: Option<&str>) -> Result<Self, String> {
+        let Some(include) = include else {
+            return Ok(Include::Value);
+        };
+        let (mut value, mut metadata) = (false, false);
+        for part in include.split(',') {
+            match part.trim() {
+                "value" => value = true,
+                "metadata" => metadata = true,
+                part => {
+                    return Err(format!(
+                        "Invalid include of {:?}: Must be value, metadata, or both",
+                        part
+                    ))
+                }
+            }
+        }
+        Ok(match (value, metadata) {
+            (true, true) => Include::Both,
+            (false, true) => Include::Metadata,
+            _ => Include::Value,
+        })
+    }
+}
+
+/// An entry as JSON, for `GET /{key}?include=metadata`.
+#[derive(Serialize)]
+struct EntryEnvelope {
+    key: String,
+    mime: String,
+    size: usize,
+    etag: String,
+    meta: Metadata,
+    /// Milliseconds since the Unix epoch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    modified_at: Option<u64>,
+    /// Milliseconds since the Unix epoch.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+    /// When the value was last read, in milliseconds since the Unix epoch. Only as
+    /// precise as the configured access time granularity.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    accessed_at: Option<u64>,
+    pinned: bool,
+    /// Base64 encoded, only included with `include=value,metadata`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+}
+
+impl EntryEnvelope {
+    fn new(key: String, info: &EntryInfo, value: Option<&[u8]>) -> Self {
+        Self {
+            key,
+            mime: info.mime.to_string(),
+            size: info.value_len,
+            etag: info.etag(),
+            meta: info.meta.clone(),
+            modified_at: info.modified_at.map(to_millis),
+            expires_at: info.expires_at.map(to_millis),
+            accessed_at: None,
+            pinned: info.pinned,
+            value: value.map(|value| BASE64_STANDARD.encode(value)),
+        }
+    }
+}
+
+/// The default value the client supplied for a missing key, if any. Returns why it
+/// was rejected if it isn't valid base64.
+///
+/// URL-safe base64 without padding is accepted as well, as the `+` and `/` of the
+/// standard alphabet have to be percent-encoded in the query, where `+` means a space.
+fn default_value(req: &HttpRequest, query: &GetQuery) -> Result<Option<Vec<u8>>, String> {
+    let encoded = match (&query.default, req.headers().get(X_KV_DEFAULT)) {
+        (Some(default), _) => default.as_bytes(),
+        (None, Some(default)) => default.as_bytes(),
+        (None, None) => return Ok(None),
+    };
+    BASE64_STANDARD
+        .decode(encoded)
+        .or_else(|e| BASE64_URL_SAFE_NO_PAD.decode(encoded).map_err(|_| e))
+        .map(Some)
+        .map_err(|e| format!("Invalid default value, must be base64: {}", e))
+}
+
+/// The body of a HEAD response, which announces the length of the value it stands in
+/// for without holding it.
+struct LengthOnly(u64);
+
+impl MessageBody for LengthOnly {
+    type Error = Infallible;
+
+    fn size(&self) -> BodySize {
+        BodySize::Sized(self.0)
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<web::Bytes, Self::Error>>> {
+        Poll::Ready(None)
+    }
 }
 
-async fn get_value(
+/// Serves `GET /{key}`, and `HEAD /{key}`, which answers with the same headers but
+/// without the value. HEAD only reads the value when it's converted to another charset,
+/// or transcoded to JSON. With `include`, the entry is sent as an `EntryEnvelope`.
+/// With `snapshot`, the entry is read as it was when the snapshot was taken.
+async fn get_value<B: StorageBackend>(
     req: HttpRequest,
-    data: web::Data<AppState>,
+    data: web::Data<AppState<B>>,
     key: web::Path<String>,
-) -> impl Responder {
-    let store = data.store.lock().unwrap();
-    match store.get(&key) {
-        Some(value) => {
-            if let Some(accept_header) = req.headers().get(ACCEPT) {
-                if let Ok(accept) = accept_header.to_str() {
-                    if !accept_header_matches(accept, &value.mime) {
-                        return HttpResponse::NotAcceptable().body("Mismatched MIME type");
-                    }
+    query: web::Query<GetQuery>,
+) -> HttpResponse {
+    if let Some(response) = authorize(&req, &data, Operation::Read, &key).await {
+        return response;
+    }
+    let include = match Include::parse(query.include.as_deref()) {
+        Ok(include) => include,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    // Whether the value is read depends on the entry, so the store task decides, and
+    // reads it along with the entry. HEAD only needs the value if it's converted,
+    // which it is under the same conditions as below.
+    let head = req.method() == Method::HEAD;
+    let wants_charset = req.headers().contains_key(ACCEPT_CHARSET);
+    let accept = req
+        .headers()
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .map(str::to_owned);
+    let needs_value = move |info: &EntryInfo| match include {
+        Include::Value => {
+            !head
+                || (wants_charset && charset::is_utf8_text(&info.mime))
+                || accept
+                    .as_deref()
+                    .is_some_and(|accept| !accept_header_matches(accept, &info.mime))
+        }
+        Include::Metadata => false,
+        Include::Both => true,
+    };
+    let snapshot = match query.snapshot.as_deref() {
+        Some(token) => match data.snapshots.get(token, Instant::now()) {
+            Some(snapshot) => Some(snapshot),
+            None => return snapshots::snapshot_gone(),
+        },
+        None => None,
+    };
+    let lookup_key = key.clone();
+    let lookup = data.store.try_run(move |store| {
+        Box::pin(async move {
+            let info = match &snapshot {
+                Some(snapshot) => store.info_at(snapshot, &lookup_key)?,
+                None => store.info(&lookup_key),
+            };
+            let Some(info) = info.cloned() else {
+                return Ok(None);
+            };
+            let mut value = None;
+            if needs_value(&info) {
+                let entry = match &snapshot {
+                    Some(snapshot) => store.peek_at(snapshot, &lookup_key).await?,
+                    None => store.get(&lookup_key).await?,
+                };
+                let Some(entry) = entry else {
+                    return Ok(None);
+                };
+                value = Some(entry.value);
+            }
+            // Access times aren't part of snapshots.
+            let accessed_at = match snapshot {
+                Some(_) => None,
+                None => store.accessed_at(&lookup_key),
+            };
+            Ok(Some((info, accessed_at, value)))
+        })
+    });
+    let (info, accessed_at, mut value) = match lookup.await {
+        Ok(Some(found)) => found,
+        Ok(None) if include == Include::Value => {
+            return match default_value(&req, &query) {
+                Ok(Some(default)) => HttpResponse::Ok()
+                    .content_type("application/octet-stream")
+                    .insert_header((X_KV_DEFAULT_USED, "true"))
+                    .body(default),
+                Ok(None) => KVError::NotFound(key.into_inner()).error_response(),
+                Err(e) => HttpResponse::BadRequest().body(e),
+            };
+        }
+        Ok(None) => return KVError::NotFound(key.into_inner()).error_response(),
+        Err(e) => return e.error_response(),
+    };
+    if include != Include::Value {
+        return HttpResponse::Ok().json(EntryEnvelope {
+            accessed_at: accessed_at.map(to_millis),
+            ..EntryEnvelope::new(key.into_inner(), &info, value.as_deref())
+        });
+    }
+    let schema = data.schemas.find(&key, &info.mime);
+    // Protobuf values with a schema are transcoded for clients which only accept JSON.
+    let accept = req
+        .headers()
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok());
+    let transcode = match accept {
+        Some(accept) if !accept_header_matches(accept, &info.mime) => {
+            match schema
+                .as_ref()
+                .filter(|_| accept_header_matches(accept, "application/json"))
+            {
+                Some(schema) => Some(schema),
+                None => return HttpResponse::NotAcceptable().body("Mismatched MIME type"),
+            }
+        }
+        _ => None,
+    };
+    let mut etag = info.etag();
+    let mut content_type = match transcode {
+        Some(_) => "application/json".to_string(),
+        None => info.mime.to_string(),
+    };
+    let text = charset::is_utf8_text(&content_type);
+    let accept_charset = req
+        .headers()
+        .get(ACCEPT_CHARSET)
+        .and_then(|accept_charset| accept_charset.to_str().ok())
+        .filter(|_| text);
+    if let (Some(schema), Some(value)) = (transcode, value.as_mut()) {
+        match schema.to_json(value) {
+            Ok(json) => {
+                etag = format!("{}-json", etag);
+                *value = json;
+            }
+            Err(e) => {
+                log::error!("Error transcoding value of {:?} to JSON: {}", key, e);
+                return HttpResponse::InternalServerError().body("Error transcoding value");
+            }
+        }
+    }
+    if let (Some(accept_charset), Some(value)) = (accept_charset, value.as_mut()) {
+        match charset::convert(&content_type, value, accept_charset) {
+            Ok(Some((charset, converted))) => {
+                // Every charset is a representation of its own, with its own ETag.
+                if charset != Charset::Utf8 {
+                    etag = format!("{}-{}", etag, charset);
                 }
+                content_type = charset::with_charset(&content_type, charset);
+                *value = converted;
+            }
+            Ok(None) => {}
+            Err(NotAcceptable) => {
+                return HttpResponse::NotAcceptable().body("No acceptable charset")
             }
-            HttpResponse::Ok()
-                .content_type(value.mime.clone())
-                .body(value.value.clone())
         }
-        None => HttpResponse::NotFound().finish(),
+    }
+    let etag = EntityTag::new_strong(etag);
+    let not_modified = match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(items)) => items.iter().any(|item| item.weak_eq(&etag)),
+        None => false,
+    };
+    if not_modified {
+        return HttpResponse::NotModified()
+            .insert_header(ETag(etag))
+            .finish();
+    }
+    let mut response = HttpResponse::Ok();
+    response.content_type(content_type);
+    response.insert_header(ETag(etag));
+    if let Some(modified_at) = info.modified_at {
+        response.insert_header(LastModified(modified_at.into()));
+    }
+    if let Some(expires_at) = info.expires_at {
+        response.insert_header(Expires(expires_at.into()));
+    }
+    if text {
+        response.insert_header((VARY, "Accept-Charset"));
+    }
+    if schema.is_some() {
+        response.insert_header((VARY, "Accept"));
+    }
+    for (name, meta) in &info.meta {
+        let name = HeaderName::try_from(format!("{}{}", META_HEADER_PREFIX, name));
+        if let (Ok(name), Ok(meta)) = (name, HeaderValue::from_str(meta)) {
+            response.insert_header((name, meta));
+        }
+    }
+    match value {
+        Some(value) => response.body(value),
+        None => response.body(LengthOnly(info.value_len as u64)),
     }
 }
 
-async fn set_value(
+async fn set_value<B: StorageBackend>(
     req: HttpRequest,
-    data: web::Data<AppState>,
+    data: web::Data<AppState<B>>,
     key: web::Path<String>,
     value: web::Bytes,
+) -> HttpResponse {
+    if let Some(response) = check_reserved(&key) {
+        return response;
+    }
+    store_value(&req, &data, &key, value).await
+}
+
+/// Stores the request body under `key` like `POST /{key}`, even if `key` is reserved,
+/// which is up to the caller to allow, like for the keys of buckets.
+pub(crate) async fn store_value<B: StorageBackend>(
+    req: &HttpRequest,
+    data: &AppState<B>,
+    key: &str,
+    value: web::Bytes,
+) -> HttpResponse {
+    if let Some(response) = authorize(req, data, Operation::Write, key).await {
+        return response;
+    }
+    let body = value.clone();
+    idempotency::once(req, data, &body, async {
+        match write_value(req, data, key, value).await {
+            Ok(warnings) => {
+                let mut response = HttpResponse::Ok();
+                add_warnings(&mut response, &warnings);
+                response.finish()
+            }
+            Err(response) => response,
+        }
+    })
+    .await
+}
+
+/// Response body for values stored under a generated key.
+#[derive(Serialize)]
+struct CreatedKey {
+    key: String,
+    url: String,
+    mime: String,
+    size: usize,
+    /// Milliseconds since the Unix epoch, taken from the generated id.
+    created_at: u64,
+}
+
+/// The kind of id generated as the key of posted values. Both sort by the time they
+/// were generated.
+#[derive(Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum GeneratedId {
+    #[default]
+    Ulid,
+    /// A version 7 UUID, in its hyphenated form.
+    Uuid,
+}
+
+impl GeneratedId {
+    /// Generates a new id, returned with when it was generated in milliseconds since
+    /// the Unix epoch.
+    fn generate(self) -> (String, u64) {
+        match self {
+            GeneratedId::Ulid => {
+                let id = Ulid::new();
+                (id.to_string(), id.timestamp_ms())
+            }
+            GeneratedId::Uuid => {
+                let id = Uuid::now_v7();
+                let (seconds, nanos) = id
+                    .get_timestamp()
+                    .expect("version 7 UUIDs have a timestamp")
+                    .to_unix();
+                (
+                    id.to_string(),
+                    seconds * 1000 + u64::from(nanos) / 1_000_000,
+                )
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CreateQuery {
+    #[serde(default)]
+    id: GeneratedId,
+}
+
+/// Stores the value under a newly generated, unique key (a ULID, or a UUID with
+/// `?id=uuid`).
+async fn create_value<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    query: web::Query<CreateQuery>,
+    value: web::Bytes,
 ) -> impl Responder {
-    let mut store = data.store.lock().unwrap();
-    if req.content_type().contains("*") {
-        return HttpResponse::BadRequest().body("Invalid Content-Type: Must be non-generic");
+    create_value_with_prefix(&req, &data, "", query.id, value).await
+}
+
+/// Stores the value under a newly generated key below `{prefix}/`.
+async fn create_prefixed_value<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    prefix: web::Path<String>,
+    query: web::Query<CreateQuery>,
+    value: web::Bytes,
+) -> impl Responder {
+    create_value_with_prefix(&req, &data, &format!("{}/", prefix), query.id, value).await
+}
+
+async fn create_value_with_prefix<B: StorageBackend>(
+    req: &HttpRequest,
+    data: &AppState<B>,
+    prefix: &str,
+    id: GeneratedId,
+    value: web::Bytes,
+) -> HttpResponse {
+    if let Some(response) = check_reserved(prefix) {
+        return response;
     }
-    match store
-        .set(
-            &key,
-            Entry::new(value.to_vec(), req.content_type().to_string()),
-        )
-        .await
-    {
-        Ok(_) => (),
+    // Generated before it's known whether the request is a retry, so that retries are
+    // authorized like the first attempt, for a new key below the prefix.
+    let (id, created_at) = id.generate();
+    let key = format!("{}{}", prefix, id);
+    if let Some(response) = authorize(req, data, Operation::Write, &key).await {
+        return response;
+    }
+    let body = value.clone();
+    idempotency::once(req, data, &body, async {
+        let size = value.len();
+        let warnings = match write_value(req, data, &key, value).await {
+            Ok(warnings) => warnings,
+            Err(response) => return response,
+        };
+        let connection = req.connection_info();
+        let url = format!("{}://{}/{}", connection.scheme(), connection.host(), key);
+        let mut response = HttpResponse::Created();
+        add_warnings(&mut response, &warnings);
+        response
+            .insert_header((LOCATION, format!("/{}", key)))
+            .json(CreatedKey {
+                key,
+                url,
+                mime: req.content_type().to_string(),
+                size,
+                created_at,
+            })
+    })
+    .await
+}
+
+/// Stores the request body under `key`, using the request's Content-Type as the MIME
+/// type. Returns warnings about limits the write came close to, or the response to
+/// send if the value couldn't be stored. The callers authorize the write, and check
+/// whether `key` is reserved.
+async fn write_value<B: StorageBackend>(
+    req: &HttpRequest,
+    data: &AppState<B>,
+    key: &str,
+    value: web::Bytes,
+) -> Result<Vec<String>, HttpResponse> {
+    if let Some(response) = check_writable(data) {
+        return Err(response);
+    }
+    if let Some(response) = throttle(data, [key]).await {
+        return Err(response);
+    }
+    if let Some(response) = check_content_type(req, data, key) {
+        return Err(response);
+    }
+    let (entry, warnings) = entry_from_request(req, data, key, value)
+        .map_err(|e| HttpResponse::BadRequest().body(e))?;
+    let WritePrecondition { if_match, merge } =
+        write_precondition(req).map_err(|e| HttpResponse::BadRequest().body(e))?;
+    let condition = move |current: Option<&Entry>| match (&if_match, current) {
+        (None, _) => true,
+        (Some(if_match), Some(current)) => etag_matches(if_match, current),
+        (Some(_), None) => false,
+    };
+    let proposed = merge.then(|| entry.clone());
+    match data.writes.set(key.to_owned(), entry, condition).await {
+        Ok(SetOutcome::Set) => data.write_guard.record_success(),
+        Ok(SetOutcome::ConditionFailed(Some(conflict))) => {
+            return Err(match proposed {
+                Some(proposed) => merge_conflict(data, key, proposed).await,
+                None => precondition_failed(req, conflict),
+            })
+        }
+        Ok(SetOutcome::ConditionFailed(None)) => {
+            return Err(HttpResponse::PreconditionFailed().body("Key does not exist"))
+        }
         Err(e) => {
-            log::error!("Error setting value: {:?}", e);
-            return HttpResponse::InternalServerError().body("Error setting value");
+            log::error!("Error setting value: {}", e);
+            return Err(write_error_response(data, &e));
         }
     }
-    HttpResponse::Ok().finish()
+    Ok(warnings)
 }
 
-async fn start_server(store: kv::store::FileBackedKVStore) -> std::io::Result<()> {
-    let data = web::Data::new(AppState {
-        store: Mutex::new(store),
-    });
+/// The entry to set `key` to, made of the request's body and its headers, along with
+/// warnings about the limits it came close to. Returns why if the request is
+/// invalid.
+pub(crate) fn entry_from_request<B: StorageBackend>(
+    req: &HttpRequest,
+    data: &AppState<B>,
+    key: &str,
+    value: web::Bytes,
+) -> Result<(Entry, Vec<String>), String> {
+    if req.content_type().contains("*") {
+        return Err("Invalid Content-Type: Must be non-generic".to_owned());
+    }
+    let meta = metadata_from_headers(req)?;
+    let ttl = ttl_from_headers(req)?;
+    if let Some(schema) = data.schemas.find(key, req.content_type()) {
+        schema.validate(&value)?;
+    }
+    let percent = data.soft_limits.percent_of(key);
+    let warnings = soft_limit_warnings(value.len(), data.max_value_size, &meta, percent);
+    let mut entry = Entry::new(value.to_vec(), req.content_type().to_string()).with_meta(meta);
+    if let Some(ttl) = ttl {
+        entry = entry.with_expiry(expiry_after(data, ttl)?);
+    }
+    Ok((entry, warnings))
+}
+
+/// Removes the value for `key`. With an `If-Match` header, the value is only removed
+/// if its current ETag matches.
+async fn delete_value<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    key: web::Path<String>,
+) -> HttpResponse {
+    if let Some(response) = check_reserved(&key) {
+        return response;
+    }
+    remove_value(&req, &data, &key).await
+}
 
-    HttpServer::new(move || {
-        App::new()
-            .app_data(data.clone())
-            .route("/{key}", web::get().to(get_value))
-            .route("/{key}", web::post().to(set_value))
+/// Removes the value for `key` like `DELETE /{key}`, even if `key` is reserved, which
+/// is up to the caller to allow, like for the keys of buckets.
+pub(crate) async fn remove_value<B: StorageBackend>(
+    req: &HttpRequest,
+    data: &AppState<B>,
+    key: &str,
+) -> HttpResponse {
+    if let Some(response) = authorize(req, data, Operation::Delete, key).await {
+        return response;
+    }
+    idempotency::once(req, data, &[], async {
+        if let Some(response) = check_writable(data) {
+            return response;
+        }
+        if let Some(response) = throttle(data, [key]).await {
+            return response;
+        }
+        let if_match = match if_match_header(req) {
+            Ok(if_match) => if_match.unwrap_or(IfMatch::Any),
+            Err(e) => return HttpResponse::BadRequest().body(e),
+        };
+        let condition = move |current: &Entry| etag_matches(&if_match, current);
+        match data.writes.remove(key.to_string(), condition).await {
+            Ok(RemoveOutcome::Removed) => {
+                data.write_guard.record_success();
+                HttpResponse::NoContent().finish()
+            }
+            Ok(RemoveOutcome::NotFound) => KVError::NotFound(key.to_string()).error_response(),
+            Ok(RemoveOutcome::ConditionFailed(conflict)) => precondition_failed(req, conflict),
+            Err(e) => {
+                log::error!("Error removing value: {}", e);
+                write_error_response(data, &e)
+            }
+        }
     })
-    .bind("127.0.0.1:8080")?
-    .run()
     .await
 }
 
+/// Pins the value for `key`, so that it never expires, see `Entry::pinned`.
+async fn pin_value<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    key: web::Path<String>,
+) -> impl Responder {
+    set_pinned(&req, &data, key.into_inner(), true).await
+}
+
+/// Unpins the value for `key`. If it expired while it was pinned, it's gone right away.
+async fn unpin_value<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    key: web::Path<String>,
+) -> impl Responder {
+    set_pinned(&req, &data, key.into_inner(), false).await
+}
+
+async fn set_pinned<B: StorageBackend>(
+    req: &HttpRequest,
+    data: &AppState<B>,
+    key: String,
+    pinned: bool,
+) -> HttpResponse {
+    if let Some(response) = authorize(req, data, Operation::Write, &key).await {
+        return response;
+    }
+    if let Some(response) = check_reserved(&key) {
+        return response;
+    }
+    if let Some(response) = check_writable(data) {
+        return response;
+    }
+    if let Some(response) = throttle(data, [key.as_str()]).await {
+        return response;
+    }
+    match data.writes.set_pinned(key.clone(), pinned).await {
+        Ok(true) => {
+            data.write_guard.record_success();
+            HttpResponse::NoContent().finish()
+        }
+        Ok(false) => KVError::NotFound(key).error_response(),
+        Err(e) => {
+            log::error!("Error setting pinned = {}: {}", pinned, e);
+            write_error_response(data, &e)
+        }
+    }
+}
+
+/// The request's `If-Match` header, if it has one. Returns why it was rejected if
+/// it's invalid.
+fn if_match_header(req: &HttpRequest) -> Result<Option<IfMatch>, String> {
+    if !req.headers().contains_key(IF_MATCH) {
+        return Ok(None);
+    }
+    req.get_header::<IfMatch>()
+        .map(Some)
+        .ok_or_else(|| "Invalid If-Match header".to_string())
+}
+
+/// Header which asks for a merge conflict response instead of 412 if the write's
+/// `If-Match` doesn't match, when set to `merge`.
+const X_KV_ON_CONFLICT: &str = "x-kv-on-conflict";
+/// Header with the merge token of a merge conflict response. The write then only
+/// goes ahead if the value is still the one the conflict was about.
+const X_KV_MERGE_TOKEN: &str = "x-kv-merge-token";
+
+/// The conditions a write request sets for itself.
+struct WritePrecondition {
+    if_match: Option<IfMatch>,
+    /// Whether to respond with a merge conflict if `if_match` doesn't match.
+    merge: bool,
+}
+
+/// Collects the `If-Match`, `X-KV-On-Conflict`, and `X-KV-Merge-Token` headers of a
+/// write. Returns why they were rejected if they're invalid.
+fn write_precondition(req: &HttpRequest) -> Result<WritePrecondition, String> {
+    let if_match = if_match_header(req)?;
+    let on_conflict = req.headers().get(X_KV_ON_CONFLICT);
+    let merge = match on_conflict.map(|value| value.to_str()) {
+        None => false,
+        Some(Ok("merge")) => true,
+        Some(_) => return Err("Invalid X-KV-On-Conflict: Must be merge".to_string()),
+    };
+    let Some(token) = req.headers().get(X_KV_MERGE_TOKEN) else {
+        return Ok(WritePrecondition { if_match, merge });
+    };
+    if if_match.is_some() {
+        return Err("X-KV-Merge-Token can't be combined with If-Match".to_string());
+    }
+    // Merge tokens are ETags of the current value, without the quotes.
+    match token.to_str() {
+        Ok(token) if !token.is_empty() && token.chars().all(|c| c.is_ascii_alphanumeric()) => {
+            Ok(WritePrecondition {
+                if_match: Some(IfMatch::Items(vec![EntityTag::new_strong(
+                    token.to_owned(),
+                )])),
+                merge: true,
+            })
+        }
+        _ => Err("Invalid X-KV-Merge-Token".to_string()),
+    }
+}
+
+/// One side of a merge conflict.
+#[derive(Serialize)]
+struct Version {
+    etag: String,
+    mime: String,
+    /// Length of the value in bytes.
+    size: usize,
+    /// Base64 encoded, left out if the value is larger than `CONFLICT_VALUE_LIMIT`,
+    /// so that conflicts over large values don't make for huge responses.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+}
+
+impl Version {
+    fn new(entry: &Entry) -> Self {
+        Self {
+            etag: entry.etag(),
+            mime: entry.mime.to_string(),
+            size: entry.value.len(),
+            value: (entry.value.len() <= CONFLICT_VALUE_LIMIT)
+                .then(|| BASE64_STANDARD.encode(&entry.value)),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct MergeConflict {
+    /// Send this as `X-KV-Merge-Token` along with the merged value.
+    merge_token: String,
+    current: Version,
+    proposed: Version,
+}
+
+/// Responds to a write in merge mode whose precondition failed with both the
+/// current and the proposed value, so that the client can merge them with the
+/// version it started from.
+async fn merge_conflict<B: StorageBackend>(
+    data: &AppState<B>,
+    key: &str,
+    proposed: Entry,
+) -> HttpResponse {
+    // The conflict only holds small values, so the current value is read again. It
+    // may have changed since, but the merge token always matches the returned value.
+    let current = match data.store.peek(key.to_owned()).await {
+        Ok(Some(current)) => current,
+        Ok(None) => return HttpResponse::PreconditionFailed().body("Key does not exist"),
+        Err(e) => return e.error_response(),
+    };
+    HttpResponse::Conflict()
+        .insert_header(ETag(EntityTag::new_strong(current.etag())))
+        .json(MergeConflict {
+            merge_token: current.etag(),
+            current: Version::new(&current),
+            proposed: Version::new(&proposed),
+        })
+}
+
+/// Whether the current value satisfies an `If-Match` header, which requires a
+/// strong comparison.
+fn etag_matches(if_match: &IfMatch, current: &Entry) -> bool {
+    match if_match {
+        IfMatch::Any => true,
+        IfMatch::Items(items) => {
+            let etag = EntityTag::new_strong(current.etag());
+            items.iter().any(|item| item.strong_eq(&etag))
+        }
+    }
+}
+
+/// Responds to a write whose precondition failed with the current ETag, and the
+/// current value if it's small enough and acceptable to the client, so the client
+/// can retry without another GET.
+fn precondition_failed(req: &HttpRequest, conflict: Conflict) -> HttpResponse {
+    let mut response = HttpResponse::PreconditionFailed();
+    response.insert_header(ETag(EntityTag::new_strong(conflict.etag)));
+    let accepted = |current: &Entry| match req.headers().get(ACCEPT) {
+        Some(accept) => accept
+            .to_str()
+            .is_ok_and(|accept| accept_header_matches(accept, &current.mime)),
+        None => true,
+    };
+    match conflict.current {
+        Some(current) if accepted(&current) => {
+            let mut response = response.content_type(&*current.mime).body(current.value);
+            response.extensions_mut().insert(errors::Verbatim);
+            response
+        }
+        _ => response.body("ETag mismatch"),
+    }
+}
+
+/// How many keys are read from the store at a time while streaming a listing, so
+/// that writers aren't blocked for the whole scan.
+const LIST_BATCH_SIZE: usize = 1000;
+
+#[derive(Deserialize)]
+struct ListQuery {
+    #[serde(default)]
+    prefix: String,
+    /// `1` or `true` streams the keys as NDJSON instead of a JSON array.
+    stream: Option<String>,
+    /// Token of the snapshot to list the keys of, see `snapshots`.
+    snapshot: Option<String>,
+    /// The key to continue after, from the `Link` header of a listing cut short.
+    after: Option<String>,
+}
+
+/// An entry in a key listing.
+#[derive(Serialize)]
+struct KeyInfo {
+    key: String,
+    mime: String,
+    size: usize,
+}
+
+impl KeyInfo {
+    fn new(key: String, entry: &EntryInfo) -> Self {
+        Self {
+            key,
+            mime: entry.mime.to_string(),
+            size: entry.value_len,
+        }
+    }
+}
+
+/// Up to `limit` of the keys starting with `prefix`, which take up about `max_len`
+/// bytes at most, after `after` if given, read from `snapshot` if given. Returns
+/// whether there are more.
+fn scan_keys<B: StorageBackend>(
+    store: &KVStore<B>,
+    snapshot: Option<&Snapshot>,
+    prefix: &str,
+    after: Option<&str>,
+    limit: usize,
+    max_len: usize,
+) -> KVResult<(Vec<KeyInfo>, bool)> {
+    let len = |info: &KeyInfo| scan::listed_len(&info.key, &info.mime, None);
+    let keys = match snapshot {
+        Some(snapshot) => {
+            let keys = store.scan_at(snapshot, prefix, after)?;
+            let keys = keys.map(|(key, entry)| KeyInfo::new(key, entry));
+            scan::take_fitting(keys, limit, max_len, len)
+        }
+        None => {
+            let keys = store.scan(prefix, after);
+            let keys = keys.map(|(key, entry)| KeyInfo::new(key, entry));
+            scan::take_fitting(keys, limit, max_len, len)
+        }
+    };
+    Ok(keys)
+}
+
+/// The last line of a stream of keys which was cut short, in place of the `Link`
+/// header a stream can't send once it started.
+#[derive(Serialize)]
+struct StreamCursor {
+    /// The last key which was sent, to pass as `after`.
+    next_cursor: String,
+    /// The URL of the rest of the listing.
+    next: String,
+}
+
+/// Lists all keys starting with `prefix`, either as a JSON array, or streamed as
+/// NDJSON (one `KeyInfo` per line). Streaming reads the keys in batches while
+/// sending them, so memory use stays flat no matter how many keys match. Both are
+/// cut short once they would take up more than about `max_response_size` bytes, an
+/// array with a `Link` header pointing at the rest, a stream with a `StreamCursor`
+/// as its last line. With `snapshot`, the keys are
+/// listed as they were when the snapshot was taken, which also keeps the batches of
+/// a stream and the parts of a listing consistent with each other.
+async fn list_keys<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    query: web::Query<ListQuery>,
+) -> impl Responder {
+    if let Some(response) = authorize(&req, &data, Operation::List, &query.prefix).await {
+        return response;
+    }
+    let ListQuery {
+        prefix,
+        stream,
+        snapshot,
+        after,
+    } = query.into_inner();
+    let snapshot = match snapshot {
+        Some(token) => match data.snapshots.get(&token, Instant::now()) {
+            Some(snapshot) => Some(snapshot),
+            None => return snapshots::snapshot_gone(),
+        },
+        None => None,
+    };
+    if !matches!(stream.as_deref(), Some("1" | "true")) {
+        let max_len = data.max_response_size;
+        let keys = data.store.try_run(move |store| {
+            Box::pin(async move {
+                let snapshot = snapshot.as_deref();
+                scan_keys(
+                    store,
+                    snapshot,
+                    &prefix,
+                    after.as_deref(),
+                    usize::MAX,
+                    max_len,
+                )
+            })
+        });
+        return match keys.await {
+            Ok((keys, false)) => HttpResponse::Ok().json(keys),
+            Ok((keys, true)) => {
+                let next = scan::next_link(&req, &keys[keys.len() - 1].key);
+                HttpResponse::Ok().insert_header((LINK, next)).json(keys)
+            }
+            Err(e) => e.error_response(),
+        };
+    }
+
+    // A small channel, so that scanning only runs ahead of the client by a few batches.
+    let (sender, receiver) = mpsc::channel::<web::Bytes>(4);
+    actix_web::rt::spawn(async move {
+        let mut after = after;
+        let mut remaining = data.max_response_size;
+        loop {
+            let (prefix, start) = (prefix.clone(), after.clone());
+            let snapshot = snapshot.clone();
+            let batch = data.store.try_run(move |store| {
+                Box::pin(async move {
+                    let snapshot = snapshot.as_deref();
+                    let after = start.as_deref();
+                    scan_keys(store, snapshot, &prefix, after, LIST_BATCH_SIZE, remaining)
+                })
+            });
+            let (batch, more) = match batch.await {
+                Ok(batch) => batch,
+                Err(e) => {
+                    log::error!("Error listing keys: {}", e);
+                    break;
+                }
+            };
+            // Batches only come up short of their limit if the rest doesn't fit.
+            let cut_short = more && batch.len() < LIST_BATCH_SIZE;
+            let done = !more;
+            let mut lines = Vec::new();
+            for info in batch {
+                remaining = remaining.saturating_sub(scan::listed_len(&info.key, &info.mime, None));
+                after = Some(info.key.clone());
+                serde_json::to_writer(&mut lines, &info).expect("KeyInfo always serializes");
+                lines.push(b'\n');
+            }
+            if let Some(last) = after.as_ref().filter(|_| cut_short) {
+                let cursor = StreamCursor {
+                    next_cursor: last.clone(),
+                    next: scan::next_url(&req, last),
+                };
+                serde_json::to_writer(&mut lines, &cursor).expect("StreamCursor always serializes");
+                lines.push(b'\n');
+                _ = sender.send(lines.into()).await;
+                break;
+            }
+            // Sending only fails if the client went away.
+            if (!lines.is_empty() && sender.send(lines.into()).await.is_err()) || done {
+                break;
+            }
+        }
+    });
+    HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(ReceiverStream::new(receiver).map(Ok::<_, Infallible>))
+}
+
+#[derive(Deserialize)]
+struct ExpiringQuery {
+    /// How soon keys need to expire to be listed, e.g. `1h`, see `parse_duration`.
+    within: String,
+    #[serde(default)]
+    prefix: String,
+}
+
+#[derive(Serialize)]
+struct ExpiringKey {
+    key: String,
+    /// Milliseconds since the Unix epoch.
+    expires_at: u64,
+}
+
+/// Lists the keys starting with `prefix` which expire within the given duration,
+/// soonest first, so clients can renew leases and caches before they're gone.
+async fn list_expiring<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    query: web::Query<ExpiringQuery>,
+) -> Result<HttpResponse, WriteError> {
+    if let Some(response) = authorize(&req, &data, Operation::List, &query.prefix).await {
+        return Ok(response);
+    }
+    let Some(within) = parse_duration(&query.within) else {
+        return Ok(HttpResponse::BadRequest().body("Invalid within: Must be a duration like 1h"));
+    };
+    if SystemTime::now().checked_add(within).is_none() {
+        return Ok(HttpResponse::BadRequest().body("Invalid within: Out of range"));
+    }
+    let prefix = query.into_inner().prefix;
+    let keys = data.store.run(move |store| {
+        Box::pin(async move {
+            store
+                .expiring(&prefix, within)
+                .map(|(key, expires_at)| ExpiringKey {
+                    key: key.to_owned(),
+                    expires_at: to_millis(expires_at),
+                })
+                .collect::<Vec<_>>()
+        })
+    });
+    Ok(HttpResponse::Ok().json(keys.await?))
+}
+
+#[derive(Deserialize)]
+struct PrefixQuery {
+    prefix: String,
+}
+
+#[derive(Serialize)]
+struct RemovedKeys {
+    removed: usize,
+}
+
+/// Removes all keys starting with the given prefix, which is recorded as a single
+/// tombstone no matter how many keys it covers. Needs a capability token if
+/// `confirm_delete_prefix` is set, which is only used up if the keys were removed,
+/// see `capabilities`.
+async fn delete_prefix<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    query: web::Query<PrefixQuery>,
+) -> impl Responder {
+    if query.prefix.is_empty() {
+        return HttpResponse::BadRequest().body("Prefix must not be empty");
+    }
+    if let Some(response) = authorize(&req, &data, Operation::DeletePrefix, &query.prefix).await {
+        return response;
+    }
+    if let Some(response) = check_reserved(&query.prefix) {
+        return response;
+    }
+    if let Some(response) = check_writable(&data) {
+        return response;
+    }
+    let capability = if data.confirm_delete_prefix {
+        match check_capability(&req, &data, Action::DeletePrefix, &query.prefix) {
+            Ok(capability) => Some(capability),
+            Err(response) => return response,
+        }
+    } else {
+        None
+    };
+    match data.writes.remove_prefix(query.prefix.clone()).await {
+        Ok(removed) => {
+            if let Some(capability) = capability {
+                capability.consume();
+            }
+            data.write_guard.record_success();
+            HttpResponse::Ok().json(RemovedKeys { removed })
+        }
+        Err(e) => {
+            log::error!("Error removing prefix {:?}: {}", query.prefix, e);
+            write_error_response(&data, &e)
+        }
+    }
+}
+
+/// Largest `/_batch` request body, in bytes.
+const MAX_BATCH_SIZE: usize = 16 * 1024 * 1024;
+/// Most entries a single `/_batch` request may hold.
+const MAX_BATCH_ENTRIES: usize = 1000;
+
+/// One value of a `/_batch` request, or a line of a bulk import.
+#[derive(Deserialize)]
+struct BatchEntry {
+    key: String,
+    mime: String,
+    /// Base64 encoded, so values don't have to be UTF-8.
+    value: String,
+}
+
+#[derive(Serialize)]
+struct WrittenKeys {
+    written: usize,
+}
+
+/// Sets all keys of a JSON array of `BatchEntry`s at once, under a single lock
+/// acquisition and with a single flush of the backing storage. If any entry is
+/// rejected, none of them are written.
+async fn write_batch<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    body: web::Bytes,
+) -> impl Responder {
+    if let Some(response) = authorize_batch(&req, &data, &body).await {
+        return response;
+    }
+    idempotency::once(&req, &data, &body, async {
+        if let Some(response) = check_writable(&data) {
+            return response;
+        }
+        let batch: Vec<BatchEntry> = match serde_json::from_slice(&body) {
+            Ok(batch) => batch,
+            Err(e) => return HttpResponse::BadRequest().body(format!("Invalid batch: {}", e)),
+        };
+        if batch.len() > MAX_BATCH_ENTRIES {
+            return HttpResponse::PayloadTooLarge().body(format!(
+                "Batch of {} entries exceeds the limit of {}",
+                batch.len(),
+                MAX_BATCH_ENTRIES
+            ));
+        }
+        let mut entries = Vec::with_capacity(batch.len());
+        let mut warnings = Vec::new();
+        for batch_entry in batch {
+            let (key, entry) = match check_batch_entry(&data, batch_entry) {
+                Ok(checked) => checked,
+                Err(response) => return response,
+            };
+            let percent = data.soft_limits.percent_of(&key);
+            let meta = Metadata::new();
+            warnings.extend(
+                soft_limit_warnings(entry.value.len(), data.max_value_size, &meta, percent)
+                    .into_iter()
+                    .map(|warning| format!("{:?}: {}", key, warning)),
+            );
+            entries.push((key, entry));
+        }
+        let keys = entries.iter().map(|(key, _)| key.as_str());
+        if let Some(response) = throttle(&data, keys).await {
+            return response;
+        }
+        let written = entries.len();
+        match data.writes.set_many(entries).await {
+            Ok(()) => data.write_guard.record_success(),
+            Err(e) => {
+                log::error!("Error writing batch of {} entries: {}", written, e);
+                return write_error_response(&data, &e);
+            }
+        }
+        let mut response = HttpResponse::Ok();
+        add_warnings(&mut response, &warnings);
+        response.json(WrittenKeys { written })
+    })
+    .await
+}
+
+/// Checks that the client may write every key of the batch in `body`. Batches which
+/// don't parse are left to `write_batch` to reject.
+async fn authorize_batch<B: StorageBackend>(
+    req: &HttpRequest,
+    data: &AppState<B>,
+    body: &[u8],
+) -> Option<HttpResponse> {
+    #[derive(Deserialize)]
+    struct Key {
+        key: String,
+    }
+    let batch: Vec<Key> = serde_json::from_slice(body).ok()?;
+    for Key { key } in batch {
+        if let Some(response) = authorize(req, data, Operation::Write, &key).await {
+            return Some(response);
+        }
+    }
+    None
+}
+
+/// Checks that `entry` is valid, returning the key and the entry to set it to, or the
+/// response rejecting it. The callers authorize writing its key.
+// The response is returned by the handler right away, like those of the other checks.
+#[allow(clippy::result_large_err)]
+fn check_batch_entry<B: StorageBackend>(
+    data: &AppState<B>,
+    BatchEntry { key, mime, value }: BatchEntry,
+) -> Result<(String, Entry), HttpResponse> {
+    if key.is_empty() {
+        return Err(HttpResponse::BadRequest().body("Key must not be empty"));
+    }
+    if let Some(response) = check_reserved(&key) {
+        return Err(response);
+    }
+    if mime.contains('*') {
+        return Err(HttpResponse::BadRequest().body(format!(
+            "Invalid media type of {:?}: Must be non-generic",
+            key
+        )));
+    }
+    if let Err(e) = data.mime_policies.check(&key, &mime) {
+        return Err(HttpResponse::UnprocessableEntity().body(format!("{:?}: {}", key, e)));
+    }
+    let value = match BASE64_STANDARD.decode(value) {
+        Ok(value) => value,
+        Err(e) => {
+            return Err(HttpResponse::BadRequest()
+                .body(format!("Invalid value of {:?}, must be base64: {}", key, e)))
+        }
+    };
+    if value.len() > data.max_value_size {
+        return Err(HttpResponse::PayloadTooLarge().body(format!(
+            "Value of {:?} exceeds the limit of {} bytes",
+            key, data.max_value_size
+        )));
+    }
+    if let Some(schema) = data.schemas.find(&key, &mime) {
+        if let Err(e) = schema.validate(&value) {
+            return Err(HttpResponse::BadRequest().body(format!("{:?}: {}", key, e)));
+        }
+    }
+    Ok((key, Entry::new(value, mime)))
+}
+
+/// Keys starting with this are the server's own records, like blobs, idempotency
+/// records and throttles, which are only written through their own endpoints.
+const RESERVED_PREFIX: &str = "_";
+
+/// Returns the response to send if `key`, or the keys starting with it, are reserved,
+/// see `RESERVED_PREFIX`.
+fn check_reserved(key: &str) -> Option<HttpResponse> {
+    key.starts_with(RESERVED_PREFIX).then(|| {
+        HttpResponse::Forbidden().body(format!(
+            "Key {:?} is reserved: Keys starting with {:?} are written by the server",
+            key, RESERVED_PREFIX
+        ))
+    })
+}
+
+/// Returns the response to send if writes aren't accepted right now.
+fn check_writable<B: StorageBackend>(data: &AppState<B>) -> Option<HttpResponse> {
+    if data.read_only {
+        return Some(KVError::ReadOnly.error_response());
+    }
+    if data.frozen.load(Ordering::SeqCst) {
+        return Some(HttpResponse::ServiceUnavailable().body("Writes are frozen"));
+    }
+    if !data.write_guard.allows_write() {
+        let retry_after = data.write_guard.retry_interval().as_secs().max(1);
+        return Some(
+            HttpResponse::ServiceUnavailable()
+                .insert_header((RETRY_AFTER, retry_after.to_string()))
+                .body("Storage is read-only after write errors"),
+        );
+    }
+    None
+}
+
+/// Records a failed write and returns the response to send for it.
+fn write_error_response<B: StorageBackend>(data: &AppState<B>, error: &WriteError) -> HttpResponse {
+    if let WriteError::Store(error) = error {
+        // Rejections happen before anything is written, so they don't count against
+        // the storage.
+        let rejected = !error.status_code().is_server_error();
+        if !rejected {
+            data.write_guard.record_failure(error);
+        }
+    }
+    error.error_response()
+}
+
+async fn freeze_writes<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+) -> impl Responder {
+    if let Some(response) = authorize(&req, &data, Operation::Admin, "").await {
+        return response;
+    }
+    data.frozen.store(true, Ordering::SeqCst);
+    log::info!("Writes frozen");
+    HttpResponse::Ok().finish()
+}
+
+async fn unfreeze_writes<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+) -> impl Responder {
+    if let Some(response) = authorize(&req, &data, Operation::Admin, "").await {
+        return response;
+    }
+    data.frozen.store(false, Ordering::SeqCst);
+    log::info!("Writes unfrozen");
+    HttpResponse::Ok().finish()
+}
+
+#[derive(Serialize)]
+struct Compacted {
+    before: u64,
+    after: u64,
+    entries: usize,
+}
+
+/// Rewrites the database file with only the live entries. Not while writes are
+/// frozen, which keeps the file as it is for snapshots and restores.
+async fn compact<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+) -> Result<HttpResponse, WriteError> {
+    if let Some(response) = authorize(&req, &data, Operation::Admin, "").await {
+        return Ok(response);
+    }
+    if let Some(response) = check_writable(&data) {
+        return Ok(response);
+    }
+    let _compacting = data.throttles.compacting();
+    let report = compact_store(&data).await?;
+    Ok(HttpResponse::Ok().json(Compacted {
+        before: report.before,
+        after: report.after,
+        entries: report.entries,
+    }))
+}
+
+/// Compacts the store, see `KVStore::begin_compaction`. The store task only reads the
+/// live entries in batches, and writes and replaces the compacted copy at the end,
+/// while the rest is written in between, so that reads and writes go on meanwhile.
+async fn compact_store<B: StorageBackend>(
+    data: &AppState<B>,
+) -> Result<CompactionReport, WriteError> {
+    let mut compaction = data
+        .store
+        .try_run(|store| Box::pin(async move { store.begin_compaction().await }))
+        .await?;
+    loop {
+        let (returned, batch) = data
+            .store
+            .run(move |store| {
+                Box::pin(async move {
+                    let batch = store.compaction_batch(&mut compaction).await;
+                    (compaction, batch)
+                })
+            })
+            .await?;
+        compaction = returned;
+        let batch = batch.map_err(WriteError::Store)?;
+        if batch.is_empty() {
+            break;
+        }
+        compaction.write(batch).await.map_err(WriteError::Store)?;
+    }
+    data.store
+        .try_run(move |store| Box::pin(async move { store.finish_compaction(compaction).await }))
+        .await
+}
+
+/// How many levels of prefixes `/_admin/usage` reports without a `depth`.
+const DEFAULT_USAGE_DEPTH: usize = 1;
+
+#[derive(Deserialize)]
+struct UsageQuery {
+    depth: Option<usize>,
+}
+
+/// Usage of one prefix in the `/_admin/usage` report.
+#[derive(Serialize)]
+struct PrefixUsage {
+    prefix: String,
+    values: usize,
+    /// Size of the keys and values in bytes.
+    bytes: u64,
+}
+
+/// Reports how many values and bytes are stored under each prefix, down to `depth`
+/// levels of `/`-separated prefixes, like `du` does for directories.
+async fn usage<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    query: web::Query<UsageQuery>,
+) -> Result<HttpResponse, WriteError> {
+    if let Some(response) = authorize(&req, &data, Operation::Admin, "").await {
+        return Ok(response);
+    }
+    let depth = query.depth.unwrap_or(DEFAULT_USAGE_DEPTH);
+    let breakdown = data
+        .store
+        .run(move |store| Box::pin(async move { store.usage().breakdown(depth) }))
+        .await?;
+    let report: Vec<PrefixUsage> = breakdown
+        .into_iter()
+        .map(|(prefix, usage)| PrefixUsage {
+            prefix,
+            values: usage.values,
+            bytes: usage.bytes,
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(report))
+}
+
+/// One line of `/_admin/tail`.
+#[derive(Serialize)]
+struct TailRecord {
+    sequence: u64,
+    offset: u64,
+    operation: &'static str,
+    key: String,
+    mime: String,
+    value_len: usize,
+    flags: Vec<&'static str>,
+}
+
+impl From<LogRecord> for TailRecord {
+    fn from(record: LogRecord) -> Self {
+        let operation = match record.kind {
+            EntryKind::Value => "set",
+            EntryKind::Tombstone => "remove",
+            EntryKind::PrefixTombstone => "remove_prefix",
+            EntryKind::Touch => "touch",
+        };
+        Self {
+            sequence: record.sequence,
+            offset: record.offset,
+            operation,
+            key: record.key,
+            mime: record.mime,
+            value_len: record.value_len,
+            flags: codec::flag_names(record.flags),
+        }
+    }
+}
+
+/// Streams a description of every record appended to the database file from now on
+/// as NDJSON (one `TailRecord` per line), like `tail -f` for the database. Meant for
+/// debugging, so values aren't included.
+async fn tail_log<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+) -> Result<HttpResponse, WriteError> {
+    if let Some(response) = authorize(&req, &data, Operation::Admin, "").await {
+        return Ok(response);
+    }
+    let records = data
+        .store
+        .run(|store| Box::pin(async move { store.tail() }))
+        .await?;
+    let lines = records.map(|record| {
+        let mut line =
+            serde_json::to_vec(&TailRecord::from(record)).expect("TailRecord always serializes");
+        line.push(b'\n');
+        Ok::<_, Infallible>(web::Bytes::from(line))
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(lines))
+}
+
+/// Reports whether the node is healthy or degraded, along with a score that load
+/// balancers can use to shift traffic away before the node actually fails.
+async fn healthz<B: StorageBackend>(data: web::Data<AppState<B>>) -> impl Responder {
+    // Nothing can be read or written without it, so the node needs a restart.
+    if !data.store.is_running() {
+        return HttpResponse::ServiceUnavailable().body("The store task stopped");
+    }
+    let report = HealthReport::new(
+        data.write_latency.average(),
+        data.write_guard.state(),
+        data.write_guard.trips(),
+        data.frozen.load(Ordering::SeqCst),
+    );
+    HttpResponse::Ok()
+        .insert_header((X_HEALTH_SCORE, report.score.to_string()))
+        .json(report)
+}
+
+/// How long `/readyz` waits for the store task, which is busy while it compacts.
+const READY_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Reports whether the node is ready for traffic, which it is while the backing
+/// storage accepts writes, or is still there if it's read-only. Unlike `/healthz`, this
+/// probes the storage each time.
+async fn readyz<B: StorageBackend>(data: web::Data<AppState<B>>) -> impl Responder {
+    if data.write_guard.state() == CircuitState::Open {
+        return HttpResponse::ServiceUnavailable().body("The backing storage failed");
+    }
+    let check = data
+        .store
+        .try_run(|store| Box::pin(async move { store.check_storage().await }));
+    match tokio::time::timeout(READY_CHECK_TIMEOUT, check).await {
+        // Reads are still served, so the node stays in rotation.
+        Ok(Ok(())) if data.frozen.load(Ordering::SeqCst) => {
+            HttpResponse::Ok().body("ready, writes are frozen")
+        }
+        Ok(Ok(())) => HttpResponse::Ok().body("ready"),
+        Ok(Err(WriteError::Store(e))) if e.is_storage_full() => {
+            log::error!("Readiness check failed: {}", e);
+            HttpResponse::ServiceUnavailable().body("The backing storage is full")
+        }
+        Ok(Err(e)) => {
+            log::error!("Readiness check failed: {}", e);
+            HttpResponse::ServiceUnavailable().body("The backing storage doesn't accept writes")
+        }
+        Err(_) => HttpResponse::ServiceUnavailable().body("Timed out waiting for the store"),
+    }
+}
+
+/// Samples `sample_size` keys every `interval` and checks that the index still matches
+/// what's on disk. Backends which can be read from apart from the store are read
+/// through outside of the store task, so writes don't wait for that.
+async fn verify_store_periodically<B: StorageBackend>(
+    data: web::Data<AppState<B>>,
+    interval: Duration,
+    sample_size: usize,
+) {
+    let mut interval = tokio::time::interval(interval);
+    // The first tick completes immediately, right after the store was loaded.
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let verified = async {
+            let sample = data
+                .store
+                .try_run(move |store| {
+                    Box::pin(async move { store.verify_sample(sample_size).await })
+                })
+                .await?;
+            match sample {
+                Some(sample) => sample.check().await.map_err(WriteError::Store),
+                None => {
+                    data.store
+                        .try_run(move |store| {
+                            Box::pin(async move { store.verify(sample_size).await })
+                        })
+                        .await
+                }
+            }
+        };
+        match verified.await {
+            Ok(report) if report.is_consistent() => {
+                log::debug!("Verified {} keys against disk", report.checked)
+            }
+            Ok(report) => log::error!(
+                "Index drifted from disk: mismatched = {:?}, missing = {:?}",
+                report.mismatched,
+                report.missing
+            ),
+            Err(e) => log::error!("Error verifying store: {:?}", e),
+        }
+    }
+}
+
+/// How often expired entries are removed.
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Removes expired entries through the write queue, so that their removal is
+/// persisted. Reads already skip expired entries in between.
+async fn expire_entries_periodically<B: StorageBackend>(data: web::Data<AppState<B>>) {
+    let mut interval = tokio::time::interval(EXPIRY_INTERVAL);
+    loop {
+        interval.tick().await;
+        if data.frozen.load(Ordering::SeqCst) || !data.write_guard.allows_write() {
+            continue;
+        }
+        match data.writes.remove_expired().await {
+            Ok(0) => {}
+            Ok(removed) => log::debug!("Removed {} expired entries", removed),
+            // Busy with client writes, try again later.
+            Err(WriteError::QueueFull) => {}
+            Err(e) => log::error!("Error removing expired entries: {}", e),
+        }
+    }
+}
+
+/// Persists the access times updated by reads through the write queue, in batches,
+/// every `interval`.
+async fn persist_access_times_periodically<B: StorageBackend>(
+    data: web::Data<AppState<B>>,
+    interval: Duration,
+) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        if data.frozen.load(Ordering::SeqCst) || !data.write_guard.allows_write() {
+            continue;
+        }
+        match data.writes.persist_access_times().await {
+            Ok(0) => {}
+            Ok(persisted) => log::debug!("Persisted {} access times", persisted),
+            // Busy with client writes, try again later.
+            Err(WriteError::QueueFull) => {}
+            Err(e) => log::error!("Error persisting access times: {}", e),
+        }
+    }
+}
+
+/// Syncs writes to the backing storage every `interval`, for
+/// `SyncPolicy::EveryNMillis`.
+async fn sync_store_periodically<B: StorageBackend>(
+    data: web::Data<AppState<B>>,
+    interval: Duration,
+) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        let synced = data
+            .store
+            .try_run(|store| Box::pin(async move { store.sync_pending().await }));
+        if let Err(e) = synced.await {
+            log::error!("Error syncing store: {}", e);
+        }
+    }
+}
+
+/// How often the write guard is asked whether the backing storage should be checked.
+const STORAGE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Checks the backing storage while writes are rejected because it failed, so that
+/// writes are let through again once it recovers.
+async fn check_storage_periodically<B: StorageBackend>(data: web::Data<AppState<B>>) {
+    let mut interval = tokio::time::interval(STORAGE_CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        if !data.write_guard.check_due() {
+            continue;
+        }
+        let result = data
+            .store
+            .run(|store| Box::pin(async move { store.check_storage().await }))
+            .await;
+        match result {
+            Ok(result) => data.write_guard.record_check(result.as_ref().copied()),
+            Err(e) => log::error!("Error checking storage: {}", e),
+        }
+    }
+}
+
+/// The database file is compacted once it grows beyond the threshold and to this many
+/// times its size after the last compaction, so that a store whose live entries alone
+/// exceed the threshold isn't compacted over and over.
+const COMPACTION_GROWTH_FACTOR: u64 = 2;
+
+/// Checks the size of the database file every `interval`, and compacts it whenever
+/// it grew beyond `threshold`, unless writes are frozen or failing.
+async fn compact_store_periodically<B: StorageBackend>(
+    data: web::Data<AppState<B>>,
+    interval: Duration,
+    threshold: u64,
+) {
+    let mut interval = tokio::time::interval(interval);
+    let mut compacted_len = 0;
+    loop {
+        interval.tick().await;
+        if data.frozen.load(Ordering::SeqCst) || !data.write_guard.allows_write() {
+            continue;
+        }
+        let len = match data
+            .store
+            .run(|store| Box::pin(async move { store.log_len() }))
+            .await
+        {
+            Ok(len) => len,
+            Err(e) => {
+                log::error!("Error compacting store: {}", e);
+                continue;
+            }
+        };
+        if len < threshold.max(compacted_len * COMPACTION_GROWTH_FACTOR) {
+            continue;
+        }
+        let _compacting = data.throttles.compacting();
+        match compact_store(&data).await {
+            Ok(report) => compacted_len = report.after,
+            Err(e) => log::error!("Error compacting store: {:?}", e),
+        }
+    }
+}
+
+/// Registers the endpoints. Keys are the rest of the path, `/` included, so their
+/// routes come after every other one.
+fn routes<B: StorageBackend>(cfg: &mut web::ServiceConfig) {
+    cfg.route("/", web::post().to(create_value::<B>))
+        .route("/healthz", web::get().to(healthz::<B>))
+        .route("/readyz", web::get().to(readyz::<B>))
+        .route("/_keys", web::get().to(list_keys::<B>))
+        .route("/_cas", web::post().to(cas::store_blob::<B>))
+        .route("/_cas/{hash}", web::get().to(cas::get_blob::<B>))
+        .route("/_cas/{hash}", web::head().to(cas::get_blob::<B>))
+        .route("/_keys", web::delete().to(delete_prefix::<B>))
+        .route("/_expiring", web::get().to(list_expiring::<B>))
+        .route("/_scan", web::get().to(scan::scan::<B>))
+        .route("/_range", web::get().to(scan::range::<B>))
+        .route("/_prefetch", web::post().to(prefetch::prefetch::<B>))
+        .service(
+            web::resource("/_batch")
+                .app_data(web::PayloadConfig::new(MAX_BATCH_SIZE))
+                .route(web::post().to(write_batch::<B>)),
+        )
+        .route("/_txn/begin", web::post().to(transactions::begin::<B>))
+        .route(
+            "/_txn/{id}/keys/{key:.+}",
+            web::post().to(transactions::set_value::<B>),
+        )
+        .route(
+            "/_txn/{id}/keys/{key:.+}",
+            web::delete().to(transactions::delete_value::<B>),
+        )
+        .route(
+            "/_txn/{id}/commit",
+            web::post().to(transactions::commit::<B>),
+        )
+        .route("/_txn/{id}/abort", web::post().to(transactions::abort::<B>))
+        .route("/_snapshots", web::post().to(snapshots::take_snapshot::<B>))
+        .route(
+            "/_snapshots/{token}",
+            web::delete().to(snapshots::release_snapshot::<B>),
+        )
+        .route("/_schemas", web::get().to(schemas::list_schemas::<B>))
+        .route("/_schemas", web::post().to(schemas::register_schema::<B>))
+        .route("/_schemas", web::delete().to(schemas::delete_schema::<B>))
+        .route(
+            "/_namespaces/{name}",
+            web::post().to(namespaces::create_namespace::<B>),
+        )
+        .route(
+            "/_namespaces/{name}",
+            web::delete().to(namespaces::delete_namespace::<B>),
+        )
+        .route("/_admin/freeze", web::post().to(freeze_writes::<B>))
+        .route("/_admin/unfreeze", web::post().to(unfreeze_writes::<B>))
+        .route("/_admin/compact", web::post().to(compact::<B>))
+        .route(
+            "/_admin/bulk-import",
+            web::get().to(bulk_import::import_progress::<B>),
+        )
+        .route(
+            "/_admin/bulk-import",
+            web::post().to(bulk_import::bulk_import::<B>),
+        )
+        .route("/_admin/usage", web::get().to(usage::<B>))
+        .route("/_admin/tail", web::get().to(tail_log::<B>))
+        .route(
+            "/_admin/throttles",
+            web::get().to(throttle::list_throttles::<B>),
+        )
+        .route(
+            "/_admin/throttles/{namespace}",
+            web::post().to(throttle::set_throttle::<B>),
+        )
+        .route(
+            "/_admin/throttles/{namespace}",
+            web::delete().to(throttle::delete_throttle::<B>),
+        )
+        .route(
+            "/_admin/mime-policies",
+            web::get().to(mime_policy::list_policies::<B>),
+        )
+        .route(
+            "/_admin/mime-policies/{namespace}",
+            web::post().to(mime_policy::set_policy::<B>),
+        )
+        .route(
+            "/_admin/mime-policies/{namespace}",
+            web::delete().to(mime_policy::delete_policy::<B>),
+        )
+        .route(
+            "/_admin/soft-limits",
+            web::get().to(soft_limits::list_soft_limits::<B>),
+        )
+        .route(
+            "/_admin/soft-limits/{namespace}",
+            web::post().to(soft_limits::set_soft_limit::<B>),
+        )
+        .route(
+            "/_admin/soft-limits/{namespace}",
+            web::delete().to(soft_limits::delete_soft_limit::<B>),
+        )
+        .route("/_admin/buckets", web::get().to(buckets::list_buckets::<B>))
+        .route(
+            "/_admin/buckets/{name}",
+            web::get().to(buckets::bucket_stats::<B>),
+        )
+        .route(
+            "/_admin/buckets/{name}",
+            web::post().to(buckets::create_bucket::<B>),
+        )
+        .route(
+            "/_admin/buckets/{name}",
+            web::delete().to(buckets::delete_bucket::<B>),
+        )
+        .route(
+            "/_admin/retention",
+            web::get().to(retention::list_limits::<B>),
+        )
+        .route(
+            "/_admin/retention/{namespace}",
+            web::post().to(retention::set_limit::<B>),
+        )
+        .route(
+            "/_admin/retention/{namespace}",
+            web::delete().to(retention::delete_limit::<B>),
+        )
+        .route(
+            "/_admin/lifecycle",
+            web::get().to(lifecycle::list_rules::<B>),
+        )
+        .route(
+            "/_admin/lifecycle",
+            web::post().to(lifecycle::set_rule::<B>),
+        )
+        .route(
+            "/_admin/lifecycle",
+            web::delete().to(lifecycle::delete_rule::<B>),
+        )
+        .route(
+            "/_admin/capabilities",
+            web::post().to(capabilities::issue_capability::<B>),
+        )
+        .route(
+            "/_debug/contention",
+            web::get().to(contention::contention::<B>),
+        )
+        .route("/_admin/metrics", web::get().to(contention::metrics::<B>))
+        .route("/_debug/request", web::route().to(debug::echo_request::<B>));
+    #[cfg(feature = "chaos")]
+    cfg.route("/_admin/chaos", web::get().to(chaos::get_chaos::<B>))
+        .route("/_admin/chaos", web::put().to(chaos::set_chaos::<B>))
+        .route("/_admin/chaos", web::delete().to(chaos::reset_chaos::<B>));
+    cfg.route("/_pin/{key:.+}", web::post().to(pin_value::<B>))
+        .route("/_unpin/{key:.+}", web::post().to(unpin_value::<B>))
+        .route("/{prefix:.+}/", web::post().to(create_prefixed_value::<B>))
+        .route(
+            "/_buckets/{bucket}/{key:.+}",
+            web::get().to(buckets::get_value::<B>),
+        )
+        .route(
+            "/_buckets/{bucket}/{key:.+}",
+            web::head().to(buckets::get_value::<B>),
+        )
+        .route(
+            "/_buckets/{bucket}/{key:.+}",
+            web::post().to(buckets::set_value::<B>),
+        )
+        .route(
+            "/_buckets/{bucket}/{key:.+}",
+            web::delete().to(buckets::delete_value::<B>),
+        )
+        .route("/{key:.+}", web::get().to(get_value::<B>))
+        .route("/{key:.+}", web::head().to(get_value::<B>))
+        .route("/{key:.+}", web::post().to(set_value::<B>))
+        .route("/{key:.+}", web::delete().to(delete_value::<B>));
+}
+
+/// The state of the handlers, for the `store` which is then owned by the store task,
+/// along with what the store task receives, see `write_queue::run_store`.
+async fn app_state<B: StorageBackend>(
+    store: &KVStore<B>,
+    authorizer: Arc<dyn Authorizer>,
+    config: &ServerConfig,
+) -> std::io::Result<(web::Data<AppState<B>>, WriteCommands, StoreJobs<B>)> {
+    let schemas = SchemaRegistry::load(store)
+        .await
+        .map_err(std::io::Error::other)?;
+    let throttles = Throttles::load(store)
+        .await
+        .map_err(std::io::Error::other)?;
+    let lifecycle = Lifecycle::load(store)
+        .await
+        .map_err(std::io::Error::other)?;
+    let mime_policies = MimePolicies::load(store)
+        .await
+        .map_err(std::io::Error::other)?;
+    let retention = Retention::load(store)
+        .await
+        .map_err(std::io::Error::other)?;
+    let soft_limits = SoftLimits::load(store)
+        .await
+        .map_err(std::io::Error::other)?;
+    let buckets = Buckets::load(store).await.map_err(std::io::Error::other)?;
+    let (writes, commands) = WriteQueue::new(config.write_queue_capacity);
+    let (handle, jobs) = StoreHandle::new(STORE_JOB_CAPACITY);
+    let data = web::Data::new(AppState {
+        store: handle,
+        clock: store.clock(),
+        frozen: AtomicBool::new(false),
+        write_guard: WriteGuard::new(
+            config.write_failure_threshold,
+            Duration::from_secs(config.write_retry_interval),
+        ),
+        write_latency: WriteLatency::new(),
+        store_activity: StoreActivity::new(),
+        writes,
+        authorizer,
+        schemas,
+        capabilities: Capabilities::new(),
+        throttles,
+        lifecycle,
+        mime_policies,
+        retention,
+        soft_limits,
+        buckets,
+        transactions: Transactions::new(),
+        snapshots: Snapshots::new(Duration::from_secs(config.snapshot_ttl)),
+        imports: BulkImports::new(),
+        read_only: config.read_only,
+        max_value_size: config.max_value_size,
+        max_response_size: config.max_response_size,
+        confirm_delete_prefix: config.confirm_delete_prefix,
+    });
+    Ok((data, commands, jobs))
+}
+
+async fn start_server<B: StorageBackend>(
+    store: KVStore<B>,
+    authorizer: Arc<dyn Authorizer>,
+    config: &ServerConfig,
+) -> std::io::Result<()> {
+    let (data, commands, jobs) = app_state(&store, authorizer, config).await?;
+    actix_web::rt::spawn(verify_store_periodically(
+        data.clone(),
+        Duration::from_secs(config.verify_interval),
+        config.verify_sample_size,
+    ));
+    // The other tasks write to the store.
+    if !config.read_only {
+        actix_web::rt::spawn(compact_store_periodically(
+            data.clone(),
+            Duration::from_secs(config.compaction_check_interval),
+            config.compaction_threshold,
+        ));
+        actix_web::rt::spawn(expire_entries_periodically(data.clone()));
+        actix_web::rt::spawn(persist_access_times_periodically(
+            data.clone(),
+            Duration::from_secs(config.access_time_persist_interval),
+        ));
+        actix_web::rt::spawn(check_storage_periodically(data.clone()));
+        actix_web::rt::spawn(namespaces::remove_expired_namespaces_periodically(
+            data.clone(),
+        ));
+        actix_web::rt::spawn(lifecycle::apply_rules_periodically(
+            data.clone(),
+            Duration::from_secs(config.lifecycle_sweep_interval),
+        ));
+        actix_web::rt::spawn(retention::enforce_limits_periodically(data.clone()));
+    }
+    if let Some(interval) = config.sync.interval() {
+        actix_web::rt::spawn(sync_store_periodically(data.clone(), interval));
+    }
+    {
+        let data = data.clone();
+        actix_web::rt::spawn(async move {
+            let (latency, activity) = (&data.write_latency, &data.store_activity);
+            write_queue::run_store(store, latency, activity, commands, jobs).await
+        });
+    }
+
+    let max_value_size = config.max_value_size;
+    let mut servers = Vec::new();
+    for listener in config.listeners() {
+        let app_data = data.clone();
+        let listener_data = web::Data::new(listener.clone());
+        let server = HttpServer::new(move || {
+            let app = App::new()
+                .app_data(app_data.clone())
+                .app_data(listener_data.clone())
+                .app_data(web::PayloadConfig::new(max_value_size))
+                .wrap(from_fn(listeners::enforce))
+                .wrap(from_fn(debug::request_id))
+                .configure(routes::<B>);
+            #[cfg(feature = "chaos")]
+            let app = app.wrap(from_fn(chaos::delay_requests));
+            // Outermost, so that errors of the other middleware are negotiated too.
+            app.wrap(from_fn(errors::negotiate))
+        });
+        let server = match config.workers {
+            Some(workers) => server.workers(workers),
+            None => server,
+        };
+        let server = match &listener.tls {
+            None => server.bind(&listener.bind)?,
+            #[cfg(feature = "tls")]
+            Some(tls) => server.bind_rustls_0_23(&listener.bind, listeners::tls_config(tls)?)?,
+            #[cfg(not(feature = "tls"))]
+            Some(_) => {
+                return Err(std::io::Error::other(format!(
+                    "Can't serve HTTPS on {} without the tls feature",
+                    listener.bind
+                )))
+            }
+        };
+        servers.push(actix_web::rt::spawn(server.run()));
+    }
+    // Each runs until SIGINT or SIGTERM, after which it stops accepting connections,
+    // and waits for the requests being handled.
+    for server in servers {
+        server.await.map_err(std::io::Error::other)??;
+    }
+    log::info!("Flushing the store before shutting down");
+    data.writes
+        .flush()
+        .await
+        .map_err(|e| std::io::Error::other(e.to_string()))
+}
+
+/// Settings given here take precedence over the environment and the configuration
+/// file, see `config`.
+#[derive(Parser)]
+#[command(version, about)]
+struct Args {
+    /// Configuration file to read, `$KV_CONFIG` or else `./kv-api.toml` if it exists.
+    #[arg(long)]
+    config: Option<PathBuf>,
+    /// Address and port to listen on [default: 127.0.0.1:8080]
+    #[arg(long)]
+    bind: Option<String>,
+    /// Path of the database file, which is created if it doesn't exist [default: ./test.db]
+    #[arg(long)]
+    db: Option<PathBuf>,
+    /// Path of a database to load read-only underneath the database file, which then
+    /// only holds the changes made on top of it
+    #[arg(long)]
+    seed: Option<PathBuf>,
+    /// Number of threads handling requests [default: one per CPU core]
+    #[arg(long)]
+    workers: Option<usize>,
+    /// Serve the database file without writing to it, rejecting writes with 403
+    #[arg(long)]
+    read_only: bool,
+    /// How keys are normalized: none, lowercase, nfc, or nfc_lowercase [default: what
+    /// the database records]
+    #[arg(long)]
+    key_normalization: Option<KeyNormalization>,
+    /// Most verbose level of messages to log: off, error, warn, info, debug, or trace.
+    /// Trace messages are compiled out, so trace logs the same as debug [default: info]
+    #[arg(long)]
+    log_level: Option<log::LevelFilter>,
+}
+
 #[actix_web::main]
 async fn main() {
-    env_logger::builder()
-        .filter_level(log::LevelFilter::Debug)
-        .init();
-
-    let mut options = File::options();
-    options.write(true);
-    options.read(true);
-    options.create(true);
-    let file = options.open("./test.db").await.unwrap();
-    let mut store = kv::store::FileBackedKVStore::new(Box::new(file))
+    let args = Args::parse();
+
+    let env = |name: &str| std::env::var(name).ok();
+    let config_path = args.config.or_else(|| env("KV_CONFIG").map(PathBuf::from));
+    let loaded = ServerConfig::load(config_path.as_deref())
+        .and_then(|mut config| config.apply_env(env).map(|()| config));
+    let mut config = match loaded {
+        Ok(config) => config,
+        Err(e) => {
+            // Without a configuration, the level can only come from the flag.
+            let level = args.log_level.unwrap_or(ServerConfig::default().log_level);
+            env_logger::builder().filter_level(level).init();
+            log::error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Some(level) = args.log_level {
+        config.log_level = level;
+    }
+    env_logger::builder().filter_level(config.log_level).init();
+    if let Some(bind) = args.bind {
+        config.bind = Some(bind);
+    }
+    if let Some(db) = args.db {
+        config.db = db;
+    }
+    if let Some(seed) = args.seed {
+        config.seed = Some(seed);
+    }
+    if let Some(workers) = args.workers {
+        config.workers = Some(workers);
+    }
+    if args.read_only {
+        config.read_only = true;
+    }
+    if let Some(normalization) = args.key_normalization {
+        config.key_normalization = Some(normalization);
+    }
+    if let Err(e) = config.validate() {
+        log::error!("{}", e);
+        std::process::exit(1);
+    }
+
+    // Read-only databases in an older format fail to load instead.
+    if !config.read_only {
+        if let Some(version) =
+            kv::migration::migrate_file(&config.db).expect("database couldnt be migrated")
+        {
+            log::info!("Migrated database from format version {}", version);
+        }
+    }
+
+    let backend = if config.read_only {
+        log::info!("Serving {} read-only", config.db.display());
+        FileBackend::open_read_only(&config.db).await
+    } else {
+        FileBackend::open(&config.db).await
+    };
+    let mut backend = backend
+        .expect("database couldnt be opened")
+        .with_limits(config.limits());
+    if let Some(mirror) = config.mirror.as_ref().filter(|_| !config.read_only) {
+        backend = backend.with_mirror(mirror);
+    }
+    #[cfg(feature = "chaos")]
+    let backend = chaos::ChaosBackend::new(backend);
+    let mut builder = KVStore::builder(backend)
+        .compression_threshold(config.compression_threshold)
+        .compression_level(config.compression_level)
+        .sync_policy(config.sync)
+        .max_value_size(config.max_value_size)
+        .read_only(config.read_only);
+    if let Some(normalization) = config.key_normalization {
+        builder = builder.key_normalization(normalization);
+    }
+    if let Some(seed_path) = &config.seed {
+        match File::open(seed_path).await {
+            Ok(seed) => {
+                log::info!(
+                    "Using {} as seed, with {} as overlay",
+                    seed_path.display(),
+                    config.db.display()
+                );
+                builder = builder.seed(BufReader::new(seed));
+            }
+            Err(e) => {
+                log::error!(
+                    "Seed database {} couldnt be opened: {}",
+                    seed_path.display(),
+                    e
+                );
+                std::process::exit(1);
+            }
+        }
+    }
+    let mut store = builder
+        .build()
         .await
         .expect("file backed kv store couldnt be created");
-    start_server(store).await.unwrap();
+    // Access times of a read-only store would never be persisted.
+    store.track_access_times(
+        (config.access_time_granularity > 0 && !config.read_only)
+            .then(|| Duration::from_secs(config.access_time_granularity)),
+    );
+    store.set_cache_size(config.cache_size);
+    store.set_delta_threshold((config.delta_threshold > 0).then_some(config.delta_threshold));
+    start_server(store, config.authorizer(), &config)
+        .await
+        .unwrap();
 }
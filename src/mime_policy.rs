@@ -0,0 +1,248 @@
+//! MIME policies of namespaces, which decide what Content-Types the values written
+//! to them may have.
+//!
+//! By default, a value is stored with whatever Content-Type it arrives with, or none,
+//! as long as it isn't generic like `*/*`. Namespaces with the strict policy reject
+//! writes without a Content-Type, or with one which isn't a valid media type, with
+//! 422 Unprocessable Entity, so that their values can be relied on to have one.
+//!
+//! Like with throttles, a namespace is the part of a key before its first `/` (see
+//! `throttle`). Every policy is stored as an entry below `MIME_POLICY_PREFIX`, so
+//! policies survive restarts.
+
+use std::{collections::BTreeMap, sync::RwLock};
+
+use actix_web::{
+    http::header::CONTENT_TYPE, mime::Mime, web, HttpRequest, HttpResponse, Responder,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::throttle::namespace_of;
+use crate::write_queue::{RemoveOutcome, SetOutcome};
+use crate::{authorize, check_writable, write_error_response, AppState};
+use polling_test::auth::Operation;
+use polling_test::kv::{backend::StorageBackend, entry::Entry, result::KVResult, store::KVStore};
+
+/// MIME policy entries are stored under this prefix, followed by the namespace.
+const MIME_POLICY_PREFIX: &str = "_mime_policies/";
+
+/// Which Content-Types values written to a namespace may have.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "policy", rename_all = "snake_case")]
+pub(crate) enum MimePolicy {
+    /// Any Content-Type which isn't generic, or none.
+    #[default]
+    Lenient,
+    /// A valid media type, which must be given.
+    Strict,
+}
+
+impl MimePolicy {
+    /// Checks `content_type`, the Content-Type a value is written with, if any.
+    /// Returns why it's rejected.
+    fn check(&self, content_type: Option<&str>) -> Result<(), String> {
+        if *self == MimePolicy::Lenient {
+            return Ok(());
+        }
+        match content_type.map(str::trim) {
+            None | Some("") => Err("Missing Content-Type: Required in this namespace".to_owned()),
+            Some(content_type) => match content_type.parse::<Mime>() {
+                Ok(_) => Ok(()),
+                Err(e) => Err(format!("Invalid Content-Type {:?}: {}", content_type, e)),
+            },
+        }
+    }
+}
+
+/// The MIME policies by namespace.
+pub(crate) struct MimePolicies {
+    policies: RwLock<BTreeMap<String, MimePolicy>>,
+}
+
+impl MimePolicies {
+    /// Loads the policies stored in the store. Entries which aren't valid policies
+    /// are skipped with an error.
+    pub(crate) async fn load<B: StorageBackend>(store: &KVStore<B>) -> KVResult<Self> {
+        let keys: Vec<String> = store
+            .scan_prefix(MIME_POLICY_PREFIX)
+            .map(|(key, _)| key)
+            .collect();
+        let mut policies = BTreeMap::new();
+        for key in keys {
+            let Some(entry) = store.peek(&key).await? else {
+                continue;
+            };
+            let namespace = &key[MIME_POLICY_PREFIX.len()..];
+            match serde_json::from_slice(&entry.value) {
+                Ok(policy) => _ = policies.insert(namespace.to_owned(), policy),
+                Err(e) => log::error!("Skipping MIME policy of namespace {:?}: {}", namespace, e),
+            }
+        }
+        Ok(Self {
+            policies: RwLock::new(policies),
+        })
+    }
+
+    /// The policy of the namespace of `key`.
+    fn policy_of(&self, key: &str) -> MimePolicy {
+        let policies = self.policies.read().unwrap();
+        namespace_of(key)
+            .and_then(|namespace| policies.get(namespace).copied())
+            .unwrap_or_default()
+    }
+
+    /// Checks `mime`, the MIME type of a value written to `key` in a batch, against
+    /// the policy of its namespace. Returns why it's rejected.
+    pub(crate) fn check(&self, key: &str, mime: &str) -> Result<(), String> {
+        self.policy_of(key).check(Some(mime))
+    }
+}
+
+/// Returns the response to send if the request's Content-Type breaks the MIME policy
+/// of the namespace of `key`, which it's written to.
+pub(crate) fn check_content_type<B: StorageBackend>(
+    req: &HttpRequest,
+    data: &AppState<B>,
+    key: &str,
+) -> Option<HttpResponse> {
+    // Unlike `HttpMessage::content_type`, this keeps the parameters, which are
+    // checked as well.
+    let content_type = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .map(|value| String::from_utf8_lossy(value.as_bytes()));
+    match data
+        .mime_policies
+        .policy_of(key)
+        .check(content_type.as_deref())
+    {
+        Ok(()) => None,
+        Err(e) => Some(HttpResponse::UnprocessableEntity().body(e)),
+    }
+}
+
+#[derive(Serialize)]
+struct NamespacePolicy {
+    namespace: String,
+    #[serde(flatten)]
+    policy: MimePolicy,
+}
+
+/// Lists the MIME policies of the namespaces which have one.
+pub(crate) async fn list_policies<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+) -> impl Responder {
+    if let Some(response) = authorize(&req, &data, Operation::Admin, "").await {
+        return response;
+    }
+    let policies = data.mime_policies.policies.read().unwrap();
+    let list: Vec<NamespacePolicy> = policies
+        .iter()
+        .map(|(namespace, policy)| NamespacePolicy {
+            namespace: namespace.clone(),
+            policy: *policy,
+        })
+        .collect();
+    HttpResponse::Ok().json(list)
+}
+
+/// Sets the MIME policy of `{namespace}` to the one in the body.
+pub(crate) async fn set_policy<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    namespace: web::Path<String>,
+    policy: web::Json<MimePolicy>,
+) -> impl Responder {
+    if let Some(response) = authorize(&req, &data, Operation::Admin, "").await {
+        return response;
+    }
+    if let Some(response) = check_writable(&data) {
+        return response;
+    }
+    let policy = policy.into_inner();
+    let value = serde_json::to_vec(&policy).expect("MimePolicy always serializes");
+    let key = format!("{}{}", MIME_POLICY_PREFIX, namespace);
+    match data
+        .writes
+        .set(key, Entry::new(value, "application/json"), |_| true)
+        .await
+    {
+        Ok(SetOutcome::Set) => data.write_guard.record_success(),
+        Ok(SetOutcome::ConditionFailed(_)) => unreachable!("the condition always holds"),
+        Err(e) => {
+            log::error!("Error setting MIME policy of {:?}: {}", namespace, e);
+            return write_error_response(&data, &e);
+        }
+    }
+    data.mime_policies
+        .policies
+        .write()
+        .unwrap()
+        .insert(namespace.clone(), policy);
+    HttpResponse::Ok().json(NamespacePolicy {
+        namespace: namespace.into_inner(),
+        policy,
+    })
+}
+
+/// Removes the MIME policy of `{namespace}`, so that it's lenient again.
+pub(crate) async fn delete_policy<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    namespace: web::Path<String>,
+) -> impl Responder {
+    if let Some(response) = authorize(&req, &data, Operation::Admin, "").await {
+        return response;
+    }
+    if let Some(response) = check_writable(&data) {
+        return response;
+    }
+    let key = format!("{}{}", MIME_POLICY_PREFIX, namespace);
+    match data.writes.remove(key, |_| true).await {
+        Ok(RemoveOutcome::Removed) => data.write_guard.record_success(),
+        Ok(RemoveOutcome::NotFound) => return HttpResponse::NotFound().finish(),
+        Ok(RemoveOutcome::ConditionFailed(_)) => unreachable!("the condition always holds"),
+        Err(e) => {
+            log::error!("Error removing MIME policy of {:?}: {}", namespace, e);
+            return write_error_response(&data, &e);
+        }
+    }
+    data.mime_policies
+        .policies
+        .write()
+        .unwrap()
+        .remove(&*namespace);
+    HttpResponse::NoContent().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mime_policies() {
+        let policies = MimePolicies {
+            policies: RwLock::new(BTreeMap::from([
+                ("strict".to_owned(), MimePolicy::Strict),
+                ("lenient".to_owned(), MimePolicy::Lenient),
+            ])),
+        };
+        assert!(policies.check("strict/1", "text/plain").is_ok());
+        assert!(policies
+            .check("strict/1", "application/json; charset=utf-8")
+            .is_ok());
+        assert!(policies.check("strict/1", "").is_err());
+        assert!(policies.check("strict/1", "plain").is_err());
+        assert!(policies.check("strict/1", "text/pl ain").is_err());
+        // Only keys below the namespace are governed by its policy.
+        assert!(policies.check("strict", "plain").is_ok());
+        assert!(policies.check("lenient/1", "plain").is_ok());
+        assert!(policies.check("other/1", "").is_ok());
+
+        assert_eq!(
+            serde_json::from_str::<MimePolicy>(r#"{"policy": "strict"}"#).unwrap(),
+            MimePolicy::Strict
+        );
+    }
+}
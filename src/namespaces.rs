@@ -0,0 +1,227 @@
+//! Ephemeral namespaces: key prefixes which are removed as a whole once they expire,
+//! e.g. for CI runs or preview environments.
+//!
+//! Every namespace is recorded as a marker entry below `NAMESPACE_MARKER_PREFIX`,
+//! whose value is the time it expires at, so namespaces survive restarts. A
+//! namespace bound to a session is created with a short TTL, and kept alive by
+//! renewing it for as long as the session lasts. Expired namespaces with pinned keys
+//! are kept until those are unpinned.
+
+use std::{sync::atomic::Ordering, time::Duration};
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use serde::Serialize;
+
+use crate::buckets::check_name;
+use crate::capabilities::{check_capability, Action};
+use crate::write_queue::{RemoveOutcome, SetOutcome, WriteError};
+use crate::{
+    authorize, check_writable, expiry_after, ttl_from_headers, write_error_response, AppState,
+};
+use polling_test::auth::Operation;
+use polling_test::kv::{backend::StorageBackend, codec::to_millis, entry::Entry};
+
+/// Marker entries of namespaces are stored under this prefix, followed by the name.
+const NAMESPACE_MARKER_PREFIX: &str = "_namespaces/";
+/// How often expired namespaces are removed.
+const NAMESPACE_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Serialize)]
+struct Namespace {
+    name: String,
+    /// All keys of the namespace start with this.
+    prefix: String,
+    /// Milliseconds since the Unix epoch.
+    expires_at: u64,
+}
+
+fn marker_key(name: &str) -> String {
+    format!("{}{}", NAMESPACE_MARKER_PREFIX, name)
+}
+
+pub(crate) fn key_prefix(name: &str) -> String {
+    format!("{}/", name)
+}
+
+/// When the namespace with the given marker expires. Markers with invalid values
+/// are treated as expired.
+fn expires_at(marker: &Entry) -> u64 {
+    std::str::from_utf8(&marker.value)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Creates the namespace `{name}`, or renews it if it exists, so that it expires
+/// after the number of seconds in the `X-KV-TTL` header.
+pub(crate) async fn create_namespace<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    name: web::Path<String>,
+) -> impl Responder {
+    let prefix = key_prefix(&name);
+    if let Some(response) = authorize(&req, &data, Operation::Write, &prefix).await {
+        return response;
+    }
+    if let Some(response) = check_writable(&data) {
+        return response;
+    }
+    if let Err(e) = check_name("namespace", &name) {
+        return HttpResponse::BadRequest().body(e);
+    }
+    let ttl = match ttl_from_headers(&req) {
+        Ok(Some(ttl)) => ttl,
+        Ok(None) => return HttpResponse::BadRequest().body("Missing X-KV-TTL header"),
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    let expires_at = match expiry_after(&data, ttl) {
+        Ok(expires_at) => to_millis(expires_at),
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    let marker = Entry::new(expires_at.to_string().into_bytes(), "text/plain");
+    match data.writes.set(marker_key(&name), marker, |_| true).await {
+        Ok(SetOutcome::Set) => data.write_guard.record_success(),
+        Ok(SetOutcome::ConditionFailed(_)) => unreachable!("the condition always holds"),
+        Err(e) => {
+            log::error!("Error creating namespace {:?}: {}", name, e);
+            return write_error_response(&data, &e);
+        }
+    }
+    HttpResponse::Ok().json(Namespace {
+        name: name.into_inner(),
+        prefix,
+        expires_at,
+    })
+}
+
+/// Removes the namespace `{name}` and all of its keys right away. Needs a capability
+/// token, see `capabilities`.
+pub(crate) async fn delete_namespace<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    name: web::Path<String>,
+) -> impl Responder {
+    if let Some(response) =
+        authorize(&req, &data, Operation::DeletePrefix, &key_prefix(&name)).await
+    {
+        return response;
+    }
+    if let Some(response) = check_writable(&data) {
+        return response;
+    }
+    if let Err(e) = check_name("namespace", &name) {
+        return HttpResponse::BadRequest().body(e);
+    }
+    let capability = match check_capability(&req, &data, Action::DeleteNamespace, &name) {
+        Ok(capability) => capability,
+        Err(response) => return response,
+    };
+    match remove_namespace(&data, &name, |_| true).await {
+        Ok(true) => {
+            capability.consume();
+            data.write_guard.record_success();
+            HttpResponse::NoContent().finish()
+        }
+        Ok(false) => HttpResponse::NotFound().finish(),
+        Err(e) => {
+            log::error!("Error removing namespace {:?}: {}", name, e);
+            write_error_response(&data, &e)
+        }
+    }
+}
+
+/// Removes the keys of the namespace, and then its marker if `condition` still holds
+/// for it. Returns whether the marker was removed.
+async fn remove_namespace<B: StorageBackend>(
+    data: &AppState<B>,
+    name: &str,
+    condition: impl FnOnce(&Entry) -> bool + Send + 'static,
+) -> Result<bool, WriteError> {
+    if data.store.peek(marker_key(name)).await?.is_none() {
+        return Ok(false);
+    }
+    // The store publishes the removal of the prefix to its subscribers.
+    let removed = data.writes.remove_prefix(key_prefix(name)).await?;
+    log::info!("Removed namespace {:?} with {} keys", name, removed);
+    let outcome = data.writes.remove(marker_key(name), condition).await?;
+    Ok(matches!(outcome, RemoveOutcome::Removed))
+}
+
+/// The names of the namespaces which expired at `now`.
+async fn expired_namespaces<B: StorageBackend>(
+    data: &AppState<B>,
+    now: u64,
+) -> Result<Vec<String>, WriteError> {
+    data.store
+        .try_run(move |store| {
+            Box::pin(async move {
+                let markers: Vec<String> = store
+                    .scan_prefix(NAMESPACE_MARKER_PREFIX)
+                    .map(|(key, _)| key)
+                    .collect();
+                let mut expired = Vec::new();
+                for key in markers {
+                    let name = &key[NAMESPACE_MARKER_PREFIX.len()..];
+                    if let Some(marker) = store.peek(&key).await? {
+                        let pinned = || {
+                            store
+                                .scan_prefix(&key_prefix(name))
+                                .any(|(_, info)| info.pinned)
+                        };
+                        if expires_at(&marker) <= now && !pinned() {
+                            expired.push(name.to_owned());
+                        }
+                    }
+                }
+                Ok(expired)
+            })
+        })
+        .await
+}
+
+/// Removes namespaces once they expired.
+pub(crate) async fn remove_expired_namespaces_periodically<B: StorageBackend>(
+    data: web::Data<AppState<B>>,
+) {
+    let mut interval = tokio::time::interval(NAMESPACE_SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        if !data.write_guard.allows_write() || data.frozen.load(Ordering::SeqCst) {
+            continue;
+        }
+        let now = to_millis(data.clock.now());
+        let expired = match expired_namespaces(&data, now).await {
+            Ok(expired) => expired,
+            Err(e) => {
+                log::error!("Error reading namespaces: {}", e);
+                continue;
+            }
+        };
+        for name in expired {
+            // A namespace renewed after it expired, but before it was removed, keeps
+            // its marker. Its keys from before are gone nonetheless.
+            let still_expired = move |marker: &Entry| expires_at(marker) <= now;
+            if let Err(e) = remove_namespace(&data, &name, still_expired).await {
+                log::error!("Error removing expired namespace {:?}: {}", name, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_expires_at() {
+        let marker = |value: &str| Entry::new(value.as_bytes().to_vec(), "text/plain");
+        assert_eq!(expires_at(&marker("1700000000000")), 1_700_000_000_000);
+        assert_eq!(expires_at(&marker("garbage")), 0);
+        assert_eq!(marker_key("ci-42"), "_namespaces/ci-42");
+        assert_eq!(key_prefix("ci-42"), "ci-42/");
+        assert!(check_name("namespace", "ci-42").is_ok());
+        for invalid in ["", "_keys", "ci/42"] {
+            assert!(check_name("namespace", invalid).is_err());
+        }
+    }
+}
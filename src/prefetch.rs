@@ -0,0 +1,112 @@
+//! Warming the value cache ahead of bulk reads, so that a client which knows what it's
+//! about to read doesn't wait for the disk on every request.
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use serde::Deserialize;
+
+use crate::{authorize, AppState};
+use polling_test::auth::Operation;
+use polling_test::kv::backend::StorageBackend;
+
+/// Most keys a single `/_prefetch` request may list.
+const MAX_PREFETCH_KEYS: usize = 1000;
+/// How many values are read per store job, so that other requests aren't blocked
+/// for the whole prefetch.
+const PREFETCH_BATCH_SIZE: usize = 64;
+
+/// The values to prefetch: Either the listed keys, or every key with the prefix.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct PrefetchRequest {
+    #[serde(default)]
+    keys: Vec<String>,
+    prefix: Option<String>,
+}
+
+/// Reads the requested values into the cache in the background. Responds with 202
+/// right away, as the prefetch only pays off if the client doesn't wait for it.
+pub(crate) async fn prefetch<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    request: web::Json<PrefetchRequest>,
+) -> impl Responder {
+    match request.into_inner() {
+        PrefetchRequest {
+            keys,
+            prefix: Some(prefix),
+        } if keys.is_empty() => {
+            if let Some(response) = authorize(&req, &data, Operation::Read, &prefix).await {
+                return response;
+            }
+            actix_web::rt::spawn(prefetch_prefix(data, prefix));
+        }
+        PrefetchRequest { keys, prefix: None } if !keys.is_empty() => {
+            if keys.len() > MAX_PREFETCH_KEYS {
+                return HttpResponse::PayloadTooLarge().body(format!(
+                    "Too many keys: At most {} per request",
+                    MAX_PREFETCH_KEYS
+                ));
+            }
+            for key in &keys {
+                if let Some(response) = authorize(&req, &data, Operation::Read, key).await {
+                    return response;
+                }
+            }
+            actix_web::rt::spawn(prefetch_keys(data, keys));
+        }
+        _ => {
+            return HttpResponse::BadRequest()
+                .body("Invalid prefetch: Needs either keys or a prefix");
+        }
+    }
+    HttpResponse::Accepted().finish()
+}
+
+async fn prefetch_keys<B: StorageBackend>(data: web::Data<AppState<B>>, keys: Vec<String>) {
+    let mut read = 0;
+    for batch in keys.chunks(PREFETCH_BATCH_SIZE) {
+        let batch = batch.to_vec();
+        let prefetched = data
+            .store
+            .try_run(move |store| Box::pin(async move { store.prefetch(&batch).await }));
+        match prefetched.await {
+            Ok(count) => read += count,
+            Err(e) => {
+                log::warn!("Error prefetching values: {}", e);
+                return;
+            }
+        }
+    }
+    log::debug!("Prefetched {} of {} values", read, keys.len());
+}
+
+async fn prefetch_prefix<B: StorageBackend>(data: web::Data<AppState<B>>, prefix: String) {
+    let mut after: Option<String> = None;
+    let mut read = 0;
+    loop {
+        let (scanned, start) = (prefix.clone(), after.clone());
+        let batch = data.store.try_run(move |store| {
+            Box::pin(async move {
+                let keys: Vec<String> = store
+                    .scan(&scanned, start.as_deref())
+                    .take(PREFETCH_BATCH_SIZE)
+                    .map(|(key, _)| key)
+                    .collect();
+                let count = store.prefetch(&keys).await?;
+                Ok((keys.last().cloned(), count))
+            })
+        });
+        match batch.await {
+            Ok((Some(last), count)) => {
+                after = Some(last);
+                read += count;
+            }
+            Ok((None, _)) => break,
+            Err(e) => {
+                log::warn!("Error prefetching values under {:?}: {}", prefix, e);
+                return;
+            }
+        }
+    }
+    log::debug!("Prefetched {} values under {:?}", read, prefix);
+}
@@ -0,0 +1,312 @@
+//! Retention limits of namespaces, which bound them to a number of keys or bytes like
+//! ring buffers: Once a namespace holds more, its oldest values are removed, e.g. for
+//! caches of recent events which would need a cleanup job otherwise.
+//!
+//! Values are as old as the time they were last set, and those written before their
+//! modification time was recorded count as the oldest. Pinned values are kept, but
+//! count against the limits. Namespaces are checked every `RETENTION_SWEEP_INTERVAL`,
+//! so they can exceed their limits until then.
+//!
+//! Like with throttles, a namespace is the part of a key before its first `/` (see
+//! `throttle`). Every limit is stored as an entry below `RETENTION_PREFIX`, so limits
+//! survive restarts.
+
+use std::{
+    collections::BTreeMap,
+    sync::{atomic::Ordering, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+
+use crate::write_queue::{RemoveOutcome, SetOutcome, WriteError};
+use crate::{authorize, check_writable, write_error_response, AppState};
+use polling_test::auth::Operation;
+use polling_test::kv::{backend::StorageBackend, entry::Entry, result::KVResult, store::KVStore};
+
+/// Retention limits are stored under this prefix, followed by the namespace.
+const RETENTION_PREFIX: &str = "_retention/";
+/// How often namespaces are checked against their limits.
+const RETENTION_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How much a namespace may hold. Either limit may be left out, but not both.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct RetentionLimit {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_keys: Option<usize>,
+    /// Size of the keys and values, like `/_admin/usage` reports it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_bytes: Option<u64>,
+}
+
+impl RetentionLimit {
+    fn exceeded_by(&self, keys: usize, bytes: u64) -> bool {
+        self.max_keys.is_some_and(|max| keys > max) || self.max_bytes.is_some_and(|max| bytes > max)
+    }
+}
+
+/// A value of a namespace, as far as its limit is concerned.
+struct Retained {
+    key: String,
+    modified_at: Option<SystemTime>,
+    bytes: u64,
+    pinned: bool,
+}
+
+/// The values to remove, oldest first, so that the rest fit `limit`, along with when
+/// they were last set.
+fn overflow(
+    limit: &RetentionLimit,
+    mut values: Vec<Retained>,
+) -> Vec<(String, Option<SystemTime>)> {
+    let mut keys = values.len();
+    let mut bytes: u64 = values.iter().map(|value| value.bytes).sum();
+    values.sort_by(|a, b| (a.modified_at, &a.key).cmp(&(b.modified_at, &b.key)));
+    let mut removed = Vec::new();
+    for value in values.into_iter().filter(|value| !value.pinned) {
+        if !limit.exceeded_by(keys, bytes) {
+            break;
+        }
+        keys -= 1;
+        bytes -= value.bytes;
+        removed.push((value.key, value.modified_at));
+    }
+    removed
+}
+
+/// The retention limits by namespace.
+pub(crate) struct Retention {
+    limits: RwLock<BTreeMap<String, RetentionLimit>>,
+}
+
+impl Retention {
+    /// Loads the limits stored in the store. Entries which aren't valid limits are
+    /// skipped with an error.
+    pub(crate) async fn load<B: StorageBackend>(store: &KVStore<B>) -> KVResult<Self> {
+        let keys: Vec<String> = store
+            .scan_prefix(RETENTION_PREFIX)
+            .map(|(key, _)| key)
+            .collect();
+        let mut limits = BTreeMap::new();
+        for key in keys {
+            let Some(entry) = store.peek(&key).await? else {
+                continue;
+            };
+            let namespace = &key[RETENTION_PREFIX.len()..];
+            match serde_json::from_slice(&entry.value) {
+                Ok(limit) => _ = limits.insert(namespace.to_owned(), limit),
+                Err(e) => log::error!(
+                    "Skipping retention limit of namespace {:?}: {}",
+                    namespace,
+                    e
+                ),
+            }
+        }
+        Ok(Self {
+            limits: RwLock::new(limits),
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct NamespaceLimit {
+    namespace: String,
+    #[serde(flatten)]
+    limit: RetentionLimit,
+}
+
+/// Lists the retention limits.
+pub(crate) async fn list_limits<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+) -> impl Responder {
+    if let Some(response) = authorize(&req, &data, Operation::Admin, "").await {
+        return response;
+    }
+    let limits = data.retention.limits.read().unwrap();
+    let list: Vec<NamespaceLimit> = limits
+        .iter()
+        .map(|(namespace, limit)| NamespaceLimit {
+            namespace: namespace.clone(),
+            limit: *limit,
+        })
+        .collect();
+    HttpResponse::Ok().json(list)
+}
+
+/// Sets the retention limit of `{namespace}` to the one in the body, replacing any it
+/// had.
+pub(crate) async fn set_limit<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    namespace: web::Path<String>,
+    limit: web::Json<RetentionLimit>,
+) -> impl Responder {
+    if let Some(response) = authorize(&req, &data, Operation::Admin, "").await {
+        return response;
+    }
+    if let Some(response) = check_writable(&data) {
+        return response;
+    }
+    let limit = limit.into_inner();
+    if limit.max_keys.is_none() && limit.max_bytes.is_none() {
+        return HttpResponse::BadRequest().body("Needs max_keys, max_bytes, or both");
+    }
+    let value = serde_json::to_vec(&limit).expect("RetentionLimit always serializes");
+    let key = format!("{}{}", RETENTION_PREFIX, namespace);
+    match data
+        .writes
+        .set(key, Entry::new(value, "application/json"), |_| true)
+        .await
+    {
+        Ok(SetOutcome::Set) => data.write_guard.record_success(),
+        Ok(SetOutcome::ConditionFailed(_)) => unreachable!("the condition always holds"),
+        Err(e) => {
+            log::error!("Error setting retention limit of {:?}: {}", namespace, e);
+            return write_error_response(&data, &e);
+        }
+    }
+    data.retention
+        .limits
+        .write()
+        .unwrap()
+        .insert(namespace.clone(), limit);
+    HttpResponse::Ok().json(NamespaceLimit {
+        namespace: namespace.into_inner(),
+        limit,
+    })
+}
+
+/// Removes the retention limit of `{namespace}`.
+pub(crate) async fn delete_limit<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    namespace: web::Path<String>,
+) -> impl Responder {
+    if let Some(response) = authorize(&req, &data, Operation::Admin, "").await {
+        return response;
+    }
+    if let Some(response) = check_writable(&data) {
+        return response;
+    }
+    let key = format!("{}{}", RETENTION_PREFIX, namespace);
+    match data.writes.remove(key, |_| true).await {
+        Ok(RemoveOutcome::Removed) => data.write_guard.record_success(),
+        Ok(RemoveOutcome::NotFound) => return HttpResponse::NotFound().finish(),
+        Ok(RemoveOutcome::ConditionFailed(_)) => unreachable!("the condition always holds"),
+        Err(e) => {
+            log::error!("Error removing retention limit of {:?}: {}", namespace, e);
+            return write_error_response(&data, &e);
+        }
+    }
+    data.retention.limits.write().unwrap().remove(&*namespace);
+    HttpResponse::NoContent().finish()
+}
+
+/// The values which namespaces have to lose to fit their limits, along with when they
+/// were last set.
+async fn overflowing_keys<B: StorageBackend>(
+    data: &AppState<B>,
+) -> Result<Vec<(String, Option<SystemTime>)>, WriteError> {
+    let limits = data.retention.limits.read().unwrap().clone();
+    data.store
+        .run(move |store| {
+            Box::pin(async move {
+                let mut overflowing = Vec::new();
+                for (namespace, limit) in &limits {
+                    let values = store
+                        .scan_prefix(&format!("{}/", namespace))
+                        .map(|(key, info)| Retained {
+                            bytes: (key.len() + info.value_len) as u64,
+                            modified_at: info.modified_at,
+                            pinned: info.pinned,
+                            key,
+                        })
+                        .collect();
+                    overflowing.extend(overflow(limit, values));
+                }
+                overflowing
+            })
+        })
+        .await
+}
+
+/// Removes the oldest values of namespaces which exceed their retention limits.
+pub(crate) async fn enforce_limits_periodically<B: StorageBackend>(data: web::Data<AppState<B>>) {
+    let mut interval = tokio::time::interval(RETENTION_SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        if !data.write_guard.allows_write() || data.frozen.load(Ordering::SeqCst) {
+            continue;
+        }
+        let overflowing = match overflowing_keys(&data).await {
+            Ok(overflowing) => overflowing,
+            Err(e) => {
+                log::error!("Error enforcing retention limits: {}", e);
+                continue;
+            }
+        };
+        let mut removed = 0;
+        for (key, modified_at) in overflowing {
+            // Values set again since they were found are the newest now.
+            let unchanged =
+                move |current: &Entry| current.modified_at == modified_at && !current.pinned;
+            match data.writes.remove(key.clone(), unchanged).await {
+                Ok(RemoveOutcome::Removed) => removed += 1,
+                Ok(_) => {}
+                Err(e) => {
+                    log::error!("Error removing {:?} by its retention limit: {}", key, e);
+                    break;
+                }
+            }
+        }
+        if removed > 0 {
+            log::debug!("Removed {} values by their retention limits", removed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overflow() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let value = |key: &str, age: Option<u64>, pinned| Retained {
+            key: key.to_owned(),
+            modified_at: age.map(|age| start - Duration::from_secs(age)),
+            bytes: 10,
+            pinned,
+        };
+        let values = || {
+            vec![
+                value("events/new", Some(1), false),
+                value("events/old", Some(3), false),
+                value("events/legacy", None, false),
+                value("events/pinned", Some(5), true),
+                value("events/mid", Some(2), false),
+            ]
+        };
+        let keys = |limit: RetentionLimit| -> Vec<String> {
+            overflow(&limit, values())
+                .into_iter()
+                .map(|(key, _)| key)
+                .collect()
+        };
+        let limit = |max_keys, max_bytes| RetentionLimit {
+            max_keys,
+            max_bytes,
+        };
+        assert!(keys(limit(Some(5), None)).is_empty());
+        assert_eq!(keys(limit(Some(3), None)), ["events/legacy", "events/old"]);
+        assert_eq!(
+            keys(limit(Some(4), Some(30))),
+            ["events/legacy", "events/old"]
+        );
+        // Pinned values are kept, even if that leaves the namespace over its limit.
+        assert_eq!(keys(limit(Some(0), None)).len(), 4);
+    }
+}
@@ -0,0 +1,347 @@
+//! Scanning the keys with a prefix a page at a time, optionally along with their
+//! values, so that hierarchical key layouts like `users/42/...` can be read back a
+//! level at a time without a request per key.
+//!
+//! Unlike `/_keys`, which lists every key with the prefix at once, a scan returns at
+//! most `limit` entries, and the key to continue after with the next page.
+//!
+//! `/_range` pages through the keys between two bounds the same way, for keys which
+//! are ordered by what they hold, like timestamps, in ascending or descending order.
+//!
+//! Pages are also cut short once they'd take up more than about `max_response_size`
+//! bytes, e.g. if the values are large. Their `next` key is sent as a `Link` header
+//! with `rel="next"` as well, which is all `/_keys` has to point at the rest of a
+//! listing it cuts short. Streams of `/_keys` end with a line pointing at the rest
+//! instead.
+
+use std::ops::Bound;
+
+use actix_web::{http::header::LINK, web, HttpRequest, HttpResponse};
+use base64::prelude::{Engine, BASE64_STANDARD};
+use serde::{Deserialize, Serialize};
+
+use crate::write_queue::WriteError;
+use crate::{authorize, AppState};
+use polling_test::auth::Operation;
+use polling_test::kv::{
+    backend::StorageBackend, entry::EntryInfo, result::KVResult, store::KVStore,
+};
+
+/// Entries returned by a scan without a `limit`.
+const DEFAULT_SCAN_LIMIT: usize = 100;
+/// Most entries a single scan may return.
+const MAX_SCAN_LIMIT: usize = 1000;
+
+#[derive(Deserialize)]
+pub(crate) struct ScanQuery {
+    #[serde(default)]
+    prefix: String,
+    limit: Option<usize>,
+    /// The `next` key of the previous page.
+    after: Option<String>,
+    /// `1` or `true` includes the values.
+    values: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RangeQuery {
+    /// The first key of the range, if it has one.
+    from: Option<String>,
+    /// The key the range ends before, if it has one.
+    to: Option<String>,
+    /// `1` or `true` pages through the range from its end.
+    reverse: Option<String>,
+    limit: Option<usize>,
+    /// The `next` key of the previous page.
+    after: Option<String>,
+    /// `1` or `true` includes the values.
+    values: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ScannedEntry {
+    key: String,
+    mime: String,
+    size: usize,
+    /// Base64 encoded, if the values were asked for.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+}
+
+#[derive(Serialize)]
+struct ScanPage {
+    entries: Vec<ScannedEntry>,
+    /// The key to continue after, if there are more entries.
+    next: Option<String>,
+}
+
+/// Returns a page of the entries with the prefix, in key order, see the module
+/// documentation.
+pub(crate) async fn scan<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    query: web::Query<ScanQuery>,
+) -> Result<HttpResponse, WriteError> {
+    let ScanQuery {
+        prefix,
+        limit,
+        after,
+        values,
+    } = query.into_inner();
+    let with_values = is_set(values.as_deref());
+    if let Some(response) = authorize_scan(&req, &data, &prefix, with_values).await {
+        return Ok(response);
+    }
+    let limit = limit.unwrap_or(DEFAULT_SCAN_LIMIT);
+    if let Some(response) = check_limit(limit) {
+        return Ok(response);
+    }
+    let max_len = data.max_response_size;
+    let page = data
+        .store
+        .try_run(move |store| {
+            Box::pin(async move {
+                let scanned = store.scan(&prefix, after.as_deref());
+                read_page(store, scanned, limit, max_len, with_values).await
+            })
+        })
+        .await?;
+    Ok(page_response(&req, page))
+}
+
+/// Returns a page of the entries from `from` up to, but not including, `to`, in
+/// ascending key order, or descending with `reverse`. Either bound may be left out.
+pub(crate) async fn range<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    query: web::Query<RangeQuery>,
+) -> Result<HttpResponse, WriteError> {
+    let RangeQuery {
+        from,
+        to,
+        reverse,
+        limit,
+        after,
+        values,
+    } = query.into_inner();
+    let (reverse, with_values) = (is_set(reverse.as_deref()), is_set(values.as_deref()));
+    // Every key of the range starts with what the bounds have in common.
+    let prefix = match (&from, &to) {
+        (Some(from), Some(to)) => common_prefix(from, to),
+        _ => "",
+    };
+    if let Some(response) = authorize_scan(&req, &data, prefix, with_values).await {
+        return Ok(response);
+    }
+    let limit = limit.unwrap_or(DEFAULT_SCAN_LIMIT);
+    if let Some(response) = check_limit(limit) {
+        return Ok(response);
+    }
+    let max_len = data.max_response_size;
+    let page = data
+        .store
+        .try_run(move |store| {
+            Box::pin(async move {
+                let start = from.as_deref().map_or(Bound::Unbounded, Bound::Included);
+                let end = to.as_deref().map_or(Bound::Unbounded, Bound::Excluded);
+                // A page continues after the last key of the one before, which is
+                // below it when going in reverse.
+                if reverse {
+                    let end = match (end, after.as_deref()) {
+                        (Bound::Excluded(end), Some(after)) if after >= end => Bound::Excluded(end),
+                        (_, Some(after)) => Bound::Excluded(after),
+                        (end, None) => end,
+                    };
+                    let ranged = store.range_rev((start, end));
+                    read_page(store, ranged, limit, max_len, with_values).await
+                } else {
+                    let start = match (start, after.as_deref()) {
+                        (Bound::Included(start), Some(after)) if after < start => {
+                            Bound::Included(start)
+                        }
+                        (_, Some(after)) => Bound::Excluded(after),
+                        (start, None) => start,
+                    };
+                    let ranged = store.range((start, end));
+                    read_page(store, ranged, limit, max_len, with_values).await
+                }
+            })
+        })
+        .await?;
+    Ok(page_response(&req, page))
+}
+
+fn is_set(flag: Option<&str>) -> bool {
+    matches!(flag, Some("1" | "true"))
+}
+
+/// Returns the response to send if the request may not list the keys with `prefix`,
+/// or read their values if it asks for them.
+async fn authorize_scan<B: StorageBackend>(
+    req: &HttpRequest,
+    data: &AppState<B>,
+    prefix: &str,
+    with_values: bool,
+) -> Option<HttpResponse> {
+    if let Some(response) = authorize(req, data, Operation::List, prefix).await {
+        return Some(response);
+    }
+    if with_values {
+        return authorize(req, data, Operation::Read, prefix).await;
+    }
+    None
+}
+
+fn check_limit(limit: usize) -> Option<HttpResponse> {
+    (limit == 0 || limit > MAX_SCAN_LIMIT).then(|| {
+        HttpResponse::BadRequest().body(format!(
+            "Invalid limit: Must be between 1 and {}",
+            MAX_SCAN_LIMIT
+        ))
+    })
+}
+
+/// Reads a page of at most `limit` of the `scanned` entries, which takes up about
+/// `max_len` bytes at most, along with their values if `with_values` is set.
+async fn read_page<'a, B: StorageBackend>(
+    store: &KVStore<B>,
+    scanned: impl Iterator<Item = (String, &'a EntryInfo)>,
+    limit: usize,
+    max_len: usize,
+    with_values: bool,
+) -> KVResult<ScanPage> {
+    let scanned = scanned.map(|(key, info)| ScannedEntry {
+        key,
+        mime: info.mime.to_string(),
+        size: info.value_len,
+        value: None,
+    });
+    let (mut scanned, more) = take_fitting(scanned, limit, max_len, |scanned| {
+        listed_len(
+            &scanned.key,
+            &scanned.mime,
+            with_values.then_some(scanned.size),
+        )
+    });
+    let next = more.then(|| scanned[scanned.len() - 1].key.clone());
+    if with_values {
+        for scanned in &mut scanned {
+            // Entries which expired since they were scanned are left without a
+            // value.
+            if let Some(entry) = store.get(&scanned.key).await? {
+                scanned.value = Some(BASE64_STANDARD.encode(&entry.value));
+            }
+        }
+    }
+    Ok(ScanPage {
+        entries: scanned,
+        next,
+    })
+}
+
+fn page_response(req: &HttpRequest, page: ScanPage) -> HttpResponse {
+    let mut response = HttpResponse::Ok();
+    if let Some(next) = &page.next {
+        response.insert_header((LINK, next_link(req, next)));
+    }
+    response.json(page)
+}
+
+/// Takes up to `limit` of the `entries`, as long as they take up no more than
+/// `max_len` bytes by `len`, but at least one. Returns whether any were left.
+pub(crate) fn take_fitting<T>(
+    entries: impl Iterator<Item = T>,
+    limit: usize,
+    max_len: usize,
+    len: impl Fn(&T) -> usize,
+) -> (Vec<T>, bool) {
+    let mut entries = entries.peekable();
+    let mut taken = Vec::new();
+    let mut taken_len = 0;
+    while let Some(entry) = entries.peek() {
+        let entry_len = len(entry);
+        if taken.len() == limit || (!taken.is_empty() && taken_len + entry_len > max_len) {
+            return (taken, true);
+        }
+        taken_len += entry_len;
+        taken.extend(entries.next());
+    }
+    (taken, false)
+}
+
+/// About how many bytes an entry of a listing takes up as JSON, with a value of
+/// `value_len` bytes if it's included. The value is base64 encoded.
+pub(crate) fn listed_len(key: &str, mime: &str, value_len: Option<usize>) -> usize {
+    /// The field names, quotes and so on.
+    const SYNTAX_LEN: usize = 48;
+    let value_len = value_len.map_or(0, |len| len.div_ceil(3) * 4 + 10);
+    key.len() + mime.len() + value_len + SYNTAX_LEN
+}
+
+/// The value of a `Link` header pointing at the page of a listing after `after`, see
+/// `next_url`.
+pub(crate) fn next_link(req: &HttpRequest, after: &str) -> String {
+    format!("<{}>; rel=\"next\"", next_url(req, after))
+}
+
+/// The URL of the page of a listing after `after`, which repeats the request with
+/// `after` instead of any it had.
+pub(crate) fn next_url(req: &HttpRequest, after: &str) -> String {
+    let mut query = web::Query::<Vec<(String, String)>>::from_query(req.query_string())
+        .map(web::Query::into_inner)
+        .unwrap_or_default();
+    query.retain(|(name, _)| name != "after");
+    query.push(("after".to_owned(), after.to_owned()));
+    let query = serde_urlencoded::to_string(&query).expect("strings always serialize");
+    format!("{}?{}", req.path(), query)
+}
+
+/// The longest prefix `a` and `b` have in common, which ends on a character boundary.
+fn common_prefix<'a>(a: &'a str, b: &str) -> &'a str {
+    let len = a
+        .char_indices()
+        .zip(b.chars())
+        .find(|((_, a), b)| a != b)
+        .map_or(a.len().min(b.len()), |((index, _), _)| index);
+    &a[..len]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_common_prefix() {
+        assert_eq!(
+            common_prefix("events/2024-01", "events/2024-03"),
+            "events/2024-0"
+        );
+        assert_eq!(common_prefix("events/", "events/2024"), "events/");
+        assert_eq!(common_prefix("a", "b"), "");
+        assert_eq!(common_prefix("ü", "ü"), "ü");
+        // Both start with the byte 0xC3, but they're different characters.
+        assert_eq!(common_prefix("xü", "xé"), "x");
+    }
+
+    #[test]
+    fn test_take_fitting() {
+        let take = |limit, max_len| take_fitting([3, 3, 3, 3].into_iter(), limit, max_len, |n| *n);
+        assert_eq!(take(10, 100), (vec![3, 3, 3, 3], false));
+        assert_eq!(take(4, 100), (vec![3, 3, 3, 3], false));
+        assert_eq!(take(3, 100), (vec![3, 3, 3], true));
+        assert_eq!(take(10, 7), (vec![3, 3], true));
+        // A page always has an entry, or there'd be no next one to continue after.
+        assert_eq!(take(10, 1), (vec![3], true));
+    }
+
+    #[test]
+    fn test_next_link() {
+        let req = actix_web::test::TestRequest::get()
+            .uri("/_scan?prefix=a%2Fb&after=x&limit=2")
+            .to_http_request();
+        assert_eq!(
+            next_link(&req, "a/b/ü"),
+            "</_scan?prefix=a%2Fb&limit=2&after=a%2Fb%2F%C3%BC>; rel=\"next\""
+        );
+    }
+}
@@ -0,0 +1,312 @@
+//! Protobuf schemas for key prefixes, so that `application/protobuf` values are
+//! validated when they're written, and can be read back as JSON.
+//!
+//! A schema is registered as a compiled `FileDescriptorSet` (as written by
+//! `protoc --descriptor_set_out`) along with the name of the message type the values
+//! under its prefix have. It's stored as an entry below `SCHEMA_PREFIX`, so schemas
+//! survive restarts, and compiled into `SchemaRegistry` to be used.
+
+use std::{collections::BTreeMap, ops::Bound, sync::RwLock};
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+
+use crate::write_queue::{RemoveOutcome, SetOutcome};
+use crate::{authorize, check_writable, write_error_response, AppState};
+use polling_test::auth::Operation;
+use polling_test::kv::{
+    backend::StorageBackend,
+    entry::{Entry, Metadata},
+    result::KVResult,
+    store::KVStore,
+};
+
+/// Schema entries are stored under this prefix, followed by the key prefix they apply to.
+const SCHEMA_PREFIX: &str = "_schemas/";
+/// Metadata of a schema entry, naming the message type of the values.
+const MESSAGE_META: &str = "message";
+/// Media type of schema entries.
+const DESCRIPTOR_SET_MIME: &str = "application/x-protobuf-descriptor-set";
+
+/// Whether values of this media type are protobuf messages.
+fn is_protobuf(mime: &str) -> bool {
+    let essence = mime.split(';').next().unwrap_or_default().trim();
+    essence.eq_ignore_ascii_case("application/protobuf")
+        || essence.eq_ignore_ascii_case("application/x-protobuf")
+}
+
+/// The message type of the values under a prefix.
+#[derive(Clone)]
+pub(crate) struct Schema {
+    /// Full name of the message type, e.g. `shop.v1.Order`.
+    name: String,
+    #[cfg(feature = "protobuf")]
+    message: prost_reflect::MessageDescriptor,
+}
+
+impl Schema {
+    /// Finds the message type `name` in an encoded `FileDescriptorSet`.
+    #[cfg(feature = "protobuf")]
+    fn compile(descriptor_set: &[u8], name: &str) -> Result<Self, String> {
+        let pool = prost_reflect::DescriptorPool::decode(descriptor_set)
+            .map_err(|e| format!("Invalid descriptor set: {}", e))?;
+        let message = pool
+            .get_message_by_name(name)
+            .ok_or_else(|| format!("Message type {:?} not found in descriptor set", name))?;
+        Ok(Self {
+            name: name.to_owned(),
+            message,
+        })
+    }
+
+    #[cfg(not(feature = "protobuf"))]
+    fn compile(_descriptor_set: &[u8], _name: &str) -> Result<Self, String> {
+        Err("Protobuf support is not enabled".to_string())
+    }
+
+    /// Checks that the value is an encoded message of this type.
+    #[cfg(feature = "protobuf")]
+    pub(crate) fn validate(&self, value: &[u8]) -> Result<(), String> {
+        prost_reflect::DynamicMessage::decode(self.message.clone(), value)
+            .map(|_| ())
+            .map_err(|e| format!("Value is not a valid {}: {}", self.name, e))
+    }
+
+    #[cfg(not(feature = "protobuf"))]
+    pub(crate) fn validate(&self, _value: &[u8]) -> Result<(), String> {
+        Err("Protobuf support is not enabled".to_string())
+    }
+
+    /// Transcodes an encoded message of this type to its canonical JSON mapping.
+    #[cfg(feature = "protobuf")]
+    pub(crate) fn to_json(&self, value: &[u8]) -> Result<Vec<u8>, String> {
+        let message = prost_reflect::DynamicMessage::decode(self.message.clone(), value)
+            .map_err(|e| format!("Value is not a valid {}: {}", self.name, e))?;
+        serde_json::to_vec(&message).map_err(|e| e.to_string())
+    }
+
+    #[cfg(not(feature = "protobuf"))]
+    pub(crate) fn to_json(&self, _value: &[u8]) -> Result<Vec<u8>, String> {
+        Err("Protobuf support is not enabled".to_string())
+    }
+}
+
+/// The compiled schemas, by the key prefix they apply to.
+pub(crate) struct SchemaRegistry {
+    schemas: RwLock<BTreeMap<String, Schema>>,
+}
+
+impl SchemaRegistry {
+    /// Compiles the schemas stored in the store. Schemas which don't compile (anymore)
+    /// are skipped with an error.
+    pub(crate) async fn load<B: StorageBackend>(store: &KVStore<B>) -> KVResult<Self> {
+        let keys: Vec<String> = store
+            .scan_prefix(SCHEMA_PREFIX)
+            .map(|(key, _)| key)
+            .collect();
+        let mut schemas = BTreeMap::new();
+        for key in keys {
+            let Some(entry) = store.peek(&key).await? else {
+                continue;
+            };
+            let prefix = &key[SCHEMA_PREFIX.len()..];
+            let name = entry.meta.get(MESSAGE_META).map_or("", String::as_str);
+            match Schema::compile(&entry.value, name) {
+                Ok(schema) => _ = schemas.insert(prefix.to_owned(), schema),
+                Err(e) => log::error!("Skipping schema for prefix {:?}: {}", prefix, e),
+            }
+        }
+        Ok(Self {
+            schemas: RwLock::new(schemas),
+        })
+    }
+
+    /// The schema of protobuf values under `key`, from the longest registered prefix
+    /// of it. Values of other media types don't have one.
+    pub(crate) fn find(&self, key: &str, mime: &str) -> Option<Schema> {
+        if !is_protobuf(mime) {
+            return None;
+        }
+        let schemas = self.schemas.read().unwrap();
+        // Prefixes of `key` sort before it, and longer ones after shorter ones.
+        schemas
+            .range::<str, _>((Bound::Unbounded, Bound::Included(key)))
+            .rev()
+            .find(|(prefix, _)| key.starts_with(prefix.as_str()))
+            .map(|(_, schema)| schema.clone())
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct SchemaQuery {
+    prefix: String,
+    /// Full name of the message type, only needed to register a schema.
+    message: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SchemaInfo {
+    prefix: String,
+    message: String,
+}
+
+/// Lists the registered schemas.
+pub(crate) async fn list_schemas<B: StorageBackend>(
+    data: web::Data<AppState<B>>,
+) -> impl Responder {
+    let schemas = data.schemas.schemas.read().unwrap();
+    let list: Vec<SchemaInfo> = schemas
+        .iter()
+        .map(|(prefix, schema)| SchemaInfo {
+            prefix: prefix.clone(),
+            message: schema.name.clone(),
+        })
+        .collect();
+    HttpResponse::Ok().json(list)
+}
+
+/// Registers the `FileDescriptorSet` in the body as the schema of the protobuf values
+/// under `prefix`, replacing any schema the prefix had. Values already stored aren't
+/// checked against it.
+pub(crate) async fn register_schema<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    query: web::Query<SchemaQuery>,
+    descriptor_set: web::Bytes,
+) -> impl Responder {
+    if let Some(response) = authorize(&req, &data, Operation::Write, &query.prefix).await {
+        return response;
+    }
+    if let Some(response) = check_writable(&data) {
+        return response;
+    }
+    let Some(name) = &query.message else {
+        return HttpResponse::BadRequest().body("Missing message query parameter");
+    };
+    let schema = match Schema::compile(&descriptor_set, name) {
+        Ok(schema) => schema,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    let meta = Metadata::from([(MESSAGE_META.to_owned(), name.clone())]);
+    let entry = Entry::new(descriptor_set.to_vec(), DESCRIPTOR_SET_MIME).with_meta(meta);
+    let key = format!("{}{}", SCHEMA_PREFIX, query.prefix);
+    match data.writes.set(key, entry, |_| true).await {
+        Ok(SetOutcome::Set) => data.write_guard.record_success(),
+        Ok(SetOutcome::ConditionFailed(_)) => unreachable!("the condition always holds"),
+        Err(e) => {
+            log::error!("Error registering schema for {:?}: {}", query.prefix, e);
+            return write_error_response(&data, &e);
+        }
+    }
+    data.schemas
+        .schemas
+        .write()
+        .unwrap()
+        .insert(query.prefix.clone(), schema);
+    HttpResponse::Ok().json(SchemaInfo {
+        prefix: query.prefix.clone(),
+        message: name.clone(),
+    })
+}
+
+/// Removes the schema of `prefix`, after which its values are no longer validated.
+pub(crate) async fn delete_schema<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    query: web::Query<SchemaQuery>,
+) -> impl Responder {
+    if let Some(response) = authorize(&req, &data, Operation::Write, &query.prefix).await {
+        return response;
+    }
+    if let Some(response) = check_writable(&data) {
+        return response;
+    }
+    let key = format!("{}{}", SCHEMA_PREFIX, query.prefix);
+    match data.writes.remove(key, |_| true).await {
+        Ok(RemoveOutcome::Removed) => data.write_guard.record_success(),
+        Ok(RemoveOutcome::NotFound) => return HttpResponse::NotFound().finish(),
+        Ok(RemoveOutcome::ConditionFailed(_)) => unreachable!("the condition always holds"),
+        Err(e) => {
+            log::error!("Error removing schema for {:?}: {}", query.prefix, e);
+            return write_error_response(&data, &e);
+        }
+    }
+    data.schemas.schemas.write().unwrap().remove(&query.prefix);
+    HttpResponse::NoContent().finish()
+}
+
+#[cfg(all(test, feature = "protobuf"))]
+mod tests {
+    use super::*;
+    use prost_reflect::{
+        prost::Message,
+        prost_types::{
+            field_descriptor_proto::{Label, Type},
+            DescriptorProto, FieldDescriptorProto, FileDescriptorProto,
+        },
+        DescriptorPool, DynamicMessage,
+    };
+
+    /// A descriptor set with `test.Order { string id = 1; uint32 count = 2; }`.
+    fn descriptor_set() -> Vec<u8> {
+        let field = |name: &str, number, r#type: Type| FieldDescriptorProto {
+            name: Some(name.to_owned()),
+            number: Some(number),
+            label: Some(Label::Optional as i32),
+            r#type: Some(r#type as i32),
+            json_name: Some(name.to_owned()),
+            ..Default::default()
+        };
+        let file = FileDescriptorProto {
+            name: Some("order.proto".to_owned()),
+            package: Some("test".to_owned()),
+            message_type: vec![DescriptorProto {
+                name: Some("Order".to_owned()),
+                field: vec![
+                    field("id", 1, Type::String),
+                    field("count", 2, Type::Uint32),
+                ],
+                ..Default::default()
+            }],
+            syntax: Some("proto3".to_owned()),
+            ..Default::default()
+        };
+        let mut pool = DescriptorPool::new();
+        pool.add_file_descriptor_proto(file).unwrap();
+        pool.encode_to_vec()
+    }
+
+    #[test]
+    fn test_schema() {
+        assert!(Schema::compile(&descriptor_set(), "test.Missing").is_err());
+        assert!(Schema::compile(b"not a descriptor set", "test.Order").is_err());
+        let schema = Schema::compile(&descriptor_set(), "test.Order").unwrap();
+
+        let mut deserializer = serde_json::Deserializer::from_str(r#"{"id":"a1","count":3}"#);
+        let order = DynamicMessage::deserialize(schema.message.clone(), &mut deserializer)
+            .unwrap()
+            .encode_to_vec();
+        assert!(schema.validate(&order).is_ok());
+        // Field 2 as a length-delimited field, which doesn't match its type.
+        assert!(schema.validate(&[0x12, 0x01, 0x00]).is_err());
+        assert_eq!(schema.to_json(&order).unwrap(), br#"{"id":"a1","count":3}"#);
+
+        let registry = SchemaRegistry {
+            schemas: RwLock::new(BTreeMap::from([
+                ("orders/".to_owned(), schema.clone()),
+                (
+                    "orders/archived/".to_owned(),
+                    Schema {
+                        name: "test.ArchivedOrder".to_owned(),
+                        ..schema
+                    },
+                ),
+            ])),
+        };
+        assert!(registry.find("orders/1", "application/protobuf").is_some());
+        assert!(registry.find("orders/1", "application/json").is_none());
+        assert!(registry.find("other", "application/x-protobuf").is_none());
+        let archived = registry.find("orders/archived/1", "application/protobuf");
+        assert_eq!(archived.unwrap().name, "test.ArchivedOrder");
+    }
+}
@@ -0,0 +1,166 @@
+//! Snapshots for clients which read several keys over more than one request, and need
+//! all of them to be read from the same version of the store.
+//!
+//! `POST /_snapshots` takes a snapshot and responds with a token. `GET /{key}` and
+//! `GET /_keys` read from the snapshot instead of the current entries when passed the
+//! token as `?snapshot=`. Snapshots are dropped `snapshot_ttl` seconds after they
+//! were taken, when they're released with `DELETE /_snapshots/{token}`, and when the
+//! store is compacted, see `KVStore::snapshot`.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    http::header::RETRY_AFTER, web, HttpRequest, HttpResponse, Responder, ResponseError,
+};
+use serde::Serialize;
+
+use crate::{authorize, AppState};
+use polling_test::auth::Operation;
+use polling_test::kv::{backend::StorageBackend, store::Snapshot};
+
+/// Most snapshots which may be open at the same time. Every one holds a copy of the
+/// index.
+const MAX_SNAPSHOTS: usize = 16;
+
+/// The snapshots which were taken, and neither expired nor released yet.
+pub(crate) struct Snapshots {
+    open: Mutex<HashMap<String, (Arc<Snapshot>, Instant)>>,
+    /// How long snapshots are kept after they were taken.
+    ttl: Duration,
+}
+
+impl Snapshots {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        Self {
+            open: Mutex::new(HashMap::new()),
+            ttl,
+        }
+    }
+
+    /// Whether another snapshot may be taken at `now`.
+    fn has_room(&self, now: Instant) -> bool {
+        let mut open = self.open.lock().unwrap();
+        // Snapshots which weren't released would pile up otherwise.
+        open.retain(|_, (_, expires_at)| *expires_at > now);
+        open.len() < MAX_SNAPSHOTS
+    }
+
+    /// Keeps `snapshot` until `ttl` after `now`, returning its token, unless too many
+    /// are open already.
+    fn insert(&self, snapshot: Snapshot, now: Instant) -> Option<String> {
+        if !self.has_room(now) {
+            return None;
+        }
+        let token = format!("{:032x}", rand::random::<u128>());
+        let expires_at = now + self.ttl;
+        let mut open = self.open.lock().unwrap();
+        open.insert(token.clone(), (Arc::new(snapshot), expires_at));
+        Some(token)
+    }
+
+    /// The snapshot of `token`, unless it doesn't exist or expired.
+    pub(crate) fn get(&self, token: &str, now: Instant) -> Option<Arc<Snapshot>> {
+        let open = self.open.lock().unwrap();
+        let (snapshot, expires_at) = open.get(token)?;
+        (*expires_at > now).then(|| snapshot.clone())
+    }
+
+    /// Drops the snapshot of `token`, returning whether it existed.
+    fn remove(&self, token: &str, now: Instant) -> bool {
+        let removed = self.open.lock().unwrap().remove(token);
+        removed.is_some_and(|(_, expires_at)| expires_at > now)
+    }
+}
+
+/// The response to reads from a snapshot which doesn't exist or expired. Reads from
+/// snapshots invalidated by compacting the store fail with
+/// `KVError::SnapshotInvalidated`, which is answered with 410 as well.
+pub(crate) fn snapshot_gone() -> HttpResponse {
+    HttpResponse::Gone().body("Snapshot does not exist or expired")
+}
+
+#[derive(Serialize)]
+struct Taken {
+    token: String,
+    /// Seconds until the snapshot expires.
+    expires_in: u64,
+}
+
+/// Takes a snapshot of the store, responding with its token. Reading from the
+/// snapshot is authorized like any other read, so this only checks that the client
+/// may list the whole store.
+pub(crate) async fn take_snapshot<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+) -> impl Responder {
+    if let Some(response) = authorize(&req, &data, Operation::List, "").await {
+        return response;
+    }
+    let too_many = || {
+        HttpResponse::ServiceUnavailable()
+            .insert_header((RETRY_AFTER, "1"))
+            .body("Too many open snapshots")
+    };
+    // Copying the index holds up the store, so the limit is checked before.
+    if !data.snapshots.has_room(Instant::now()) {
+        return too_many();
+    }
+    let snapshot = data
+        .store
+        .run(|store| Box::pin(async move { store.snapshot() }));
+    let snapshot = match snapshot.await {
+        Ok(snapshot) => snapshot,
+        Err(e) => return e.error_response(),
+    };
+    match data.snapshots.insert(snapshot, Instant::now()) {
+        Some(token) => HttpResponse::Ok().json(Taken {
+            token,
+            expires_in: data.snapshots.ttl.as_secs(),
+        }),
+        None => too_many(),
+    }
+}
+
+/// Releases the snapshot of `{token}` before it expires.
+pub(crate) async fn release_snapshot<B: StorageBackend>(
+    data: web::Data<AppState<B>>,
+    token: web::Path<String>,
+) -> impl Responder {
+    if data.snapshots.remove(&token, Instant::now()) {
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::NotFound().finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use polling_test::kv::{backend::MemoryBackend, store::KVStore};
+
+    #[tokio::test]
+    async fn test_snapshots() {
+        let store = KVStore::new(MemoryBackend::new()).await.unwrap();
+        let snapshots = Snapshots::new(Duration::from_secs(60));
+        let now = Instant::now();
+        let token = snapshots.insert(store.snapshot(), now).unwrap();
+        assert!(snapshots.get(&token, now).is_some());
+        assert!(snapshots.get("unknown", now).is_none());
+        assert!(snapshots.get(&token, now + snapshots.ttl).is_none());
+        assert!(snapshots.remove(&token, now));
+        assert!(!snapshots.remove(&token, now));
+
+        for _ in 0..MAX_SNAPSHOTS {
+            snapshots.insert(store.snapshot(), now).unwrap();
+        }
+        assert!(snapshots.insert(store.snapshot(), now).is_none());
+        // Expired snapshots make room for new ones.
+        assert!(snapshots
+            .insert(store.snapshot(), now + snapshots.ttl)
+            .is_some());
+    }
+}
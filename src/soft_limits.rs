@@ -0,0 +1,189 @@
+//! Soft limits of namespaces, which decide how close a write may come to the limits
+//! on values and metadata before it's answered with a `Warning` header, so clients
+//! notice before they hit the limits.
+//!
+//! Writes which use more than `DEFAULT_SOFT_LIMIT_PERCENT` of a limit are warned
+//! about, unless the namespace has a soft limit of its own. Like with throttles, a
+//! namespace is the part of a key before its first `/` (see `throttle`). Every soft
+//! limit is stored as an entry below `SOFT_LIMIT_PREFIX`, so they survive restarts.
+
+use std::{collections::BTreeMap, sync::RwLock};
+
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+
+use crate::throttle::namespace_of;
+use crate::write_queue::{RemoveOutcome, SetOutcome};
+use crate::{authorize, check_writable, write_error_response, AppState};
+use polling_test::auth::Operation;
+use polling_test::kv::{backend::StorageBackend, entry::Entry, result::KVResult, store::KVStore};
+
+/// Soft limits are stored under this prefix, followed by the namespace.
+const SOFT_LIMIT_PREFIX: &str = "_soft_limits/";
+/// Writes which use more than this percentage of a limit are warned about, in
+/// namespaces without a soft limit.
+pub(crate) const DEFAULT_SOFT_LIMIT_PERCENT: u8 = 80;
+
+/// How close writes to a namespace may come to the limits without a warning.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct SoftLimit {
+    /// Writes which use more than this percentage of a limit are warned about. 100
+    /// turns the warnings off.
+    percent: u8,
+}
+
+/// The soft limits by namespace.
+pub(crate) struct SoftLimits {
+    limits: RwLock<BTreeMap<String, SoftLimit>>,
+}
+
+impl SoftLimits {
+    /// Loads the soft limits stored in the store. Entries which aren't valid soft
+    /// limits are skipped with an error.
+    pub(crate) async fn load<B: StorageBackend>(store: &KVStore<B>) -> KVResult<Self> {
+        let keys: Vec<String> = store
+            .scan_prefix(SOFT_LIMIT_PREFIX)
+            .map(|(key, _)| key)
+            .collect();
+        let mut limits = BTreeMap::new();
+        for key in keys {
+            let Some(entry) = store.peek(&key).await? else {
+                continue;
+            };
+            let namespace = &key[SOFT_LIMIT_PREFIX.len()..];
+            match serde_json::from_slice(&entry.value) {
+                Ok(limit) => _ = limits.insert(namespace.to_owned(), limit),
+                Err(e) => log::error!("Skipping soft limit of namespace {:?}: {}", namespace, e),
+            }
+        }
+        Ok(Self {
+            limits: RwLock::new(limits),
+        })
+    }
+
+    /// The percentage of a limit writes to `key` may use without a warning.
+    pub(crate) fn percent_of(&self, key: &str) -> u8 {
+        let limits = self.limits.read().unwrap();
+        namespace_of(key)
+            .and_then(|namespace| limits.get(namespace))
+            .map_or(DEFAULT_SOFT_LIMIT_PERCENT, |limit| limit.percent)
+    }
+}
+
+#[derive(Serialize)]
+struct NamespaceSoftLimit {
+    namespace: String,
+    #[serde(flatten)]
+    limit: SoftLimit,
+}
+
+/// Lists the soft limits of the namespaces which have one.
+pub(crate) async fn list_soft_limits<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+) -> impl Responder {
+    if let Some(response) = authorize(&req, &data, Operation::Admin, "").await {
+        return response;
+    }
+    let limits = data.soft_limits.limits.read().unwrap();
+    let list: Vec<NamespaceSoftLimit> = limits
+        .iter()
+        .map(|(namespace, limit)| NamespaceSoftLimit {
+            namespace: namespace.clone(),
+            limit: *limit,
+        })
+        .collect();
+    HttpResponse::Ok().json(list)
+}
+
+/// Sets the soft limit of `{namespace}` to the one in the body.
+pub(crate) async fn set_soft_limit<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    namespace: web::Path<String>,
+    limit: web::Json<SoftLimit>,
+) -> impl Responder {
+    if let Some(response) = authorize(&req, &data, Operation::Admin, "").await {
+        return response;
+    }
+    if let Some(response) = check_writable(&data) {
+        return response;
+    }
+    let limit = limit.into_inner();
+    if limit.percent == 0 || limit.percent > 100 {
+        return HttpResponse::BadRequest().body("Invalid percent: Must be from 1 to 100");
+    }
+    let value = serde_json::to_vec(&limit).expect("SoftLimit always serializes");
+    let key = format!("{}{}", SOFT_LIMIT_PREFIX, namespace);
+    match data
+        .writes
+        .set(key, Entry::new(value, "application/json"), |_| true)
+        .await
+    {
+        Ok(SetOutcome::Set) => data.write_guard.record_success(),
+        Ok(SetOutcome::ConditionFailed(_)) => unreachable!("the condition always holds"),
+        Err(e) => {
+            log::error!("Error setting soft limit of {:?}: {}", namespace, e);
+            return write_error_response(&data, &e);
+        }
+    }
+    data.soft_limits
+        .limits
+        .write()
+        .unwrap()
+        .insert(namespace.clone(), limit);
+    HttpResponse::Ok().json(NamespaceSoftLimit {
+        namespace: namespace.into_inner(),
+        limit,
+    })
+}
+
+/// Removes the soft limit of `{namespace}`, so that `DEFAULT_SOFT_LIMIT_PERCENT`
+/// applies again.
+pub(crate) async fn delete_soft_limit<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    namespace: web::Path<String>,
+) -> impl Responder {
+    if let Some(response) = authorize(&req, &data, Operation::Admin, "").await {
+        return response;
+    }
+    if let Some(response) = check_writable(&data) {
+        return response;
+    }
+    let key = format!("{}{}", SOFT_LIMIT_PREFIX, namespace);
+    match data.writes.remove(key, |_| true).await {
+        Ok(RemoveOutcome::Removed) => data.write_guard.record_success(),
+        Ok(RemoveOutcome::NotFound) => return HttpResponse::NotFound().finish(),
+        Ok(RemoveOutcome::ConditionFailed(_)) => unreachable!("the condition always holds"),
+        Err(e) => {
+            log::error!("Error removing soft limit of {:?}: {}", namespace, e);
+            return write_error_response(&data, &e);
+        }
+    }
+    data.soft_limits.limits.write().unwrap().remove(&*namespace);
+    HttpResponse::NoContent().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_soft_limits() {
+        let limits = SoftLimits {
+            limits: RwLock::new(BTreeMap::from([(
+                "tenant".to_owned(),
+                SoftLimit { percent: 50 },
+            )])),
+        };
+        assert_eq!(limits.percent_of("tenant/1"), 50);
+        // Only keys below the namespace are governed by its soft limit.
+        assert_eq!(limits.percent_of("tenant"), DEFAULT_SOFT_LIMIT_PERCENT);
+        assert_eq!(limits.percent_of("other/1"), DEFAULT_SOFT_LIMIT_PERCENT);
+
+        assert!(serde_json::from_str::<SoftLimit>(r#"{"percent": 90}"#).is_ok());
+        assert!(serde_json::from_str::<SoftLimit>(r#"{"percent": 300}"#).is_err());
+    }
+}
@@ -0,0 +1,282 @@
+//! Write throttles for namespaces which apply while the store is compacted, so that
+//! bulk writers to some namespaces back off instead of slowing down everyone else.
+//!
+//! A namespace is the part of a key before its first `/`, whether or not it was
+//! created as an ephemeral namespace (see `namespaces`). Every throttle is stored as an
+//! entry below `THROTTLE_PREFIX`, so throttles survive restarts.
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        RwLock,
+    },
+    time::Duration,
+};
+
+use actix_web::{http::header::RETRY_AFTER, web, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+
+use crate::write_queue::{RemoveOutcome, SetOutcome};
+use crate::{authorize, check_writable, write_error_response, AppState};
+use polling_test::auth::Operation;
+use polling_test::kv::{backend::StorageBackend, entry::Entry, result::KVResult, store::KVStore};
+
+/// Throttle entries are stored under this prefix, followed by the namespace.
+const THROTTLE_PREFIX: &str = "_throttles/";
+/// Longest delay a throttle may add to a write.
+const MAX_THROTTLE_DELAY: Duration = Duration::from_secs(10);
+
+/// What happens to writes to a namespace while the store is compacted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub(crate) enum Throttle {
+    /// Writes wait this many milliseconds before they're queued.
+    Delay { millis: u64 },
+    /// Writes are answered with 429 Too Many Requests.
+    Reject,
+}
+
+impl Throttle {
+    /// Whether this throttle holds back writes more than `other`.
+    fn is_stricter_than(&self, other: &Throttle) -> bool {
+        match (self, other) {
+            (Throttle::Reject, Throttle::Delay { .. }) => true,
+            (Throttle::Delay { millis }, Throttle::Delay { millis: other }) => millis > other,
+            _ => false,
+        }
+    }
+}
+
+/// The namespace of a key, if it has one.
+pub(crate) fn namespace_of(key: &str) -> Option<&str> {
+    key.split_once('/').map(|(namespace, _)| namespace)
+}
+
+/// The throttles by namespace, and whether they currently apply.
+pub(crate) struct Throttles {
+    throttles: RwLock<BTreeMap<String, Throttle>>,
+    compacting: AtomicBool,
+}
+
+impl Throttles {
+    /// Loads the throttles stored in the store. Entries which aren't valid throttles
+    /// are skipped with an error.
+    pub(crate) async fn load<B: StorageBackend>(store: &KVStore<B>) -> KVResult<Self> {
+        let keys: Vec<String> = store
+            .scan_prefix(THROTTLE_PREFIX)
+            .map(|(key, _)| key)
+            .collect();
+        let mut throttles = BTreeMap::new();
+        for key in keys {
+            let Some(entry) = store.peek(&key).await? else {
+                continue;
+            };
+            let namespace = &key[THROTTLE_PREFIX.len()..];
+            match serde_json::from_slice(&entry.value) {
+                Ok(throttle) => _ = throttles.insert(namespace.to_owned(), throttle),
+                Err(e) => log::error!("Skipping throttle of namespace {:?}: {}", namespace, e),
+            }
+        }
+        Ok(Self {
+            throttles: RwLock::new(throttles),
+            compacting: AtomicBool::new(false),
+        })
+    }
+
+    /// Makes the throttles apply until the returned guard is dropped.
+    pub(crate) fn compacting(&self) -> CompactionGuard<'_> {
+        self.compacting.store(true, Ordering::SeqCst);
+        CompactionGuard(&self.compacting)
+    }
+
+    /// The strictest throttle of the namespaces of `keys`.
+    fn strictest<'a>(&self, keys: impl IntoIterator<Item = &'a str>) -> Option<Throttle> {
+        let throttles = self.throttles.read().unwrap();
+        keys.into_iter()
+            .filter_map(namespace_of)
+            .filter_map(|namespace| throttles.get(namespace).copied())
+            .reduce(|strictest, throttle| {
+                if throttle.is_stricter_than(&strictest) {
+                    throttle
+                } else {
+                    strictest
+                }
+            })
+    }
+}
+
+/// Keeps the throttles applying while the store is compacted.
+pub(crate) struct CompactionGuard<'a>(&'a AtomicBool);
+
+impl Drop for CompactionGuard<'_> {
+    fn drop(&mut self) {
+        self.0.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Applies the strictest throttle of the namespaces of `keys`, if the store is being
+/// compacted: Waits for its delay, or returns the response to send if it rejects
+/// the write.
+pub(crate) async fn throttle<'a, B: StorageBackend>(
+    data: &AppState<B>,
+    keys: impl IntoIterator<Item = &'a str>,
+) -> Option<HttpResponse> {
+    if !data.throttles.compacting.load(Ordering::SeqCst) {
+        return None;
+    }
+    match data.throttles.strictest(keys)? {
+        Throttle::Delay { millis } => {
+            tokio::time::sleep(Duration::from_millis(millis)).await;
+            None
+        }
+        Throttle::Reject => Some(
+            HttpResponse::TooManyRequests()
+                .insert_header((RETRY_AFTER, "1"))
+                .body("Writes to this namespace are throttled during compaction"),
+        ),
+    }
+}
+
+#[derive(Serialize)]
+struct NamespaceThrottle {
+    namespace: String,
+    #[serde(flatten)]
+    throttle: Throttle,
+}
+
+#[derive(Serialize)]
+struct ThrottlePolicy {
+    /// Whether the throttles currently apply.
+    compacting: bool,
+    throttles: Vec<NamespaceThrottle>,
+}
+
+/// Lists the throttles, and whether they currently apply.
+pub(crate) async fn list_throttles<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+) -> impl Responder {
+    if let Some(response) = authorize(&req, &data, Operation::Admin, "").await {
+        return response;
+    }
+    let throttles = data.throttles.throttles.read().unwrap();
+    HttpResponse::Ok().json(ThrottlePolicy {
+        compacting: data.throttles.compacting.load(Ordering::SeqCst),
+        throttles: throttles
+            .iter()
+            .map(|(namespace, throttle)| NamespaceThrottle {
+                namespace: namespace.clone(),
+                throttle: *throttle,
+            })
+            .collect(),
+    })
+}
+
+/// Sets the throttle of `{namespace}` to the one in the body, replacing any it had.
+pub(crate) async fn set_throttle<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    namespace: web::Path<String>,
+    throttle: web::Json<Throttle>,
+) -> impl Responder {
+    if let Some(response) = authorize(&req, &data, Operation::Admin, "").await {
+        return response;
+    }
+    if let Some(response) = check_writable(&data) {
+        return response;
+    }
+    let throttle = throttle.into_inner();
+    if let Throttle::Delay { millis } = throttle {
+        if millis > MAX_THROTTLE_DELAY.as_millis() as u64 {
+            return HttpResponse::BadRequest().body(format!(
+                "Delay exceeds the limit of {} milliseconds",
+                MAX_THROTTLE_DELAY.as_millis()
+            ));
+        }
+    }
+    let value = serde_json::to_vec(&throttle).expect("Throttle always serializes");
+    let key = format!("{}{}", THROTTLE_PREFIX, namespace);
+    match data
+        .writes
+        .set(key, Entry::new(value, "application/json"), |_| true)
+        .await
+    {
+        Ok(SetOutcome::Set) => data.write_guard.record_success(),
+        Ok(SetOutcome::ConditionFailed(_)) => unreachable!("the condition always holds"),
+        Err(e) => {
+            log::error!("Error setting throttle of {:?}: {}", namespace, e);
+            return write_error_response(&data, &e);
+        }
+    }
+    data.throttles
+        .throttles
+        .write()
+        .unwrap()
+        .insert(namespace.clone(), throttle);
+    HttpResponse::Ok().json(NamespaceThrottle {
+        namespace: namespace.into_inner(),
+        throttle,
+    })
+}
+
+/// Removes the throttle of `{namespace}`.
+pub(crate) async fn delete_throttle<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    namespace: web::Path<String>,
+) -> impl Responder {
+    if let Some(response) = authorize(&req, &data, Operation::Admin, "").await {
+        return response;
+    }
+    if let Some(response) = check_writable(&data) {
+        return response;
+    }
+    let key = format!("{}{}", THROTTLE_PREFIX, namespace);
+    match data.writes.remove(key, |_| true).await {
+        Ok(RemoveOutcome::Removed) => data.write_guard.record_success(),
+        Ok(RemoveOutcome::NotFound) => return HttpResponse::NotFound().finish(),
+        Ok(RemoveOutcome::ConditionFailed(_)) => unreachable!("the condition always holds"),
+        Err(e) => {
+            log::error!("Error removing throttle of {:?}: {}", namespace, e);
+            return write_error_response(&data, &e);
+        }
+    }
+    data.throttles
+        .throttles
+        .write()
+        .unwrap()
+        .remove(&*namespace);
+    HttpResponse::NoContent().finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strictest() {
+        let throttles = Throttles {
+            throttles: RwLock::new(BTreeMap::from([
+                ("bulk".to_owned(), Throttle::Delay { millis: 100 }),
+                ("slow".to_owned(), Throttle::Delay { millis: 500 }),
+                ("imports".to_owned(), Throttle::Reject),
+            ])),
+            compacting: AtomicBool::new(false),
+        };
+        assert_eq!(throttles.strictest(["users/1", "bulk"]), None);
+        assert_eq!(
+            throttles.strictest(["bulk/1", "slow/1", "bulk/2"]),
+            Some(Throttle::Delay { millis: 500 })
+        );
+        assert_eq!(
+            throttles.strictest(["slow/1", "imports/1"]),
+            Some(Throttle::Reject)
+        );
+
+        let guard = throttles.compacting();
+        assert!(throttles.compacting.load(Ordering::SeqCst));
+        drop(guard);
+        assert!(!throttles.compacting.load(Ordering::SeqCst));
+    }
+}
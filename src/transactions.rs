@@ -0,0 +1,424 @@
+//! Transactions for clients which write several keys at once, but can't send all of
+//! the writes in a single `/_batch` request.
+//!
+//! `POST /_txn/begin` starts a transaction, after which sets and removals sent to
+//! `/_txn/{id}/keys/{key}` are buffered instead of applied. `POST /_txn/{id}/commit`
+//! applies all of them at once, with a single write to the backing storage, see
+//! `KVStore::write_many`, and `POST /_txn/{id}/abort` drops them. Transactions which
+//! aren't used for `TRANSACTION_TIMEOUT` are dropped as well.
+//!
+//! Only requests with the bearer token of the request which began a transaction may
+//! use it, and to everyone else it doesn't exist.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::{http::header::RETRY_AFTER, web, HttpRequest, HttpResponse, Responder};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::mime_policy::check_content_type;
+use crate::{
+    authorize, check_reserved, check_writable, entry_from_request, throttle, write_error_response,
+    AppState,
+};
+use polling_test::auth::{Identity, Operation};
+use polling_test::kv::{backend::StorageBackend, store::Write};
+
+/// Transactions are dropped once they weren't used for this long.
+const TRANSACTION_TIMEOUT: Duration = Duration::from_secs(60);
+/// Most transactions which may be open at the same time.
+const MAX_OPEN_TRANSACTIONS: usize = 1024;
+/// Most writes a single transaction may buffer.
+const MAX_TRANSACTION_WRITES: usize = 1000;
+/// Most bytes of keys and values a single transaction may buffer.
+const MAX_TRANSACTION_SIZE: usize = 16 * 1024 * 1024;
+/// Most bytes of keys and values all open transactions may buffer together, as each
+/// of them may only use a fraction of it.
+const MAX_TRANSACTIONS_SIZE: usize = 256 * 1024 * 1024;
+
+/// The SHA-256 digest of the bearer token of the request which began a transaction,
+/// if it had one.
+type Owner = Option<[u8; 32]>;
+
+fn owner(req: &HttpRequest) -> Owner {
+    let token = Identity::from_request(req).bearer_token?;
+    Some(Sha256::digest(token).into())
+}
+
+struct Transaction {
+    owner: Owner,
+    writes: Vec<Write>,
+    /// Bytes of the keys and values of the writes.
+    size: usize,
+    expires_at: Instant,
+}
+
+/// Why a write wasn't added to a transaction.
+#[derive(Debug, PartialEq)]
+enum Rejected {
+    /// The transaction doesn't exist, or it expired.
+    NotFound,
+    /// The transaction holds too many writes or bytes to add it.
+    TooLarge,
+    /// All open transactions together hold too many bytes to add it.
+    Full,
+}
+
+/// The transactions which were started, and neither committed nor aborted yet.
+pub(crate) struct Transactions {
+    open: Mutex<Open>,
+    /// Most bytes all of them may buffer together, see `MAX_TRANSACTIONS_SIZE`.
+    max_size: usize,
+}
+
+#[derive(Default)]
+struct Open {
+    transactions: HashMap<String, Transaction>,
+    /// Bytes of the keys and values of all of them.
+    size: usize,
+}
+
+impl Open {
+    /// The transaction `id`, unless it expired, or `owner` didn't begin it.
+    fn get_mut(&mut self, id: &str, owner: &Owner, now: Instant) -> Option<&mut Transaction> {
+        self.transactions
+            .get_mut(id)
+            .filter(|transaction| transaction.expires_at > now && transaction.owner == *owner)
+    }
+}
+
+impl Transactions {
+    pub(crate) fn new() -> Self {
+        Self::with_max_size(MAX_TRANSACTIONS_SIZE)
+    }
+
+    fn with_max_size(max_size: usize) -> Self {
+        Self {
+            open: Mutex::new(Open::default()),
+            max_size,
+        }
+    }
+
+    /// Starts a transaction of `owner` which expires `TRANSACTION_TIMEOUT` after
+    /// `now`, and returns its ID, unless too many are open already.
+    fn begin(&self, owner: Owner, now: Instant) -> Option<String> {
+        let mut open = self.open.lock().unwrap();
+        // Abandoned transactions would pile up otherwise.
+        let mut expired = 0;
+        open.transactions.retain(|_, transaction| {
+            let keep = transaction.expires_at > now;
+            if !keep {
+                expired += transaction.size;
+            }
+            keep
+        });
+        open.size -= expired;
+        if open.transactions.len() >= MAX_OPEN_TRANSACTIONS {
+            return None;
+        }
+        let id = format!("{:032x}", rand::random::<u128>());
+        let transaction = Transaction {
+            owner,
+            writes: Vec::new(),
+            size: 0,
+            expires_at: now + TRANSACTION_TIMEOUT,
+        };
+        open.transactions.insert(id.clone(), transaction);
+        Some(id)
+    }
+
+    /// Buffers `write` in the transaction, which then expires `TRANSACTION_TIMEOUT`
+    /// after `now`. Returns how many writes it buffers.
+    fn add(&self, id: &str, owner: &Owner, write: Write, now: Instant) -> Result<usize, Rejected> {
+        let mut open = self.open.lock().unwrap();
+        let len = match &write {
+            Write::Set(key, entry) => key.len() + entry.value.len(),
+            Write::Remove(key) => key.len(),
+        };
+        let total = open.size + len;
+        let transaction = open.get_mut(id, owner, now).ok_or(Rejected::NotFound)?;
+        let size = transaction.size + len;
+        if transaction.writes.len() >= MAX_TRANSACTION_WRITES || size > MAX_TRANSACTION_SIZE {
+            return Err(Rejected::TooLarge);
+        }
+        if total > self.max_size {
+            return Err(Rejected::Full);
+        }
+        transaction.writes.push(write);
+        transaction.size = size;
+        transaction.expires_at = now + TRANSACTION_TIMEOUT;
+        let writes = transaction.writes.len();
+        open.size = total;
+        Ok(writes)
+    }
+
+    /// The keys the transaction writes, unless it doesn't exist or expired.
+    fn keys(&self, id: &str, owner: &Owner, now: Instant) -> Option<Vec<String>> {
+        let mut open = self.open.lock().unwrap();
+        let transaction = open.get_mut(id, owner, now)?;
+        let keys = transaction.writes.iter().map(|write| match write {
+            Write::Set(key, _) | Write::Remove(key) => key.clone(),
+        });
+        Some(keys.collect())
+    }
+
+    /// Ends the transaction, returning its writes, unless it doesn't exist or expired.
+    fn take(&self, id: &str, owner: &Owner, now: Instant) -> Option<Vec<Write>> {
+        let mut open = self.open.lock().unwrap();
+        if open.transactions.get(id)?.owner != *owner {
+            return None;
+        }
+        let transaction = open.transactions.remove(id)?;
+        open.size -= transaction.size;
+        (transaction.expires_at > now).then_some(transaction.writes)
+    }
+}
+
+#[derive(Serialize)]
+struct Started {
+    id: String,
+    /// Seconds the transaction stays open without being used.
+    expires_in: u64,
+}
+
+#[derive(Serialize)]
+struct Buffered {
+    /// How many writes the transaction buffers now.
+    writes: usize,
+}
+
+#[derive(Serialize)]
+struct Committed {
+    written: usize,
+}
+
+fn not_found() -> HttpResponse {
+    HttpResponse::NotFound().body("Transaction does not exist or expired")
+}
+
+/// Starts a transaction, responding with its ID.
+pub(crate) async fn begin<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+) -> impl Responder {
+    match data.transactions.begin(owner(&req), Instant::now()) {
+        Some(id) => HttpResponse::Ok().json(Started {
+            id,
+            expires_in: TRANSACTION_TIMEOUT.as_secs(),
+        }),
+        None => HttpResponse::ServiceUnavailable()
+            .insert_header((RETRY_AFTER, "1"))
+            .body("Too many open transactions"),
+    }
+}
+
+/// Buffers `write` in the transaction `id`, responding with how many writes it
+/// buffers now.
+fn buffer<B: StorageBackend>(
+    req: &HttpRequest,
+    data: &AppState<B>,
+    id: &str,
+    write: Write,
+) -> HttpResponse {
+    match data
+        .transactions
+        .add(id, &owner(req), write, Instant::now())
+    {
+        Ok(writes) => HttpResponse::Accepted().json(Buffered { writes }),
+        Err(Rejected::NotFound) => not_found(),
+        Err(Rejected::TooLarge) => HttpResponse::PayloadTooLarge().body(format!(
+            "Transactions are limited to {} writes and {} bytes",
+            MAX_TRANSACTION_WRITES, MAX_TRANSACTION_SIZE
+        )),
+        Err(Rejected::Full) => HttpResponse::ServiceUnavailable()
+            .insert_header((RETRY_AFTER, "1"))
+            .body("Open transactions buffer too many bytes"),
+    }
+}
+
+/// Buffers setting `{key}` to the body, like `POST /{key}` without preconditions.
+pub(crate) async fn set_value<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    path: web::Path<(String, String)>,
+    value: web::Bytes,
+) -> impl Responder {
+    let (id, key) = path.into_inner();
+    if let Some(response) = authorize(&req, &data, Operation::Write, &key).await {
+        return response;
+    }
+    if let Some(response) = check_reserved(&key) {
+        return response;
+    }
+    if let Some(response) = check_content_type(&req, &data, &key) {
+        return response;
+    }
+    let entry = match entry_from_request(&req, &data, &key, value) {
+        Ok((entry, _)) => entry,
+        Err(e) => return HttpResponse::BadRequest().body(e),
+    };
+    buffer(&req, &data, &id, Write::Set(key, entry))
+}
+
+/// Buffers removing `{key}`. Keys which don't exist by the time the transaction is
+/// committed are skipped.
+pub(crate) async fn delete_value<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    path: web::Path<(String, String)>,
+) -> impl Responder {
+    let (id, key) = path.into_inner();
+    if let Some(response) = authorize(&req, &data, Operation::Delete, &key).await {
+        return response;
+    }
+    if let Some(response) = check_reserved(&key) {
+        return response;
+    }
+    buffer(&req, &data, &id, Write::Remove(key))
+}
+
+/// Applies the writes of the transaction all at once, and ends it. The transaction
+/// ends even if the writes fail, in which case none of them are applied.
+pub(crate) async fn commit<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    id: web::Path<String>,
+) -> impl Responder {
+    if let Some(response) = check_writable(&data) {
+        return response;
+    }
+    let owner = owner(&req);
+    let Some(keys) = data.transactions.keys(&id, &owner, Instant::now()) else {
+        return not_found();
+    };
+    if let Some(response) = throttle(&data, keys.iter().map(String::as_str)).await {
+        return response;
+    }
+    let Some(writes) = data.transactions.take(&id, &owner, Instant::now()) else {
+        return not_found();
+    };
+    let written = writes.len();
+    match data.writes.write_many(writes).await {
+        Ok(()) => data.write_guard.record_success(),
+        Err(e) => {
+            log::error!("Error committing transaction of {} writes: {}", written, e);
+            return write_error_response(&data, &e);
+        }
+    }
+    HttpResponse::Ok().json(Committed { written })
+}
+
+/// Drops the writes of the transaction, and ends it.
+pub(crate) async fn abort<B: StorageBackend>(
+    req: HttpRequest,
+    data: web::Data<AppState<B>>,
+    id: web::Path<String>,
+) -> impl Responder {
+    match data.transactions.take(&id, &owner(&req), Instant::now()) {
+        Some(_) => HttpResponse::NoContent().finish(),
+        None => not_found(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use polling_test::kv::entry::Entry;
+
+    #[test]
+    fn test_transactions() {
+        let transactions = Transactions::new();
+        let now = Instant::now();
+        let id = transactions.begin(None, now).unwrap();
+        let remove = |key: &str| Write::Remove(key.to_owned());
+        assert_eq!(transactions.add(&id, &None, remove("a"), now), Ok(1));
+        assert_eq!(transactions.add(&id, &None, remove("b"), now), Ok(2));
+        assert_eq!(
+            transactions.add("unknown", &None, remove("a"), now),
+            Err(Rejected::NotFound)
+        );
+        assert_eq!(transactions.keys(&id, &None, now).unwrap(), ["a", "b"]);
+        assert_eq!(transactions.take(&id, &None, now).unwrap().len(), 2);
+        assert!(transactions.take(&id, &None, now).is_none());
+
+        // Every write keeps the transaction open for longer.
+        let id = transactions.begin(None, now).unwrap();
+        let later = now + TRANSACTION_TIMEOUT / 2;
+        transactions.add(&id, &None, remove("a"), later).unwrap();
+        assert!(transactions
+            .keys(&id, &None, now + TRANSACTION_TIMEOUT)
+            .is_some());
+        assert!(transactions
+            .take(&id, &None, later + TRANSACTION_TIMEOUT)
+            .is_none());
+    }
+
+    #[test]
+    fn test_transaction_owner() {
+        let transactions = Transactions::new();
+        let now = Instant::now();
+        let owner = Some([1; 32]);
+        let id = transactions.begin(owner, now).unwrap();
+        let remove = Write::Remove("a".to_owned());
+        for other in [None, Some([2; 32])] {
+            assert_eq!(
+                transactions.add(&id, &other, remove.clone(), now),
+                Err(Rejected::NotFound)
+            );
+            assert!(transactions.keys(&id, &other, now).is_none());
+            assert!(transactions.take(&id, &other, now).is_none());
+        }
+        assert_eq!(transactions.add(&id, &owner, remove, now), Ok(1));
+        assert!(transactions.take(&id, &owner, now).is_some());
+    }
+
+    #[test]
+    fn test_transaction_limits() {
+        let transactions = Transactions::new();
+        let now = Instant::now();
+        let id = transactions.begin(None, now).unwrap();
+        for i in 0..MAX_TRANSACTION_WRITES {
+            transactions
+                .add(&id, &None, Write::Remove(i.to_string()), now)
+                .unwrap();
+        }
+        assert_eq!(
+            transactions.add(&id, &None, Write::Remove("a".to_owned()), now),
+            Err(Rejected::TooLarge)
+        );
+
+        for _ in 1..MAX_OPEN_TRANSACTIONS {
+            transactions.begin(None, now).unwrap();
+        }
+        assert!(transactions.begin(None, now).is_none());
+        // Expired transactions make room for new ones.
+        assert!(transactions
+            .begin(None, now + TRANSACTION_TIMEOUT)
+            .is_some());
+    }
+
+    #[test]
+    fn test_transactions_size() {
+        let transactions = Transactions::with_max_size(30);
+        let now = Instant::now();
+        let set = |key: &str| Write::Set(key.to_owned(), Entry::new(vec![0; 9], "text/plain"));
+        let ids: Vec<String> = (0..3)
+            .map(|_| transactions.begin(None, now).unwrap())
+            .collect();
+        for id in &ids {
+            transactions.add(id, &None, set("a"), now).unwrap();
+        }
+        let id = transactions.begin(None, now).unwrap();
+        assert_eq!(
+            transactions.add(&id, &None, set("a"), now),
+            Err(Rejected::Full)
+        );
+        // Ending a transaction makes room for the others.
+        transactions.take(&ids[0], &None, now).unwrap();
+        assert_eq!(transactions.add(&id, &None, set("a"), now), Ok(1));
+    }
+}
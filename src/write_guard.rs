@@ -0,0 +1,193 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use polling_test::kv::result::KVError;
+
+/// State of the circuit breaker in `WriteGuard`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum CircuitState {
+    /// Writes go to the backing storage as usual.
+    Closed,
+    /// Writing failed repeatedly, so writes are rejected up front.
+    Open,
+    /// The backing storage passed a check after failing, and writes are let through
+    /// again. The first one to fail opens the circuit again.
+    HalfOpen,
+}
+
+struct Breaker {
+    state: CircuitState,
+    /// Failures in a row while closed.
+    failures: u32,
+    /// When the circuit opened, or the backing storage last failed a check since.
+    failed_at: Instant,
+    /// How often the circuit opened since the server started.
+    trips: u64,
+}
+
+/// A circuit breaker for writes to the backing storage, which switches the server to
+/// read-only mode when writing fails repeatedly, for example because the disk is full.
+///
+/// While the circuit is open, writes are rejected up front instead of each one failing
+/// on its own, and reads are still served. Once `retry_interval` has passed since the
+/// last failure, the backing storage is checked in the background, and if it passes,
+/// writes are let through again.
+pub(crate) struct WriteGuard {
+    breaker: Mutex<Breaker>,
+    failure_threshold: u32,
+    retry_interval: Duration,
+}
+
+impl WriteGuard {
+    /// The circuit opens after `failure_threshold` failed writes in a row, or right away
+    /// if the storage is full.
+    pub(crate) fn new(failure_threshold: u32, retry_interval: Duration) -> Self {
+        Self {
+            breaker: Mutex::new(Breaker {
+                state: CircuitState::Closed,
+                failures: 0,
+                failed_at: Instant::now(),
+                trips: 0,
+            }),
+            failure_threshold,
+            retry_interval,
+        }
+    }
+
+    /// Whether a write should be attempted right now.
+    pub(crate) fn allows_write(&self) -> bool {
+        self.state() != CircuitState::Open
+    }
+
+    pub(crate) fn state(&self) -> CircuitState {
+        self.breaker.lock().unwrap().state
+    }
+
+    /// How often the circuit opened so far.
+    pub(crate) fn trips(&self) -> u64 {
+        self.breaker.lock().unwrap().trips
+    }
+
+    /// How long clients should wait before retrying a rejected write.
+    pub(crate) fn retry_interval(&self) -> Duration {
+        self.retry_interval
+    }
+
+    pub(crate) fn record_success(&self) {
+        let mut breaker = self.breaker.lock().unwrap();
+        breaker.failures = 0;
+        if breaker.state != CircuitState::Closed {
+            breaker.state = CircuitState::Closed;
+            log::warn!("Writing to the backing storage works again, leaving read-only mode");
+        }
+    }
+
+    pub(crate) fn record_failure(&self, error: &KVError) {
+        let mut breaker = self.breaker.lock().unwrap();
+        breaker.failures += 1;
+        breaker.failed_at = Instant::now();
+        let trips = match breaker.state {
+            CircuitState::Closed => {
+                breaker.failures >= self.failure_threshold || error.is_storage_full()
+            }
+            CircuitState::HalfOpen => true,
+            CircuitState::Open => false,
+        };
+        if !trips {
+            return;
+        }
+        breaker.state = CircuitState::Open;
+        breaker.trips += 1;
+        let reason = if error.is_storage_full() {
+            "storage is full"
+        } else {
+            "write error"
+        };
+        log::error!(
+            "Writing to the backing storage failed ({}), switching to read-only mode: {}",
+            reason,
+            error
+        );
+    }
+
+    /// Whether the circuit is open, and it's time to check the backing storage again.
+    pub(crate) fn check_due(&self) -> bool {
+        let breaker = self.breaker.lock().unwrap();
+        breaker.state == CircuitState::Open && breaker.failed_at.elapsed() >= self.retry_interval
+    }
+
+    /// Records the outcome of checking the backing storage while the circuit is open.
+    pub(crate) fn record_check(&self, result: Result<(), &KVError>) {
+        let mut breaker = self.breaker.lock().unwrap();
+        if breaker.state != CircuitState::Open {
+            return;
+        }
+        match result {
+            Ok(()) => {
+                breaker.state = CircuitState::HalfOpen;
+                log::warn!("The backing storage passed a check, letting writes through again");
+            }
+            Err(error) => {
+                breaker.failed_at = Instant::now();
+                log::debug!("The backing storage failed a check: {}", error);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn test_read_only_until_probe_succeeds() {
+        let guard = WriteGuard::new(3, Duration::from_millis(20));
+        assert!(guard.allows_write());
+
+        guard.record_failure(&KVError::IO(io::ErrorKind::StorageFull.into()));
+        assert!(!guard.allows_write());
+        assert!(!guard.check_due());
+
+        std::thread::sleep(Duration::from_millis(30));
+        assert!(guard.check_due());
+        guard.record_check(Ok(()));
+        assert_eq!(guard.state(), CircuitState::HalfOpen);
+        assert!(guard.allows_write());
+        guard.record_success();
+        assert_eq!(guard.state(), CircuitState::Closed);
+        assert_eq!(guard.trips(), 1);
+    }
+
+    #[test]
+    fn test_trips_after_repeated_failures() {
+        let guard = WriteGuard::new(3, Duration::from_millis(20));
+        let error = || KVError::IO(io::ErrorKind::Other.into());
+        guard.record_failure(&error());
+        guard.record_failure(&error());
+        guard.record_success();
+        guard.record_failure(&error());
+        guard.record_failure(&error());
+        assert!(guard.allows_write());
+        guard.record_failure(&error());
+        assert!(!guard.allows_write());
+
+        // A failed check keeps the circuit open for another interval.
+        std::thread::sleep(Duration::from_millis(30));
+        guard.record_check(Err(&error()));
+        assert!(!guard.check_due());
+        assert_eq!(guard.state(), CircuitState::Open);
+
+        // A write failing while half open opens the circuit right away.
+        std::thread::sleep(Duration::from_millis(30));
+        guard.record_check(Ok(()));
+        guard.record_failure(&error());
+        assert_eq!(guard.state(), CircuitState::Open);
+        assert_eq!(guard.trips(), 2);
+    }
+}
@@ -0,0 +1,780 @@
+//! The store task, which owns the store and is the only one touching it. Writes are
+//! handed to it through a `WriteQueue`, and everything else through a `StoreHandle`.
+
+use std::{
+    fmt,
+    future::Future,
+    panic::AssertUnwindSafe,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Instant,
+};
+
+use actix_web::{
+    http::{header::RETRY_AFTER, StatusCode},
+    HttpResponse, ResponseError,
+};
+use futures_util::FutureExt;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::contention::StoreActivity;
+use crate::health::WriteLatency;
+use polling_test::kv::{
+    backend::StorageBackend,
+    entry::Entry,
+    result::{KVError, KVResult},
+    store::{KVStore, Write},
+};
+
+/// How many writes may wait for the storage before new ones are rejected.
+pub(crate) const WRITE_QUEUE_CAPACITY: usize = 256;
+/// How many reads and maintenance jobs may wait for the store before new ones wait
+/// for room, see `StoreHandle`.
+pub(crate) const STORE_JOB_CAPACITY: usize = 256;
+/// Most sets which are written to the backing storage together, see `run_store`.
+const MAX_GROUP_SIZE: usize = 64;
+/// Values up to this size are handed back when a condition fails, so clients
+/// can retry without fetching the value first.
+pub(crate) const CONFLICT_VALUE_LIMIT: usize = 4096;
+
+/// Decides whether a write goes ahead, given the current value, if there is one.
+type SetCondition = Box<dyn FnOnce(Option<&Entry>) -> bool + Send>;
+/// Decides whether a removal goes ahead, given the current value.
+type RemoveCondition = Box<dyn FnOnce(&Entry) -> bool + Send>;
+
+struct PendingSet {
+    key: String,
+    entry: Entry,
+    condition: SetCondition,
+    reply: oneshot::Sender<Result<SetOutcome, KVError>>,
+}
+
+enum WriteCommand {
+    Set(PendingSet),
+    Remove {
+        key: String,
+        condition: RemoveCondition,
+        reply: oneshot::Sender<Result<RemoveOutcome, KVError>>,
+    },
+    SetMany {
+        entries: Vec<(String, Entry)>,
+        reply: oneshot::Sender<Result<(), KVError>>,
+    },
+    WriteMany {
+        writes: Vec<Write>,
+        reply: oneshot::Sender<Result<(), KVError>>,
+    },
+    Import {
+        entries: Vec<(String, Entry)>,
+        reply: oneshot::Sender<Result<(), KVError>>,
+    },
+    RemovePrefix {
+        prefix: String,
+        reply: oneshot::Sender<Result<usize, KVError>>,
+    },
+    RemoveExpired {
+        reply: oneshot::Sender<Result<usize, KVError>>,
+    },
+    PersistAccessTimes {
+        reply: oneshot::Sender<Result<usize, KVError>>,
+    },
+    SetPinned {
+        key: String,
+        pinned: bool,
+        reply: oneshot::Sender<Result<bool, KVError>>,
+    },
+    Flush {
+        reply: oneshot::Sender<Result<(), KVError>>,
+    },
+}
+
+impl WriteCommand {
+    /// What the command is called in `contention` reports.
+    fn name(&self) -> &'static str {
+        match self {
+            WriteCommand::Set(_) => "set",
+            WriteCommand::Remove { .. } => "remove",
+            WriteCommand::SetMany { .. } => "set_many",
+            WriteCommand::WriteMany { .. } => "write_many",
+            WriteCommand::Import { .. } => "import",
+            WriteCommand::RemovePrefix { .. } => "remove_prefix",
+            WriteCommand::RemoveExpired { .. } => "remove_expired",
+            WriteCommand::PersistAccessTimes { .. } => "persist_access_times",
+            WriteCommand::SetPinned { .. } => "set_pinned",
+            WriteCommand::Flush { .. } => "flush",
+        }
+    }
+}
+
+/// Outcome of `WriteQueue::set`.
+#[derive(Debug)]
+pub(crate) enum SetOutcome {
+    Set,
+    /// The condition failed. Holds the current state of the key, unless it doesn't
+    /// exist.
+    ConditionFailed(Option<Conflict>),
+}
+
+/// Outcome of `WriteQueue::remove`.
+#[derive(Debug)]
+pub(crate) enum RemoveOutcome {
+    Removed,
+    NotFound,
+    ConditionFailed(Conflict),
+}
+
+/// The current state of a key whose write condition failed.
+#[derive(Debug)]
+pub(crate) struct Conflict {
+    pub(crate) etag: String,
+    /// The current value, unless it's larger than `CONFLICT_VALUE_LIMIT`.
+    pub(crate) current: Option<Entry>,
+}
+
+impl Conflict {
+    fn new(current: Entry) -> Self {
+        Self {
+            etag: current.etag(),
+            current: (current.value.len() <= CONFLICT_VALUE_LIMIT).then_some(current),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum WriteError {
+    /// Too many writes are already waiting, the client should retry later.
+    QueueFull,
+    /// The store task is gone, or it panicked while handling the request.
+    Stopped,
+    Store(KVError),
+}
+
+/// Lets read handlers return `WriteError`s with `?`. Failed writes go through
+/// `write_error_response` instead, which also tells the `WriteGuard`.
+impl ResponseError for WriteError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            WriteError::QueueFull => StatusCode::SERVICE_UNAVAILABLE,
+            WriteError::Stopped => StatusCode::INTERNAL_SERVER_ERROR,
+            WriteError::Store(err) => err.status_code(),
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            WriteError::QueueFull => HttpResponse::ServiceUnavailable()
+                .insert_header((RETRY_AFTER, "1"))
+                .body("Too many pending writes"),
+            WriteError::Stopped => {
+                HttpResponse::InternalServerError().body("Store task is not running")
+            }
+            WriteError::Store(err) => err.error_response(),
+        }
+    }
+}
+
+impl fmt::Display for WriteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WriteError::QueueFull => write!(f, "Write queue is full"),
+            WriteError::Stopped => write!(f, "Store task is not running"),
+            WriteError::Store(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+/// Hands writes from the HTTP handlers to the store task, which applies them one
+/// after another.
+///
+/// The queue is bounded, so under overload writes are rejected right away with
+/// `WriteError::QueueFull` instead of piling up behind the store.
+#[derive(Clone)]
+pub(crate) struct WriteQueue {
+    sender: mpsc::Sender<WriteCommand>,
+    /// Writes rejected with `WriteError::QueueFull`.
+    rejected: Arc<AtomicU64>,
+}
+
+/// The receiving end of a `WriteQueue`, to be passed to `run_store`.
+pub(crate) struct WriteCommands(mpsc::Receiver<WriteCommand>);
+
+/// Something for the store task to do with the store, which replies on its own.
+type StoreJob<B> = Box<
+    dyn for<'a> FnOnce(&'a mut KVStore<B>) -> Pin<Box<dyn Future<Output = ()> + Send + 'a>> + Send,
+>;
+
+/// Hands reads and maintenance, like compaction, to the store task. Unlike writes,
+/// these wait for room in the queue instead of being rejected when it's full.
+pub(crate) struct StoreHandle<B: StorageBackend> {
+    sender: mpsc::Sender<StoreJob<B>>,
+    /// Jobs waiting for room in the queue.
+    waiting: AtomicUsize,
+}
+
+/// The receiving end of a `StoreHandle`, to be passed to `run_store`.
+pub(crate) struct StoreJobs<B: StorageBackend>(mpsc::Receiver<StoreJob<B>>);
+
+impl<B: StorageBackend> StoreHandle<B> {
+    pub(crate) fn new(capacity: usize) -> (Self, StoreJobs<B>) {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let handle = Self {
+            sender,
+            waiting: AtomicUsize::new(0),
+        };
+        (handle, StoreJobs(receiver))
+    }
+
+    /// How many jobs are waiting for the store.
+    pub(crate) fn queued(&self) -> usize {
+        self.capacity() - self.sender.capacity()
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.sender.max_capacity()
+    }
+
+    /// How many jobs are waiting for room in the queue, as it's full.
+    pub(crate) fn waiting(&self) -> usize {
+        self.waiting.load(Ordering::Relaxed)
+    }
+
+    /// Whether the store task is still running, and so anything can be done with the
+    /// store.
+    pub(crate) fn is_running(&self) -> bool {
+        !self.sender.is_closed()
+    }
+
+    /// Runs `job` on the store task, between the writes, and returns what it
+    /// returned. Jobs should only read what they need and return, as every other job
+    /// and write waits for them.
+    pub(crate) async fn run<T, F>(&self, job: F) -> Result<T, WriteError>
+    where
+        T: Send + 'static,
+        F: for<'a> FnOnce(&'a mut KVStore<B>) -> Pin<Box<dyn Future<Output = T> + Send + 'a>>
+            + Send
+            + 'static,
+    {
+        let (reply, response) = oneshot::channel();
+        let job: StoreJob<B> = Box::new(move |store| {
+            Box::pin(async move {
+                // Sending only fails if the caller went away.
+                _ = reply.send(job(store).await);
+            })
+        });
+        match self.sender.try_send(job) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(job)) => {
+                self.waiting.fetch_add(1, Ordering::Relaxed);
+                let sent = self.sender.send(job).await;
+                self.waiting.fetch_sub(1, Ordering::Relaxed);
+                sent.map_err(|_| WriteError::Stopped)?;
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => return Err(WriteError::Stopped),
+        }
+        response.await.map_err(|_| WriteError::Stopped)
+    }
+
+    /// Like `run`, for jobs which can fail.
+    pub(crate) async fn try_run<T, F>(&self, job: F) -> Result<T, WriteError>
+    where
+        T: Send + 'static,
+        F: for<'a> FnOnce(
+                &'a mut KVStore<B>,
+            ) -> Pin<Box<dyn Future<Output = KVResult<T>> + Send + 'a>>
+            + Send
+            + 'static,
+    {
+        self.run(job).await?.map_err(WriteError::Store)
+    }
+
+    /// Reads the entry of `key`, see `KVStore::peek`.
+    pub(crate) async fn peek(&self, key: String) -> Result<Option<Entry>, WriteError> {
+        self.try_run(|store| Box::pin(async move { store.peek(&key).await }))
+            .await
+    }
+}
+
+impl WriteQueue {
+    pub(crate) fn new(capacity: usize) -> (Self, WriteCommands) {
+        let (sender, receiver) = mpsc::channel(capacity);
+        let queue = Self {
+            sender,
+            rejected: Arc::new(AtomicU64::new(0)),
+        };
+        (queue, WriteCommands(receiver))
+    }
+
+    /// How many writes are waiting for the store.
+    pub(crate) fn queued(&self) -> usize {
+        self.capacity() - self.sender.capacity()
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.sender.max_capacity()
+    }
+
+    /// How many writes were rejected as the queue was full.
+    pub(crate) fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Sets `key` to `entry` if `condition` holds for its current value. Like for
+    /// `remove`, the condition is checked by the writer.
+    pub(crate) async fn set(
+        &self,
+        key: String,
+        entry: Entry,
+        condition: impl FnOnce(Option<&Entry>) -> bool + Send + 'static,
+    ) -> Result<SetOutcome, WriteError> {
+        let condition = Box::new(condition);
+        self.send(|reply| {
+            WriteCommand::Set(PendingSet {
+                key,
+                entry,
+                condition,
+                reply,
+            })
+        })
+        .await
+    }
+
+    /// Removes `key` if `condition` holds for its current value. The condition is
+    /// checked by the writer, so no other write can happen in between.
+    pub(crate) async fn remove(
+        &self,
+        key: String,
+        condition: impl FnOnce(&Entry) -> bool + Send + 'static,
+    ) -> Result<RemoveOutcome, WriteError> {
+        let condition = Box::new(condition);
+        self.send(|reply| WriteCommand::Remove {
+            key,
+            condition,
+            reply,
+        })
+        .await
+    }
+
+    /// Sets all keys at once, see `KVStore::set_many`.
+    pub(crate) async fn set_many(&self, entries: Vec<(String, Entry)>) -> Result<(), WriteError> {
+        self.send(|reply| WriteCommand::SetMany { entries, reply })
+            .await
+    }
+
+    /// Applies all writes at once, see `KVStore::write_many`.
+    pub(crate) async fn write_many(&self, writes: Vec<Write>) -> Result<(), WriteError> {
+        self.send(|reply| WriteCommand::WriteMany { writes, reply })
+            .await
+    }
+
+    /// Writes a chunk of a bulk import, see `KVStore::import`. Like `flush`, this
+    /// waits for room in the queue instead of being rejected, so that an import
+    /// slows down rather than fails under load.
+    pub(crate) async fn import(&self, entries: Vec<(String, Entry)>) -> Result<(), WriteError> {
+        let (reply, response) = oneshot::channel();
+        self.sender
+            .send(WriteCommand::Import { entries, reply })
+            .await
+            .map_err(|_| WriteError::Stopped)?;
+        response
+            .await
+            .map_err(|_| WriteError::Stopped)?
+            .map_err(WriteError::Store)
+    }
+
+    pub(crate) async fn remove_prefix(&self, prefix: String) -> Result<usize, WriteError> {
+        self.send(|reply| WriteCommand::RemovePrefix { prefix, reply })
+            .await
+    }
+
+    /// Removes every expired entry, see `KVStore::remove_expired`.
+    pub(crate) async fn remove_expired(&self) -> Result<usize, WriteError> {
+        self.send(|reply| WriteCommand::RemoveExpired { reply })
+            .await
+    }
+
+    /// Persists the access times updated since, see `KVStore::persist_access_times`.
+    pub(crate) async fn persist_access_times(&self) -> Result<usize, WriteError> {
+        self.send(|reply| WriteCommand::PersistAccessTimes { reply })
+            .await
+    }
+
+    /// Pins or unpins the entry of `key`, see `KVStore::set_pinned`.
+    pub(crate) async fn set_pinned(&self, key: String, pinned: bool) -> Result<bool, WriteError> {
+        self.send(|reply| WriteCommand::SetPinned { key, pinned, reply })
+            .await
+    }
+
+    /// Persists the access times, and then everything written to the store, once the
+    /// writes queued before are done. Unlike other writes, this waits for room in the
+    /// queue instead of being rejected, as it's meant to be the last one.
+    pub(crate) async fn flush(&self) -> Result<(), WriteError> {
+        let (reply, response) = oneshot::channel();
+        self.sender
+            .send(WriteCommand::Flush { reply })
+            .await
+            .map_err(|_| WriteError::Stopped)?;
+        response
+            .await
+            .map_err(|_| WriteError::Stopped)?
+            .map_err(WriteError::Store)
+    }
+
+    async fn send<T>(
+        &self,
+        command: impl FnOnce(oneshot::Sender<Result<T, KVError>>) -> WriteCommand,
+    ) -> Result<T, WriteError> {
+        let (reply, response) = oneshot::channel();
+        self.sender
+            .try_send(command(reply))
+            .map_err(|err| match err {
+                mpsc::error::TrySendError::Full(_) => {
+                    self.rejected.fetch_add(1, Ordering::Relaxed);
+                    WriteError::QueueFull
+                }
+                mpsc::error::TrySendError::Closed(_) => WriteError::Stopped,
+            })?;
+        response
+            .await
+            .map_err(|_| WriteError::Stopped)?
+            .map_err(WriteError::Store)
+    }
+}
+
+/// Owns the store, and applies queued writes and runs queued jobs one at a time until
+/// every `WriteQueue` and `StoreHandle` is dropped. Jobs don't wait behind a long
+/// queue of writes, as the two queues take turns.
+///
+/// Sets which queued up while the store was busy are committed as a group, with a
+/// single write to the backing storage and a single sync, see `set_group`. What runs
+/// is recorded in `activity`.
+///
+/// A job or write which panics is answered with `WriteError::Stopped`, but the task
+/// goes on with the next one, as nothing else could use the store otherwise.
+pub(crate) async fn run_store<B: StorageBackend>(
+    mut store: KVStore<B>,
+    latency: &WriteLatency,
+    activity: &StoreActivity,
+    mut commands: WriteCommands,
+    mut jobs: StoreJobs<B>,
+) {
+    // A command taken from the queue which didn't fit into the last group.
+    let mut next = None;
+    let (mut writing, mut running) = (true, true);
+    while writing || running {
+        let command = match next.take() {
+            Some(command) => command,
+            None => tokio::select! {
+                command = commands.0.recv(), if writing => match command {
+                    Some(command) => command,
+                    None => {
+                        writing = false;
+                        continue;
+                    }
+                },
+                job = jobs.0.recv(), if running => {
+                    match job {
+                        Some(job) => {
+                            let _hold = activity.hold("job");
+                            let job = AssertUnwindSafe(job(&mut store));
+                            if job.catch_unwind().await.is_err() {
+                                log::error!("A job panicked on the store task");
+                            }
+                        }
+                        None => running = false,
+                    }
+                    continue;
+                }
+            },
+        };
+        let store = &mut store;
+        let name = command.name();
+        let hold = activity.hold(name);
+        let started = Instant::now();
+        // Errors sending replies only mean that the client went away.
+        let applied = AssertUnwindSafe(async {
+            match command {
+                WriteCommand::Set(set) => {
+                    let normalization = store.key_normalization();
+                    let mut group = vec![set];
+                    while group.len() < MAX_GROUP_SIZE {
+                        match commands.0.try_recv() {
+                            // A set of a key already in the group needs to see the
+                            // value the group sets, so it starts the next group.
+                            Ok(WriteCommand::Set(set))
+                                if !group.iter().any(|pending| {
+                                    normalization.normalize(&pending.key)
+                                        == normalization.normalize(&set.key)
+                                }) =>
+                            {
+                                group.push(set)
+                            }
+                            Ok(command) => {
+                                next = Some(command);
+                                break;
+                            }
+                            Err(_) => break,
+                        }
+                    }
+                    set_group(store, group).await;
+                }
+                WriteCommand::Remove {
+                    key,
+                    condition,
+                    reply,
+                } => {
+                    let result = match store.peek(&key).await {
+                        Ok(None) => Ok(RemoveOutcome::NotFound),
+                        Ok(Some(current)) if !condition(&current) => {
+                            Ok(RemoveOutcome::ConditionFailed(Conflict::new(current)))
+                        }
+                        Ok(Some(_)) => store.remove(&key).await.map(|_| RemoveOutcome::Removed),
+                        Err(err) => Err(err),
+                    };
+                    _ = reply.send(result);
+                }
+                WriteCommand::SetMany { entries, reply } => {
+                    _ = reply.send(store.set_many(entries).await);
+                }
+                WriteCommand::WriteMany { writes, reply } => {
+                    _ = reply.send(store.write_many(writes).await);
+                }
+                WriteCommand::Import { entries, reply } => {
+                    _ = reply.send(store.import(entries).await);
+                }
+                WriteCommand::RemovePrefix { prefix, reply } => {
+                    _ = reply.send(store.remove_prefix(&prefix).await);
+                }
+                WriteCommand::RemoveExpired { reply } => {
+                    _ = reply.send(store.remove_expired().await);
+                }
+                WriteCommand::PersistAccessTimes { reply } => {
+                    _ = reply.send(store.persist_access_times().await);
+                }
+                WriteCommand::SetPinned { key, pinned, reply } => {
+                    _ = reply.send(store.set_pinned(&key, pinned).await);
+                }
+                WriteCommand::Flush { reply } => {
+                    let result = match store.persist_access_times().await {
+                        Ok(_) => store.flush().await,
+                        Err(err) => Err(err),
+                    };
+                    _ = reply.send(result);
+                }
+            }
+        });
+        if applied.catch_unwind().await.is_err() {
+            log::error!("Writing with {} panicked on the store task", name);
+        }
+        latency.record(started.elapsed());
+        drop(hold);
+    }
+}
+
+/// Sets every key in `group` whose condition holds with a single `KVStore::set_many`,
+/// and replies to all of them once it's done. Sets the store rejects are answered
+/// right away, so they don't fail the rest of the group.
+async fn set_group<B: StorageBackend>(store: &mut KVStore<B>, group: Vec<PendingSet>) {
+    let mut entries = Vec::with_capacity(group.len());
+    let mut replies = Vec::with_capacity(group.len());
+    for set in group {
+        if let Err(err) = store.validate(&set.key, &set.entry) {
+            _ = set.reply.send(Err(err));
+            continue;
+        }
+        match store.peek(&set.key).await {
+            Ok(current) if (set.condition)(current.as_ref()) => {
+                entries.push((set.key, set.entry));
+                replies.push(set.reply);
+            }
+            Ok(current) => {
+                _ = set
+                    .reply
+                    .send(Ok(SetOutcome::ConditionFailed(current.map(Conflict::new))));
+            }
+            Err(err) => _ = set.reply.send(Err(err)),
+        }
+    }
+    if entries.is_empty() {
+        return;
+    }
+    match store.set_many(entries).await {
+        Ok(()) => {
+            for reply in replies {
+                _ = reply.send(Ok(SetOutcome::Set));
+            }
+        }
+        Err(err) => {
+            for reply in replies {
+                _ = reply.send(Err(err.clone()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use polling_test::kv::backend::MemoryBackend;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_rejects_writes_when_full() {
+        let (queue, _commands) = WriteQueue::new(1);
+        let entry = Entry::new(b"test_value".to_vec(), "text/plain");
+        // Nothing processes the queue, so the first write stays queued.
+        let first = tokio::time::timeout(
+            Duration::from_millis(10),
+            queue.set("a".to_string(), entry.clone(), |_| true),
+        );
+        assert!(first.await.is_err());
+        assert!(matches!(
+            queue.set("b".to_string(), entry, |_| true).await,
+            Err(WriteError::QueueFull)
+        ));
+        assert_eq!(queue.queued(), 1);
+        assert_eq!(queue.rejected(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_run_store() -> Result<(), WriteError> {
+        let store = KVStore::new(MemoryBackend::new())
+            .await
+            .map_err(WriteError::Store)?;
+        let latency = WriteLatency::new();
+        let activity = StoreActivity::new();
+        let (queue, commands) = WriteQueue::new(WRITE_QUEUE_CAPACITY);
+        let (handle, jobs) = StoreHandle::new(STORE_JOB_CAPACITY);
+        let writes = async move {
+            let entry = Entry::new(b"test_value".to_vec(), "text/plain");
+            queue.set("a".to_string(), entry.clone(), |_| true).await?;
+            queue
+                .set("b/1".to_string(), entry.clone(), |_| true)
+                .await?;
+            assert!(matches!(
+                queue
+                    .set("c".to_string(), entry.clone(), |current| current.is_some())
+                    .await?,
+                SetOutcome::ConditionFailed(None)
+            ));
+            match queue.remove("a".to_string(), |_| false).await? {
+                RemoveOutcome::ConditionFailed(conflict) => {
+                    assert_eq!(conflict.etag, entry.etag());
+                    assert_eq!(conflict.current.unwrap().value, entry.value);
+                }
+                outcome => panic!("expected a conflict, got {:?}", outcome),
+            }
+            assert!(matches!(
+                queue.remove("a".to_string(), |_| true).await?,
+                RemoveOutcome::Removed
+            ));
+            assert!(matches!(
+                queue.remove("a".to_string(), |_| true).await?,
+                RemoveOutcome::NotFound
+            ));
+            assert_eq!(queue.remove_prefix("b/".to_string()).await?, 1);
+            queue
+                .set_many(vec![
+                    ("d/1".to_string(), entry.clone()),
+                    ("d/2".to_string(), entry),
+                ])
+                .await?;
+            queue.flush().await?;
+
+            let removed = handle
+                .try_run(|store| Box::pin(async move { store.get("b/1").await }))
+                .await?;
+            assert!(removed.is_none());
+            let count = handle
+                .run(|store| Box::pin(async move { store.scan_prefix("d/").count() }))
+                .await?;
+            assert_eq!(count, 2);
+            Ok(())
+        };
+
+        // The store task stops once the queue and the handle are dropped at the end of
+        // `writes`.
+        let ((), result) = tokio::join!(
+            run_store(store, &latency, &activity, commands, jobs),
+            writes
+        );
+        result
+    }
+
+    #[tokio::test]
+    async fn test_run_store_survives_panics() -> Result<(), WriteError> {
+        let store = KVStore::new(MemoryBackend::new())
+            .await
+            .map_err(WriteError::Store)?;
+        let latency = WriteLatency::new();
+        let activity = StoreActivity::new();
+        let (queue, commands) = WriteQueue::new(WRITE_QUEUE_CAPACITY);
+        let (handle, jobs) = StoreHandle::new(STORE_JOB_CAPACITY);
+        let run = async move {
+            let _queue = queue;
+            let panicked = handle
+                .run(|_| Box::pin(async move { panic!("the job fails") }))
+                .await;
+            assert!(matches!(panicked, Err(WriteError::Stopped)));
+            assert!(handle.is_running());
+            let count = handle
+                .run(|store| Box::pin(async move { store.len() }))
+                .await?;
+            assert_eq!(count, 0);
+            Ok(())
+        };
+
+        let (result, ()) = tokio::join!(run, run_store(store, &latency, &activity, commands, jobs));
+        result
+    }
+
+    #[tokio::test]
+    async fn test_group_commit() -> Result<(), WriteError> {
+        let mut store = KVStore::new(MemoryBackend::new())
+            .await
+            .map_err(WriteError::Store)?;
+        store.set_max_value_size(Some(16));
+        let latency = WriteLatency::new();
+        let activity = StoreActivity::new();
+        let (queue, commands) = WriteQueue::new(WRITE_QUEUE_CAPACITY);
+        let (handle, jobs) = StoreHandle::new(STORE_JOB_CAPACITY);
+        let writes = async move {
+            let entry = Entry::new(b"test_value".to_vec(), "text/plain");
+            // All of these are queued before the writer gets to the first one.
+            let large = Entry::new(vec![0; 17], "text/plain");
+            let (a, b, c, d, again) = tokio::join!(
+                queue.set("a".to_string(), entry.clone(), |_| true),
+                queue.set("b".to_string(), entry.clone(), |_| true),
+                queue.set("c".to_string(), entry.clone(), |current| current.is_some()),
+                queue.set("d".to_string(), large, |_| true),
+                queue.set("a".to_string(), entry.clone(), |current| current.is_some()),
+            );
+            assert!(matches!(a?, SetOutcome::Set));
+            assert!(matches!(b?, SetOutcome::Set));
+            assert!(matches!(c?, SetOutcome::ConditionFailed(None)));
+            // Only fails itself, not the rest of the group.
+            assert!(matches!(
+                d,
+                Err(WriteError::Store(KVError::ValueTooLarge {
+                    len: 17,
+                    max: 16
+                }))
+            ));
+            // Sees the value set earlier in the same batch of queued writes.
+            assert!(matches!(again?, SetOutcome::Set));
+            let count = handle
+                .run(|store| Box::pin(async move { store.len() }))
+                .await?;
+            assert_eq!(count, 2);
+            Ok(())
+        };
+
+        let (result, ()) = tokio::join!(
+            writes,
+            run_store(store, &latency, &activity, commands, jobs)
+        );
+        result
+    }
+}